@@ -0,0 +1,247 @@
+//! A 4-port USB-MIDI to DIN-MIDI interface.
+//!
+//! Every DIN input is routed to the USB cable with the same number and every
+//! USB cable to the DIN output with the same number. The LED of a port lights
+//! up while MIDI arrives on its DIN input, and the user button sends All
+//! Sound Off and All Notes Off on every channel of every output. Input on a
+//! DIN port wakes up a suspended host. Real-time messages overtake anything
+//! else on the way to a DIN output, so the clock stays steady during SysEx
+//! dumps.
+
+#![no_std]
+#![no_main]
+#![feature(type_alias_impl_trait)]
+
+use defmt::{info, warn};
+use embassy_executor::Spawner;
+use embassy_futures::select::{select, Either};
+use embassy_stm32::exti::ExtiInput;
+use embassy_stm32::gpio::{AnyPin, Input, Level, Output, Pin, Pull, Speed};
+use embassy_stm32::time::mhz;
+use embassy_stm32::usart::{self, BasicInstance, RxDma, TxDma, Uart, UartRx, UartTx};
+use embassy_stm32::usb_otg::Driver;
+use embassy_stm32::{interrupt, Config};
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::channel;
+use embassy_time::{with_timeout, Duration};
+use futures::future::{join, join4, join5};
+use usb_midi_rs::cc::{ALL_NOTES_OFF, ALL_SOUND_OFF};
+use usb_midi_rs::{
+    CableNumber, Channel, Counter, DeviceIdentity, DinParser, DinSerializer, Dispatcher, Event, MidiMessage,
+    PowerConfig, Queues, RemoteWakeup, Router, TxQueue, UsbMidiBuffers, UsbMidiClass,
+};
+use {defmt_rtt as _, panic_probe as _};
+
+const PORTS: usize = 4;
+
+/// Offset of the DIN ports in the router, which lists the USB cables first.
+const DIN: usize = PORTS;
+
+const QUEUE_SIZE: usize = 16;
+
+/// How long the LED of a port stays lit after the last received byte.
+const LED_TIME: Duration = Duration::from_millis(20);
+/// Idle time after which a DIN output sends the status byte again.
+const STATUS_REFRESH_TIME: Duration = Duration::from_millis(500);
+
+type DinQueue = channel::Channel<NoopRawMutex, Event, QUEUE_SIZE>;
+
+/// Queues towards the USB and DIN outputs.
+struct Outputs {
+    router: Router<{ 2 * PORTS }>,
+    usb: TxQueue<NoopRawMutex, PORTS>,
+    din: [DinQueue; PORTS],
+    din_real_time: [DinQueue; PORTS],
+    overflows: Counter,
+}
+
+impl Outputs {
+    fn route(&self, source: usize, event: Event) {
+        for destination in self.router.destinations(source) {
+            let sent = match destination.checked_sub(DIN) {
+                None => self.usb.try_write_event(cable(destination), event).is_ok(),
+                Some(port) if event.is_real_time() => self.din_real_time[port].try_send(event).is_ok(),
+                Some(port) => self.din[port].try_send(event).is_ok(),
+            };
+            if !sent {
+                self.overflows.increment();
+            }
+        }
+    }
+
+    /// Silences everything connected to any output.
+    async fn panic(&self) {
+        for channel in 0..16 {
+            for control in [ALL_SOUND_OFF, ALL_NOTES_OFF] {
+                let event = MidiMessage::ControlChange(Channel::new(channel), control, 0).into();
+                for port in 0..PORTS {
+                    self.usb.write_event(cable(port), event).await;
+                    self.din[port].send(event).await;
+                }
+            }
+        }
+    }
+}
+
+fn cable(port: usize) -> CableNumber<PORTS> {
+    CableNumber::new(port as u8).unwrap()
+}
+
+async fn din_port<'d, T: BasicInstance, TxCh: TxDma<T>, RxCh: RxDma<T>>(
+    port: usize,
+    mut tx: UartTx<'d, T, TxCh>,
+    mut rx: UartRx<'d, T, RxCh>,
+    mut led: Output<'d, AnyPin>,
+    outputs: &Outputs,
+    wakeup: &RemoteWakeup<NoopRawMutex>,
+) {
+    let input = async {
+        let mut parser = DinParser::new();
+        let mut byte = [0];
+        loop {
+            match with_timeout(LED_TIME, rx.read(&mut byte)).await {
+                Ok(Ok(())) => {
+                    led.set_high();
+                    if let Some(event) = parser.push(byte[0]) {
+                        wakeup.activity();
+                        outputs.route(DIN + port, event);
+                    }
+                }
+                Ok(Err(error)) => {
+                    parser.framing_error();
+                    warn!("DIN input {}: {} ({} errors)", port, error, parser.errors());
+                }
+                Err(_) => led.set_low(),
+            }
+        }
+    };
+
+    let output = async {
+        let mut serializer = DinSerializer::with_running_status(true);
+        loop {
+            while let Ok(event) = outputs.din_real_time[port].try_recv() {
+                let _ = serializer.push(event);
+            }
+            match serializer.next_byte() {
+                Some(byte) => {
+                    if let Err(error) = tx.write(&[byte]).await {
+                        warn!("DIN output {}: {}", port, error);
+                        serializer.invalidate_running_status();
+                    }
+                }
+                None => {
+                    let next = select(outputs.din[port].recv(), outputs.din_real_time[port].recv());
+                    let event = match with_timeout(STATUS_REFRESH_TIME, next).await {
+                        Ok(Either::First(event) | Either::Second(event)) => event,
+                        Err(_) => {
+                            serializer.invalidate_running_status();
+                            continue;
+                        }
+                    };
+                    let _ = serializer.push(event);
+                }
+            }
+        }
+    };
+
+    join(input, output).await;
+}
+
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    info!("USB-DIN MIDI interface");
+
+    let mut config = Config::default();
+    config.rcc.sys_ck = Some(mhz(180));
+    config.rcc.pll48 = true;
+
+    let p = embassy_stm32::init(config);
+
+    let mut usb_config = DeviceIdentity::DEVELOPMENT
+        .with_product("USB-DIN MIDI interface")
+        .config();
+    PowerConfig::bus_powered(100).configure(&mut usb_config);
+
+    let wakeup = RemoteWakeup::<NoopRawMutex>::new(true);
+    wakeup.configure(&mut usb_config);
+
+    let mut buffers = UsbMidiBuffers::<PORTS>::new();
+    let irq = interrupt::take!(OTG_FS);
+    let (mut builder, state) = buffers.builder(
+        |ep_out_buffer| Driver::new_fs(p.USB_OTG_FS, irq, p.PA12, p.PA11, ep_out_buffer),
+        usb_config,
+    );
+    let midi_class = UsbMidiClass::<_, PORTS>::new(&mut builder, state);
+    let mut usb = builder.build();
+
+    let (mut sender, receiver) = midi_class.split();
+    let queues = Queues::<NoopRawMutex, PORTS>::new();
+    let mut dispatcher = Dispatcher::new(receiver, &queues);
+
+    let mut router = Router::new();
+    for port in 0..PORTS {
+        router.connect(port, DIN + port);
+        router.connect(DIN + port, port);
+    }
+    let outputs = Outputs {
+        router,
+        usb: TxQueue::new(),
+        din: core::array::from_fn(|_| DinQueue::new()),
+        din_real_time: core::array::from_fn(|_| DinQueue::new()),
+        overflows: Counter::new(),
+    };
+
+    let mut uart_config = usart::Config::default();
+    uart_config.baudrate = 31_250;
+    let (tx0, rx0) = Uart::new(p.USART6, p.PC7, p.PC6, p.DMA2_CH6, p.DMA2_CH1, uart_config).split();
+    let (tx1, rx1) = Uart::new(p.USART2, p.PD6, p.PD5, p.DMA1_CH6, p.DMA1_CH5, uart_config).split();
+    let (tx2, rx2) = Uart::new(p.USART3, p.PB11, p.PB10, p.DMA1_CH3, p.DMA1_CH1, uart_config).split();
+    let (tx3, rx3) = Uart::new(p.UART4, p.PC11, p.PC10, p.DMA1_CH4, p.DMA1_CH2, uart_config).split();
+
+    let led0 = Output::new(p.PE2.degrade(), Level::Low, Speed::Low);
+    let led1 = Output::new(p.PE3.degrade(), Level::Low, Speed::Low);
+    let led2 = Output::new(p.PE4.degrade(), Level::Low, Speed::Low);
+    let led3 = Output::new(p.PE5.degrade(), Level::Low, Speed::Low);
+
+    let mut button = ExtiInput::new(Input::new(p.PC13, Pull::Down), p.EXTI13);
+
+    let usb_fut = wakeup.run(&mut usb, &mut ());
+
+    let dispatch_fut = dispatcher.run(&mut ());
+
+    let usb_input = |port: usize| {
+        let queues = &queues;
+        let outputs = &outputs;
+        async move {
+            loop {
+                let event = queues.receive(cable(port)).await;
+                outputs.route(port, event);
+            }
+        }
+    };
+
+    let usb_output_fut = outputs.usb.run(&mut sender);
+
+    let panic_fut = async {
+        loop {
+            button.wait_for_rising_edge().await;
+            info!("Panic! ({} events dropped so far)", outputs.overflows.get());
+            outputs.panic().await;
+        }
+    };
+
+    join5(
+        usb_fut,
+        dispatch_fut,
+        usb_output_fut,
+        join4(usb_input(0), usb_input(1), usb_input(2), usb_input(3)),
+        join5(
+            din_port(0, tx0, rx0, led0, &outputs, &wakeup),
+            din_port(1, tx1, rx1, led1, &outputs, &wakeup),
+            din_port(2, tx2, rx2, led2, &outputs, &wakeup),
+            din_port(3, tx3, rx3, led3, &outputs, &wakeup),
+            panic_fut,
+        ),
+    )
+    .await;
+}
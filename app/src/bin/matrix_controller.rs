@@ -0,0 +1,151 @@
+//! A class-compliant MIDI controller built from a key matrix.
+//!
+//! The first three rows of a 4x8 diode matrix hold 24 keys sending notes,
+//! the last row the switches of four rotary encoders sending controllers.
+//! The matrix is scanned from a timer; its messages go through a `TxQueue`,
+//! so the scan never waits for the host.
+
+#![no_std]
+#![no_main]
+#![feature(type_alias_impl_trait)]
+
+use defmt::{info, warn};
+use embassy_executor::Spawner;
+use embassy_stm32::gpio::{AnyPin, Input, Level, Output, Pin, Pull, Speed};
+use embassy_stm32::time::mhz;
+use embassy_stm32::usb_otg::Driver;
+use embassy_stm32::{interrupt, Config};
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_time::{Duration, Ticker};
+use futures::future::join3;
+use futures::StreamExt;
+use usb_midi_rs::{
+    build_usb_midi_device, CableNumber, Channel, Debouncer, DeviceIdentity, Encoder, KeyMatrix, MidiMessage, Note,
+    TxQueue, UsbMidiBuffers,
+};
+use {defmt_rtt as _, panic_probe as _};
+
+const ROWS: usize = 4;
+const COLUMNS: usize = 8;
+const ENCODER_ROW: usize = 3;
+const ENCODERS: usize = COLUMNS / 2;
+
+const SCAN_INTERVAL: Duration = Duration::from_millis(1);
+const DEBOUNCE_SCANS: u8 = 5;
+
+const CHANNEL: Channel = Channel::new(0);
+const FIRST_NOTE: u8 = 48;
+const VELOCITY: u8 = 100;
+const FIRST_CONTROL: u8 = 16;
+
+struct GpioMatrix<'d> {
+    rows: [Output<'d, AnyPin>; ROWS],
+    columns: [Input<'d, AnyPin>; COLUMNS],
+}
+
+impl KeyMatrix<ROWS> for GpioMatrix<'_> {
+    fn scan(&mut self) -> [u16; ROWS] {
+        let mut state = [0; ROWS];
+        for (row, output) in self.rows.iter_mut().enumerate() {
+            output.set_high();
+            // Let the column lines settle.
+            cortex_m::asm::delay(100);
+            for (column, input) in self.columns.iter().enumerate() {
+                if input.is_high() {
+                    state[row] |= 1 << column;
+                }
+            }
+            output.set_low();
+        }
+        state
+    }
+}
+
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    info!("USB-MIDI matrix controller");
+
+    let mut config = Config::default();
+    config.rcc.sys_ck = Some(mhz(180));
+    config.rcc.pll48 = true;
+
+    let p = embassy_stm32::init(config);
+
+    let mut buffers = UsbMidiBuffers::<1>::new();
+    let irq = interrupt::take!(OTG_FS);
+    let (midi_class, mut usb) = build_usb_midi_device(
+        &mut buffers,
+        |ep_out_buffer| Driver::new_fs(p.USB_OTG_FS, irq, p.PA12, p.PA11, ep_out_buffer),
+        &DeviceIdentity::DEVELOPMENT.with_product("USB-MIDI matrix controller"),
+    );
+
+    let (mut sender, _receiver) = midi_class.split();
+    let cable = CableNumber::new(0).unwrap();
+    let tx = TxQueue::<NoopRawMutex, 1>::new();
+
+    let mut matrix = GpioMatrix {
+        rows: [
+            Output::new(p.PD0.degrade(), Level::Low, Speed::Low),
+            Output::new(p.PD1.degrade(), Level::Low, Speed::Low),
+            Output::new(p.PD2.degrade(), Level::Low, Speed::Low),
+            Output::new(p.PD3.degrade(), Level::Low, Speed::Low),
+        ],
+        columns: [
+            Input::new(p.PE8.degrade(), Pull::Down),
+            Input::new(p.PE9.degrade(), Pull::Down),
+            Input::new(p.PE10.degrade(), Pull::Down),
+            Input::new(p.PE11.degrade(), Pull::Down),
+            Input::new(p.PE12.degrade(), Pull::Down),
+            Input::new(p.PE13.degrade(), Pull::Down),
+            Input::new(p.PE14.degrade(), Pull::Down),
+            Input::new(p.PE15.degrade(), Pull::Down),
+        ],
+    };
+
+    let usb_fut = usb.run();
+
+    let tx_fut = tx.run(&mut sender);
+
+    let scan_fut = async {
+        let mut ticker = Ticker::every(SCAN_INTERVAL);
+        let mut debouncer = Debouncer::<ROWS>::new(DEBOUNCE_SCANS);
+        let mut encoders: [_; ENCODERS] = core::array::from_fn(|_| Encoder::new(4));
+        let mut values = [64u8; ENCODERS];
+
+        loop {
+            ticker.next().await;
+
+            for key in debouncer.update(matrix.scan()) {
+                if key.row == ENCODER_ROW {
+                    continue;
+                }
+                let note = Note::new(FIRST_NOTE + (key.row * COLUMNS + key.column) as u8);
+                let message = if key.pressed {
+                    MidiMessage::NoteOn(CHANNEL, note, VELOCITY)
+                } else {
+                    MidiMessage::NoteOff(CHANNEL, note, 0)
+                };
+                if tx.try_write_message(cable, message).is_err() {
+                    warn!("TX queue full, dropped {}", message);
+                }
+            }
+
+            let switches = debouncer.state()[ENCODER_ROW];
+            for (index, encoder) in encoders.iter_mut().enumerate() {
+                let a = switches & 1 << (2 * index) != 0;
+                let b = switches & 1 << (2 * index + 1) != 0;
+                let detents = encoder.update(a, b);
+                let value = (i16::from(values[index]) + i16::from(detents)).clamp(0, 127) as u8;
+                if value != values[index] {
+                    values[index] = value;
+                    let message = MidiMessage::ControlChange(CHANNEL, FIRST_CONTROL + index as u8, value);
+                    if tx.try_write_message(cable, message).is_err() {
+                        warn!("TX queue full, dropped {}", message);
+                    }
+                }
+            }
+        }
+    };
+
+    join3(usb_fut, tx_fut, scan_fut).await;
+}
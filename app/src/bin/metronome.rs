@@ -0,0 +1,101 @@
+//! A metronome following the MIDI clock of the host.
+//!
+//! Start the transport in the DAW: the green LED flashes on every beat and
+//! the red LED together with a pulse on PG0 on the first beat of every bar.
+//! Events are timestamped when their transfer arrives, so the tempo estimate
+//! is not disturbed by the time they spend in the queue.
+
+#![no_std]
+#![no_main]
+#![feature(type_alias_impl_trait)]
+
+use defmt::info;
+use embassy_executor::Spawner;
+use embassy_stm32::gpio::{Level, Output, Speed};
+use embassy_stm32::time::mhz;
+use embassy_stm32::usb_otg::Driver;
+use embassy_stm32::{interrupt, Config};
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Timer};
+use futures::future::join4;
+use usb_midi_rs::{
+    build_usb_midi_device, CableNumber, ClockEvent, ClockFollower, DeviceIdentity, Dispatcher, MidiMessage, Queues,
+    UsbMidiBuffers,
+};
+use {defmt_rtt as _, panic_probe as _};
+
+const BEATS_PER_BAR: u32 = 4;
+const PULSE_WIDTH: Duration = Duration::from_millis(30);
+
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    info!("USB-MIDI metronome");
+
+    let mut config = Config::default();
+    config.rcc.sys_ck = Some(mhz(180));
+    config.rcc.pll48 = true;
+
+    let p = embassy_stm32::init(config);
+
+    let mut buffers = UsbMidiBuffers::<1>::new();
+    let irq = interrupt::take!(OTG_FS);
+    let (midi_class, mut usb) = build_usb_midi_device(
+        &mut buffers,
+        |ep_out_buffer| Driver::new_fs(p.USB_OTG_FS, irq, p.PA12, p.PA11, ep_out_buffer),
+        &DeviceIdentity::DEVELOPMENT.with_product("USB-MIDI metronome"),
+    );
+
+    let (_sender, receiver) = midi_class.split();
+    let queues = Queues::<NoopRawMutex, 1>::new();
+    let mut dispatcher = Dispatcher::new(receiver, &queues);
+    let cable = CableNumber::new(0).unwrap();
+
+    let mut beat_led = Output::new(p.PB0, Level::Low, Speed::Low);
+    let mut bar_led = Output::new(p.PB14, Level::Low, Speed::Low);
+    let mut sync_out = Output::new(p.PG0, Level::Low, Speed::Low);
+
+    // Signals the beats, `true` for the first beat of a bar.
+    let beats = Signal::<NoopRawMutex, bool>::new();
+
+    let usb_fut = usb.run();
+
+    let dispatch_fut = dispatcher.run(&mut ());
+
+    let clock_fut = async {
+        let mut clock = ClockFollower::new();
+        loop {
+            let (at, event) = queues.receive_timestamped(cable).await;
+            let Some(message) = MidiMessage::from_event(event) else {
+                continue;
+            };
+            match clock.update(message, at) {
+                Some(ClockEvent::Beat(beat)) => {
+                    if beat % BEATS_PER_BAR == 0 {
+                        info!("bar {} at {} BPM", beat / BEATS_PER_BAR + 1, clock.bpm());
+                    }
+                    beats.signal(beat % BEATS_PER_BAR == 0);
+                }
+                Some(event) => info!("{}", event),
+                None => {}
+            }
+        }
+    };
+
+    let pulse_fut = async {
+        loop {
+            let downbeat = beats.wait().await;
+            beat_led.set_high();
+            if downbeat {
+                bar_led.set_high();
+                sync_out.set_high();
+            }
+            Timer::after(PULSE_WIDTH).await;
+            beat_led.set_low();
+            bar_led.set_low();
+            sync_out.set_low();
+        }
+    };
+
+    join4(usb_fut, dispatch_fut, clock_fut, pulse_fut).await;
+}
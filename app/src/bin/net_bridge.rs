@@ -0,0 +1,321 @@
+//! A network MIDI interface bridging an AppleMIDI session to USB and DIN.
+//!
+//! The board gets its address via DHCP and waits for an invitation on UDP
+//! port 5004, e.g. from the Audio MIDI Setup of macOS or from rtpMIDI on
+//! Windows. The session is connected to USB cable 0 and to the DIN port on
+//! USART6, while USB and DIN are connected to each other as well; the router
+//! set up in `main` decides what goes where.
+
+#![no_std]
+#![no_main]
+#![feature(type_alias_impl_trait)]
+
+use core::cell::{Cell, RefCell};
+
+use defmt::{info, unwrap, warn};
+use embassy_executor::Spawner;
+use embassy_net::udp::UdpSocket;
+use embassy_net::{ConfigStrategy, IpEndpoint, PacketMetadata, Stack, StackResources};
+use embassy_stm32::eth::generic_smi::GenericSMI;
+use embassy_stm32::eth::{self, Ethernet};
+use embassy_stm32::peripherals::ETH;
+use embassy_stm32::rng::Rng;
+use embassy_stm32::time::mhz;
+use embassy_stm32::usart::{self, Uart};
+use embassy_stm32::usb_otg::Driver;
+use embassy_stm32::{interrupt, Config};
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::channel;
+use embassy_time::Instant;
+use futures::future::{join, join5};
+use rand_core::RngCore;
+use static_cell::StaticCell;
+use usb_midi_rs::rtp::{self, Port, Session, SessionState};
+use usb_midi_rs::{
+    build_usb_midi_device, CableNumber, Counter, DeviceIdentity, DinParser, Dispatcher, Event, Queues, Router, TxQueue,
+    UsbMidiBuffers,
+};
+use {defmt_rtt as _, panic_probe as _};
+
+const CONTROL_PORT: u16 = 5004;
+const DATA_PORT: u16 = CONTROL_PORT + 1;
+
+/// Nodes of the router.
+const USB: usize = 0;
+const DIN: usize = 1;
+const NET: usize = 2;
+
+const QUEUE_SIZE: usize = 16;
+
+/// Largest datagram handled, enough for the invitations and short SysEx.
+const PACKET_SIZE: usize = 256;
+
+type Device = Ethernet<'static, ETH, GenericSMI, 4, 4>;
+
+type EventQueue = channel::Channel<NoopRawMutex, Event, QUEUE_SIZE>;
+
+/// Queues towards the USB, DIN and network outputs.
+struct Outputs {
+    router: Router<3>,
+    usb: TxQueue<NoopRawMutex, 1>,
+    din: EventQueue,
+    net: EventQueue,
+    overflows: Counter,
+}
+
+impl Outputs {
+    fn route(&self, source: usize, event: Event) {
+        for destination in self.router.destinations(source) {
+            let sent = match destination {
+                USB => self.usb.try_write_event(CableNumber::new(0).unwrap(), event).is_ok(),
+                DIN => self.din.try_send(event).is_ok(),
+                _ => self.net.try_send(event).is_ok(),
+            };
+            if !sent {
+                self.overflows.increment();
+            }
+        }
+    }
+}
+
+/// Local time in the 100 µs units of AppleMIDI.
+fn now() -> u64 {
+    Instant::now().as_micros() / 100
+}
+
+/// Answers invitations on the control port.
+async fn control_port(stack: &Stack<Device>, session: &RefCell<Session<'_>>) {
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0; PACKET_SIZE];
+    let mut tx_buffer = [0; PACKET_SIZE];
+    let mut socket = UdpSocket::new(stack, &mut rx_meta, &mut rx_buffer, &mut tx_meta, &mut tx_buffer);
+    unwrap!(socket.bind(CONTROL_PORT));
+
+    let mut packet = [0; PACKET_SIZE];
+    let mut reply = [0; 64];
+    loop {
+        let (len, remote) = match socket.recv_from(&mut packet).await {
+            Ok(received) => received,
+            Err(error) => {
+                warn!("control port: {}", error);
+                continue;
+            }
+        };
+        let handled = session
+            .borrow_mut()
+            .handle(Port::Control, &packet[..len], now(), &mut reply);
+        match handled {
+            Ok(Some(len)) => {
+                if let Err(error) = socket.send_to(&reply[..len], remote).await {
+                    warn!("control port: {}", error);
+                }
+            }
+            Ok(None) => {}
+            Err(error) => warn!("control port: {}", error),
+        }
+    }
+}
+
+/// Exchanges MIDI and clock synchronization on the data port.
+async fn data_port(stack: &Stack<Device>, session: &RefCell<Session<'_>>, outputs: &Outputs) {
+    let mut rx_meta = [PacketMetadata::EMPTY; 8];
+    let mut tx_meta = [PacketMetadata::EMPTY; 8];
+    let mut rx_buffer = [0; 2 * PACKET_SIZE];
+    let mut tx_buffer = [0; 2 * PACKET_SIZE];
+    let mut socket = UdpSocket::new(stack, &mut rx_meta, &mut rx_buffer, &mut tx_meta, &mut tx_buffer);
+    unwrap!(socket.bind(DATA_PORT));
+
+    // Where the peer receives MIDI, known once it joined the data port.
+    let peer = Cell::<Option<IpEndpoint>>::new(None);
+
+    let input = async {
+        let mut packet = [0; PACKET_SIZE];
+        let mut reply = [0; 64];
+        loop {
+            let (len, remote) = match socket.recv_from(&mut packet).await {
+                Ok(received) => received,
+                Err(error) => {
+                    warn!("data port: {}", error);
+                    continue;
+                }
+            };
+            if let Some(packet) = rtp::decode(&packet[..len]) {
+                if session.borrow().peer() == Some(packet.ssrc) {
+                    for (_, event) in packet.events() {
+                        outputs.route(NET, event);
+                    }
+                }
+                continue;
+            }
+            let handled = session
+                .borrow_mut()
+                .handle(Port::Data, &packet[..len], now(), &mut reply);
+            match session.borrow().state() {
+                SessionState::Connected if peer.get().is_none() => {
+                    info!("session connected");
+                    peer.set(Some(remote));
+                }
+                SessionState::Idle if peer.get().is_some() => {
+                    info!("session ended");
+                    peer.set(None);
+                }
+                _ => {}
+            }
+            match handled {
+                Ok(Some(len)) => {
+                    if let Err(error) = socket.send_to(&reply[..len], remote).await {
+                        warn!("data port: {}", error);
+                    }
+                }
+                Ok(None) => {}
+                Err(error) => warn!("data port: {}", error),
+            }
+        }
+    };
+
+    let output = async {
+        loop {
+            let event = outputs.net.recv().await;
+            // Events are dropped while nobody is connected.
+            let Some(remote) = peer.get() else {
+                continue;
+            };
+            let mut packet = session.borrow_mut().packet::<PACKET_SIZE>(now());
+            if packet.push_event(event).is_err() {
+                continue;
+            }
+            if let Err(error) = socket.send_to(packet.as_bytes(), remote).await {
+                warn!("data port: {}", error);
+            }
+        }
+    };
+
+    join(input, output).await;
+}
+
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    info!("USB-RTP MIDI bridge");
+
+    let mut config = Config::default();
+    config.rcc.sys_ck = Some(mhz(180));
+    config.rcc.pll48 = true;
+
+    let p = embassy_stm32::init(config);
+
+    let mut seed = [0; 8];
+    Rng::new(p.RNG).fill_bytes(&mut seed);
+    let seed = u64::from_le_bytes(seed);
+
+    // RMII pins of the Nucleo-144 board with its LAN8742A at address 0.
+    static ETH_STATE: StaticCell<eth::State<'static, ETH, 4, 4>> = StaticCell::new();
+    let mac_address = [0x02, 0x00, 0x00, 0x00, 0x00, 0x01];
+    let eth_irq = interrupt::take!(ETH);
+    let device = unsafe {
+        Ethernet::new(
+            ETH_STATE.init(eth::State::new()),
+            p.ETH,
+            eth_irq,
+            p.PA1,
+            p.PA2,
+            p.PC1,
+            p.PA7,
+            p.PC4,
+            p.PC5,
+            p.PG13,
+            p.PB13,
+            p.PG11,
+            GenericSMI,
+            mac_address,
+            0,
+        )
+    };
+
+    static RESOURCES: StaticCell<StackResources<1, 2, 8>> = StaticCell::new();
+    let stack = Stack::new(
+        device,
+        ConfigStrategy::Dhcp,
+        RESOURCES.init(StackResources::new()),
+        seed,
+    );
+
+    let mut buffers = UsbMidiBuffers::<1>::new();
+    let irq = interrupt::take!(OTG_FS);
+    let (midi_class, mut usb) = build_usb_midi_device(
+        &mut buffers,
+        |ep_out_buffer| Driver::new_fs(p.USB_OTG_FS, irq, p.PA12, p.PA11, ep_out_buffer),
+        &DeviceIdentity::DEVELOPMENT.with_product("USB-RTP MIDI bridge"),
+    );
+
+    let (mut sender, receiver) = midi_class.split();
+    let queues = Queues::<NoopRawMutex, 1>::new();
+    let mut dispatcher = Dispatcher::new(receiver, &queues);
+
+    let mut router = Router::new();
+    for (source, destination) in [(USB, DIN), (DIN, USB), (NET, USB), (NET, DIN), (USB, NET), (DIN, NET)] {
+        router.connect(source, destination);
+    }
+    let outputs = Outputs {
+        router,
+        usb: TxQueue::new(),
+        din: EventQueue::new(),
+        net: EventQueue::new(),
+        overflows: Counter::new(),
+    };
+
+    // The SSRC identifies the device in the session and should be random.
+    let session = RefCell::new(Session::new("MIDIbox", seed as u32));
+
+    let mut uart_config = usart::Config::default();
+    uart_config.baudrate = 31_250;
+    let (mut din_tx, mut din_rx) = Uart::new(p.USART6, p.PC7, p.PC6, p.DMA2_CH6, p.DMA2_CH1, uart_config).split();
+
+    let usb_fut = usb.run();
+
+    let dispatch_fut = dispatcher.run(&mut ());
+
+    let usb_input_fut = async {
+        let cable = CableNumber::new(0).unwrap();
+        loop {
+            let event = queues.receive(cable).await;
+            outputs.route(USB, event);
+        }
+    };
+
+    let usb_output_fut = outputs.usb.run(&mut sender);
+
+    let din_input_fut = async {
+        let mut parser = DinParser::new();
+        let mut byte = [0];
+        loop {
+            match din_rx.read(&mut byte).await {
+                Ok(()) => {
+                    if let Some(event) = parser.push(byte[0]) {
+                        outputs.route(DIN, event);
+                    }
+                }
+                Err(error) => warn!("DIN input: {}", error),
+            }
+        }
+    };
+
+    let din_output_fut = async {
+        loop {
+            let event = outputs.din.recv().await;
+            let packet = event.to_packet(0);
+            if let Err(error) = din_tx.write(&packet[1..1 + event.size()]).await {
+                warn!("DIN output: {}", error);
+            }
+        }
+    };
+
+    join5(
+        join(usb_fut, dispatch_fut),
+        join(usb_input_fut, usb_output_fut),
+        join(din_input_fut, din_output_fut),
+        stack.run(),
+        join(control_port(&stack, &session), data_port(&stack, &session, &outputs)),
+    )
+    .await;
+}
@@ -0,0 +1,137 @@
+//! Device profiles for finding out what a problematic host accepts.
+//!
+//! Jumpers from PF0–PF3 to ground select the profile at reset:
+//!
+//! - PF0 and PF1 select 1, 2, 4 or 8 ports, PF0 being the low bit.
+//! - PF2 switches the PID from 0xcafe to 0xcaff, so the host cannot reuse
+//!   descriptors it cached for the other profile.
+//! - PF3 adds interface association descriptors with device class 0xef.
+//!
+//! Every port echoes what it receives, so a MIDI monitor on the host shows
+//! right away whether the ports work. The selected profile is logged and
+//! reported in the product string.
+
+#![no_std]
+#![no_main]
+#![feature(type_alias_impl_trait)]
+
+use defmt::{info, warn, Format};
+use embassy_executor::Spawner;
+use embassy_stm32::gpio::{Input, Pull};
+use embassy_stm32::peripherals::USB_OTG_FS;
+use embassy_stm32::time::mhz;
+use embassy_stm32::usb_otg::Driver;
+use embassy_stm32::{interrupt, Config};
+use embassy_usb::Builder;
+use futures::future::join;
+use usb_midi_rs::{DeviceIdentity, State, UsbMidiClass, MAX_PACKET_SIZE};
+use {defmt_rtt as _, panic_probe as _};
+
+#[derive(Copy, Clone, Format)]
+struct Profile {
+    ports: usize,
+    alternate_pid: bool,
+    iads: bool,
+}
+
+impl Profile {
+    fn product(&self) -> &'static str {
+        match (self.ports, self.iads) {
+            (1, false) => "Quirk test 1 port",
+            (2, false) => "Quirk test 2 ports",
+            (4, false) => "Quirk test 4 ports",
+            (8, false) => "Quirk test 8 ports",
+            (1, true) => "Quirk test 1 port IAD",
+            (2, true) => "Quirk test 2 ports IAD",
+            (4, true) => "Quirk test 4 ports IAD",
+            _ => "Quirk test 8 ports IAD",
+        }
+    }
+}
+
+/// Most ports a profile selects.
+const MAX_PORTS: usize = 8;
+
+/// Runs the device until the end of time.
+async fn run(driver: Driver<'_, USB_OTG_FS>, profile: Profile) {
+    let mut identity = DeviceIdentity::DEVELOPMENT.with_product(profile.product());
+    if profile.alternate_pid {
+        identity.product_id = 0xcaff;
+    }
+    let mut usb_config = identity.config();
+    if profile.iads {
+        usb_config.device_class = 0xef;
+        usb_config.device_sub_class = 0x02;
+        usb_config.device_protocol = 0x01;
+        usb_config.composite_with_iads = true;
+    }
+
+    // Large enough for eight ports.
+    let mut device_descriptor = [0; 256];
+    let mut config_descriptor = [0; 1024];
+    let mut bos_descriptor = [0; 64];
+    let mut control_buf = [0; 64];
+    let mut state = State::new();
+
+    let mut builder = Builder::new(
+        driver,
+        usb_config,
+        &mut device_descriptor,
+        &mut config_descriptor,
+        &mut bos_descriptor,
+        &mut control_buf,
+        None,
+    );
+    let midi_class = UsbMidiClass::<_, MAX_PORTS>::with_ports(&mut builder, &mut state, profile.ports);
+    let mut usb = builder.build();
+
+    let (mut sender, mut receiver) = midi_class.split();
+
+    let echo_fut = async {
+        let mut buf = [0; MAX_PACKET_SIZE as usize];
+        loop {
+            receiver.wait_connection().await;
+            info!("connected");
+            loop {
+                let events = match receiver.read_events(&mut buf).await {
+                    Ok(events) => events,
+                    Err(error) => {
+                        warn!("read failed: {}", error);
+                        break;
+                    }
+                };
+                for (cable, event) in events.flatten() {
+                    let _ = sender.write_event(cable, event).await;
+                }
+            }
+        }
+    };
+
+    join(usb.run(), echo_fut).await;
+}
+
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    let mut config = Config::default();
+    config.rcc.sys_ck = Some(mhz(180));
+    config.rcc.pll48 = true;
+
+    let p = embassy_stm32::init(config);
+
+    let ports = [
+        Input::new(p.PF0, Pull::Up).is_low(),
+        Input::new(p.PF1, Pull::Up).is_low(),
+    ];
+    let profile = Profile {
+        ports: 1 << (usize::from(ports[0]) | usize::from(ports[1]) << 1),
+        alternate_pid: Input::new(p.PF2, Pull::Up).is_low(),
+        iads: Input::new(p.PF3, Pull::Up).is_low(),
+    };
+    info!("USB-MIDI quirk test: {}", profile);
+
+    let mut ep_out_buffer = [0; 256];
+    let irq = interrupt::take!(OTG_FS);
+    let driver = Driver::new_fs(p.USB_OTG_FS, irq, p.PA12, p.PA11, &mut ep_out_buffer);
+
+    run(driver, profile).await;
+}
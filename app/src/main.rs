@@ -2,26 +2,30 @@
 #![no_main]
 #![feature(type_alias_impl_trait)]
 
-mod usb_midi;
-
 use defmt::{info, trace};
 use embassy_executor::Spawner;
 use embassy_stm32::time::mhz;
-use embassy_stm32::usb_otg::{DmPin, DpPin, Driver, Instance};
-use embassy_stm32::{interrupt, Config, Peripheral};
-use embassy_usb::{Builder, UsbDevice};
-use futures::future::join;
-
-use crate::usb_midi::{Event, State, UsbMidiClass};
+use embassy_stm32::usb_otg::Driver;
+use embassy_stm32::{interrupt, Config};
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use futures::future::join4;
+use usb_midi_rs::serial::{Stm32UniqueId, STM32F4_UID_ADDRESS};
+use usb_midi_rs::{
+    build_usb_midi_device, Channel, ConnectionHandler, DeviceIdentity, Dispatcher, MidiMessage, Note, Queues,
+    SerialNumber, UsbMidiBuffers,
+};
 use {defmt_rtt as _, panic_probe as _};
 
-struct UsbDeviceBuilder {
-    device_descriptor: [u8; 256],
-    config_descriptor: [u8; 256],
-    bos_descriptor: [u8; 64],
-    control_buf: [u8; 64],
-    ep_out_buffer: [u8; 256],
-    state: State,
+struct Connection;
+
+impl ConnectionHandler for Connection {
+    fn connected(&mut self) {
+        info!("### Connected ###");
+    }
+
+    fn disconnected(&mut self) {
+        info!("### Disconnected ###");
+    }
 }
 
 enum UsbEvent {}
@@ -30,63 +34,6 @@ enum UsbEvent {}
 //
 // }
 
-impl UsbDeviceBuilder {
-    fn new() -> UsbDeviceBuilder {
-        let device_descriptor = [0; 256];
-        let config_descriptor = [0; 256];
-        let bos_descriptor = [0; 64];
-        let control_buf = [0; 64];
-        let ep_out_buffer = [0; 256];
-
-        UsbDeviceBuilder {
-            device_descriptor,
-            config_descriptor,
-            bos_descriptor,
-            control_buf,
-            ep_out_buffer,
-            state: State::new(),
-        }
-    }
-
-    fn build<'a, UsbInstance, UsbPeripheral, Dp, Dm>(
-        &'a mut self,
-        usb: UsbPeripheral,
-        irq: UsbInstance::Interrupt,
-        dp: Dp,
-        dm: Dm,
-    ) -> (UsbMidiClass<Driver<UsbInstance>, 2>, UsbDevice<Driver<UsbInstance>>)
-    where
-        UsbInstance: Instance,
-        UsbPeripheral: Peripheral<P = UsbInstance> + 'a,
-        Dp: Peripheral + 'a,
-        Dp::P: DpPin<UsbInstance>,
-        Dm: Peripheral + 'a,
-        Dm::P: DmPin<UsbInstance>,
-    {
-        let driver = Driver::new_fs(usb, irq, dp, dm, &mut self.ep_out_buffer);
-
-        let mut config = embassy_usb::Config::new(0xc0de, 0xcafe);
-        config.manufacturer = Some("MIDIbox");
-        config.product = Some("USB-MIDI example");
-        config.serial_number = Some("87654321");
-
-        let mut builder = Builder::new(
-            driver,
-            config,
-            &mut self.device_descriptor,
-            &mut self.config_descriptor,
-            &mut self.bos_descriptor,
-            &mut self.control_buf,
-            None,
-        );
-
-        let midi_class = UsbMidiClass::new(&mut builder, &mut self.state);
-        let usb_device = builder.build();
-
-        (midi_class, usb_device)
-    }
-}
-
 #[embassy_executor::main]
 async fn main(_spawner: Spawner) {
     info!("USB MIDI!");
@@ -99,33 +46,44 @@ async fn main(_spawner: Spawner) {
 
     let irq = interrupt::take!(OTG_FS);
 
-    let mut usb_device_builder = UsbDeviceBuilder::new();
+    // Safety: the STM32F439 has its unique ID at the STM32F4 address.
+    let serial_number = SerialNumber::of(&unsafe { Stm32UniqueId::at(STM32F4_UID_ADDRESS) });
+    let mut buffers = UsbMidiBuffers::<2>::new();
+    let (midi_class, mut usb) = build_usb_midi_device(
+        &mut buffers,
+        |ep_out_buffer| Driver::new_fs(p.USB_OTG_FS, irq, p.PA12, p.PA11, ep_out_buffer),
+        &DeviceIdentity::DEVELOPMENT
+            .with_product("USB-MIDI example")
+            .with_serial_number(serial_number.as_str()),
+    );
 
-    let (mut midi_class, mut usb) = usb_device_builder.build(p.USB_OTG_FS, irq, p.PA12, p.PA11);
+    let (cable_a, cable_b) = midi_class.split_cables();
+    let (mut sender, receiver) = midi_class.split();
 
-    let _cables = midi_class.split_cables();
+    let queues = Queues::<NoopRawMutex, 2>::new();
+    let mut dispatcher = Dispatcher::new(receiver, &queues);
 
     let usb_fut = usb.run();
 
-    let midi_fut = async {
+    let dispatch_fut = dispatcher.run(&mut Connection);
+
+    let cable_a_fut = async {
+        loop {
+            let event = queues.receive(cable_a).await;
+            trace!("### cable {}: event {}", cable_a, event);
+            let message = MidiMessage::NoteOn(Channel::new(3), Note::new(53), 124);
+            let _ = sender.write_message(cable_b, message).await;
+        }
+    };
+
+    let cable_b_fut = async {
         loop {
-            let mut buf = [0; 64];
-            midi_class.wait_connection().await;
-            info!("### Connected ###");
-            loop {
-                let cnt = midi_class.read_packets(&mut buf).await.unwrap();
-                trace!("read_packet: cnt={}", cnt);
-                for packet in buf[0..cnt].chunks_exact(4) {
-                    let cable = packet[0] >> 4;
-                    let event = Event::new(packet);
-                    trace!("### cable {}: event {}", cable, event);
-                }
-                let _ = midi_class.write_packet(&[1 << 4 | 9, 147, 53, 124]).await;
-            }
+            let event = queues.receive(cable_b).await;
+            trace!("### cable {}: event {}", cable_b, event);
         }
     };
 
     // Run everything concurrently.
     // If we had made everything `'static` above instead, we could do this using separate tasks instead.
-    join(usb_fut, midi_fut).await;
+    join4(usb_fut, dispatch_fut, cable_a_fut, cable_b_fut).await;
 }
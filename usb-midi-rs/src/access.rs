@@ -0,0 +1,310 @@
+use embassy_time::{Duration, Instant};
+
+use crate::report::{read_number, Writer, NUMBER_LEN};
+use crate::soak::xorshift32;
+use crate::sysex::{SYSEX_END, SYSEX_START};
+use crate::Error;
+
+/// Command byte of a request for an unlock challenge.
+pub const CHALLENGE_REQUEST: u8 = 0x0a;
+/// Command byte of [`AccessReply::Challenge`].
+pub const CHALLENGE_REPLY: u8 = 0x0b;
+/// Command byte of an unlock attempt.
+pub const UNLOCK_REQUEST: u8 = 0x0c;
+/// Command byte of [`AccessReply::Unlock`].
+pub const UNLOCK_REPLY: u8 = 0x0d;
+
+/// Time a device stays unlocked without a destructive operation.
+pub const UNLOCK_TIME: Duration = Duration::from_secs(10);
+/// Failed unlock attempts in a row after which further attempts are refused
+/// for a while.
+pub const MAX_FAILURES: u32 = 3;
+/// Time unlock attempts are refused after [`MAX_FAILURES`] failures,
+/// doubling with every further failure up to 64 times.
+pub const BACK_OFF_TIME: Duration = Duration::from_secs(1);
+
+/// What a host has to send to unlock destructive operations.
+#[derive(Copy, Clone, Debug)]
+pub enum Lock<'a> {
+    /// No unlock is needed.
+    Open,
+    /// The host sends the PIN, a string of 7-bit bytes.
+    Pin(&'a [u8]),
+    /// The host requests a challenge and sends back the number the function
+    /// computes from it, e.g. a keyed hash only the vendor tool knows.
+    Challenge(fn(u32) -> u32),
+}
+
+/// Answer of the device to an unlock message.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum AccessReply {
+    Challenge(u32),
+    /// Whether the device is unlocked now.
+    Unlock(bool),
+}
+
+impl AccessReply {
+    /// Writes the reply to `data`, returning its length.
+    ///
+    /// The reply is `F0 <manufacturer> 0B <challenge> F7` with the challenge
+    /// as five 7-bit groups or `F0 <manufacturer> 0D <unlocked> F7`.
+    pub fn write(&self, manufacturer: &[u8], data: &mut [u8]) -> Result<usize, Error> {
+        let len = match self {
+            AccessReply::Challenge(_) => manufacturer.len() + 3 + NUMBER_LEN,
+            AccessReply::Unlock(_) => manufacturer.len() + 4,
+        };
+        let data = data.get_mut(..len).ok_or(Error::BufferOverflow)?;
+        let mut writer = Writer { data, len: 0 };
+        writer.bytes(&[SYSEX_START]);
+        writer.bytes(manufacturer);
+        match *self {
+            AccessReply::Challenge(challenge) => {
+                writer.bytes(&[CHALLENGE_REPLY]);
+                writer.number(challenge);
+            }
+            AccessReply::Unlock(unlocked) => writer.bytes(&[UNLOCK_REPLY, unlocked as u8]),
+        }
+        writer.bytes(&[SYSEX_END]);
+        Ok(len)
+    }
+}
+
+/// Keeps destructive System Exclusive operations, e.g. entering DFU, a
+/// factory reset or changing the identity, locked until the host unlocks
+/// them, so a stray librarian dump cannot wipe a device.
+///
+/// With a [`Lock::Pin`], the host sends `F0 <manufacturer> 0C <pin> F7`.
+/// With a [`Lock::Challenge`], it sends `F0 <manufacturer> 0A F7` first and
+/// `F0 <manufacturer> 0C <response> F7` with the response as five 7-bit
+/// groups then. A challenge is only good for one attempt. An unlock lasts
+/// for one operation, which the handler of the operation claims with
+/// [`authorize`](Self::authorize), and expires after [`UNLOCK_TIME`].
+///
+/// After [`MAX_FAILURES`] failed attempts in a row, attempts are refused
+/// without being checked for a growing [`BACK_OFF_TIME`], so PINs and
+/// responses cannot be guessed at the speed of the bus. Locking the device
+/// again does not end the back-off.
+#[derive(Clone, Debug)]
+pub struct AccessGuard<'a> {
+    lock: Lock<'a>,
+    random: u32,
+    challenge: Option<u32>,
+    unlocked_until: Option<Instant>,
+    failures: u32,
+    refused_until: Option<Instant>,
+}
+
+impl<'a> AccessGuard<'a> {
+    /// A locked guard. Challenges are derived from `seed`, which should
+    /// differ between boots and devices, e.g. the ticks at the first request
+    /// mixed with the [`UniqueId`](crate::UniqueId).
+    pub const fn new(lock: Lock<'a>, seed: u32) -> Self {
+        Self {
+            lock,
+            random: if seed == 0 { 0x2545_f491 } else { seed },
+            challenge: None,
+            unlocked_until: None,
+            failures: 0,
+            refused_until: None,
+        }
+    }
+
+    /// Number of failed unlock attempts since the last successful one.
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    /// Whether unlock attempts are refused at `now` after too many failures.
+    pub fn is_backing_off(&self, now: Instant) -> bool {
+        matches!(self.refused_until, Some(until) if now < until)
+    }
+
+    /// Whether a destructive operation would be authorized at `now`.
+    pub fn is_unlocked(&self, now: Instant) -> bool {
+        match self.lock {
+            Lock::Open => true,
+            _ => matches!(self.unlocked_until, Some(until) if now < until),
+        }
+    }
+
+    /// Locks the device again, e.g. when the host disconnects.
+    pub fn lock(&mut self) {
+        self.challenge = None;
+        self.unlocked_until = None;
+    }
+
+    /// Claims the unlock for a destructive operation at `now`, returning
+    /// whether the operation may go ahead. Handlers of locked operations
+    /// fail with [`Error::Unsupported`] otherwise.
+    pub fn authorize(&mut self, now: Instant) -> bool {
+        let unlocked = self.is_unlocked(now);
+        self.unlocked_until = None;
+        unlocked
+    }
+
+    /// Handles the complete System Exclusive `message` received at `now`,
+    /// returning the reply to send or `None` if it is not an unlock message.
+    pub fn handle(&mut self, message: &[u8], manufacturer: &[u8], now: Instant) -> Option<AccessReply> {
+        let body = message
+            .strip_prefix(&[SYSEX_START][..])?
+            .strip_prefix(manufacturer)?
+            .strip_suffix(&[SYSEX_END][..])?;
+        let (&command, payload) = body.split_first()?;
+        match command {
+            CHALLENGE_REQUEST => {
+                let challenge = xorshift32(&mut self.random);
+                self.challenge = Some(challenge);
+                Some(AccessReply::Challenge(challenge))
+            }
+            UNLOCK_REQUEST if self.is_backing_off(now) => {
+                warn!("Unlock refused while backing off");
+                self.challenge = None;
+                Some(AccessReply::Unlock(false))
+            }
+            UNLOCK_REQUEST => {
+                let unlocked = self.check(payload);
+                self.challenge = None;
+                self.unlocked_until = unlocked.then_some(now + UNLOCK_TIME);
+                match unlocked {
+                    true => self.failures = 0,
+                    false => self.fail(now),
+                }
+                Some(AccessReply::Unlock(unlocked))
+            }
+            _ => None,
+        }
+    }
+
+    fn fail(&mut self, now: Instant) {
+        warn!("Unlock refused");
+        self.failures = self.failures.saturating_add(1);
+        if let Some(excess) = self.failures.checked_sub(MAX_FAILURES) {
+            let back_off = BACK_OFF_TIME.as_ticks() << excess.min(6);
+            self.refused_until = Some(now + Duration::from_ticks(back_off));
+        }
+    }
+
+    fn check(&self, key: &[u8]) -> bool {
+        match self.lock {
+            Lock::Open => true,
+            Lock::Pin(pin) => key == pin,
+            Lock::Challenge(respond) => match self.challenge {
+                Some(challenge) => read_number(key) == Some(respond(challenge)),
+                None => false,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MANUFACTURER: &[u8] = &[0x7d];
+
+    fn unlock(key: &[u8]) -> std::vec::Vec<u8> {
+        [&[0xf0, 0x7d, UNLOCK_REQUEST][..], key, &[0xf7]].concat()
+    }
+
+    #[test]
+    fn unlocks_with_pins() {
+        let mut guard = AccessGuard::new(Lock::Pin(b"1234"), 1);
+        let now = Instant::from_secs(1);
+        assert!(!guard.authorize(now));
+        assert_eq!(
+            guard.handle(&unlock(b"1235"), MANUFACTURER, now),
+            Some(AccessReply::Unlock(false))
+        );
+        assert_eq!(guard.failures(), 1);
+        assert_eq!(
+            guard.handle(&unlock(b"1234"), MANUFACTURER, now),
+            Some(AccessReply::Unlock(true))
+        );
+        assert!(guard.authorize(now + Duration::from_secs(9)));
+        // An unlock lasts for one operation only.
+        assert!(!guard.authorize(now + Duration::from_secs(9)));
+
+        guard.handle(&unlock(b"1234"), MANUFACTURER, now);
+        assert!(!guard.authorize(now + UNLOCK_TIME));
+        assert!(AccessGuard::new(Lock::Open, 0).authorize(now));
+        assert_eq!(guard.handle(&[0xf0, 0x7d, 0x01, 0xf7], MANUFACTURER, now), None);
+    }
+
+    #[test]
+    fn unlocks_with_challenges() {
+        fn respond(challenge: u32) -> u32 {
+            challenge.rotate_left(3) ^ 0x5a5a
+        }
+
+        let mut guard = AccessGuard::new(Lock::Challenge(respond), 7);
+        let now = Instant::from_secs(1);
+        let challenge = match guard.handle(&[0xf0, 0x7d, CHALLENGE_REQUEST, 0xf7], MANUFACTURER, now) {
+            Some(AccessReply::Challenge(challenge)) => challenge,
+            reply => panic!("unexpected reply {:?}", reply),
+        };
+        let mut key = [0; NUMBER_LEN];
+        Writer { data: &mut key, len: 0 }.number(respond(challenge));
+        assert_eq!(
+            guard.handle(&unlock(&key), MANUFACTURER, now),
+            Some(AccessReply::Unlock(true))
+        );
+        assert!(guard.is_unlocked(now));
+
+        // The challenge was used up.
+        guard.lock();
+        assert_eq!(
+            guard.handle(&unlock(&key), MANUFACTURER, now),
+            Some(AccessReply::Unlock(false))
+        );
+
+        // Bits beyond 32 are not folded away.
+        let challenge = match guard.handle(&[0xf0, 0x7d, CHALLENGE_REQUEST, 0xf7], MANUFACTURER, now) {
+            Some(AccessReply::Challenge(challenge)) => challenge,
+            reply => panic!("unexpected reply {:?}", reply),
+        };
+        Writer { data: &mut key, len: 0 }.number(respond(challenge));
+        key[0] |= 0x10;
+        assert_eq!(
+            guard.handle(&unlock(&key), MANUFACTURER, now),
+            Some(AccessReply::Unlock(false))
+        );
+
+        let mut data = [0; 16];
+        let len = AccessReply::Challenge(0x81).write(MANUFACTURER, &mut data).unwrap();
+        assert_eq!(data[..len], [0xf0, 0x7d, 0x0b, 0, 0, 0, 0x01, 0x01, 0xf7]);
+    }
+
+    #[test]
+    fn backs_off_after_failures() {
+        let mut guard = AccessGuard::new(Lock::Pin(b"1234"), 1);
+        let now = Instant::from_secs(1);
+        for _ in 0..MAX_FAILURES {
+            assert!(!guard.is_backing_off(now));
+            guard.handle(&unlock(b"0000"), MANUFACTURER, now);
+        }
+        // Even the right PIN is refused while backing off, and locking the
+        // device again does not help.
+        guard.lock();
+        assert!(guard.is_backing_off(now));
+        assert_eq!(
+            guard.handle(&unlock(b"1234"), MANUFACTURER, now),
+            Some(AccessReply::Unlock(false))
+        );
+        assert_eq!(guard.failures(), MAX_FAILURES);
+
+        // The back-off doubles with every further failure.
+        let later = now + BACK_OFF_TIME;
+        assert!(!guard.is_backing_off(later));
+        guard.handle(&unlock(b"0000"), MANUFACTURER, later);
+        assert!(guard.is_backing_off(later + BACK_OFF_TIME));
+        assert!(!guard.is_backing_off(later + BACK_OFF_TIME * 2));
+
+        let later = later + BACK_OFF_TIME * 2;
+        assert_eq!(
+            guard.handle(&unlock(b"1234"), MANUFACTURER, later),
+            Some(AccessReply::Unlock(true))
+        );
+        assert_eq!(guard.failures(), 0);
+    }
+}
@@ -0,0 +1,232 @@
+//! Packet codec for MIDI over Bluetooth Low Energy.
+//!
+//! BLE-MIDI packets carry a header byte with the upper bits of a 13-bit
+//! millisecond timestamp, followed by MIDI messages that are each preceded by
+//! a byte with the lower 7 bits of their timestamp. The codec is independent
+//! of the BLE stack: [`BleDecoder`] takes the value of characteristic writes
+//! or notifications, [`BlePacket`] assembles the value to send.
+
+use heapless::Vec;
+
+use crate::{DinParser, Error, Event, MidiMessage};
+
+/// Timestamps count milliseconds modulo 2^13.
+pub const TIMESTAMP_MASK: u16 = 0x1fff;
+
+/// Splits received BLE-MIDI packets into timestamped events.
+///
+/// The decoder keeps its state between packets, so System Exclusive messages
+/// may span several packets.
+#[derive(Default)]
+pub struct BleDecoder {
+    parser: DinParser,
+}
+
+impl BleDecoder {
+    pub const fn new() -> Self {
+        Self {
+            parser: DinParser::new(),
+        }
+    }
+
+    /// Decodes a packet. Packets without a valid header yield no events.
+    pub fn decode<'a>(&'a mut self, packet: &'a [u8]) -> BleEvents<'a> {
+        let (high, bytes) = match packet.split_first() {
+            Some((&header, bytes)) if header & 0xc0 == 0x80 => (u16::from(header & 0x3f), bytes),
+            _ => (0, &[][..]),
+        };
+        BleEvents {
+            parser: &mut self.parser,
+            bytes: bytes.iter(),
+            high,
+            low: None,
+            expect_status: false,
+        }
+    }
+}
+
+/// Events of a BLE-MIDI packet with their timestamps.
+pub struct BleEvents<'a> {
+    parser: &'a mut DinParser,
+    bytes: core::slice::Iter<'a, u8>,
+    high: u16,
+    low: Option<u8>,
+    expect_status: bool,
+}
+
+impl BleEvents<'_> {
+    fn timestamp(&self) -> u16 {
+        (self.high << 7 | u16::from(self.low.unwrap_or(0))) & TIMESTAMP_MASK
+    }
+}
+
+impl Iterator for BleEvents<'_> {
+    type Item = (u16, Event);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for &byte in self.bytes.by_ref() {
+            if byte & 0x80 != 0 && !self.expect_status {
+                // A timestamp; the low bits wrap around within a packet.
+                let low = byte & 0x7f;
+                if matches!(self.low, Some(last) if low < last) {
+                    self.high += 1;
+                }
+                self.low = Some(low);
+                self.expect_status = true;
+                continue;
+            }
+            self.expect_status = false;
+            if let Some(event) = self.parser.push(byte) {
+                return Some((self.timestamp(), event));
+            }
+        }
+        None
+    }
+}
+
+/// A BLE-MIDI packet of up to `L` bytes under construction.
+///
+/// `L` is the payload size of the connection, i.e. the ATT MTU minus 3.
+pub struct BlePacket<const L: usize> {
+    data: Vec<u8, L>,
+}
+
+impl<const L: usize> BlePacket<L> {
+    pub const fn new() -> Self {
+        Self { data: Vec::new() }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn clear(&mut self) {
+        self.data.clear();
+    }
+
+    /// Appends `event`, sent at `timestamp` milliseconds.
+    ///
+    /// Fails with [`Error::BufferOverflow`] if the event does not fit or its
+    /// timestamp is too far from the first one in the packet. In both cases
+    /// the packet should be sent and the event appended to the next one.
+    pub fn push_event(&mut self, timestamp: u16, event: Event) -> Result<(), Error> {
+        let packet = event.to_packet(0);
+        let bytes = &packet[1..1 + event.size()];
+        let statuses = bytes.iter().filter(|&&byte| byte & 0x80 != 0).count();
+
+        let timestamp = timestamp & TIMESTAMP_MASK;
+        let high = (timestamp >> 7) as u8;
+        let header = 0x80 | high;
+        let header_len = match self.data.first() {
+            None => 1,
+            Some(&first) if first == header => 0,
+            Some(_) => return Err(Error::BufferOverflow),
+        };
+        if header_len + statuses + bytes.len() > self.data.capacity() - self.data.len() {
+            return Err(Error::BufferOverflow);
+        }
+
+        if header_len > 0 {
+            let _ = self.data.push(header);
+        }
+        for &byte in bytes {
+            if byte & 0x80 != 0 {
+                let _ = self.data.push(0x80 | (timestamp & 0x7f) as u8);
+            }
+            let _ = self.data.push(byte);
+        }
+        Ok(())
+    }
+
+    pub fn push(&mut self, timestamp: u16, message: MidiMessage) -> Result<(), Error> {
+        self.push_event(timestamp, message.into())
+    }
+}
+
+impl<const L: usize> Default for BlePacket<L> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::{Channel, Note};
+
+    fn decode(packets: &[&[u8]]) -> std::vec::Vec<(u16, Event)> {
+        let mut decoder = BleDecoder::new();
+        packets
+            .iter()
+            .flat_map(|packet| decoder.decode(packet).collect::<std::vec::Vec<_>>())
+            .collect()
+    }
+
+    #[test]
+    fn decodes_running_status() {
+        // Note on with full status, then running status with and without a
+        // timestamp of its own.
+        assert_eq!(
+            decode(&[&[0x81, 0x82, 0x90, 60, 100, 0x83, 62, 100, 64, 100]]),
+            [
+                (0x82, Event::NoteOn(0x90, Note::new(60), 100)),
+                (0x83, Event::NoteOn(0x90, Note::new(62), 100)),
+                (0x83, Event::NoteOn(0x90, Note::new(64), 100)),
+            ]
+        );
+    }
+
+    #[test]
+    fn wraps_timestamps_within_a_packet() {
+        assert_eq!(
+            decode(&[&[0x80, 0xff, 0xf8, 0x81, 0xf8]]),
+            [(0x7f, Event::SingleByte(0xf8)), (0x81, Event::SingleByte(0xf8))]
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "sysex")]
+    fn decodes_sysex_across_packets() {
+        assert_eq!(
+            decode(&[&[0x80, 0x80, 0xf0, 0x01, 0x02], &[0x80, 0x03, 0x04, 0x81, 0xf7]]),
+            [
+                (0x00, Event::SysExStartCont(0xf0, 0x01, 0x02)),
+                (0x01, Event::SysExEnd3(0x03, 0x04, 0xf7)),
+            ]
+        );
+    }
+
+    #[test]
+    fn ignores_invalid_headers() {
+        assert_eq!(decode(&[&[0x40, 0x80, 0xf8], &[]]), []);
+    }
+
+    #[test]
+    fn fills_packets() {
+        let mut packet = BlePacket::<9>::new();
+        let message = MidiMessage::NoteOn(Channel::new(1), Note::new(60), 100);
+        packet.push(0x105, message).unwrap();
+        assert_eq!(packet.push(0x185, message), Err(Error::BufferOverflow));
+        packet.push(0x106, message).unwrap();
+        assert_eq!(packet.push(0x107, message), Err(Error::BufferOverflow));
+        assert_eq!(packet.as_bytes(), [0x82, 0x85, 0x91, 60, 100, 0x86, 0x91, 60, 100]);
+    }
+
+    proptest! {
+        #[test]
+        fn round_trips_messages(status in 0x80u8..=0xff, data: [u8; 2], len in 1usize..=3, timestamp in 0u16..0x2000) {
+            let bytes = [status, data[0] & 0x7f, data[1] & 0x7f];
+            if let Some(message) = MidiMessage::from_bytes(&bytes[..len]) {
+                let mut packet = BlePacket::<20>::new();
+                packet.push(timestamp, message).unwrap();
+                prop_assert_eq!(decode(&[packet.as_bytes()]), [(timestamp, Event::from(message))]);
+            }
+        }
+    }
+}
@@ -0,0 +1,190 @@
+//! Cables shared by several tasks.
+//!
+//! Sending needs no extra support: [`CableSink`](crate::transport::CableSink)s
+//! are cheap copies, any number of which feed the same [`TxQueue`](crate::TxQueue).
+//! Receiving is different, as every event of a cable queue goes to a single
+//! task. A [`Broadcast`] drains a cable queue and hands every event to all
+//! of its [`BroadcastReceiver`]s, e.g. a user interface, a sequencer and a
+//! bridge to DIN.
+
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+use embassy_sync::blocking_mutex::raw::RawMutex;
+use embassy_sync::pubsub::{PubSubChannel, Subscriber, WaitResult};
+use embassy_time::Instant;
+
+use crate::transport::MidiSource;
+use crate::{CableNumber, Error, Event, Queues, RX_QUEUE_SIZE};
+
+/// Hands the events of one cable to up to `S` receivers, keeping the last
+/// `Q` events for receivers that fall behind.
+///
+/// Publishing never waits, so a slow receiver cannot stall the others. It
+/// misses the oldest events instead, see [`BroadcastReceiver::missed`].
+pub struct Broadcast<M: RawMutex, const Q: usize = RX_QUEUE_SIZE, const S: usize = 4> {
+    channel: PubSubChannel<M, (Instant, Event), Q, S, 1>,
+}
+
+impl<M: RawMutex, const Q: usize, const S: usize> Broadcast<M, Q, S> {
+    pub fn new() -> Self {
+        Self {
+            channel: PubSubChannel::new(),
+        }
+    }
+
+    /// A new receiver, which gets the events published from now on.
+    ///
+    /// Fails with [`Error::BufferOverflow`] if there are `S` receivers
+    /// already. Dropping a receiver makes room for another one.
+    pub fn subscribe(&self) -> Result<BroadcastReceiver<'_, M, Q, S>, Error> {
+        let subscriber = self.channel.subscriber().map_err(|_| Error::BufferOverflow)?;
+        Ok(BroadcastReceiver { subscriber, missed: 0 })
+    }
+
+    /// Hands `event` received at `at` to all receivers.
+    pub fn publish(&self, at: Instant, event: Event) {
+        self.channel.immediate_publisher().publish_immediate((at, event));
+    }
+
+    /// Publishes everything received from the host on `cable`, forever.
+    pub async fn run<N: RawMutex, const C: usize, const R: usize>(
+        &self,
+        queues: &Queues<N, C, R>,
+        cable: CableNumber<C>,
+    ) -> ! {
+        loop {
+            let (at, event) = queues.receive_timestamped(cable).await;
+            self.publish(at, event);
+        }
+    }
+}
+
+impl<M: RawMutex, const Q: usize, const S: usize> Default for Broadcast<M, Q, S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// One of the receivers of a [`Broadcast`].
+pub struct BroadcastReceiver<'b, M: RawMutex, const Q: usize, const S: usize> {
+    subscriber: Subscriber<'b, M, (Instant, Event), Q, S, 1>,
+    missed: u32,
+}
+
+impl<'b, M: RawMutex, const Q: usize, const S: usize> BroadcastReceiver<'b, M, Q, S> {
+    /// Number of events missed because the receiver fell behind.
+    pub fn missed(&self) -> u32 {
+        self.missed
+    }
+
+    pub async fn receive(&mut self) -> Event {
+        self.receive_timestamped().await.1
+    }
+
+    pub fn try_receive(&mut self) -> Option<Event> {
+        self.try_receive_timestamped().map(|(_, event)| event)
+    }
+
+    pub fn receive_timestamped(&mut self) -> ReceiveFuture<'_, 'b, M, Q, S> {
+        ReceiveFuture { receiver: self }
+    }
+
+    pub fn try_receive_timestamped(&mut self) -> Option<(Instant, Event)> {
+        loop {
+            match self.subscriber.try_next_message()? {
+                WaitResult::Message(message) => return Some(message),
+                WaitResult::Lagged(missed) => self.lag(missed),
+            }
+        }
+    }
+
+    fn lag(&mut self, missed: u64) {
+        debug!("broadcast receiver missed {} events", missed);
+        self.missed = self.missed.wrapping_add(missed as u32);
+    }
+}
+
+/// Future of [`BroadcastReceiver::receive_timestamped`], skipping over
+/// missed events.
+pub struct ReceiveFuture<'r, 'b, M: RawMutex, const Q: usize, const S: usize> {
+    receiver: &'r mut BroadcastReceiver<'b, M, Q, S>,
+}
+
+impl<'r, 'b, M: RawMutex, const Q: usize, const S: usize> Future for ReceiveFuture<'r, 'b, M, Q, S> {
+    type Output = (Instant, Event);
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        loop {
+            match Pin::new(&mut self.receiver.subscriber.next_message()).poll(cx) {
+                Poll::Ready(WaitResult::Message(message)) => return Poll::Ready(message),
+                Poll::Ready(WaitResult::Lagged(missed)) => self.receiver.lag(missed),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl<'b, M: RawMutex, const Q: usize, const S: usize> MidiSource for BroadcastReceiver<'b, M, Q, S> {
+    type ReceiveFuture<'a>
+        = crate::transport::Map<ReceiveFuture<'a, 'b, M, Q, S>, Event>
+    where
+        Self: 'a;
+
+    fn receive(&mut self) -> Self::ReceiveFuture<'_> {
+        crate::transport::Map {
+            future: self.receive_timestamped(),
+            f: |(_, event)| event,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_futures::block_on;
+    use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+
+    use super::*;
+    use crate::transport::MidiSink;
+    use crate::{MidiMessage, TxQueue};
+
+    #[test]
+    fn shares_cables() {
+        let broadcast = Broadcast::<NoopRawMutex, 2, 2>::new();
+        let mut ui = broadcast.subscribe().unwrap();
+        let mut sequencer = broadcast.subscribe().unwrap();
+        assert_eq!(broadcast.subscribe().err(), Some(Error::BufferOverflow));
+
+        let start = Event::from(MidiMessage::Start);
+        let stop = Event::from(MidiMessage::Stop);
+        broadcast.publish(Instant::from_ticks(1), start);
+        assert_eq!(block_on(ui.receive_timestamped()), (Instant::from_ticks(1), start));
+        broadcast.publish(Instant::from_ticks(2), stop);
+        broadcast.publish(Instant::from_ticks(3), start);
+        assert_eq!(block_on(MidiSource::receive(&mut ui)), stop);
+        assert_eq!(ui.try_receive(), Some(start));
+        assert_eq!(ui.missed(), 0);
+        // The sequencer fell behind and lost the oldest event.
+        assert_eq!(block_on(sequencer.receive()), stop);
+        assert_eq!(sequencer.missed(), 1);
+        assert_eq!(sequencer.try_receive(), Some(start));
+        assert_eq!(sequencer.try_receive(), None);
+
+        drop(ui);
+        assert!(broadcast.subscribe().is_ok());
+    }
+
+    #[test]
+    fn copies_sinks() {
+        let tx = TxQueue::<NoopRawMutex, 2>::new();
+        let cable = CableNumber::new(1).unwrap();
+        let mut ui = tx.sink(cable);
+        let sequencer = ui;
+        block_on(ui.send(MidiMessage::Start.into())).unwrap();
+        sequencer.try_send(MidiMessage::Stop.into()).unwrap();
+        assert_eq!(sequencer.cable(), cable);
+        assert_eq!(tx.try_next(), Some((cable, MidiMessage::Start.into())));
+        assert_eq!(tx.try_next(), Some((cable, MidiMessage::Stop.into())));
+    }
+}
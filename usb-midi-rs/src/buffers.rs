@@ -0,0 +1,202 @@
+use core::{mem, slice};
+
+use embassy_usb::driver::Driver;
+use embassy_usb::{Builder, Config, DeviceStateHandler, UsbDevice};
+
+use crate::{
+    required_config_descriptor_len, DeviceIdentity, PacketSize, State, UsbMidiClass, IAD_LEN, MAX_PACKET_SIZE,
+};
+
+/// Length of the device descriptor.
+const DEVICE_DESCRIPTOR_LEN: usize = 18;
+const BOS_DESCRIPTOR_LEN: usize = 64;
+/// Control transfers are answered one packet of the default control pipe at
+/// a time.
+const CONTROL_BUF_LEN: usize = 64;
+/// One packet for the control endpoint and one for the bulk OUT endpoint,
+/// with packets of 64 bytes.
+pub const EP_OUT_BUFFER_LEN: usize = 2 * MAX_PACKET_SIZE as usize;
+
+/// Configuration descriptor bytes that do not depend on the port count,
+/// including an interface association descriptor.
+const FIXED_LEN: usize = required_config_descriptor_len(0) + IAD_LEN;
+/// Configuration descriptor bytes every port adds.
+const PORT_LEN: usize = required_config_descriptor_len(1) - required_config_descriptor_len(0);
+
+/// Buffer for the configuration descriptor of `N` ports.
+#[repr(C)]
+struct ConfigDescriptor<const N: usize> {
+    fixed: [u8; FIXED_LEN],
+    ports: [[u8; PORT_LEN]; N],
+}
+
+impl<const N: usize> ConfigDescriptor<N> {
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        // Safety: the struct only consists of byte arrays, so with `repr(C)`
+        // it is laid out as one without any padding.
+        unsafe { slice::from_raw_parts_mut(self as *mut Self as *mut u8, mem::size_of::<Self>()) }
+    }
+}
+
+/// Buffer for the packets the driver receives: one of the control endpoint
+/// and one of `P` bytes of the bulk OUT endpoint.
+#[repr(C)]
+struct EpOutBuffer<const P: usize> {
+    control: [u8; CONTROL_BUF_LEN],
+    bulk: [u8; P],
+}
+
+impl<const P: usize> EpOutBuffer<P> {
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        // Safety: as for `ConfigDescriptor`.
+        unsafe { slice::from_raw_parts_mut(self as *mut Self as *mut u8, mem::size_of::<Self>()) }
+    }
+}
+
+/// All the buffers a device with a single [`UsbMidiClass`](crate::UsbMidiClass)
+/// of up to `N` ports needs, sized for the descriptors of the class.
+///
+/// The bulk endpoints are declared with packets of `P` bytes, which must be
+/// a [`PacketSize`]. Builds short of RAM save up to 56 bytes with 8-byte
+/// packets.
+///
+/// ```ignore
+/// let mut buffers = UsbMidiBuffers::<4>::new();
+/// let (mut builder, state) = buffers.builder(
+///     |ep_out_buffer| Driver::new_fs(p.USB_OTG_FS, irq, p.PA12, p.PA11, ep_out_buffer),
+///     usb_config,
+/// );
+/// let midi_class = UsbMidiClass::<_, 4>::new(&mut builder, state);
+/// ```
+pub struct UsbMidiBuffers<const N: usize, const P: usize = { MAX_PACKET_SIZE as usize }> {
+    device_descriptor: [u8; DEVICE_DESCRIPTOR_LEN],
+    config_descriptor: ConfigDescriptor<N>,
+    bos_descriptor: [u8; BOS_DESCRIPTOR_LEN],
+    control_buf: [u8; CONTROL_BUF_LEN],
+    ep_out_buffer: EpOutBuffer<P>,
+    state: State,
+}
+
+impl<const N: usize, const P: usize> UsbMidiBuffers<N, P> {
+    pub fn new() -> Self {
+        let packet_size = PacketSize::from_len(P as u16).expect("the packet size must be 8, 16, 32 or 64 bytes");
+        Self {
+            device_descriptor: [0; DEVICE_DESCRIPTOR_LEN],
+            config_descriptor: ConfigDescriptor {
+                fixed: [0; FIXED_LEN],
+                ports: [[0; PORT_LEN]; N],
+            },
+            bos_descriptor: [0; BOS_DESCRIPTOR_LEN],
+            control_buf: [0; CONTROL_BUF_LEN],
+            ep_out_buffer: EpOutBuffer {
+                control: [0; CONTROL_BUF_LEN],
+                bulk: [0; P],
+            },
+            state: State::new().with_packet_size(packet_size),
+        }
+    }
+
+    /// Creates the driver with `driver`, which receives the buffer for the
+    /// OUT endpoints where the driver needs one, and a builder for it.
+    ///
+    /// The returned state is meant for [`UsbMidiClass::new`](crate::UsbMidiClass::new).
+    pub fn builder<'d, D: Driver<'d>>(
+        &'d mut self,
+        driver: impl FnOnce(&'d mut [u8]) -> D,
+        config: Config<'d>,
+    ) -> (Builder<'d, D>, &'d mut State) {
+        self.build(driver, config, None)
+    }
+
+    /// Like [`builder`](Self::builder), notifying `handler` of changes of the
+    /// device state, e.g. a [`ConnectionMonitor`](crate::ConnectionMonitor).
+    pub fn builder_with_handler<'d, D: Driver<'d>>(
+        &'d mut self,
+        driver: impl FnOnce(&'d mut [u8]) -> D,
+        config: Config<'d>,
+        handler: &'d dyn DeviceStateHandler,
+    ) -> (Builder<'d, D>, &'d mut State) {
+        self.build(driver, config, Some(handler))
+    }
+
+    fn build<'d, D: Driver<'d>>(
+        &'d mut self,
+        driver: impl FnOnce(&'d mut [u8]) -> D,
+        config: Config<'d>,
+        handler: Option<&'d dyn DeviceStateHandler>,
+    ) -> (Builder<'d, D>, &'d mut State) {
+        let builder = Builder::new(
+            driver(self.ep_out_buffer.as_mut_slice()),
+            config,
+            &mut self.device_descriptor,
+            self.config_descriptor.as_mut_slice(),
+            &mut self.bos_descriptor,
+            &mut self.control_buf,
+            handler,
+        );
+        (builder, &mut self.state)
+    }
+}
+
+/// Builds a device identifying as `identity` whose only function is a
+/// [`UsbMidiClass`] with `N` ports, in `buffers`.
+///
+/// `driver` creates the driver with the buffer for the OUT endpoints, as in
+/// [`UsbMidiBuffers::builder`]. Devices with other functions or a state
+/// handler are wired up with the builder instead.
+///
+/// ```ignore
+/// let mut buffers = UsbMidiBuffers::<4>::new();
+/// let (midi_class, mut usb) = build_usb_midi_device(
+///     &mut buffers,
+///     |ep_out_buffer| Driver::new_fs(p.USB_OTG_FS, irq, p.PA12, p.PA11, ep_out_buffer),
+///     &DeviceIdentity::new(0x1209, 0x0001).with_product("MIDI Thing"),
+/// );
+/// ```
+pub fn build_usb_midi_device<'d, D: Driver<'d>, const N: usize, const P: usize>(
+    buffers: &'d mut UsbMidiBuffers<N, P>,
+    driver: impl FnOnce(&'d mut [u8]) -> D,
+    identity: &DeviceIdentity<'d>,
+) -> (UsbMidiClass<'d, D, N>, UsbDevice<'d, D>) {
+    let (mut builder, state) = buffers.builder(driver, identity.config());
+    let midi_class = UsbMidiClass::new(&mut builder, state);
+    (midi_class, builder.build())
+}
+
+impl<const N: usize, const P: usize> Default for UsbMidiBuffers<N, P> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes_config_descriptors() {
+        let mut buffers = UsbMidiBuffers::<1>::new();
+        assert_eq!(buffers.config_descriptor.as_mut_slice().len(), 109);
+        let mut buffers = UsbMidiBuffers::<16>::new();
+        assert_eq!(
+            buffers.config_descriptor.as_mut_slice().len(),
+            required_config_descriptor_len(16) + IAD_LEN
+        );
+    }
+
+    #[test]
+    fn sizes_endpoint_buffers() {
+        let mut buffers = UsbMidiBuffers::<1>::new();
+        assert_eq!(buffers.ep_out_buffer.as_mut_slice().len(), EP_OUT_BUFFER_LEN);
+        assert_eq!(buffers.state.packet_size(), PacketSize::Bytes64);
+        let mut buffers = UsbMidiBuffers::<1, 8>::new();
+        assert_eq!(buffers.ep_out_buffer.as_mut_slice().len(), 72);
+        assert_eq!(buffers.state.packet_size(), PacketSize::Bytes8);
+    }
+
+    #[test]
+    #[should_panic(expected = "packet size")]
+    fn rejects_odd_packet_sizes() {
+        UsbMidiBuffers::<1, 48>::new();
+    }
+}
@@ -0,0 +1,188 @@
+use core::slice::ChunksExact;
+
+use crate::event::Event;
+
+/// Handling of packets addressed to a cable the device does not declare.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CablePolicy {
+    /// Drop the packet and count it.
+    #[default]
+    Drop,
+    /// Deliver the packet on the highest declared cable.
+    Clamp,
+    /// Report the packet as [`InvalidCable`].
+    Error,
+}
+
+/// A packet was addressed to a cable number the device does not declare.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct InvalidCable(pub u8);
+
+/// A cable number that is known to be less than `N`.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct CableNumber<const N: usize>(pub(crate) u8);
+
+impl<const N: usize> CableNumber<N> {
+    pub const fn new(number: u8) -> Option<Self> {
+        if (number as usize) < N {
+            Some(Self(number))
+        } else {
+            None
+        }
+    }
+
+    pub const fn number(self) -> u8 {
+        self.0
+    }
+}
+
+impl<const N: usize> TryFrom<u8> for CableNumber<N> {
+    type Error = InvalidCable;
+
+    fn try_from(number: u8) -> Result<Self, Self::Error> {
+        Self::new(number).ok_or(InvalidCable(number))
+    }
+}
+
+impl<const N: usize> From<CableNumber<N>> for u8 {
+    fn from(cable: CableNumber<N>) -> u8 {
+        cable.0
+    }
+}
+
+/// Events of a transfer received from the host, tagged with their cable
+/// number.
+pub struct Events<'a, const N: usize> {
+    packets: ChunksExact<'a, u8>,
+    cables: u8,
+    policy: CablePolicy,
+    dropped: &'a mut u32,
+    normalize_note_offs: bool,
+}
+
+impl<'a, const N: usize> Events<'a, N> {
+    /// Splits `transfer` into events for the first `cables` of the `N`
+    /// cables.
+    pub(crate) fn new(transfer: &'a [u8], cables: u8, policy: CablePolicy, dropped: &'a mut u32) -> Self {
+        Self {
+            packets: transfer.chunks_exact(4),
+            cables,
+            policy,
+            dropped,
+            normalize_note_offs: false,
+        }
+    }
+
+    /// Rewrites every Note On with velocity 0 into a Note Off, see
+    /// [`Event::normalize_note_off`].
+    pub fn normalize_note_offs(mut self) -> Self {
+        self.normalize_note_offs = true;
+        self
+    }
+}
+
+impl<const N: usize> Iterator for Events<'_, N> {
+    type Item = Result<(CableNumber<N>, Event), InvalidCable>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let packet = self.packets.next()?;
+            let cable = packet[0] >> 4;
+            let mut event = Event::new(packet);
+            if self.normalize_note_offs {
+                event = event.normalize_note_off();
+            }
+            if cable < self.cables {
+                return Some(Ok((CableNumber(cable), event)));
+            }
+            match self.policy {
+                CablePolicy::Drop => {
+                    warn!("packet for cable {} dropped", cable);
+                    *self.dropped = self.dropped.wrapping_add(1);
+                }
+                CablePolicy::Clamp => return Some(Ok((CableNumber(self.cables - 1), event))),
+                CablePolicy::Error => return Some(Err(InvalidCable(cable))),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Note;
+
+    const TRANSFER: [u8; 12] = [0x09, 0x90, 60, 100, 0x29, 0x90, 62, 100, 0x19, 0x90, 64, 100];
+
+    #[test]
+    fn drops_invalid_cables() {
+        let mut dropped = 0;
+        let cables: Vec<_> = Events::<2>::new(&TRANSFER, 2, CablePolicy::Drop, &mut dropped)
+            .map(|result| result.unwrap().0.number())
+            .collect();
+        assert_eq!(cables, [0, 1]);
+        assert_eq!(dropped, 1);
+    }
+
+    #[test]
+    fn clamps_invalid_cables() {
+        let mut dropped = 0;
+        let cables: Vec<_> = Events::<2>::new(&TRANSFER, 2, CablePolicy::Clamp, &mut dropped)
+            .map(|result| result.unwrap().0.number())
+            .collect();
+        assert_eq!(cables, [0, 1, 1]);
+        assert_eq!(dropped, 0);
+    }
+
+    #[test]
+    fn reports_invalid_cables() {
+        let mut dropped = 0;
+        let mut events = Events::<2>::new(&TRANSFER, 2, CablePolicy::Error, &mut dropped);
+        assert!(events.next().unwrap().is_ok());
+        assert_eq!(events.next(), Some(Err(InvalidCable(2))));
+        assert!(events.next().unwrap().is_ok());
+        assert_eq!(events.next(), None);
+    }
+
+    #[test]
+    fn normalizes_note_offs() {
+        let mut dropped = 0;
+        let transfer = [0x09, 0x90, 60, 0, 0x09, 0x90, 62, 100];
+        let events: Vec<_> = Events::<1>::new(&transfer, 1, CablePolicy::Drop, &mut dropped)
+            .normalize_note_offs()
+            .map(|result| result.unwrap().1)
+            .collect();
+        assert_eq!(
+            events,
+            [
+                Event::NoteOff(0x80, Note::new(60), 64),
+                Event::NoteOn(0x90, Note::new(62), 100)
+            ]
+        );
+    }
+
+    #[test]
+    fn limits_cables_at_runtime() {
+        let mut dropped = 0;
+        let cables: Vec<_> = Events::<4>::new(&TRANSFER, 1, CablePolicy::Clamp, &mut dropped)
+            .map(|result| result.unwrap().0.number())
+            .collect();
+        assert_eq!(cables, [0, 0, 0]);
+
+        let cables: Vec<_> = Events::<4>::new(&TRANSFER, 2, CablePolicy::Drop, &mut dropped)
+            .map(|result| result.unwrap().0.number())
+            .collect();
+        assert_eq!(cables, [0, 1]);
+        assert_eq!(dropped, 1);
+    }
+
+    #[test]
+    fn validates_cable_numbers() {
+        assert_eq!(CableNumber::<4>::new(3).map(CableNumber::number), Some(3));
+        assert_eq!(CableNumber::<4>::new(4), None);
+        assert_eq!(CableNumber::<4>::try_from(7), Err(InvalidCable(7)));
+    }
+}
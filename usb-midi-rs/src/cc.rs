@@ -0,0 +1,102 @@
+//! Numbers of the MIDI control changes.
+//!
+//! Controllers 0 to 31 are the MSBs of 14-bit controllers whose LSBs are
+//! [`LSB`] higher. Controllers 120 to 127 are reserved for the channel mode
+//! messages.
+
+pub const BANK_SELECT: u8 = 0;
+pub const MODULATION: u8 = 1;
+pub const BREATH: u8 = 2;
+pub const FOOT: u8 = 4;
+pub const PORTAMENTO_TIME: u8 = 5;
+pub const DATA_ENTRY: u8 = 6;
+pub const VOLUME: u8 = 7;
+pub const BALANCE: u8 = 8;
+pub const PAN: u8 = 10;
+pub const EXPRESSION: u8 = 11;
+pub const EFFECT_CONTROL_1: u8 = 12;
+pub const EFFECT_CONTROL_2: u8 = 13;
+pub const GENERAL_PURPOSE_1: u8 = 16;
+pub const GENERAL_PURPOSE_2: u8 = 17;
+pub const GENERAL_PURPOSE_3: u8 = 18;
+pub const GENERAL_PURPOSE_4: u8 = 19;
+
+/// Offset from the MSB to the LSB of a 14-bit controller.
+pub const LSB: u8 = 32;
+pub const BANK_SELECT_LSB: u8 = BANK_SELECT + LSB;
+pub const DATA_ENTRY_LSB: u8 = DATA_ENTRY + LSB;
+
+/// Switches, on from 64.
+pub const SUSTAIN: u8 = 64;
+pub const PORTAMENTO: u8 = 65;
+pub const SOSTENUTO: u8 = 66;
+pub const SOFT_PEDAL: u8 = 67;
+pub const LEGATO: u8 = 68;
+pub const HOLD_2: u8 = 69;
+
+/// Sound controllers, named after their default meaning in GM2.
+pub const SOUND_VARIATION: u8 = 70;
+pub const RESONANCE: u8 = 71;
+pub const RELEASE_TIME: u8 = 72;
+pub const ATTACK_TIME: u8 = 73;
+pub const BRIGHTNESS: u8 = 74;
+pub const DECAY_TIME: u8 = 75;
+pub const VIBRATO_RATE: u8 = 76;
+pub const VIBRATO_DEPTH: u8 = 77;
+pub const VIBRATO_DELAY: u8 = 78;
+
+pub const PORTAMENTO_CONTROL: u8 = 84;
+pub const REVERB_SEND: u8 = 91;
+pub const CHORUS_SEND: u8 = 93;
+
+/// Registered and non-registered parameter numbers.
+pub const DATA_INCREMENT: u8 = 96;
+pub const DATA_DECREMENT: u8 = 97;
+pub const NRPN_LSB: u8 = 98;
+pub const NRPN_MSB: u8 = 99;
+pub const RPN_LSB: u8 = 100;
+pub const RPN_MSB: u8 = 101;
+
+/// Channel mode messages.
+pub const ALL_SOUND_OFF: u8 = 120;
+pub const RESET_ALL_CONTROLLERS: u8 = 121;
+pub const LOCAL_CONTROL: u8 = 122;
+pub const ALL_NOTES_OFF: u8 = 123;
+pub const OMNI_OFF: u8 = 124;
+pub const OMNI_ON: u8 = 125;
+pub const MONO_ON: u8 = 126;
+pub const POLY_ON: u8 = 127;
+
+/// Whether `control` is a channel mode message rather than a controller.
+pub const fn is_channel_mode_message(control: u8) -> bool {
+    matches!(control, ALL_SOUND_OFF..=POLY_ON)
+}
+
+/// Whether `control` is a switch, which is off below 64 and on from 64.
+pub const fn is_switch(control: u8) -> bool {
+    matches!(control, SUSTAIN..=HOLD_2)
+}
+
+/// The LSB controller belonging to the MSB controller `control`, if it has
+/// one.
+pub const fn lsb(control: u8) -> Option<u8> {
+    match control {
+        0..=31 => Some(control + LSB),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_controls() {
+        assert!(is_channel_mode_message(ALL_NOTES_OFF));
+        assert!(!is_channel_mode_message(RPN_MSB));
+        assert!(is_switch(SUSTAIN) && is_switch(HOLD_2));
+        assert!(!is_switch(SOUND_VARIATION));
+        assert_eq!(lsb(MODULATION), Some(33));
+        assert_eq!(lsb(BANK_SELECT_LSB), None);
+    }
+}
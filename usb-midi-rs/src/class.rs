@@ -0,0 +1,958 @@
+use core::mem::MaybeUninit;
+
+use embassy_sync::blocking_mutex::raw::RawMutex;
+use embassy_time::{with_timeout, Duration};
+use embassy_usb::control::ControlHandler;
+use embassy_usb::descriptor::EndpointExtra;
+use embassy_usb::driver::{Driver, Endpoint, EndpointError, EndpointIn, EndpointOut};
+use embassy_usb::types::StringIndex;
+use embassy_usb::{Builder, Config, InterfaceAltBuilder};
+
+use crate::descriptor::{
+    AcHeader, CsEndpoint, Descriptor, InJack, JackType, MsHeader, OutJack, Source, AUDIO_ENDPOINT_LEN,
+};
+use crate::port_names::{NameTable, PORT_NAME_LEN};
+use crate::{
+    BufferTooSmall, CableNumber, CablePolicy, Error, Event, Events, MidiMessage, PortNames,
+    AUDIO_SUBCLASS_MIDISTREAMING, MAX_PACKET_SIZE, USB_CLASS_AUDIO,
+};
+
+const AUDIO_SUBCLASS_AUDIOCONTROL: u8 = 0x01;
+const AUDIO_PROTOCOL_UNDEFINED: u8 = 0x00;
+/// Class, subclass and protocol of the function, written to the interface
+/// association descriptor with `composite_with_iads`. The class must not be
+/// 0 there.
+const FUNCTION_CLASS: (u8, u8, u8) = (USB_CLASS_AUDIO, AUDIO_SUBCLASS_AUDIOCONTROL, AUDIO_PROTOCOL_UNDEFINED);
+
+/// Length of the standard configuration descriptor.
+const CONFIGURATION_LEN: usize = 9;
+/// Length of a standard interface descriptor.
+const INTERFACE_LEN: usize = 9;
+/// Length of an interface association descriptor.
+pub const IAD_LEN: usize = 8;
+const MAX_MIDI_INTERFACE_COUNT: u8 = 16;
+
+const PORT_NAMES: [&str; MAX_MIDI_INTERFACE_COUNT as usize] = [
+    "Port 1", "Port 2", "Port 3", "Port 4", "Port 5", "Port 6", "Port 7", "Port 8", "Port 9", "Port 10", "Port 11",
+    "Port 12", "Port 13", "Port 14", "Port 15", "Port 16",
+];
+
+/// `wMaxPacketSize` of the bulk endpoints.
+///
+/// Full-speed bulk endpoints may only declare these sizes. Smaller packets
+/// take more transfers for the same traffic, but shrink the buffers of
+/// drivers that receive into RAM of their own, see
+/// [`UsbMidiBuffers`](crate::UsbMidiBuffers).
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PacketSize {
+    Bytes8 = 8,
+    Bytes16 = 16,
+    Bytes32 = 32,
+    #[default]
+    Bytes64 = 64,
+}
+
+impl PacketSize {
+    /// The packet size of `len` bytes, if bulk endpoints may declare it.
+    pub const fn from_len(len: u16) -> Option<Self> {
+        match len {
+            8 => Some(PacketSize::Bytes8),
+            16 => Some(PacketSize::Bytes16),
+            32 => Some(PacketSize::Bytes32),
+            64 => Some(PacketSize::Bytes64),
+            _ => None,
+        }
+    }
+
+    pub const fn bytes(self) -> usize {
+        self as usize
+    }
+
+    /// Number of USB-MIDI packets fitting into a packet of this size.
+    pub const fn events(self) -> usize {
+        self.bytes() / 4
+    }
+}
+
+/// Packet size of a pair of bulk endpoints, which must agree on a size bulk
+/// endpoints may declare.
+fn packet_size_of(read_ep: &impl Endpoint, write_ep: &impl Endpoint) -> PacketSize {
+    let (read, write) = (read_ep.info().max_packet_size, write_ep.info().max_packet_size);
+    assert_eq!(read, write, "the bulk endpoints must have the same packet size");
+    PacketSize::from_len(read).expect("the packet size must be 8, 16, 32 or 64 bytes")
+}
+
+pub struct Control {
+    string_offset: u8,
+    ports: u8,
+    names: Option<&'static dyn NameTable>,
+    /// The name last read from `names`.
+    name: [u8; PORT_NAME_LEN],
+}
+
+pub struct State {
+    control: MaybeUninit<Control>,
+    names: Option<&'static dyn NameTable>,
+    packet_size: PacketSize,
+}
+
+impl State {
+    pub fn new() -> Self {
+        Self {
+            control: MaybeUninit::uninit(),
+            names: None,
+            packet_size: PacketSize::default(),
+        }
+    }
+
+    /// Names the ports with `names` instead of "Port 1", "Port 2" and so on.
+    pub fn with_port_names<M: RawMutex + 'static, const N: usize>(names: &'static PortNames<M, N>) -> Self {
+        Self {
+            names: Some(names),
+            ..Self::new()
+        }
+    }
+
+    /// Declares bulk endpoints of `packet_size` instead of 64 bytes.
+    pub fn with_packet_size(mut self, packet_size: PacketSize) -> Self {
+        self.packet_size = packet_size;
+        self
+    }
+
+    /// `wMaxPacketSize` the bulk endpoints are declared with.
+    pub fn packet_size(&self) -> PacketSize {
+        self.packet_size
+    }
+}
+
+impl ControlHandler for Control {
+    fn get_string(&mut self, index: StringIndex, _lang_id: u16) -> Option<&str> {
+        let index: u8 = index.into();
+        let port = match index.checked_sub(self.string_offset) {
+            Some(port) if port < self.ports => port as usize,
+            _ => return None,
+        };
+        let len = self.names.map_or(0, |names| names.copy_name(port, &mut self.name));
+        match len {
+            0 => Some(PORT_NAMES[port]),
+            len => core::str::from_utf8(&self.name[..len]).ok(),
+        }
+    }
+}
+
+/// Jacks of a single MIDI port.
+///
+/// The embedded IN jack receives data from the host and is wired to the
+/// external OUT jack. The external IN jack is wired to the embedded OUT jack,
+/// which sends data to the host.
+struct Port {
+    name: u8,
+    in_embedded: u8,
+    in_external: u8,
+    out_embedded: u8,
+    out_external: u8,
+}
+
+impl Port {
+    /// Combined length of the jack descriptors of a port.
+    const DESCRIPTORS_LEN: usize = 2 * InJack::LEN + 2 * OutJack::len(1);
+
+    fn new(index: usize, name: u8) -> Self {
+        let offset = index as u8 * 4;
+        Self {
+            name,
+            in_embedded: offset + 0x01,
+            in_external: offset + 0x02,
+            out_embedded: offset + 0x03,
+            out_external: offset + 0x04,
+        }
+    }
+
+    fn in_jacks(&self) -> [InJack; 2] {
+        [
+            InJack {
+                jack_type: JackType::Embedded,
+                id: self.in_embedded,
+                name: self.name,
+            },
+            InJack {
+                jack_type: JackType::External,
+                id: self.in_external,
+                name: 0,
+            },
+        ]
+    }
+
+    /// Sources of the embedded and the external OUT jack.
+    fn out_jack_sources(&self) -> [[Source; 1]; 2] {
+        [
+            [Source {
+                id: self.in_external,
+                pin: 0x01,
+            }],
+            [Source {
+                id: self.in_embedded,
+                pin: 0x01,
+            }],
+        ]
+    }
+
+    fn out_jacks<'a>(&self, sources: &'a [[Source; 1]; 2]) -> [OutJack<'a>; 2] {
+        [
+            OutJack {
+                jack_type: JackType::Embedded,
+                id: self.out_embedded,
+                sources: &sources[0],
+                name: self.name,
+            },
+            OutJack {
+                jack_type: JackType::External,
+                id: self.out_external,
+                sources: &sources[1],
+                name: 0,
+            },
+        ]
+    }
+
+    fn write_descriptors(&self, sink: &mut impl DescriptorSink) {
+        let sources = self.out_jack_sources();
+        for jack in &self.in_jacks() {
+            write_descriptor(sink, jack);
+        }
+        for jack in &self.out_jacks(&sources) {
+            write_descriptor(sink, jack);
+        }
+    }
+}
+
+/// Combined length of the class-specific MIDIStreaming header and everything
+/// after it, as in `wTotalLength`.
+const fn ms_descriptors_len(ports: usize) -> usize {
+    MsHeader::LEN + ports * Port::DESCRIPTORS_LEN + 2 * (AUDIO_ENDPOINT_LEN + CsEndpoint::len(ports))
+}
+
+/// Length of the configuration descriptor of a device whose only function is
+/// a [`UsbMidiClass`] with `ports` ports.
+///
+/// Add [`IAD_LEN`] if the device sets `composite_with_iads`, as well as the
+/// descriptors of any other function. See also
+/// [`check_config_descriptor`].
+pub const fn required_config_descriptor_len(ports: usize) -> usize {
+    CONFIGURATION_LEN + INTERFACE_LEN + AcHeader::len(1) + INTERFACE_LEN + ms_descriptors_len(ports)
+}
+
+/// Checks that `buffer` holds the configuration descriptor of a device
+/// configured with `config` whose only function is a [`UsbMidiClass`] with
+/// `ports` ports.
+///
+/// Embassy panics without telling how much space is missing if the buffer is
+/// too small, so call this before passing the buffer to the [`Builder`].
+pub fn check_config_descriptor(buffer: &[u8], config: &Config<'_>, ports: usize) -> Result<(), BufferTooSmall> {
+    let iad_len = if config.composite_with_iads { IAD_LEN } else { 0 };
+    let required = required_config_descriptor_len(ports) + iad_len;
+    if buffer.len() < required {
+        return Err(BufferTooSmall {
+            required,
+            len: buffer.len(),
+        });
+    }
+    Ok(())
+}
+
+/// Where class-specific descriptors are written to: the interface being
+/// built, or a plain buffer in the tests.
+trait DescriptorSink {
+    fn descriptor(&mut self, descriptor_type: u8, body: &[u8]);
+}
+
+impl<'d, D: Driver<'d>> DescriptorSink for InterfaceAltBuilder<'_, 'd, D> {
+    fn descriptor(&mut self, descriptor_type: u8, body: &[u8]) {
+        InterfaceAltBuilder::descriptor(self, descriptor_type, body)
+    }
+}
+
+fn write_descriptor<T: Descriptor>(sink: &mut impl DescriptorSink, descriptor: &T) {
+    sink.descriptor(T::DESCRIPTOR_TYPE, &descriptor.body());
+}
+
+/// Writes the class-specific MIDIStreaming header followed by the jacks of
+/// `ports`.
+fn write_ms_descriptors(ports: &[Port], sink: &mut impl DescriptorSink) {
+    write_descriptor(
+        sink,
+        &MsHeader {
+            total_length: ms_descriptors_len(ports.len()) as u16,
+        },
+    );
+    for port in ports {
+        port.write_descriptors(sink);
+    }
+}
+
+/// Writes the class-specific descriptor of the bulk IN endpoint, which is
+/// associated with the embedded OUT jacks of `ports`, or of the bulk OUT
+/// endpoint, which is associated with the embedded IN jacks.
+fn write_endpoint_descriptor(ports: &[Port], input: bool, sink: &mut impl DescriptorSink) {
+    let mut jacks = [0; MAX_MIDI_INTERFACE_COUNT as usize];
+    for (jack, port) in jacks.iter_mut().zip(ports) {
+        *jack = if input { port.out_embedded } else { port.in_embedded };
+    }
+    write_descriptor(
+        sink,
+        &CsEndpoint {
+            jacks: &jacks[..ports.len()],
+        },
+    );
+}
+
+pub struct UsbMidiClass<'d, D: Driver<'d>, const N: usize> {
+    sender: Sender<'d, D, N>,
+    receiver: Receiver<'d, D, N>,
+}
+
+impl<'d, D: Driver<'d>, const N: usize> UsbMidiClass<'d, D, N> {
+    pub fn new(builder: &mut Builder<'d, D>, state: &'d mut State) -> Self {
+        Self::with_ports(builder, state, N)
+    }
+
+    /// Creates the class with only the first `ports` of the `N` ports, e.g.
+    /// with a port count read from stored settings at boot.
+    ///
+    /// The descriptors declare `ports` ports. Packets from the host on other
+    /// cables are handled according to the cable policy, and writing to them
+    /// fails with [`Error::Unsupported`].
+    pub fn with_ports(builder: &mut Builder<'d, D>, state: &'d mut State, ports: usize) -> Self {
+        assert!(N > 0, "interface count must be at least 1");
+        assert!(
+            N <= MAX_MIDI_INTERFACE_COUNT as usize,
+            "interface count must not be greater than 16"
+        );
+        assert!(
+            ports > 0 && ports <= N,
+            "port count must be between 1 and the interface count"
+        );
+
+        let (class, subclass, protocol) = FUNCTION_CLASS;
+        let mut func = builder.function(class, subclass, protocol);
+
+        // AudioControl Interface
+        //
+        let mut iface = func.interface();
+        let mut alt = iface.alt_setting(USB_CLASS_AUDIO, AUDIO_SUBCLASS_AUDIOCONTROL, AUDIO_PROTOCOL_UNDEFINED);
+        write_descriptor(
+            &mut alt,
+            &AcHeader {
+                streaming_interfaces: &[0x01], // MS interface 1 belongs to this AC interface
+            },
+        );
+
+        // MIDIStreaming Interface
+        //
+        let mut iface = func.interface();
+
+        // reserve string indices for port names
+        let mut port_names = [0u8; N];
+        for idx in &mut port_names[..ports] {
+            *idx = iface.string().into();
+        }
+
+        let control = state.control.write(Control {
+            string_offset: port_names[0],
+            ports: ports as u8,
+            names: state.names,
+            name: [0; PORT_NAME_LEN],
+        });
+        iface.handler(control);
+
+        let mut alt = iface.alt_setting(USB_CLASS_AUDIO, AUDIO_SUBCLASS_MIDISTREAMING, AUDIO_PROTOCOL_UNDEFINED);
+
+        let cables = ports as u8;
+        let all_ports: [Port; N] = core::array::from_fn(|i| Port::new(i, port_names[i]));
+        let ports = &all_ports[..cables as usize];
+
+        // Class-specific MS Interface Descriptor and jacks
+        write_ms_descriptors(ports, &mut alt);
+
+        // Standard Bulk OUT Endpoint Descriptor
+        let max_packet_size = state.packet_size.bytes() as u16;
+        let read_ep = alt.endpoint_bulk_out(max_packet_size, EndpointExtra::audio(0, 0));
+        write_endpoint_descriptor(ports, false, &mut alt);
+
+        let write_ep = alt.endpoint_bulk_in(max_packet_size, EndpointExtra::audio(0, 0));
+        write_endpoint_descriptor(ports, true, &mut alt);
+
+        // Drivers may not support every size, check they declare what was asked for.
+        let packet_size = packet_size_of(&read_ep, &write_ep);
+        assert_eq!(packet_size, state.packet_size, "the driver changed the packet size");
+
+        UsbMidiClass {
+            sender: Sender {
+                write_ep,
+                cables,
+                stalled: false,
+                packet_size,
+            },
+            receiver: Receiver {
+                read_ep,
+                cables,
+                cable_policy: CablePolicy::default(),
+                dropped_packets: 0,
+                packet_size,
+            },
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn from_endpoints(read_ep: D::EndpointOut, write_ep: D::EndpointIn, ports: usize) -> Self {
+        packet_size_of(&read_ep, &write_ep);
+        Self {
+            sender: Sender::from_endpoint(write_ep, ports as u8),
+            receiver: Receiver::from_endpoint(read_ep, ports as u8),
+        }
+    }
+
+    /// Number of ports the device declares.
+    pub fn ports(&self) -> usize {
+        self.sender.cables as usize
+    }
+
+    /// `wMaxPacketSize` of the bulk endpoints.
+    pub fn packet_size(&self) -> PacketSize {
+        self.sender.packet_size
+    }
+
+    /// Splits the class into a sender and a receiver, so that reading and
+    /// writing can happen in different tasks.
+    pub fn split(self) -> (Sender<'d, D, N>, Receiver<'d, D, N>) {
+        (self.sender, self.receiver)
+    }
+
+    pub async fn read_packets(&mut self, data: &mut [u8]) -> Result<usize, EndpointError> {
+        self.receiver.read_packets(data).await
+    }
+
+    pub async fn read_events<'a>(&'a mut self, data: &'a mut [u8]) -> Result<Events<'a, N>, Error> {
+        self.receiver.read_events(data).await
+    }
+
+    pub fn set_cable_policy(&mut self, policy: CablePolicy) {
+        self.receiver.set_cable_policy(policy)
+    }
+
+    pub fn dropped_packets(&self) -> u32 {
+        self.receiver.dropped_packets()
+    }
+
+    pub async fn write_packet(&mut self, data: &[u8]) -> Result<(), EndpointError> {
+        self.sender.write_packet(data).await
+    }
+
+    pub async fn write_packet_timeout(&mut self, data: &[u8], timeout: Duration) -> Result<(), Error> {
+        self.sender.write_packet_timeout(data, timeout).await
+    }
+
+    pub fn is_stalled(&self) -> bool {
+        self.sender.is_stalled()
+    }
+
+    pub async fn write_event(&mut self, cable: CableNumber<N>, event: Event) -> Result<(), Error> {
+        self.sender.write_event(cable, event).await
+    }
+
+    pub async fn write_message(&mut self, cable: CableNumber<N>, message: MidiMessage) -> Result<(), Error> {
+        self.sender.write_message(cable, message).await
+    }
+
+    pub async fn write_events<E: Copy + Into<Event>>(
+        &mut self,
+        cable: CableNumber<N>,
+        events: &[E],
+    ) -> Result<(), Error> {
+        self.sender.write_events(cable, events).await
+    }
+
+    pub async fn wait_connection(&mut self) {
+        self.receiver.wait_connection().await
+    }
+}
+
+impl<'d, D: Driver<'d>> UsbMidiClass<'d, D, 2> {
+    pub fn split_cables(&self) -> (CableNumber<2>, CableNumber<2>) {
+        (CableNumber(0), CableNumber(1))
+    }
+}
+
+/// Receiving half of a [`UsbMidiClass`].
+pub struct Receiver<'d, D: Driver<'d>, const N: usize> {
+    read_ep: D::EndpointOut,
+    cables: u8,
+    cable_policy: CablePolicy,
+    dropped_packets: u32,
+    packet_size: PacketSize,
+}
+
+impl<'d, D: Driver<'d>, const N: usize> Receiver<'d, D, N> {
+    pub async fn read_packets(&mut self, data: &mut [u8]) -> Result<usize, EndpointError> {
+        self.read_ep.read(data).await
+    }
+
+    /// Reads a transfer into `data` and returns its events.
+    ///
+    /// Packets addressed to cables the device does not declare are handled
+    /// according to the cable policy.
+    pub async fn read_events<'a>(&'a mut self, data: &'a mut [u8]) -> Result<Events<'a, N>, Error> {
+        let count = self.read_ep.read(data).await?;
+        trace_io!("OUT {=[u8]:02x}", &data[..count]);
+        Ok(Events::new(
+            &data[..count],
+            self.cables,
+            self.cable_policy,
+            &mut self.dropped_packets,
+        ))
+    }
+
+    pub fn set_cable_policy(&mut self, policy: CablePolicy) {
+        self.cable_policy = policy;
+    }
+
+    /// Number of packets dropped because of an invalid cable number.
+    pub fn dropped_packets(&self) -> u32 {
+        self.dropped_packets
+    }
+
+    pub async fn wait_connection(&mut self) {
+        self.read_ep.wait_enabled().await
+    }
+
+    /// `wMaxPacketSize` of the endpoint, the least `data` must hold for
+    /// reading.
+    pub fn packet_size(&self) -> PacketSize {
+        self.packet_size
+    }
+
+    #[cfg(test)]
+    pub(crate) fn from_endpoint(read_ep: D::EndpointOut, cables: u8) -> Self {
+        let packet_size = PacketSize::from_len(read_ep.info().max_packet_size).unwrap();
+        Self {
+            read_ep,
+            cables,
+            cable_policy: CablePolicy::default(),
+            dropped_packets: 0,
+            packet_size,
+        }
+    }
+}
+
+/// Sending half of a [`UsbMidiClass`].
+pub struct Sender<'d, D: Driver<'d>, const N: usize> {
+    write_ep: D::EndpointIn,
+    cables: u8,
+    stalled: bool,
+    packet_size: PacketSize,
+}
+
+impl<'d, D: Driver<'d>, const N: usize> Sender<'d, D, N> {
+    /// Writes raw packets, at most [`packet_size`](Self::packet_size) bytes.
+    ///
+    /// The cable numbers of the packets are not checked; prefer
+    /// [`write_event`](Self::write_event) and
+    /// [`write_message`](Self::write_message), which only accept cables the
+    /// device declares.
+    pub async fn write_packet(&mut self, data: &[u8]) -> Result<(), EndpointError> {
+        trace_io!("IN {=[u8]:02x}", data);
+        let result = self.write_ep.write(data).await;
+        if result.is_ok() {
+            self.stalled = false;
+        }
+        result
+    }
+
+    /// Writes raw packets, giving up if the host does not read them within
+    /// `timeout`.
+    ///
+    /// A timeout marks the sender as stalled until a write completes again.
+    pub async fn write_packet_timeout(&mut self, data: &[u8], timeout: Duration) -> Result<(), Error> {
+        trace_io!("IN {=[u8]:02x}", data);
+        let result = with_timeout(timeout, self.write_ep.write(data)).await;
+        if result.is_err() && !self.stalled {
+            warn!("host stopped reading");
+        }
+        self.stalled = result.is_err();
+        Ok(result??)
+    }
+
+    /// Returns `true` if the host stopped reading, e.g. because the
+    /// application that opened the port was closed.
+    pub fn is_stalled(&self) -> bool {
+        self.stalled
+    }
+
+    /// Writes an event to the host.
+    ///
+    /// Fails with [`Error::Unsupported`] if the device does not declare
+    /// `cable`, see [`UsbMidiClass::with_ports`].
+    pub async fn write_event(&mut self, cable: CableNumber<N>, event: Event) -> Result<(), Error> {
+        if cable.number() >= self.cables {
+            return Err(Error::Unsupported);
+        }
+        Ok(self.write_packet(&event.to_packet(cable.number())).await?)
+    }
+
+    pub async fn write_message(&mut self, cable: CableNumber<N>, message: MidiMessage) -> Result<(), Error> {
+        self.write_event(cable, message.into()).await
+    }
+
+    /// Writes events or messages, e.g. the notes of a chord, packed into as
+    /// few transfers as possible.
+    ///
+    /// Fails like [`write_event`](Self::write_event). Transfers written
+    /// before an error stay written.
+    pub async fn write_events<E: Copy + Into<Event>>(
+        &mut self,
+        cable: CableNumber<N>,
+        events: &[E],
+    ) -> Result<(), Error> {
+        if cable.number() >= self.cables {
+            return Err(Error::Unsupported);
+        }
+        let mut buf = [0; MAX_PACKET_SIZE as usize];
+        for chunk in events.chunks(self.packet_size.events()) {
+            for (packet, &event) in buf.chunks_exact_mut(4).zip(chunk) {
+                packet.copy_from_slice(&event.into().to_packet(cable.number()));
+            }
+            self.write_packet(&buf[..4 * chunk.len()]).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn wait_connection(&mut self) {
+        self.write_ep.wait_enabled().await
+    }
+
+    /// Number of ports the device declares.
+    pub fn ports(&self) -> usize {
+        self.cables as usize
+    }
+
+    /// `wMaxPacketSize` of the endpoint, the most a single write may carry.
+    pub fn packet_size(&self) -> PacketSize {
+        self.packet_size
+    }
+
+    #[cfg(test)]
+    pub(crate) fn from_endpoint(write_ep: D::EndpointIn, cables: u8) -> Self {
+        let packet_size = PacketSize::from_len(write_ep.info().max_packet_size).unwrap();
+        Self {
+            write_ep,
+            cables,
+            stalled: false,
+            packet_size,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::pin::pin;
+    use core::task::Poll;
+
+    use embassy_futures::block_on;
+
+    use super::*;
+    use crate::descriptor::{CS_INTERFACE, MIDI_IN_JACK, MIDI_OUT_JACK};
+    use crate::fault::{endpoints, poll_once, sized_endpoints, Faults, FaultyDriver, Pipe};
+
+    /// The "MIDI Adapter" of appendix B of the USB MIDI 1.0 specification:
+    /// the AudioControl and MIDIStreaming interfaces and the endpoints.
+    #[rustfmt::skip]
+    const SPEC_ADAPTER: [u8; 92] = [
+        0x09, 0x04, 0x00, 0x00, 0x00, 0x01, 0x01, 0x00, 0x00,
+        0x09, 0x24, 0x01, 0x00, 0x01, 0x09, 0x00, 0x01, 0x01,
+        0x09, 0x04, 0x01, 0x00, 0x02, 0x01, 0x03, 0x00, 0x00,
+        0x07, 0x24, 0x01, 0x00, 0x01, 0x41, 0x00,
+        0x06, 0x24, 0x02, 0x01, 0x01, 0x00,
+        0x06, 0x24, 0x02, 0x02, 0x02, 0x00,
+        0x09, 0x24, 0x03, 0x01, 0x03, 0x01, 0x02, 0x01, 0x00,
+        0x09, 0x24, 0x03, 0x02, 0x04, 0x01, 0x01, 0x01, 0x00,
+        0x09, 0x05, 0x01, 0x02, 0x40, 0x00, 0x00, 0x00, 0x00,
+        0x05, 0x25, 0x01, 0x01, 0x01,
+        0x09, 0x05, 0x81, 0x02, 0x40, 0x00, 0x00, 0x00, 0x00,
+        0x05, 0x25, 0x01, 0x01, 0x03,
+    ];
+
+    /// The interface association descriptor of an audio function with an
+    /// AudioControl and a MIDIStreaming interface, as in the Interface
+    /// Association Descriptor ECN of the USB 2.0 specification, which
+    /// does not allow a function class of 0.
+    const AUDIO_IAD: [u8; IAD_LEN] = [0x08, 0x0b, 0x00, 0x02, 0x01, 0x01, 0x00, 0x00];
+
+    impl DescriptorSink for std::vec::Vec<u8> {
+        fn descriptor(&mut self, descriptor_type: u8, body: &[u8]) {
+            self.extend_from_slice(&[body.len() as u8 + 2, descriptor_type]);
+            self.extend_from_slice(body);
+        }
+    }
+
+    /// The interfaces of a class with `ports` ports, with the standard
+    /// descriptors written by embassy in between.
+    fn interfaces(ports: usize) -> std::vec::Vec<u8> {
+        let ports: std::vec::Vec<_> = (0..ports).map(|i| Port::new(i, 0)).collect();
+        let mut dump = std::vec::Vec::new();
+        dump.extend_from_slice(&[0x09, 0x04, 0x00, 0x00, 0x00, 0x01, 0x01, 0x00, 0x00]);
+        write_descriptor(
+            &mut dump,
+            &AcHeader {
+                streaming_interfaces: &[0x01],
+            },
+        );
+        dump.extend_from_slice(&[0x09, 0x04, 0x01, 0x00, 0x02, 0x01, 0x03, 0x00, 0x00]);
+        write_ms_descriptors(&ports, &mut dump);
+        dump.extend_from_slice(&[0x09, 0x05, 0x01, 0x02, 0x40, 0x00, 0x00, 0x00, 0x00]);
+        write_endpoint_descriptor(&ports, false, &mut dump);
+        dump.extend_from_slice(&[0x09, 0x05, 0x81, 0x02, 0x40, 0x00, 0x00, 0x00, 0x00]);
+        write_endpoint_descriptor(&ports, true, &mut dump);
+        dump
+    }
+
+    /// The interfaces of a class with `ports` ports behind the interface
+    /// association descriptor embassy writes with `composite_with_iads`.
+    fn associated_interfaces(ports: usize) -> std::vec::Vec<u8> {
+        let (class, subclass, protocol) = FUNCTION_CLASS;
+        let iad = [IAD_LEN as u8, 0x0b, 0x00, 0x02, class, subclass, protocol, 0x00];
+        [&iad[..], &interfaces(ports)].concat()
+    }
+
+    /// Splits `dump` into descriptors by their `bLength`.
+    fn split(mut dump: &[u8]) -> std::vec::Vec<&[u8]> {
+        let mut descriptors = std::vec::Vec::new();
+        while let Some(&len) = dump.first() {
+            assert!(
+                len >= 2 && len as usize <= dump.len(),
+                "bad length {} at {:02x?}",
+                len,
+                dump
+            );
+            let (descriptor, rest) = dump.split_at(len as usize);
+            descriptors.push(descriptor);
+            dump = rest;
+        }
+        descriptors
+    }
+
+    /// The descriptors of [`SPEC_ADAPTER`] with its port repeated `ports`
+    /// times and the jack IDs of port `i` offset by 4 * `i`, written out
+    /// from the specification rather than by the class.
+    fn spec_adapter(ports: u8) -> std::vec::Vec<u8> {
+        let spec = split(&SPEC_ADAPTER);
+        let mut jacks = std::vec::Vec::new();
+        for port in 0..ports {
+            for jack in &spec[4..8] {
+                let mut jack = jack.to_vec();
+                jack[4] += 4 * port;
+                if jack[2] == MIDI_OUT_JACK {
+                    jack[6] += 4 * port;
+                }
+                jacks.extend(jack);
+            }
+        }
+        let endpoint = |jack: u8| {
+            let mut descriptor = std::vec![4 + ports, 0x25, 0x01, ports];
+            descriptor.extend((0..ports).map(|port| jack + 4 * port));
+            descriptor
+        };
+        let (out_endpoint, in_endpoint) = (endpoint(spec[9][4]), endpoint(spec[11][4]));
+        let total_length =
+            (spec[3].len() + jacks.len() + spec[8].len() + out_endpoint.len() + spec[10].len() + in_endpoint.len())
+                as u16;
+        let mut header = spec[3].to_vec();
+        header[5..7].copy_from_slice(&total_length.to_le_bytes());
+        [
+            spec[0],
+            spec[1],
+            spec[2],
+            &header,
+            &jacks,
+            spec[8],
+            &out_endpoint,
+            spec[10],
+            &in_endpoint,
+        ]
+        .concat()
+    }
+
+    /// Compares the descriptors of `dump` with those of `golden`, ignoring
+    /// the string indices of jacks.
+    fn assert_layout(dump: &[u8], golden: &[u8]) {
+        let (dump, golden) = (split(dump), split(golden));
+        assert_eq!(dump.len(), golden.len(), "number of descriptors");
+        for (i, (ours, theirs)) in dump.iter().zip(&golden).enumerate() {
+            let mut ours = ours.to_vec();
+            if ours[1] == CS_INTERFACE && (ours[2] == MIDI_IN_JACK || ours[2] == MIDI_OUT_JACK) {
+                *ours.last_mut().unwrap() = 0;
+            }
+            assert_eq!(ours, *theirs, "descriptor {}", i);
+        }
+    }
+
+    #[test]
+    fn drops_cut_packets() {
+        let (pipe, faults) = (Pipe::default(), Faults::default());
+        let mut receiver: Receiver<FaultyDriver<Pipe>, 1> = Receiver::from_endpoint(endpoints(&pipe, &faults).0, 1);
+        pipe.send(&[0x09, 0x90, 60, 100, 0x08, 0x80, 60, 0]);
+        faults.short_read(6);
+        let mut buf = [0; 64];
+        let events: std::vec::Vec<_> = block_on(receiver.read_events(&mut buf)).unwrap().collect();
+        assert_eq!(
+            events,
+            [Ok((CableNumber(0), Event::NoteOn(0x90, crate::Note::new(60), 100)))]
+        );
+
+        faults.disable();
+        pipe.send(&[0x09, 0x90, 60, 100]);
+        assert_eq!(
+            block_on(receiver.read_events(&mut buf)).err(),
+            Some(Error::Disconnected)
+        );
+    }
+
+    #[test]
+    fn waits_for_slow_hosts() {
+        let (pipe, faults) = (Pipe::default(), Faults::default());
+        let mut sender: Sender<FaultyDriver<Pipe>, 1> = Sender::from_endpoint(endpoints(&pipe, &faults).1, 1);
+        faults.delay_writes(2);
+        {
+            let mut write = pin!(sender.write_message(CableNumber(0), MidiMessage::Start));
+            assert!(poll_once(write.as_mut()).is_pending());
+            assert!(poll_once(write.as_mut()).is_pending());
+            assert_eq!(poll_once(write.as_mut()), Poll::Ready(Ok(())));
+        }
+        assert_eq!(pipe.take_written(), [[0x0f, 0xfa, 0, 0]]);
+
+        // Unplugging ends a write in progress.
+        let mut write = pin!(sender.write_message(CableNumber(0), MidiMessage::Stop));
+        assert!(poll_once(write.as_mut()).is_pending());
+        faults.disable();
+        assert_eq!(poll_once(write.as_mut()), Poll::Ready(Err(Error::Disconnected)));
+        assert!(pipe.take_written().is_empty());
+    }
+
+    #[test]
+    fn declares_small_packets() {
+        let (pipe, faults) = (Pipe::default(), Faults::default());
+        let (read_ep, write_ep) = sized_endpoints(&pipe, &faults, 16, 16);
+        let class: UsbMidiClass<FaultyDriver<Pipe>, 1> = UsbMidiClass::from_endpoints(read_ep, write_ep, 1);
+        assert_eq!(class.packet_size(), PacketSize::Bytes16);
+        let (sender, receiver) = class.split();
+        assert_eq!(
+            (sender.packet_size(), receiver.packet_size()),
+            (PacketSize::Bytes16, PacketSize::Bytes16)
+        );
+        assert_eq!(PacketSize::Bytes16.events(), 4);
+        assert_eq!(PacketSize::from_len(24), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "same packet size")]
+    fn rejects_mismatched_packet_sizes() {
+        let (pipe, faults) = (Pipe::default(), Faults::default());
+        let (read_ep, write_ep) = sized_endpoints(&pipe, &faults, 64, 32);
+        let _: UsbMidiClass<FaultyDriver<Pipe>, 1> = UsbMidiClass::from_endpoints(read_ep, write_ep, 1);
+    }
+
+    #[test]
+    fn packs_chords() {
+        let (pipe, faults) = (Pipe::default(), Faults::default());
+        let write_ep = sized_endpoints(&pipe, &faults, 8, 8).1;
+        let mut sender: Sender<FaultyDriver<Pipe>, 2> = Sender::from_endpoint(write_ep, 1);
+        let channel = crate::Channel::new(0);
+        let chord = [60, 64, 67].map(|note| MidiMessage::NoteOn(channel, crate::Note::new(note), 100));
+        block_on(sender.write_events(CableNumber(0), &chord)).unwrap();
+        assert_eq!(
+            pipe.take_written(),
+            [
+                vec![0x09, 0x90, 60, 100, 0x09, 0x90, 64, 100],
+                vec![0x09, 0x90, 67, 100]
+            ]
+        );
+
+        let events = [Event::from(MidiMessage::Start)];
+        assert_eq!(
+            block_on(sender.write_events(CableNumber(1), &events)),
+            Err(Error::Unsupported)
+        );
+        assert!(pipe.take_written().is_empty());
+    }
+
+    #[test]
+    fn renames_ports() {
+        use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+
+        static NAMES: PortNames<CriticalSectionRawMutex, 2> = PortNames::new();
+        let mut control = Control {
+            string_offset: 4,
+            ports: 2,
+            names: State::with_port_names(&NAMES).names,
+            name: [0; PORT_NAME_LEN],
+        };
+        assert_eq!(control.get_string(StringIndex::new(5), 0x0409), Some("Port 2"));
+        NAMES.set(1, "Drum Machine").unwrap();
+        assert_eq!(control.get_string(StringIndex::new(5), 0x0409), Some("Drum Machine"));
+        assert_eq!(control.get_string(StringIndex::new(4), 0x0409), Some("Port 1"));
+        assert_eq!(control.get_string(StringIndex::new(6), 0x0409), None);
+        assert_eq!(
+            NAMES.set(0, "A name much too long for any port"),
+            Err(Error::BufferOverflow)
+        );
+        NAMES.set(1, "").unwrap();
+        assert_eq!(control.get_string(StringIndex::new(5), 0x0409), Some("Port 2"));
+    }
+
+    #[test]
+    fn matches_golden_descriptors() {
+        assert_layout(&interfaces(1), &SPEC_ADAPTER);
+        assert_eq!(spec_adapter(1), SPEC_ADAPTER);
+        for ports in [2, MAX_MIDI_INTERFACE_COUNT] {
+            assert_layout(&interfaces(ports as usize), &spec_adapter(ports));
+        }
+        assert_layout(&associated_interfaces(1), &[&AUDIO_IAD[..], &SPEC_ADAPTER].concat());
+        assert_layout(&associated_interfaces(4), &[&AUDIO_IAD[..], &spec_adapter(4)].concat());
+    }
+
+    #[test]
+    fn totals_streaming_descriptors() {
+        for ports in 1..=MAX_MIDI_INTERFACE_COUNT as usize {
+            let dump = interfaces(ports);
+            // The MIDIStreaming header follows the AudioControl interface
+            // and the standard MIDIStreaming interface descriptor.
+            let header = 2 * INTERFACE_LEN + AcHeader::len(1);
+            let total_length = u16::from_le_bytes([dump[header + 5], dump[header + 6]]);
+            assert_eq!(total_length as usize, dump.len() - header);
+            assert_eq!(dump.len() + CONFIGURATION_LEN, required_config_descriptor_len(ports));
+        }
+    }
+
+    #[test]
+    fn budgets_config_descriptors() {
+        assert_eq!(required_config_descriptor_len(1), 101);
+        assert_eq!(required_config_descriptor_len(16), 581);
+
+        let mut config = Config::new(0xc0de, 0xcafe);
+        assert_eq!(check_config_descriptor(&[0; 256], &config, 4), Ok(()));
+        assert_eq!(
+            check_config_descriptor(&[0; 256], &config, 16),
+            Err(BufferTooSmall {
+                required: 581,
+                len: 256
+            })
+        );
+        config.composite_with_iads = true;
+        assert_eq!(
+            check_config_descriptor(&[0; 101], &config, 1),
+            Err(BufferTooSmall {
+                required: 109,
+                len: 101
+            })
+        );
+    }
+}
@@ -0,0 +1,630 @@
+use embassy_sync::blocking_mutex::raw::RawMutex;
+use embassy_time::{Duration, Instant, Timer};
+use portable_atomic::{AtomicBool, AtomicU16, AtomicU32, Ordering};
+
+use crate::{CableNumber, Error, MidiMessage, TxQueue};
+
+/// MIDI clock pulses per quarter note.
+pub const PPQN: u32 = 24;
+
+/// Clock pulses further apart than this are not used for tempo estimation.
+const MAX_PULSE_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Taps further apart than this start a new tempo.
+const MAX_TAP_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Transport changes and beats reported by a [`ClockFollower`].
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ClockEvent {
+    Started,
+    Continued,
+    Stopped,
+    /// The first pulse of a quarter note, counted from the song position.
+    Beat(u32),
+}
+
+/// Follows the MIDI clock of a sequencer.
+///
+/// Feed it every received message together with the time it arrived. The
+/// tempo is estimated from the clock pulses whether or not the transport is
+/// running.
+pub struct ClockFollower {
+    running: bool,
+    /// Position of the next pulse since the start of the song.
+    pulses: u32,
+    last_pulse: Option<Instant>,
+    /// Smoothed pulse interval in microseconds.
+    interval: Option<u64>,
+}
+
+impl ClockFollower {
+    pub const fn new() -> Self {
+        Self {
+            running: false,
+            pulses: 0,
+            last_pulse: None,
+            interval: None,
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    /// Song position in clock pulses.
+    pub fn position(&self) -> u32 {
+        self.pulses
+    }
+
+    /// Estimated duration of a quarter note.
+    pub fn beat_duration(&self) -> Option<Duration> {
+        self.interval
+            .map(|interval| Duration::from_micros(interval * PPQN as u64))
+    }
+
+    /// Estimated tempo in beats per minute.
+    pub fn bpm(&self) -> Option<f32> {
+        self.interval
+            .map(|interval| 60_000_000.0 / (interval * PPQN as u64) as f32)
+    }
+
+    pub fn update(&mut self, message: MidiMessage, at: Instant) -> Option<ClockEvent> {
+        match message {
+            MidiMessage::TimingClock => {
+                self.estimate(at);
+                if !self.running {
+                    return None;
+                }
+                let pulse = self.pulses;
+                self.pulses = self.pulses.wrapping_add(1);
+                match pulse % PPQN {
+                    0 => Some(ClockEvent::Beat(pulse / PPQN)),
+                    _ => None,
+                }
+            }
+            MidiMessage::Start => {
+                self.running = true;
+                self.pulses = 0;
+                Some(ClockEvent::Started)
+            }
+            MidiMessage::Continue => {
+                self.running = true;
+                Some(ClockEvent::Continued)
+            }
+            MidiMessage::Stop => {
+                self.running = false;
+                Some(ClockEvent::Stopped)
+            }
+            // Song positions count sixteenth notes of six pulses each.
+            MidiMessage::SongPosition(position) if !self.running => {
+                self.pulses = u32::from(position) * 6;
+                None
+            }
+            _ => None,
+        }
+    }
+
+    fn estimate(&mut self, at: Instant) {
+        let last = self.last_pulse.replace(at);
+        let sample = match last.and_then(|last| at.checked_duration_since(last)) {
+            Some(sample) if sample <= MAX_PULSE_INTERVAL => sample.as_micros(),
+            _ => return,
+        };
+        // Exponential moving average over about eight pulses.
+        self.interval = Some(match self.interval {
+            Some(interval) => interval - interval / 8 + sample / 8,
+            None => sample,
+        });
+    }
+}
+
+impl Default for ClockFollower {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Delays every other subdivision of the beat, for a shuffling groove.
+///
+/// The amount is the share in percent of the first subdivision of every pair,
+/// from 50 (straight) to below 100. 67 gives a triplet feel, so with
+/// sixteenth notes (subdivisions of 6 pulses) every second sixteenth is
+/// delayed by a third of a sixteenth.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Swing {
+    /// Length of a subdivision in clock pulses.
+    subdivision: u8,
+    amount: u8,
+}
+
+impl Swing {
+    pub const STRAIGHT: Self = Self::new(6, 50);
+
+    /// Swing of `amount` percent on subdivisions of `subdivision` clock
+    /// pulses.
+    pub const fn new(subdivision: u8, amount: u8) -> Self {
+        assert!(subdivision > 0, "subdivision must not be empty");
+        assert!(amount >= 50 && amount < 100, "amount must be from 50 to 99");
+        Self { subdivision, amount }
+    }
+
+    pub const fn subdivision(self) -> u8 {
+        self.subdivision
+    }
+
+    pub const fn amount(self) -> u8 {
+        self.amount
+    }
+
+    /// How much later an event at `pulse`, counted in clock pulses since
+    /// the start of the song, is played with clock pulses of `interval`.
+    ///
+    /// A sequencer adds this to the time of its note events. Times within a
+    /// pair of subdivisions are stretched evenly, so the order of events is
+    /// kept.
+    pub fn delay(self, pulse: u32, interval: Duration) -> Duration {
+        let subdivision = u32::from(self.subdivision);
+        let position = pulse % (2 * subdivision);
+        let stretched = position.min(2 * subdivision - position);
+        interval * stretched * (2 * u32::from(self.amount) - 100) / 100
+    }
+
+    const fn to_bits(self) -> u16 {
+        (self.subdivision as u16) << 8 | self.amount as u16
+    }
+
+    const fn from_bits(bits: u16) -> Self {
+        Self {
+            subdivision: (bits >> 8) as u8,
+            amount: bits as u8,
+        }
+    }
+}
+
+impl Default for Swing {
+    fn default() -> Self {
+        Self::STRAIGHT
+    }
+}
+
+/// Sends the MIDI clock at a tempo that can be changed while it runs.
+///
+/// ```
+/// use embassy_time::Duration;
+/// use usb_midi_rs::ClockGenerator;
+///
+/// static CLOCK: ClockGenerator = ClockGenerator::new(Duration::from_millis(500));
+/// CLOCK.set_bpm(140.0).unwrap();
+/// assert_eq!(CLOCK.beat_duration(), Duration::from_micros(428_568));
+/// ```
+pub struct ClockGenerator {
+    /// Microseconds between two pulses.
+    interval: AtomicU32,
+    swing: AtomicU16,
+    /// The next pulse begins a pair of subdivisions.
+    align: AtomicBool,
+}
+
+impl ClockGenerator {
+    /// A clock with quarter notes of `beat`.
+    pub const fn new(beat: Duration) -> Self {
+        Self {
+            interval: AtomicU32::new(pulse_micros(beat)),
+            swing: AtomicU16::new(Swing::STRAIGHT.to_bits()),
+            align: AtomicBool::new(false),
+        }
+    }
+
+    pub fn beat_duration(&self) -> Duration {
+        self.pulse_interval() * PPQN
+    }
+
+    /// Changes the tempo, taking effect with the next pulse.
+    pub fn set_beat_duration(&self, beat: Duration) {
+        self.interval.store(pulse_micros(beat), Ordering::Relaxed);
+    }
+
+    pub fn bpm(&self) -> f32 {
+        60_000_000.0 / self.beat_duration().as_micros() as f32
+    }
+
+    /// Changes the tempo like [`set_beat_duration`](Self::set_beat_duration).
+    ///
+    /// Fails with [`Error::Malformed`] for tempos that are not positive and
+    /// finite, keeping the current one.
+    pub fn set_bpm(&self, bpm: f32) -> Result<(), Error> {
+        if !(bpm > 0.0 && bpm.is_finite()) {
+            return Err(Error::Malformed);
+        }
+        self.set_beat_duration(Duration::from_micros((60_000_000.0 / bpm) as u64));
+        Ok(())
+    }
+
+    pub fn swing(&self) -> Swing {
+        Swing::from_bits(self.swing.load(Ordering::Relaxed))
+    }
+
+    /// Swings the clock pulses themselves, for devices following the clock
+    /// that have no swing of their own.
+    pub fn set_swing(&self, swing: Swing) {
+        self.swing.store(swing.to_bits(), Ordering::Relaxed);
+    }
+
+    /// Lets the next pulse begin a pair of swung subdivisions, e.g. when the
+    /// application sends a Start.
+    pub fn align(&self) {
+        self.align.store(true, Ordering::Relaxed);
+    }
+
+    fn pulse_interval(&self) -> Duration {
+        Duration::from_micros(self.interval.load(Ordering::Relaxed).into())
+    }
+
+    /// Writes clock pulses to `cable` of `tx`, forever.
+    ///
+    /// Pulses are dropped rather than delayed when the queue is full, so the
+    /// tempo stays steady. The swing is counted from the first pulse or the
+    /// last [`align`](Self::align).
+    pub async fn run<M: RawMutex, const N: usize, const Q: usize>(
+        &self,
+        tx: &TxQueue<M, N, Q>,
+        cable: CableNumber<N>,
+    ) -> ! {
+        let mut straight = Instant::now();
+        let mut pulse = 0u32;
+        loop {
+            if self.align.swap(false, Ordering::Relaxed) {
+                pulse = 0;
+            }
+            let interval = self.pulse_interval();
+            Timer::at(straight + self.swing().delay(pulse, interval)).await;
+            let _ = tx.try_write_message(cable, MidiMessage::TimingClock);
+            straight += interval;
+            pulse = pulse.wrapping_add(1);
+        }
+    }
+}
+
+/// Microseconds between two pulses with quarter notes of `beat`, at least 1
+/// so the clock never spins.
+const fn pulse_micros(beat: Duration) -> u32 {
+    match beat.as_micros() / PPQN as u64 {
+        0 => 1,
+        micros if micros > u32::MAX as u64 => u32::MAX,
+        micros => micros as u32,
+    }
+}
+
+/// Derives a tempo from taps on a button.
+///
+/// The beat is the average of the last `W` intervals between taps. A tap
+/// more than two seconds after the previous one begins a new tempo, and so
+/// does an interval off the average by more than a third, e.g. after a
+/// missed tap.
+///
+/// ```
+/// use embassy_time::{Duration, Instant};
+/// use usb_midi_rs::{ClockGenerator, TapTempo};
+///
+/// let clock = ClockGenerator::new(Duration::from_millis(500));
+/// let mut tap = TapTempo::<4>::new();
+/// for millis in [0, 480, 960] {
+///     if let Some(beat) = tap.tap(Instant::from_millis(millis)) {
+///         clock.set_beat_duration(beat);
+///     }
+/// }
+/// assert_eq!(clock.bpm(), 125.0);
+/// ```
+pub struct TapTempo<const W: usize> {
+    last_tap: Option<Instant>,
+    /// Intervals between taps in microseconds, the oldest ones overwritten
+    /// first.
+    intervals: [u64; W],
+    count: usize,
+}
+
+impl<const W: usize> TapTempo<W> {
+    pub const fn new() -> Self {
+        Self {
+            last_tap: None,
+            intervals: [0; W],
+            count: 0,
+        }
+    }
+
+    /// Takes a tap at `at` into account and returns the tempo, once there is
+    /// one.
+    pub fn tap(&mut self, at: Instant) -> Option<Duration> {
+        let last = self.last_tap.replace(at);
+        let interval = match last.and_then(|last| at.checked_duration_since(last)) {
+            Some(interval) if interval <= MAX_TAP_INTERVAL => interval.as_micros(),
+            _ => {
+                self.count = 0;
+                return None;
+            }
+        };
+        if let Some(beat) = self.beat_duration() {
+            let beat = beat.as_micros();
+            if interval.abs_diff(beat) > beat / 3 {
+                self.count = 0;
+            }
+        }
+        if W > 0 {
+            self.intervals[self.count % W] = interval;
+            self.count += 1;
+        }
+        self.beat_duration()
+    }
+
+    /// Average interval between the last taps.
+    pub fn beat_duration(&self) -> Option<Duration> {
+        let intervals = &self.intervals[..self.count.min(W)];
+        match intervals.len() as u64 {
+            0 => None,
+            len => Some(Duration::from_micros(intervals.iter().sum::<u64>() / len)),
+        }
+    }
+
+    pub fn bpm(&self) -> Option<f32> {
+        self.beat_duration().map(|beat| 60_000_000.0 / beat.as_micros() as f32)
+    }
+}
+
+impl<const W: usize> Default for TapTempo<W> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Clock and run/stop lines of a DIN sync or analog clock output, e.g. two
+/// GPIOs.
+pub trait SyncOutput {
+    fn set_clock(&mut self, high: bool);
+
+    /// Sets the run/stop line of DIN sync. Analog clock outputs have none.
+    fn set_running(&mut self, _running: bool) {}
+}
+
+/// Sends divided clock pulses to a [`SyncOutput`], following the MIDI clock.
+///
+/// Every `division` MIDI clock pulses, from 1 to 96, give one output pulse,
+/// so 1 drives DIN sync 24, 6 gives sixteenth notes, 24 quarter notes and 96
+/// whole notes. Pulses are sent while the transport is running, starting
+/// with the first pulse after a Start. A pulse is high for the configured
+/// width, which has to be shorter than the pulse interval; the application
+/// calls [`poll`](Self::poll) when [`deadline`](Self::deadline) is reached.
+pub struct SyncOut<O: SyncOutput> {
+    output: O,
+    division: u8,
+    width: Duration,
+    running: bool,
+    /// MIDI clock pulses since the last output pulse began.
+    pulses: u8,
+    high_until: Option<Instant>,
+}
+
+impl<O: SyncOutput> SyncOut<O> {
+    pub fn new(mut output: O, division: u8, width: Duration) -> Self {
+        assert!((1..=96).contains(&division), "division must be from 1 to 96");
+        output.set_clock(false);
+        output.set_running(false);
+        Self {
+            output,
+            division,
+            width,
+            running: false,
+            pulses: 0,
+            high_until: None,
+        }
+    }
+
+    /// Changes the division, taking effect with the next output pulse.
+    pub fn set_division(&mut self, division: u8) {
+        assert!((1..=96).contains(&division), "division must be from 1 to 96");
+        self.division = division;
+    }
+
+    pub fn set_width(&mut self, width: Duration) {
+        self.width = width;
+    }
+
+    pub fn output(&mut self) -> &mut O {
+        &mut self.output
+    }
+
+    /// Takes the message received at `at` into account.
+    pub fn update(&mut self, message: MidiMessage, at: Instant) {
+        match message {
+            MidiMessage::TimingClock if self.running => {
+                if self.pulses == 0 {
+                    self.output.set_clock(true);
+                    self.high_until = Some(at + self.width);
+                }
+                self.pulses += 1;
+                if self.pulses >= self.division {
+                    self.pulses = 0;
+                }
+            }
+            MidiMessage::Start | MidiMessage::Continue => {
+                if message == MidiMessage::Start {
+                    self.pulses = 0;
+                }
+                self.running = true;
+                self.output.set_running(true);
+            }
+            MidiMessage::Stop => {
+                self.running = false;
+                self.output.set_running(false);
+            }
+            _ => {}
+        }
+    }
+
+    /// Ends the pulse in progress if its width elapsed at `now`.
+    pub fn poll(&mut self, now: Instant) {
+        if matches!(self.high_until, Some(until) if now >= until) {
+            self.high_until = None;
+            self.output.set_clock(false);
+        }
+    }
+
+    /// When the pulse in progress ends, if any.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.high_until
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Pulse interval at 125 BPM.
+    const PULSE: u64 = 20_000;
+
+    fn at(micros: u64) -> Instant {
+        Instant::from_micros(micros)
+    }
+
+    #[test]
+    fn counts_beats() {
+        let mut clock = ClockFollower::new();
+        assert_eq!(clock.update(MidiMessage::Start, at(0)), Some(ClockEvent::Started));
+        let beats: Vec<_> = (0..2 * PPQN as u64 + 1)
+            .filter_map(|pulse| clock.update(MidiMessage::TimingClock, at(pulse * PULSE)))
+            .collect();
+        assert_eq!(beats, [ClockEvent::Beat(0), ClockEvent::Beat(1), ClockEvent::Beat(2)]);
+        assert_eq!(clock.bpm(), Some(125.0));
+        assert_eq!(clock.beat_duration(), Some(Duration::from_millis(480)));
+    }
+
+    #[cfg(feature = "usb")]
+    #[test]
+    fn follows_the_clock_of_the_host() {
+        use embassy_futures::block_on;
+
+        let (mut device, mut host) = crate::fault::virtual_bus::<1>(&crate::fault::Faults::default());
+        let cable = CableNumber::new(0).unwrap();
+        block_on(host.write_message(cable, MidiMessage::Start)).unwrap();
+        for _ in 0..PPQN {
+            block_on(host.write_message(cable, MidiMessage::TimingClock)).unwrap();
+        }
+
+        let mut clock = ClockFollower::new();
+        let mut buf = [0; 64];
+        let mut events = Vec::new();
+        for pulse in 0..=PPQN as u64 {
+            let (_, event) = block_on(device.read_events(&mut buf)).unwrap().next().unwrap().unwrap();
+            let message = MidiMessage::from_event(event).unwrap();
+            events.extend(clock.update(message, at(pulse.saturating_sub(1) * PULSE)));
+        }
+        assert_eq!(events, [ClockEvent::Started, ClockEvent::Beat(0)]);
+        assert_eq!(clock.position(), PPQN);
+        assert_eq!(clock.bpm(), Some(125.0));
+    }
+
+    #[test]
+    fn follows_song_position() {
+        let mut clock = ClockFollower::new();
+        clock.update(MidiMessage::SongPosition(8), at(0));
+        assert_eq!(clock.update(MidiMessage::Continue, at(0)), Some(ClockEvent::Continued));
+        assert_eq!(clock.update(MidiMessage::TimingClock, at(0)), Some(ClockEvent::Beat(2)));
+        assert_eq!(clock.update(MidiMessage::Stop, at(0)), Some(ClockEvent::Stopped));
+        assert_eq!(clock.update(MidiMessage::TimingClock, at(PULSE)), None);
+        assert_eq!(clock.position(), 49);
+    }
+
+    #[test]
+    fn swings_subdivisions() {
+        let interval = Duration::from_micros(PULSE);
+        let swing = Swing::new(6, 75);
+        let delays: Vec<_> = (0..14).map(|pulse| swing.delay(pulse, interval).as_micros()).collect();
+        let step = PULSE / 2;
+        assert_eq!(
+            delays,
+            [0, 1, 2, 3, 4, 5, 6, 5, 4, 3, 2, 1, 0, 1].map(|steps| steps * step)
+        );
+        assert_eq!(Swing::STRAIGHT.delay(9, interval), Duration::from_ticks(0));
+        assert_eq!(Swing::from_bits(swing.to_bits()), swing);
+    }
+
+    #[test]
+    fn never_spins() {
+        let clock = ClockGenerator::new(Duration::from_micros(PPQN as u64 - 1));
+        assert_eq!(clock.pulse_interval(), Duration::from_micros(1));
+        clock.set_beat_duration(Duration::from_ticks(0));
+        assert_eq!(clock.pulse_interval(), Duration::from_micros(1));
+
+        clock.set_bpm(120.0).unwrap();
+        let beat = clock.beat_duration();
+        for bpm in [0.0, -1.0, f32::INFINITY, f32::NAN] {
+            assert_eq!(clock.set_bpm(bpm), Err(Error::Malformed));
+        }
+        assert_eq!(clock.beat_duration(), beat);
+    }
+
+    #[test]
+    fn averages_taps() {
+        let mut tap = TapTempo::<3>::new();
+        let at = |millis| Instant::from_millis(millis);
+        assert_eq!(tap.tap(at(0)), None);
+        assert_eq!(tap.tap(at(500)), Some(Duration::from_millis(500)));
+        assert_eq!(tap.tap(at(1_010)), Some(Duration::from_millis(505)));
+        tap.tap(at(1_500));
+        assert_eq!(tap.tap(at(2_000)), Some(Duration::from_millis(500)));
+        // A missed tap starts over.
+        assert_eq!(tap.tap(at(3_000)), Some(Duration::from_millis(1_000)));
+        assert_eq!(tap.tap(at(6_000)), None);
+        assert_eq!(tap.bpm(), None);
+    }
+
+    #[derive(Default)]
+    struct Lines {
+        /// Times at which the clock line went high, in pulses.
+        pulses: Vec<u64>,
+        high: bool,
+        running: bool,
+    }
+
+    impl SyncOutput for &mut Lines {
+        fn set_clock(&mut self, high: bool) {
+            self.high = high;
+        }
+
+        fn set_running(&mut self, running: bool) {
+            self.running = running;
+        }
+    }
+
+    #[test]
+    fn divides_the_clock() {
+        let mut lines = Lines::default();
+        let mut sync = SyncOut::new(&mut lines, 6, Duration::from_micros(5_000));
+        sync.update(MidiMessage::TimingClock, at(0));
+        assert!(!sync.output().high);
+        sync.update(MidiMessage::Start, at(0));
+        for pulse in 0..13 {
+            sync.update(MidiMessage::TimingClock, at(pulse * PULSE));
+            if sync.output().high {
+                sync.output().pulses.push(pulse);
+                assert_eq!(sync.deadline(), Some(at(pulse * PULSE + 5_000)));
+            }
+            sync.poll(at(pulse * PULSE + 4_999));
+            assert_eq!(sync.output().high, pulse % 6 == 0);
+            sync.poll(at(pulse * PULSE + 5_000));
+            assert!(!sync.output().high);
+        }
+        sync.update(MidiMessage::Stop, at(13 * PULSE));
+        assert!(!lines.running);
+        assert_eq!(lines.pulses, [0, 6, 12]);
+    }
+
+    #[test]
+    fn ignores_gaps_in_the_clock() {
+        let mut clock = ClockFollower::new();
+        clock.update(MidiMessage::TimingClock, at(0));
+        clock.update(MidiMessage::TimingClock, at(1_000_000));
+        assert_eq!(clock.bpm(), None);
+    }
+}
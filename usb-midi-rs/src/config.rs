@@ -0,0 +1,271 @@
+use heapless::Vec;
+
+use crate::crc::crc16;
+#[cfg(test)]
+use crate::report::NUMBER_LEN;
+use crate::report::{read_number, Writer};
+use crate::sysex::{SYSEX_END, SYSEX_START};
+use crate::Error;
+
+/// Command byte starting a [`ConfigTransaction`].
+pub const CONFIG_BEGIN: u8 = 0x05;
+/// Command byte of a chunk of a staged configuration.
+pub const CONFIG_DATA: u8 = 0x06;
+/// Command byte committing a [`ConfigTransaction`].
+pub const CONFIG_COMMIT: u8 = 0x07;
+/// Command byte of [`ConfigReply::Ack`].
+pub const CONFIG_ACK: u8 = 0x08;
+/// Command byte of [`ConfigReply::Nak`].
+pub const CONFIG_NAK: u8 = 0x09;
+
+/// Answer of the device to a step of a [`ConfigTransaction`].
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ConfigReply {
+    Ack,
+    /// The step failed and the transaction was discarded.
+    Nak(Error),
+}
+
+impl ConfigReply {
+    /// Writes the reply to `data`, returning its length.
+    ///
+    /// The reply is `F0 <manufacturer> 08 F7` or `F0 <manufacturer> 09
+    /// <error> F7`, the error being the index of the [`Error`] variant.
+    pub fn write(&self, manufacturer: &[u8], data: &mut [u8]) -> Result<usize, Error> {
+        let len = match self {
+            ConfigReply::Ack => manufacturer.len() + 3,
+            ConfigReply::Nak(_) => manufacturer.len() + 4,
+        };
+        let data = data.get_mut(..len).ok_or(Error::BufferOverflow)?;
+        let mut writer = Writer { data, len: 0 };
+        writer.bytes(&[SYSEX_START]);
+        writer.bytes(manufacturer);
+        match self {
+            ConfigReply::Ack => writer.bytes(&[CONFIG_ACK]),
+            ConfigReply::Nak(error) => writer.bytes(&[CONFIG_NAK, error_code(*error)]),
+        }
+        writer.bytes(&[SYSEX_END]);
+        Ok(len)
+    }
+}
+
+fn error_code(error: Error) -> u8 {
+    match error {
+        Error::Disconnected => 0,
+        Error::BufferOverflow => 1,
+        Error::Malformed => 2,
+        Error::Timeout => 3,
+        Error::Unsupported => 4,
+    }
+}
+
+/// Receives a configuration blob of up to `L` bytes over System Exclusive and
+/// applies it all at once.
+///
+/// A host sends `F0 <manufacturer> 05 F7` to begin, any number of `F0
+/// <manufacturer> 06 <data> F7` with the bytes of the blob split into
+/// nibbles, the high one first, and finally `F0 <manufacturer> 07 <crc> F7`
+/// with the CRC-16/CCITT-FALSE of the whole blob as five 7-bit groups. The
+/// blob is staged until the commit and only handed to the device if the CRC
+/// matches, so a transfer cut short or garbled on the way never leaves the
+/// device half configured. Every step is answered with a [`ConfigReply`];
+/// after a NAK the previous configuration stays active and the host has to
+/// begin again.
+#[derive(Clone, Debug)]
+pub struct ConfigTransaction<const L: usize> {
+    staged: Vec<u8, L>,
+    open: bool,
+}
+
+impl<const L: usize> Default for ConfigTransaction<L> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const L: usize> ConfigTransaction<L> {
+    pub const fn new() -> Self {
+        Self {
+            staged: Vec::new(),
+            open: false,
+        }
+    }
+
+    /// Whether a transaction has begun and not been committed or discarded.
+    pub fn is_open(&self) -> bool {
+        self.open
+    }
+
+    /// Bytes staged so far.
+    pub fn staged(&self) -> &[u8] {
+        &self.staged
+    }
+
+    /// Discards the staged bytes, e.g. when the host disconnects.
+    pub fn abort(&mut self) {
+        self.staged.clear();
+        self.open = false;
+    }
+
+    /// Handles the complete System Exclusive `message`, returning the reply
+    /// to send or `None` if it is not part of the configuration protocol.
+    ///
+    /// On a commit with a matching CRC, `apply` gets the whole blob. It
+    /// must check the blob before changing anything, and the error it
+    /// returns is sent in the NAK.
+    pub fn handle(
+        &mut self,
+        message: &[u8],
+        manufacturer: &[u8],
+        apply: impl FnOnce(&[u8]) -> Result<(), Error>,
+    ) -> Option<ConfigReply> {
+        let body = message
+            .strip_prefix(&[SYSEX_START][..])?
+            .strip_prefix(manufacturer)?
+            .strip_suffix(&[SYSEX_END][..])?;
+        let (&command, payload) = body.split_first()?;
+        let result = match command {
+            CONFIG_BEGIN if payload.is_empty() => {
+                self.abort();
+                self.open = true;
+                Ok(())
+            }
+            CONFIG_BEGIN => Err(Error::Malformed),
+            CONFIG_DATA => self.stage(payload),
+            CONFIG_COMMIT => self.commit(payload, apply),
+            _ => return None,
+        };
+        match result {
+            Ok(()) => Some(ConfigReply::Ack),
+            Err(error) => {
+                self.abort();
+                Some(ConfigReply::Nak(error))
+            }
+        }
+    }
+
+    fn stage(&mut self, payload: &[u8]) -> Result<(), Error> {
+        if !self.open || payload.len() & 1 != 0 {
+            return Err(Error::Malformed);
+        }
+        for nibbles in payload.chunks(2) {
+            if nibbles[0] > 0x0f || nibbles[1] > 0x0f {
+                return Err(Error::Malformed);
+            }
+            self.staged
+                .push(nibbles[0] << 4 | nibbles[1])
+                .map_err(|_| Error::BufferOverflow)?;
+        }
+        Ok(())
+    }
+
+    fn commit(&mut self, payload: &[u8], apply: impl FnOnce(&[u8]) -> Result<(), Error>) -> Result<(), Error> {
+        let crc = read_number(payload).ok_or(Error::Malformed)?;
+        if !self.open || crc != u32::from(crc16(&self.staged)) {
+            return Err(Error::Malformed);
+        }
+        apply(&self.staged)?;
+        self.abort();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MANUFACTURER: &[u8] = &[0x7d];
+
+    fn message(command: u8, payload: &[u8]) -> std::vec::Vec<u8> {
+        [&[0xf0, 0x7d, command][..], payload, &[0xf7]].concat()
+    }
+
+    fn data(bytes: &[u8]) -> std::vec::Vec<u8> {
+        let nibbles: std::vec::Vec<u8> = bytes.iter().flat_map(|&byte| [byte >> 4, byte & 0x0f]).collect();
+        message(CONFIG_DATA, &nibbles)
+    }
+
+    fn commit(crc: u16) -> std::vec::Vec<u8> {
+        let mut payload = [0; NUMBER_LEN];
+        Writer {
+            data: &mut payload,
+            len: 0,
+        }
+        .number(u32::from(crc));
+        message(CONFIG_COMMIT, &payload)
+    }
+
+    #[test]
+    fn commits_verified_blobs() {
+        let mut transaction = ConfigTransaction::<8>::new();
+        let mut active = std::vec::Vec::new();
+        let mut apply = |blob: &[u8]| {
+            active = blob.to_vec();
+            Ok(())
+        };
+        let reply = transaction.handle(&message(CONFIG_BEGIN, &[]), MANUFACTURER, &mut apply);
+        assert_eq!(reply, Some(ConfigReply::Ack));
+        transaction.handle(&data(&[0x12, 0xab]), MANUFACTURER, &mut apply);
+        transaction.handle(&data(&[0xff]), MANUFACTURER, &mut apply);
+        assert_eq!(transaction.staged(), [0x12, 0xab, 0xff]);
+        let crc = crc16(&[0x12, 0xab, 0xff]);
+        let reply = transaction.handle(&commit(crc), MANUFACTURER, &mut apply);
+        assert_eq!(reply, Some(ConfigReply::Ack));
+        assert!(!transaction.is_open());
+        assert_eq!(active, [0x12, 0xab, 0xff]);
+
+        let mut reply = [0; 8];
+        let len = ConfigReply::Nak(Error::Malformed)
+            .write(MANUFACTURER, &mut reply)
+            .unwrap();
+        assert_eq!(reply[..len], [0xf0, 0x7d, 0x09, 0x02, 0xf7]);
+    }
+
+    #[test]
+    fn keeps_configuration_on_failures() {
+        let mut transaction = ConfigTransaction::<2>::new();
+        let mut applied = false;
+        let mut apply = |_: &[u8]| {
+            applied = true;
+            Err(Error::Unsupported)
+        };
+        let begin = message(CONFIG_BEGIN, &[]);
+        transaction.handle(&begin, MANUFACTURER, &mut apply);
+        transaction.handle(&data(&[1, 2]), MANUFACTURER, &mut apply);
+        let reply = transaction.handle(&commit(0), MANUFACTURER, &mut apply);
+        assert_eq!(reply, Some(ConfigReply::Nak(Error::Malformed)));
+        assert!(!transaction.is_open());
+
+        // Data without a transaction and blobs too large are refused.
+        let reply = transaction.handle(&data(&[1]), MANUFACTURER, &mut apply);
+        assert_eq!(reply, Some(ConfigReply::Nak(Error::Malformed)));
+        transaction.handle(&begin, MANUFACTURER, &mut apply);
+        let reply = transaction.handle(&data(&[1, 2, 3]), MANUFACTURER, &mut apply);
+        assert_eq!(reply, Some(ConfigReply::Nak(Error::BufferOverflow)));
+        assert_eq!(transaction.staged(), []);
+
+        transaction.handle(&begin, MANUFACTURER, &mut apply);
+        transaction.handle(&data(&[1]), MANUFACTURER, &mut apply);
+        let reply = transaction.handle(&commit(crc16(&[1])), MANUFACTURER, &mut apply);
+        assert_eq!(reply, Some(ConfigReply::Nak(Error::Unsupported)));
+
+        // A CRC with bits beyond 32 set does not match its lower bits.
+        transaction.handle(&begin, MANUFACTURER, &mut apply);
+        transaction.handle(&data(&[1]), MANUFACTURER, &mut apply);
+        let mut crc = commit(crc16(&[1]));
+        crc[3] |= 0x10;
+        let reply = transaction.handle(&crc, MANUFACTURER, &mut apply);
+        assert_eq!(reply, Some(ConfigReply::Nak(Error::Malformed)));
+
+        assert_eq!(
+            transaction.handle(&[0xf0, 0x7d, 0x01, 0xf7], MANUFACTURER, &mut apply),
+            None
+        );
+        assert_eq!(
+            transaction.handle(&[0xf0, 0x7e, 0x05, 0xf7], MANUFACTURER, &mut apply),
+            None
+        );
+        assert!(applied);
+    }
+}
@@ -0,0 +1,357 @@
+use embassy_sync::blocking_mutex::raw::RawMutex;
+use embassy_sync::signal::Signal;
+use embassy_usb::driver::{ControlPipe, Driver, EndpointAllocError, EndpointError, EndpointType};
+use embassy_usb::Config;
+use portable_atomic::{AtomicBool, Ordering};
+
+use crate::{PowerConfig, PowerHandler};
+
+const GET_CONFIGURATION: u8 = 0x08;
+const GET_DESCRIPTOR: u8 = 0x06;
+const SET_CONFIGURATION: u8 = 0x09;
+const DEVICE: u8 = 0x01;
+const CONFIGURATION: u8 = 0x02;
+
+/// Offset of `bNumConfigurations` in the device descriptor.
+const NUM_CONFIGURATIONS: usize = 17;
+/// Offset of `bConfigurationValue` in the configuration descriptor.
+const CONFIGURATION_VALUE: usize = 5;
+/// Offset of `bmAttributes` in the configuration descriptor.
+const ATTRIBUTES: usize = 7;
+/// Offset of `bMaxPower` in the configuration descriptor.
+const MAX_POWER: usize = 8;
+const SELF_POWERED: u8 = 0x40;
+
+/// Offers the host two configurations differing only in their power
+/// settings, e.g. a bus-powered one and a self-powered one for when an
+/// external supply is connected, and reports the one the host selects.
+///
+/// The [`Builder`](embassy_usb::Builder) of `embassy-usb` writes a single
+/// configuration with the settings of `first`. The driver returned by
+/// [`driver`](Self::driver) presents it to the host a second time, as
+/// configuration 2 with the settings of `second`, and hands a selection of
+/// configuration 2 to `embassy-usb` as one of configuration 1. Hosts choosing
+/// a configuration by its power, such as Linux, take the self-powered one
+/// while the supply is connected; Windows always takes the first one.
+///
+/// ```ignore
+/// static CONFIGURATIONS: PowerConfigurations<ThreadModeRawMutex> =
+///     PowerConfigurations::new(PowerConfig::bus_powered(100), PowerConfig::self_powered(0));
+///
+/// CONFIGURATIONS.configure(&mut usb_config);
+/// let (mut builder, state) = buffers.builder(
+///     |ep_out_buffer| CONFIGURATIONS.driver(Driver::new_fs(p.USB_OTG_FS, irq, p.PA12, p.PA11, ep_out_buffer)),
+///     usb_config,
+/// );
+/// // Switches the backlight on in the self-powered configuration.
+/// let power_fut = CONFIGURATIONS.run(&mut backlight);
+/// ```
+pub struct PowerConfigurations<M: RawMutex> {
+    first: PowerConfig,
+    second: PowerConfig,
+    second_selected: AtomicBool,
+    selected: Signal<M, PowerConfig>,
+}
+
+impl<M: RawMutex> PowerConfigurations<M> {
+    pub const fn new(first: PowerConfig, second: PowerConfig) -> Self {
+        Self {
+            first,
+            second,
+            second_selected: AtomicBool::new(false),
+            selected: Signal::new(),
+        }
+    }
+
+    /// Applies the power settings of the first configuration to the device
+    /// configuration.
+    ///
+    /// Must be called before the configuration is passed to the
+    /// [`Builder`](embassy_usb::Builder).
+    pub fn configure(&self, config: &mut Config<'_>) {
+        self.first.configure(config);
+    }
+
+    /// Wraps `driver` to offer the second configuration to the host.
+    pub fn driver<'d, D: Driver<'d>>(&'d self, driver: D) -> DualPowerDriver<'d, D, M> {
+        DualPowerDriver {
+            driver,
+            configurations: self,
+        }
+    }
+
+    /// Power settings of the configuration the host selected last, or of
+    /// the first one before the host selected any.
+    pub fn current(&self) -> PowerConfig {
+        match self.second_selected.load(Ordering::Relaxed) {
+            true => self.second,
+            false => self.first,
+        }
+    }
+
+    /// Notifies `handler` whenever the host selects a configuration.
+    pub async fn run(&self, handler: &mut impl PowerHandler) -> ! {
+        loop {
+            let power = self.selected.wait().await;
+            handler.configured(power);
+        }
+    }
+
+    fn select(&self, second: bool) {
+        debug!("Host selected configuration {}", if second { 2 } else { 1 });
+        self.second_selected.store(second, Ordering::Relaxed);
+        self.selected.signal(self.current());
+    }
+}
+
+/// A driver offering the configurations of [`PowerConfigurations`], see
+/// [`PowerConfigurations::driver`].
+pub struct DualPowerDriver<'d, D, M: RawMutex> {
+    driver: D,
+    configurations: &'d PowerConfigurations<M>,
+}
+
+impl<'d, D: Driver<'d>, M: RawMutex + 'd> Driver<'d> for DualPowerDriver<'d, D, M> {
+    type EndpointOut = D::EndpointOut;
+    type EndpointIn = D::EndpointIn;
+    type ControlPipe = DualPowerControl<'d, D::ControlPipe, M>;
+    type Bus = D::Bus;
+
+    fn alloc_endpoint_out(
+        &mut self,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<Self::EndpointOut, EndpointAllocError> {
+        self.driver.alloc_endpoint_out(ep_type, max_packet_size, interval_ms)
+    }
+
+    fn alloc_endpoint_in(
+        &mut self,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<Self::EndpointIn, EndpointAllocError> {
+        self.driver.alloc_endpoint_in(ep_type, max_packet_size, interval_ms)
+    }
+
+    fn start(self, control_max_packet_size: u16) -> (Self::Bus, Self::ControlPipe) {
+        let (bus, control) = self.driver.start(control_max_packet_size);
+        let control = DualPowerControl {
+            control,
+            configurations: self.configurations,
+            reply: Reply::Passed,
+            offset: 0,
+        };
+        (bus, control)
+    }
+}
+
+/// Reply to the current control request that needs patching.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum Reply {
+    Passed,
+    DeviceDescriptor,
+    SecondConfiguration,
+    Configuration,
+}
+
+/// Control pipe of a [`DualPowerDriver`], rewriting the standard requests
+/// concerning configurations.
+pub struct DualPowerControl<'d, C, M: RawMutex> {
+    control: C,
+    configurations: &'d PowerConfigurations<M>,
+    reply: Reply,
+    /// Bytes of the reply sent so far.
+    offset: usize,
+}
+
+impl<C, M: RawMutex> DualPowerControl<'_, C, M> {
+    /// Byte `position` of the reply, which was `byte`.
+    fn patch(&self, position: usize, byte: u8) -> u8 {
+        let second = self.configurations.second;
+        match (self.reply, position) {
+            (Reply::DeviceDescriptor, NUM_CONFIGURATIONS) => 2,
+            (Reply::SecondConfiguration, CONFIGURATION_VALUE) => 2,
+            (Reply::SecondConfiguration, ATTRIBUTES) => match second.is_self_powered() {
+                true => byte | SELF_POWERED,
+                false => byte & !SELF_POWERED,
+            },
+            (Reply::SecondConfiguration, MAX_POWER) => (second.max_power() / 2) as u8,
+            (Reply::Configuration, 0) if byte == 1 && self.configurations.second_selected.load(Ordering::Relaxed) => 2,
+            _ => byte,
+        }
+    }
+}
+
+impl<C: ControlPipe, M: RawMutex> ControlPipe for DualPowerControl<'_, C, M> {
+    fn max_packet_size(&self) -> usize {
+        self.control.max_packet_size()
+    }
+
+    async fn setup(&mut self) -> [u8; 8] {
+        let mut setup = self.control.setup().await;
+        self.reply = Reply::Passed;
+        // bmRequestType, bRequest and wValue of standard device requests.
+        match (setup[0], setup[1], setup[3], setup[2]) {
+            (0x80, GET_DESCRIPTOR, DEVICE, _) => self.reply = Reply::DeviceDescriptor,
+            (0x80, GET_DESCRIPTOR, CONFIGURATION, 1) => {
+                setup[2] = 0;
+                self.reply = Reply::SecondConfiguration;
+            }
+            (0x80, GET_CONFIGURATION, ..) => self.reply = Reply::Configuration,
+            (0x00, SET_CONFIGURATION, 0, value @ 1..=2) => {
+                setup[2] = 1;
+                self.configurations.select(value == 2);
+            }
+            _ => {}
+        }
+        setup
+    }
+
+    async fn data_out(&mut self, buf: &mut [u8], first: bool, last: bool) -> Result<usize, EndpointError> {
+        self.control.data_out(buf, first, last).await
+    }
+
+    async fn data_in(&mut self, data: &[u8], first: bool, last: bool) -> Result<(), EndpointError> {
+        if first {
+            self.offset = 0;
+        }
+        let mut patched = [0; 64];
+        let data = match patched.get_mut(..data.len()) {
+            Some(patched) if self.reply != Reply::Passed => {
+                for (position, (patched, &byte)) in patched.iter_mut().zip(data).enumerate() {
+                    *patched = self.patch(self.offset + position, byte);
+                }
+                &*patched
+            }
+            _ => data,
+        };
+        self.offset += data.len();
+        self.control.data_in(data, first, last).await
+    }
+
+    async fn accept(&mut self) {
+        self.control.accept().await
+    }
+
+    async fn reject(&mut self) {
+        self.control.reject().await
+    }
+
+    async fn accept_set_address(&mut self, addr: u8) {
+        self.control.accept_set_address(addr).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::pin::pin;
+    use std::collections::VecDeque;
+
+    use embassy_futures::block_on;
+    use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+
+    use super::*;
+    use crate::fault::poll_once;
+
+    /// A control pipe receiving `setups` and recording the data sent.
+    #[derive(Default)]
+    struct Control {
+        setups: VecDeque<[u8; 8]>,
+        sent: std::vec::Vec<u8>,
+    }
+
+    impl ControlPipe for Control {
+        fn max_packet_size(&self) -> usize {
+            8
+        }
+
+        async fn setup(&mut self) -> [u8; 8] {
+            self.setups.pop_front().unwrap()
+        }
+
+        async fn data_out(&mut self, _buf: &mut [u8], _first: bool, _last: bool) -> Result<usize, EndpointError> {
+            Ok(0)
+        }
+
+        async fn data_in(&mut self, data: &[u8], _first: bool, _last: bool) -> Result<(), EndpointError> {
+            self.sent.extend_from_slice(data);
+            Ok(())
+        }
+
+        async fn accept(&mut self) {}
+
+        async fn reject(&mut self) {}
+
+        async fn accept_set_address(&mut self, _addr: u8) {}
+    }
+
+    /// Sends `reply` to the request `setup` in packets of 8 bytes, returning
+    /// the setup packet as seen by `embassy-usb` and the bytes sent.
+    fn request(
+        control: &mut DualPowerControl<Control, NoopRawMutex>,
+        setup: [u8; 8],
+        reply: &[u8],
+    ) -> ([u8; 8], std::vec::Vec<u8>) {
+        control.control.setups.push_back(setup);
+        control.control.sent.clear();
+        let setup = block_on(control.setup());
+        let count = reply.chunks(8).count();
+        for (index, chunk) in reply.chunks(8).enumerate() {
+            block_on(control.data_in(chunk, index == 0, index + 1 == count)).unwrap();
+        }
+        (setup, control.control.sent.clone())
+    }
+
+    #[derive(Default)]
+    struct Backlight(Option<PowerConfig>);
+
+    impl PowerHandler for Backlight {
+        fn configured(&mut self, power: PowerConfig) {
+            self.0 = Some(power);
+        }
+    }
+
+    #[test]
+    fn offers_second_configuration() {
+        let configurations =
+            PowerConfigurations::<NoopRawMutex>::new(PowerConfig::bus_powered(100), PowerConfig::self_powered(0));
+        let mut control = DualPowerControl {
+            control: Control::default(),
+            configurations: &configurations,
+            reply: Reply::Passed,
+            offset: 0,
+        };
+
+        let mut device = [0; 18];
+        device[..2].copy_from_slice(&[18, 0x01]);
+        device[NUM_CONFIGURATIONS] = 1;
+        let (_, sent) = request(&mut control, [0x80, 0x06, 0, 0x01, 0, 0, 18, 0], &device);
+        assert_eq!(sent[NUM_CONFIGURATIONS], 2);
+
+        // The second configuration is the first one with other power settings.
+        let configuration = [9, 0x02, 18, 0, 1, 1, 0, 0xa0, 50, 9, 0x04, 0, 0, 0, 0x01, 0x01, 0, 0];
+        let (setup, sent) = request(&mut control, [0x80, 0x06, 1, 0x02, 0, 0, 18, 0], &configuration);
+        assert_eq!(setup, [0x80, 0x06, 0, 0x02, 0, 0, 18, 0]);
+        assert_eq!(sent[..9], [9, 0x02, 18, 0, 1, 2, 0, 0xe0, 0]);
+        assert_eq!(sent[9..], configuration[9..]);
+        let (_, sent) = request(&mut control, [0x80, 0x06, 0, 0x02, 0, 0, 18, 0], &configuration);
+        assert_eq!(sent, configuration);
+
+        let mut backlight = Backlight::default();
+        {
+            let mut run = pin!(configurations.run(&mut backlight));
+            let (setup, _) = request(&mut control, [0x00, 0x09, 2, 0, 0, 0, 0, 0], &[]);
+            assert_eq!(setup, [0x00, 0x09, 1, 0, 0, 0, 0, 0]);
+            assert_eq!(configurations.current(), PowerConfig::self_powered(0));
+            assert!(poll_once(run.as_mut()).is_pending());
+        }
+        assert_eq!(backlight.0, Some(PowerConfig::self_powered(0)));
+        assert_eq!(request(&mut control, [0x80, 0x08, 0, 0, 0, 0, 1, 0], &[1]).1, [2]);
+
+        request(&mut control, [0x00, 0x09, 1, 0, 0, 0, 0, 0], &[]);
+        assert_eq!(configurations.current(), PowerConfig::bus_powered(100));
+        assert_eq!(request(&mut control, [0x80, 0x08, 0, 0, 0, 0, 1, 0], &[1]).1, [1]);
+    }
+}
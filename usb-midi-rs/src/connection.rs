@@ -0,0 +1,150 @@
+use embassy_sync::blocking_mutex::raw::RawMutex;
+use embassy_sync::signal::Signal;
+use embassy_usb::DeviceStateHandler;
+use portable_atomic::{AtomicBool, AtomicU8, Ordering};
+
+/// State of the connection to the host as seen by a [`ConnectionMonitor`].
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ConnectionState {
+    /// No cable is plugged in, or the bus lost power.
+    Detached,
+    /// The bus is powered, but the host has not configured the device (yet).
+    Attached,
+    /// The host configured the device, MIDI can flow.
+    Configured,
+    /// The host suspended the bus, e.g. because it went to sleep.
+    Suspended,
+}
+
+impl ConnectionState {
+    const fn from_u8(value: u8) -> Self {
+        match value {
+            1 => ConnectionState::Attached,
+            2 => ConnectionState::Configured,
+            3 => ConnectionState::Suspended,
+            _ => ConnectionState::Detached,
+        }
+    }
+}
+
+/// Keeps track of the connection to the host, telling a sleeping host from
+/// an unplugged cable.
+///
+/// The monitor is passed to the [`Builder`](embassy_usb::Builder) as its
+/// device state handler. Unplugging is only noticed on drivers sensing VBUS;
+/// without VBUS sensing a detached device looks like a suspended one, as the
+/// bus falls idle in both cases.
+pub struct ConnectionMonitor<M: RawMutex> {
+    state: AtomicU8,
+    /// The device was configured before the bus was suspended.
+    configured: AtomicBool,
+    changed: Signal<M, ()>,
+}
+
+impl<M: RawMutex> ConnectionMonitor<M> {
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU8::new(ConnectionState::Detached as u8),
+            configured: AtomicBool::new(false),
+            changed: Signal::new(),
+        }
+    }
+
+    pub fn connection_state(&self) -> ConnectionState {
+        ConnectionState::from_u8(self.state.load(Ordering::Relaxed))
+    }
+
+    /// Waits until the connection is in `state`.
+    ///
+    /// Only one task may wait at a time.
+    pub async fn wait_for(&self, state: ConnectionState) {
+        while self.connection_state() != state {
+            self.changed.wait().await;
+        }
+    }
+
+    /// Waits until the cable is unplugged.
+    ///
+    /// Unlike [`Receiver::wait_connection`](crate::Receiver::wait_connection)
+    /// and endpoint errors, this does not return while the host merely
+    /// sleeps.
+    pub async fn wait_disconnected(&self) {
+        self.wait_for(ConnectionState::Detached).await
+    }
+
+    fn set(&self, state: ConnectionState) {
+        if self.state.swap(state as u8, Ordering::Relaxed) != state as u8 {
+            debug!("connection {}", state);
+            self.changed.signal(());
+        }
+    }
+}
+
+impl<M: RawMutex> Default for ConnectionMonitor<M> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<M: RawMutex> DeviceStateHandler for ConnectionMonitor<M> {
+    fn enabled(&self, enabled: bool) {
+        self.configured.store(false, Ordering::Relaxed);
+        self.set(match enabled {
+            true => ConnectionState::Attached,
+            false => ConnectionState::Detached,
+        });
+    }
+
+    fn reset(&self) {
+        self.configured.store(false, Ordering::Relaxed);
+        self.set(ConnectionState::Attached);
+    }
+
+    fn configured(&self, configured: bool) {
+        self.configured.store(configured, Ordering::Relaxed);
+        self.set(match configured {
+            true => ConnectionState::Configured,
+            false => ConnectionState::Attached,
+        });
+    }
+
+    fn suspended(&self, suspended: bool) {
+        if suspended {
+            self.set(ConnectionState::Suspended);
+        } else if self.configured.load(Ordering::Relaxed) {
+            self.set(ConnectionState::Configured);
+        } else {
+            self.set(ConnectionState::Attached);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_futures::block_on;
+    use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+
+    use super::*;
+
+    #[test]
+    fn tells_suspend_from_detach() {
+        let monitor = ConnectionMonitor::<NoopRawMutex>::new();
+        monitor.enabled(true);
+        monitor.reset();
+        monitor.configured(true);
+        assert_eq!(monitor.connection_state(), ConnectionState::Configured);
+        monitor.suspended(true);
+        assert_eq!(monitor.connection_state(), ConnectionState::Suspended);
+        monitor.suspended(false);
+        assert_eq!(monitor.connection_state(), ConnectionState::Configured);
+
+        monitor.suspended(true);
+        monitor.enabled(false);
+        assert_eq!(monitor.connection_state(), ConnectionState::Detached);
+        block_on(monitor.wait_disconnected());
+        monitor.enabled(true);
+        monitor.suspended(false);
+        assert_eq!(monitor.connection_state(), ConnectionState::Attached);
+    }
+}
@@ -0,0 +1,25 @@
+/// CRC-16/CCITT-FALSE of `bytes`.
+pub(crate) fn crc16(bytes: &[u8]) -> u16 {
+    crc16_update(0xffff, bytes)
+}
+
+/// Continues the CRC `crc` over `bytes`.
+pub(crate) fn crc16_update(crc: u16, bytes: &[u8]) -> u16 {
+    bytes.iter().fold(crc, |crc, &byte| {
+        (0..8).fold(crc ^ u16::from(byte) << 8, |crc, _| match crc & 0x8000 {
+            0 => crc << 1,
+            _ => crc << 1 ^ 0x1021,
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn computes_crcs() {
+        assert_eq!(crc16(b"123456789"), 0x29b1);
+        assert_eq!(crc16_update(crc16(b"1234"), b"56789"), 0x29b1);
+    }
+}
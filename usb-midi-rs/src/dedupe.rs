@@ -0,0 +1,153 @@
+use embassy_time::{Duration, Instant};
+use heapless::Vec;
+
+use crate::cc::{
+    is_channel_mode_message, BANK_SELECT, BANK_SELECT_LSB, DATA_ENTRY, DATA_ENTRY_LSB, DATA_INCREMENT, RPN_MSB,
+};
+use crate::pipeline::Node;
+use crate::Event;
+
+/// Drops events that only repeat the state sent before, as chained
+/// controllers like to re-send all their state.
+///
+/// A controller, Pitch Bend or Program Change is dropped if the same value
+/// was sent on its channel less than the window ago, so the state is still
+/// refreshed once per window. The last `S` values sent are kept, the oldest
+/// making room for a new one. Data Entries, Increments and Decrements,
+/// parameter selections and Bank Selects always pass, as they mean something
+/// in sequence, and a Bank Select lets the next Program Change of its channel
+/// pass. So do channel mode messages, e.g. a repeated All Notes Off.
+#[derive(Clone, Debug)]
+pub struct Dedupe<const S: usize> {
+    window: Duration,
+    sent: Vec<Sent, S>,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Sent {
+    status: u8,
+    /// Controller number, 0 for other events.
+    control: u8,
+    value: (u8, u8),
+    at: Instant,
+}
+
+impl<const S: usize> Dedupe<S> {
+    pub const fn new(window: Duration) -> Self {
+        Self {
+            window,
+            sent: Vec::new(),
+        }
+    }
+
+    /// Returns `event` unless it repeats what was sent before `at`.
+    pub fn process(&mut self, event: Event, at: Instant) -> Option<Event> {
+        let (status, control, value) = match event {
+            Event::ControlChange(status, BANK_SELECT | BANK_SELECT_LSB, _) => {
+                let program = status & 0x0f | 0xc0;
+                self.sent.retain(|sent| sent.status != program);
+                return Some(event);
+            }
+            Event::ControlChange(_, DATA_ENTRY | DATA_ENTRY_LSB | DATA_INCREMENT..=RPN_MSB, _) => return Some(event),
+            Event::ControlChange(_, control, _) if is_channel_mode_message(control) => return Some(event),
+            Event::ControlChange(status, control, value) => (status, control, (value, 0)),
+            Event::PitchBendChange(status, lsb, msb) => (status, 0, (lsb, msb)),
+            Event::ProgramChange(status, program) => (status, 0, (program, 0)),
+            event => return Some(event),
+        };
+        let sent = Sent {
+            status,
+            control,
+            value,
+            at,
+        };
+        match self
+            .sent
+            .iter_mut()
+            .find(|sent| sent.status == status && sent.control == control)
+        {
+            Some(last) if last.value == value && at < last.at + self.window => return None,
+            Some(last) => *last = sent,
+            None => {
+                if self.sent.is_full() {
+                    let oldest = (0..self.sent.len()).min_by_key(|&index| self.sent[index].at);
+                    if let Some(oldest) = oldest {
+                        self.sent.swap_remove(oldest);
+                    }
+                }
+                let _ = self.sent.push(sent);
+            }
+        }
+        Some(event)
+    }
+}
+
+impl<const S: usize> Node for Dedupe<S> {
+    fn process(&mut self, event: Event) -> Option<Event> {
+        Dedupe::process(self, event, Instant::now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cc::{ALL_NOTES_OFF, ALL_SOUND_OFF, POLY_ON};
+
+    fn at(millis: u64) -> Instant {
+        Instant::from_millis(millis)
+    }
+
+    #[test]
+    fn drops_repeated_values() {
+        let mut dedupe = Dedupe::<2>::new(Duration::from_millis(100));
+        let volume = Event::ControlChange(0xb0, 7, 100);
+        assert_eq!(dedupe.process(volume, at(0)), Some(volume));
+        assert_eq!(dedupe.process(volume, at(50)), None);
+        assert_eq!(
+            dedupe.process(Event::ControlChange(0xb1, 7, 100), at(50)),
+            Some(Event::ControlChange(0xb1, 7, 100))
+        );
+        // The state is refreshed once per window.
+        assert_eq!(dedupe.process(volume, at(100)), Some(volume));
+        assert_eq!(
+            dedupe.process(Event::ControlChange(0xb0, 7, 99), at(101)),
+            Some(Event::ControlChange(0xb0, 7, 99))
+        );
+
+        let bend = Event::PitchBendChange(0xe0, 0, 64);
+        assert_eq!(dedupe.process(bend, at(110)), Some(bend));
+        // The oldest value made room for the Pitch Bend.
+        assert_eq!(
+            dedupe.process(Event::ControlChange(0xb1, 7, 100), at(120)),
+            Some(Event::ControlChange(0xb1, 7, 100))
+        );
+        let entry = Event::ControlChange(0xb0, DATA_ENTRY, 1);
+        assert_eq!(dedupe.process(entry, at(120)), Some(entry));
+        assert_eq!(dedupe.process(entry, at(121)), Some(entry));
+        let increment = Event::ControlChange(0xb0, DATA_INCREMENT, 0);
+        assert_eq!(dedupe.process(increment, at(122)), Some(increment));
+        assert_eq!(dedupe.process(increment, at(123)), Some(increment));
+    }
+
+    #[test]
+    fn passes_channel_mode_messages() {
+        let mut dedupe = Dedupe::<4>::new(Duration::from_secs(1));
+        for control in [ALL_SOUND_OFF, ALL_NOTES_OFF, POLY_ON] {
+            let event = Event::ControlChange(0xb2, control, 0);
+            assert_eq!(dedupe.process(event, at(0)), Some(event));
+            assert_eq!(dedupe.process(event, at(1)), Some(event));
+        }
+    }
+
+    #[test]
+    fn passes_program_changes_after_bank_selects() {
+        let mut dedupe = Dedupe::<4>::new(Duration::from_secs(1));
+        let program = Event::ProgramChange(0xc3, 5);
+        assert_eq!(dedupe.process(program, at(0)), Some(program));
+        assert_eq!(dedupe.process(program, at(1)), None);
+        let bank = Event::ControlChange(0xb3, BANK_SELECT, 1);
+        assert_eq!(dedupe.process(bank, at(2)), Some(bank));
+        assert_eq!(dedupe.process(bank, at(3)), Some(bank));
+        assert_eq!(dedupe.process(program, at(4)), Some(program));
+    }
+}
@@ -0,0 +1,144 @@
+use embassy_time::Instant;
+
+#[cfg(feature = "sysex")]
+use crate::sysex::{SYSEX_END, SYSEX_START};
+#[cfg(feature = "sysex")]
+use crate::{AccessGuard, ConfigReply, Error};
+
+/// Command byte of a factory reset request.
+#[cfg(feature = "sysex")]
+pub const FACTORY_RESET: u8 = 0x0e;
+
+/// Provides the settings a device leaves the factory with.
+pub trait Defaults {
+    /// Everything a factory reset restores, e.g. the routing, the port
+    /// names, the curves and the identity of the device.
+    type Settings;
+
+    fn defaults(&self) -> Self::Settings;
+}
+
+/// Restores the [`Defaults`] of a device, on request of the firmware or of
+/// the host.
+///
+/// The settings are built completely before they replace the current ones
+/// in a single assignment, so nothing sees a mix of old and new settings.
+#[derive(Clone, Debug)]
+pub struct FactoryReset<D> {
+    defaults: D,
+    last: Option<Instant>,
+}
+
+impl<D: Defaults> FactoryReset<D> {
+    pub const fn new(defaults: D) -> Self {
+        Self { defaults, last: None }
+    }
+
+    /// Time of the last reset since boot.
+    pub fn last(&self) -> Option<Instant> {
+        self.last
+    }
+
+    /// Replaces `settings` with the defaults at `now`.
+    pub fn reset(&mut self, settings: &mut D::Settings, now: Instant) {
+        debug!("Factory reset");
+        *settings = self.defaults.defaults();
+        self.last = Some(now);
+    }
+
+    /// Handles the complete System Exclusive `message` received at `now`,
+    /// returning the reply to send or `None` if it is not a reset request,
+    /// i.e. `F0 <manufacturer> 0E F7`.
+    ///
+    /// The reset has to be unlocked at `guard` first and is refused with
+    /// [`Error::Unsupported`] otherwise.
+    #[cfg(feature = "sysex")]
+    pub fn handle(
+        &mut self,
+        message: &[u8],
+        manufacturer: &[u8],
+        guard: &mut AccessGuard,
+        settings: &mut D::Settings,
+        now: Instant,
+    ) -> Option<ConfigReply> {
+        let rest = message.strip_prefix(&[SYSEX_START][..])?.strip_prefix(manufacturer)?;
+        if rest != [FACTORY_RESET, SYSEX_END] {
+            return None;
+        }
+        if !guard.authorize(now) {
+            return Some(ConfigReply::Nak(Error::Unsupported));
+        }
+        self.reset(settings, now);
+        Some(ConfigReply::Ack)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Debug, Eq, PartialEq)]
+    struct Settings {
+        routes: [bool; 4],
+        name: &'static str,
+    }
+
+    struct Factory;
+
+    impl Defaults for Factory {
+        type Settings = Settings;
+
+        fn defaults(&self) -> Settings {
+            Settings {
+                routes: [true; 4],
+                name: "MIDI Thing",
+            }
+        }
+    }
+
+    #[test]
+    fn restores_defaults() {
+        let mut settings = Settings {
+            routes: [false; 4],
+            name: "Renamed",
+        };
+        let mut reset = FactoryReset::new(Factory);
+        reset.reset(&mut settings, Instant::from_secs(1));
+        assert_eq!(settings, Factory.defaults());
+        assert_eq!(reset.last(), Some(Instant::from_secs(1)));
+    }
+
+    #[cfg(feature = "sysex")]
+    #[test]
+    fn resets_when_unlocked() {
+        use crate::Lock;
+
+        const MANUFACTURER: &[u8] = &[0x7d];
+        const REQUEST: &[u8] = &[0xf0, 0x7d, FACTORY_RESET, 0xf7];
+
+        let mut settings = Settings {
+            routes: [false; 4],
+            name: "Renamed",
+        };
+        let mut reset = FactoryReset::new(Factory);
+        let mut guard = AccessGuard::new(Lock::Pin(b"1234"), 1);
+        let now = Instant::from_secs(1);
+        assert_eq!(
+            reset.handle(REQUEST, MANUFACTURER, &mut guard, &mut settings, now),
+            Some(ConfigReply::Nak(Error::Unsupported))
+        );
+        assert_eq!(settings.name, "Renamed");
+
+        guard.handle(&[0xf0, 0x7d, 0x0c, b'1', b'2', b'3', b'4', 0xf7], MANUFACTURER, now);
+        assert_eq!(
+            reset.handle(REQUEST, MANUFACTURER, &mut guard, &mut settings, now),
+            Some(ConfigReply::Ack)
+        );
+        assert_eq!(settings, Factory.defaults());
+        let request = [0xf0, 0x7d, 0x05, 0xf7];
+        assert_eq!(
+            reset.handle(&request, MANUFACTURER, &mut guard, &mut settings, now),
+            None
+        );
+    }
+}
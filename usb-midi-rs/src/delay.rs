@@ -0,0 +1,128 @@
+use embassy_time::{Duration, Instant};
+
+use crate::schedule::{run_timed, Schedule, Timed};
+use crate::transport::{MidiSink, MidiSource};
+use crate::{Error, Event};
+
+/// Delays all events by a fixed time, to align the outputs of a setup.
+///
+/// Receivers behind different outputs, e.g. a USB host, a DIN port and the
+/// network, hear an event at different times. Delaying the routes to the
+/// faster ones by the difference makes them play together. Every route gets
+/// a delay of its own.
+///
+/// Up to `P` events can wait at a time. If more arrive, the earliest one is
+/// sent early, so the order of the events is kept.
+pub struct Delay<const P: usize> {
+    delay: Duration,
+    pending: Schedule<Event, P>,
+}
+
+impl<const P: usize> Delay<P> {
+    pub const fn new(delay: Duration) -> Self {
+        Self {
+            delay,
+            pending: Schedule::new(),
+        }
+    }
+
+    pub fn delay(&self) -> Duration {
+        self.delay
+    }
+
+    /// Changes the delay of the events to come. Events waiting keep their
+    /// time, so shortening the delay may let new events overtake them.
+    pub fn set_delay(&mut self, delay: Duration) {
+        self.delay = delay;
+    }
+
+    /// Takes `event` received at `at` and returns an event to send at once,
+    /// if any. All other events are returned by [`poll`](Self::poll) later.
+    pub fn process(&mut self, event: Event, at: Instant) -> Option<Event> {
+        if self.delay == Duration::from_ticks(0) && self.pending.deadline().is_none() {
+            return Some(event);
+        }
+        let early = match self.pending.is_full() {
+            true => self.pending.pop(),
+            false => None,
+        };
+        let _ = self.pending.insert(at + self.delay, event);
+        early
+    }
+
+    /// Returns the next event due at `now`, if any.
+    pub fn poll(&mut self, now: Instant) -> Option<Event> {
+        self.pending.poll(now)
+    }
+
+    /// When the next waiting event is due.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.pending.deadline()
+    }
+
+    /// Delays the events of `source` for `sink` until sending fails.
+    pub async fn run(&mut self, source: &mut impl MidiSource, sink: &mut impl MidiSink) -> Error {
+        run_timed(self, source, sink).await
+    }
+}
+
+impl<const P: usize> Timed for Delay<P> {
+    fn process(&mut self, event: Event, at: Instant) -> Option<Event> {
+        Delay::process(self, event, at)
+    }
+
+    fn poll(&mut self, now: Instant) -> Option<Event> {
+        Delay::poll(self, now)
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        Delay::deadline(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delays_events() {
+        let mut delay = Delay::<2>::new(Duration::from_millis(5));
+        let (clock, start) = (Event::SingleByte(0xf8), Event::SingleByte(0xfa));
+        assert_eq!(delay.process(start, Instant::from_millis(1)), None);
+        assert_eq!(delay.process(clock, Instant::from_millis(2)), None);
+        assert_eq!(delay.deadline(), Some(Instant::from_millis(6)));
+        assert_eq!(delay.poll(Instant::from_millis(5)), None);
+        assert_eq!(delay.poll(Instant::from_millis(6)), Some(start));
+
+        // The earliest event makes room.
+        assert_eq!(delay.process(clock, Instant::from_millis(3)), None);
+        assert_eq!(delay.process(start, Instant::from_millis(4)), Some(clock));
+        assert_eq!(delay.poll(Instant::from_millis(9)), Some(clock));
+        assert_eq!(delay.poll(Instant::from_millis(9)), Some(start));
+
+        delay.set_delay(Duration::from_ticks(0));
+        assert_eq!(delay.process(clock, Instant::from_millis(10)), Some(clock));
+    }
+
+    #[test]
+    fn runs_between_queues() {
+        use embassy_futures::block_on;
+        use embassy_futures::select::{select, Either};
+        use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+        use embassy_sync::channel::Channel;
+
+        let input = Channel::<NoopRawMutex, Event, 4>::new();
+        let output = Channel::<NoopRawMutex, Event, 4>::new();
+        let (start, stop) = (Event::SingleByte(0xfa), Event::SingleByte(0xfc));
+        input.try_send(start).unwrap();
+        input.try_send(stop).unwrap();
+        let mut delay = Delay::<2>::new(Duration::from_millis(1));
+        let (mut source, mut sink) = (&input, &output);
+        let run = delay.run(&mut source, &mut sink);
+        let received = async { [output.recv().await, output.recv().await] };
+        match block_on(select(run, received)) {
+            Either::Second(events) => assert_eq!(events, [start, stop]),
+            Either::First(error) => panic!("{:?}", error),
+        }
+    }
+}
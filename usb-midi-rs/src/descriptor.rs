@@ -0,0 +1,289 @@
+//! Class-specific descriptors of the USB MIDI 1.0 device class.
+//!
+//! Every descriptor knows its own layout and serializes itself, so the class
+//! never has to assemble raw byte slices or count bytes by hand. Serialization
+//! only produces the descriptor body, i.e. everything after `bLength` and
+//! `bDescriptorType`, which is what `InterfaceAltBuilder::descriptor` expects.
+
+use heapless::Vec;
+
+pub const CS_INTERFACE: u8 = 0x24;
+pub const CS_ENDPOINT: u8 = 0x25;
+
+const HEADER: u8 = 0x01;
+const MS_HEADER: u8 = 0x01;
+pub(crate) const MIDI_IN_JACK: u8 = 0x02;
+pub(crate) const MIDI_OUT_JACK: u8 = 0x03;
+const ELEMENT: u8 = 0x04;
+pub(crate) const MS_GENERAL: u8 = 0x01;
+
+/// Release number of the Audio and MIDI specifications (1.0) in BCD.
+const REVISION: u16 = 0x0100;
+
+/// Length of a standard audio class endpoint descriptor, which carries the
+/// additional `bRefresh` and `bSynchAddress` fields.
+pub const AUDIO_ENDPOINT_LEN: usize = 9;
+
+/// Upper bound for the body of any descriptor in this module.
+pub const MAX_BODY_LEN: usize = 32;
+
+pub type Body = Vec<u8, MAX_BODY_LEN>;
+
+pub trait Descriptor {
+    /// The `bDescriptorType` of this descriptor.
+    const DESCRIPTOR_TYPE: u8;
+
+    /// Appends the descriptor body to `body`.
+    fn write_body(&self, body: &mut Body);
+
+    fn body(&self) -> Body {
+        let mut body = Body::new();
+        self.write_body(&mut body);
+        body
+    }
+
+    /// Total length of the descriptor including `bLength` and
+    /// `bDescriptorType`.
+    fn length(&self) -> usize {
+        self.body().len() + 2
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[repr(u8)]
+pub enum JackType {
+    Embedded = 0x01,
+    External = 0x02,
+}
+
+/// Connection of an input pin to an output pin of another entity.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct Source {
+    pub id: u8,
+    pub pin: u8,
+}
+
+/// Class-specific AudioControl interface header.
+pub struct AcHeader<'a> {
+    /// Interface numbers of the MIDIStreaming interfaces in this collection.
+    pub streaming_interfaces: &'a [u8],
+}
+
+impl AcHeader<'_> {
+    /// Length of a header listing `streaming_interfaces` interfaces.
+    pub const fn len(streaming_interfaces: usize) -> usize {
+        8 + streaming_interfaces
+    }
+}
+
+impl Descriptor for AcHeader<'_> {
+    const DESCRIPTOR_TYPE: u8 = CS_INTERFACE;
+
+    fn write_body(&self, body: &mut Body) {
+        let total_length = (8 + self.streaming_interfaces.len()) as u16;
+        push(body, &[HEADER]);
+        push(body, &REVISION.to_le_bytes());
+        push(body, &total_length.to_le_bytes());
+        push(body, &[self.streaming_interfaces.len() as u8]);
+        push(body, self.streaming_interfaces);
+    }
+}
+
+/// Class-specific MIDIStreaming interface header.
+pub struct MsHeader {
+    /// Combined length of this header and all jack, element and endpoint
+    /// descriptors of the interface.
+    pub total_length: u16,
+}
+
+impl MsHeader {
+    pub const LEN: usize = 7;
+}
+
+impl Descriptor for MsHeader {
+    const DESCRIPTOR_TYPE: u8 = CS_INTERFACE;
+
+    fn write_body(&self, body: &mut Body) {
+        push(body, &[MS_HEADER]);
+        push(body, &REVISION.to_le_bytes());
+        push(body, &self.total_length.to_le_bytes());
+    }
+}
+
+/// MIDI IN jack descriptor.
+pub struct InJack {
+    pub jack_type: JackType,
+    pub id: u8,
+    /// String index of the jack name, or 0.
+    pub name: u8,
+}
+
+impl InJack {
+    pub const LEN: usize = 6;
+}
+
+impl Descriptor for InJack {
+    const DESCRIPTOR_TYPE: u8 = CS_INTERFACE;
+
+    fn write_body(&self, body: &mut Body) {
+        push(body, &[MIDI_IN_JACK, self.jack_type as u8, self.id, self.name]);
+    }
+}
+
+/// MIDI OUT jack descriptor.
+pub struct OutJack<'a> {
+    pub jack_type: JackType,
+    pub id: u8,
+    pub sources: &'a [Source],
+    /// String index of the jack name, or 0.
+    pub name: u8,
+}
+
+impl OutJack<'_> {
+    /// Length of a jack with `sources` sources.
+    pub const fn len(sources: usize) -> usize {
+        7 + 2 * sources
+    }
+}
+
+impl Descriptor for OutJack<'_> {
+    const DESCRIPTOR_TYPE: u8 = CS_INTERFACE;
+
+    fn write_body(&self, body: &mut Body) {
+        push(body, &[MIDI_OUT_JACK, self.jack_type as u8, self.id]);
+        push_sources(body, self.sources);
+        push(body, &[self.name]);
+    }
+}
+
+/// Element descriptor.
+pub struct Element<'a> {
+    pub id: u8,
+    pub sources: &'a [Source],
+    pub output_pins: u8,
+    pub in_terminal_link: u8,
+    pub out_terminal_link: u8,
+    /// `bmElementCaps`, least significant byte first.
+    pub capabilities: &'a [u8],
+    /// String index of the element name, or 0.
+    pub name: u8,
+}
+
+impl Descriptor for Element<'_> {
+    const DESCRIPTOR_TYPE: u8 = CS_INTERFACE;
+
+    fn write_body(&self, body: &mut Body) {
+        push(body, &[ELEMENT, self.id]);
+        push_sources(body, self.sources);
+        push(body, &[self.output_pins, self.in_terminal_link, self.out_terminal_link]);
+        push(body, &[self.capabilities.len() as u8]);
+        push(body, self.capabilities);
+        push(body, &[self.name]);
+    }
+}
+
+/// Class-specific MIDIStreaming bulk endpoint descriptor.
+pub struct CsEndpoint<'a> {
+    /// IDs of the embedded jacks associated with the endpoint.
+    pub jacks: &'a [u8],
+}
+
+impl CsEndpoint<'_> {
+    /// Length of a descriptor associating `jacks` jacks.
+    pub const fn len(jacks: usize) -> usize {
+        4 + jacks
+    }
+}
+
+impl Descriptor for CsEndpoint<'_> {
+    const DESCRIPTOR_TYPE: u8 = CS_ENDPOINT;
+
+    fn write_body(&self, body: &mut Body) {
+        push(body, &[MS_GENERAL, self.jacks.len() as u8]);
+        push(body, self.jacks);
+    }
+}
+
+fn push(body: &mut Body, bytes: &[u8]) {
+    body.extend_from_slice(bytes).expect("descriptor too long");
+}
+
+fn push_sources(body: &mut Body, sources: &[Source]) {
+    push(body, &[sources.len() as u8]);
+    for source in sources {
+        push(body, &[source.id, source.pin]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ac_header() {
+        let header = AcHeader {
+            streaming_interfaces: &[1],
+        };
+        assert_eq!(header.body(), [0x01, 0x00, 0x01, 0x09, 0x00, 0x01, 0x01]);
+        assert_eq!(header.length(), 9);
+        assert_eq!(AcHeader::len(1), 9);
+    }
+
+    #[test]
+    fn ms_header() {
+        let header = MsHeader { total_length: 0x0141 };
+        assert_eq!(header.body(), [0x01, 0x00, 0x01, 0x41, 0x01]);
+        assert_eq!(header.length(), MsHeader::LEN);
+    }
+
+    #[test]
+    fn in_jack() {
+        let jack = InJack {
+            jack_type: JackType::External,
+            id: 2,
+            name: 0,
+        };
+        assert_eq!(jack.body(), [0x02, 0x02, 0x02, 0x00]);
+        assert_eq!(jack.length(), 6);
+        assert_eq!(InJack::LEN, 6);
+    }
+
+    #[test]
+    fn out_jack() {
+        let jack = OutJack {
+            jack_type: JackType::Embedded,
+            id: 3,
+            sources: &[Source { id: 2, pin: 1 }],
+            name: 5,
+        };
+        assert_eq!(jack.body(), [0x03, 0x01, 0x03, 0x01, 0x02, 0x01, 0x05]);
+        assert_eq!(jack.length(), 9);
+        assert_eq!(OutJack::len(1), 9);
+    }
+
+    #[test]
+    fn element() {
+        let element = Element {
+            id: 9,
+            sources: &[Source { id: 1, pin: 1 }, Source { id: 5, pin: 1 }],
+            output_pins: 1,
+            in_terminal_link: 0,
+            out_terminal_link: 0,
+            capabilities: &[0x01],
+            name: 0,
+        };
+        assert_eq!(
+            element.body(),
+            [0x04, 0x09, 0x02, 0x01, 0x01, 0x05, 0x01, 0x01, 0x00, 0x00, 0x01, 0x01, 0x00]
+        );
+        assert_eq!(element.length(), 15);
+    }
+
+    #[test]
+    fn cs_endpoint() {
+        let endpoint = CsEndpoint { jacks: &[1, 5, 9] };
+        assert_eq!(endpoint.body(), [0x01, 0x03, 0x01, 0x05, 0x09]);
+        assert_eq!(endpoint.length(), 7);
+        assert_eq!(CsEndpoint::len(3), 7);
+    }
+}
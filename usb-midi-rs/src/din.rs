@@ -0,0 +1,504 @@
+use embassy_time::{Duration, Instant, Timer};
+use heapless::Deque;
+
+use crate::event::Event;
+#[cfg(feature = "sysex")]
+use crate::sysex::SysExFragmenter;
+use crate::{Error, MidiMessage};
+
+/// Number of real-time messages a [`DinSerializer`] holds.
+const REAL_TIME_QUEUE_SIZE: usize = 4;
+
+/// Splits the byte stream of a DIN MIDI input into events.
+///
+/// Running status is expanded and real-time bytes are passed through even in
+/// the middle of another message. Data bytes without a preceding status byte
+/// are ignored. System Exclusive messages are only passed on with the
+/// `sysex` feature; an exclusive message interrupted by another status byte
+/// is cut short.
+///
+/// After garbage or a [`framing_error`](Self::framing_error) the parser
+/// resynchronizes on the next status byte.
+#[derive(Default)]
+pub struct DinParser {
+    status: u8,
+    data: [u8; 2],
+    len: usize,
+    in_sysex: bool,
+    #[cfg(feature = "sysex")]
+    sysex: SysExFragmenter,
+    errors: u32,
+}
+
+impl DinParser {
+    pub const fn new() -> Self {
+        Self {
+            status: 0,
+            data: [0; 2],
+            len: 0,
+            in_sysex: false,
+            #[cfg(feature = "sysex")]
+            sysex: SysExFragmenter::new(),
+            errors: 0,
+        }
+    }
+
+    /// Number of framing errors and data bytes that belonged to no message.
+    pub fn errors(&self) -> u32 {
+        self.errors
+    }
+
+    /// Reports that the UART lost a byte, e.g. with a framing, noise or
+    /// overrun error.
+    ///
+    /// The message being received is dropped and data bytes are ignored
+    /// until the next status byte, so that no corrupted message is passed
+    /// on. A System Exclusive message being received stays incomplete.
+    pub fn framing_error(&mut self) {
+        self.errors = self.errors.wrapping_add(1);
+        self.status = 0;
+        self.len = 0;
+        self.in_sysex = false;
+    }
+
+    pub fn push(&mut self, byte: u8) -> Option<Event> {
+        match byte {
+            0xf8..=0xff => return MidiMessage::from_bytes(&[byte]).map(Event::from),
+            0xf0 => {
+                self.status = 0;
+                self.in_sysex = true;
+                #[cfg(feature = "sysex")]
+                {
+                    self.sysex = SysExFragmenter::new();
+                    return self.sysex.push(byte);
+                }
+            }
+            0xf7 => {
+                self.status = 0;
+                #[cfg(feature = "sysex")]
+                if self.in_sysex {
+                    self.in_sysex = false;
+                    return self.sysex.push(byte);
+                }
+                self.in_sysex = false;
+            }
+            0xf4 | 0xf5 => {
+                self.status = 0;
+                self.in_sysex = false;
+            }
+            0x80..=0xf6 => {
+                self.status = byte;
+                self.len = 0;
+                self.in_sysex = false;
+                if byte == 0xf6 {
+                    return self.complete();
+                }
+            }
+            _ if self.in_sysex => {
+                #[cfg(feature = "sysex")]
+                return self.sysex.push(byte);
+            }
+            _ if self.status != 0 => {
+                self.data[self.len] = byte;
+                self.len += 1;
+                if self.len == data_len(self.status) {
+                    return self.complete();
+                }
+            }
+            _ => self.errors = self.errors.wrapping_add(1),
+        }
+        None
+    }
+
+    fn complete(&mut self) -> Option<Event> {
+        let mut bytes = [self.status, 0, 0];
+        bytes[1..1 + self.len].copy_from_slice(&self.data[..self.len]);
+        let message = MidiMessage::from_bytes(&bytes[..1 + self.len]);
+        self.len = 0;
+        // Running status only applies to channel messages.
+        if self.status >= 0xf0 {
+            self.status = 0;
+        }
+        message.map(Event::from)
+    }
+}
+
+/// Turns events into the byte stream of a DIN MIDI output.
+///
+/// Real-time messages overtake the message being sent and go out between
+/// its bytes, which MIDI allows even within System Exclusive. A clock is thus
+/// delayed by at most one byte, however long the dump currently being sent.
+#[derive(Default)]
+pub struct DinSerializer {
+    bytes: [u8; 3],
+    pos: usize,
+    len: usize,
+    real_time: Deque<u8, REAL_TIME_QUEUE_SIZE>,
+    running_status: bool,
+    note_off_as_note_on: bool,
+    /// Status of the previous channel message, or 0.
+    status: u8,
+}
+
+impl DinSerializer {
+    pub const fn new() -> Self {
+        Self {
+            bytes: [0; 3],
+            pos: 0,
+            len: 0,
+            real_time: Deque::new(),
+            running_status: false,
+            note_off_as_note_on: false,
+            status: 0,
+        }
+    }
+
+    /// A serializer leaving out the status byte of a channel message if it
+    /// is the same as that of the previous one, which saves a third of the
+    /// bandwidth for dense note or controller data.
+    ///
+    /// With `note_off_as_note_on`, Note Offs are sent as Note Ons with
+    /// velocity 0, so they share the running status of the Note Ons, see
+    /// [`Event::note_off_as_note_on`].
+    pub const fn with_running_status(note_off_as_note_on: bool) -> Self {
+        let mut serializer = Self::new();
+        serializer.running_status = true;
+        serializer.note_off_as_note_on = note_off_as_note_on;
+        serializer
+    }
+
+    /// Makes the next channel message carry its status byte even if it is
+    /// the same as that of the previous one.
+    ///
+    /// A receiver that missed the status byte, e.g. because it was plugged in
+    /// later or a byte got lost, misreads everything up to the next one. Call
+    /// this after a failed write and after the output has been idle for a
+    /// while, so such receivers recover.
+    pub fn invalidate_running_status(&mut self) {
+        self.status = 0;
+    }
+
+    /// Whether the previous event has been sent completely, so another one
+    /// other than a real-time message can be pushed.
+    pub fn is_ready(&self) -> bool {
+        self.pos == self.len
+    }
+
+    /// Queues `event` for sending.
+    ///
+    /// Fails with [`Error::BufferOverflow`] if the serializer is not ready or,
+    /// for real-time messages, already holds too many of them.
+    pub fn push(&mut self, event: Event) -> Result<(), Error> {
+        let event = match self.note_off_as_note_on {
+            true => event.note_off_as_note_on(),
+            false => event,
+        };
+        let packet = event.to_packet(0);
+        if event.is_real_time() {
+            return self.real_time.push_back(packet[1]).map_err(|_| Error::BufferOverflow);
+        }
+        if !self.is_ready() {
+            return Err(Error::BufferOverflow);
+        }
+        self.bytes.copy_from_slice(&packet[1..]);
+        self.pos = 0;
+        self.len = event.size();
+        match packet[1] {
+            status @ 0x80..=0xef => {
+                if self.running_status && status == self.status {
+                    self.pos = 1;
+                }
+                self.status = status;
+            }
+            // System Common and System Exclusive cancel running status.
+            _ => self.status = 0,
+        }
+        Ok(())
+    }
+
+    /// The next byte to send, real-time messages first.
+    pub fn next_byte(&mut self) -> Option<u8> {
+        if let Some(byte) = self.real_time.pop_front() {
+            return Some(byte);
+        }
+        let byte = *self.bytes[..self.len].get(self.pos)?;
+        self.pos += 1;
+        Some(byte)
+    }
+}
+
+/// Level of a DIN input line at rest, which depends on the input circuit.
+///
+/// The opto-isolator of the standard circuit pulls the UART input low while
+/// current flows, so the line idles high like any UART line. Some circuits
+/// drive the input through an inverting stage and idle low; their UART has
+/// to be set up to invert the input.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum InputPolarity {
+    /// Idles high.
+    #[default]
+    Normal,
+    /// Idles low.
+    Inverted,
+}
+
+impl InputPolarity {
+    /// The polarity of a line idling at `high`.
+    pub const fn from_idle_level(high: bool) -> Self {
+        match high {
+            true => InputPolarity::Normal,
+            false => InputPolarity::Inverted,
+        }
+    }
+
+    pub const fn is_inverted(self) -> bool {
+        matches!(self, InputPolarity::Inverted)
+    }
+}
+
+/// Finds the [`InputPolarity`] of a DIN input by sampling its line, so the
+/// same firmware runs with different input circuits.
+///
+/// Within a byte, the line leaves its idle level for at most the start bit
+/// and eight data bits and returns to it for the stop bit. A level lasting
+/// longer than a whole byte is thus the idle level, however busy the line.
+/// The line has to be sampled at least once per bit time, 32 µs.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PolarityDetector {
+    /// Level of the last sample and since when the line has been at it.
+    level: Option<(bool, Instant)>,
+}
+
+impl PolarityDetector {
+    /// The time of one byte at 31250 baud.
+    pub const BYTE_TIME: Duration = Duration::from_micros(320);
+
+    pub const fn new() -> Self {
+        Self { level: None }
+    }
+
+    /// Adds a sample of the line, returning its polarity once it is known.
+    pub fn sample(&mut self, high: bool, at: Instant) -> Option<InputPolarity> {
+        match self.level {
+            Some((level, since)) if level == high => {
+                (at >= since + Self::BYTE_TIME).then_some(InputPolarity::from_idle_level(high))
+            }
+            _ => {
+                self.level = Some((high, at));
+                None
+            }
+        }
+    }
+
+    /// Samples the line with `read_level`, which returns whether the line is
+    /// high, once per bit time until its polarity is known or `timeout` has
+    /// passed.
+    ///
+    /// Times out if the line does not move, e.g. because nothing is plugged
+    /// in to an input whose circuit leaves it floating.
+    pub async fn detect(mut read_level: impl FnMut() -> bool, timeout: Duration) -> Option<InputPolarity> {
+        let mut detector = Self::new();
+        let deadline = Instant::now() + timeout;
+        loop {
+            let now = Instant::now();
+            if let Some(polarity) = detector.sample(read_level(), now) {
+                return Some(polarity);
+            }
+            if now >= deadline {
+                return None;
+            }
+            Timer::after(Duration::from_micros(32)).await;
+        }
+    }
+}
+
+/// Number of data bytes following `status`.
+fn data_len(status: u8) -> usize {
+    match status {
+        0xc0..=0xdf | 0xf1 | 0xf3 => 1,
+        0x80..=0xef | 0xf2 => 2,
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::Note;
+
+    fn parse(bytes: &[u8]) -> Vec<Event> {
+        let mut parser = DinParser::new();
+        bytes.iter().filter_map(|&byte| parser.push(byte)).collect()
+    }
+
+    #[test]
+    fn expands_running_status() {
+        assert_eq!(
+            parse(&[0x90, 60, 100, 62, 100, 0xc1, 5, 6]),
+            [
+                Event::NoteOn(0x90, Note::new(60), 100),
+                Event::NoteOn(0x90, Note::new(62), 100),
+                Event::ProgramChange(0xc1, 5),
+                Event::ProgramChange(0xc1, 6),
+            ]
+        );
+    }
+
+    fn serialize(events: &[Event], real_time_after: usize) -> Vec<u8> {
+        let mut serializer = DinSerializer::new();
+        let mut bytes = Vec::new();
+        for &event in events {
+            serializer.push(event).unwrap();
+            while let Some(byte) = serializer.next_byte() {
+                bytes.push(byte);
+                if bytes.len() == real_time_after {
+                    serializer.push(Event::SingleByte(0xf8)).unwrap();
+                }
+            }
+        }
+        bytes
+    }
+
+    #[test]
+    fn serializes_events() {
+        let events = [Event::NoteOn(0x90, Note::new(60), 100), Event::ProgramChange(0xc1, 5)];
+        assert_eq!(serialize(&events, 0), [0x90, 60, 100, 0xc1, 5]);
+        assert_eq!(serialize(&events, 2), [0x90, 60, 0xf8, 100, 0xc1, 5]);
+    }
+
+    #[test]
+    fn uses_running_status() {
+        let events = [
+            Event::NoteOn(0x90, Note::new(60), 100),
+            Event::NoteOff(0x80, Note::new(60), 30),
+            Event::SingleByte(0xf8),
+            Event::NoteOn(0x90, Note::new(62), 100),
+            Event::SystemCommon2(0xf3, 1),
+            Event::NoteOn(0x90, Note::new(64), 100),
+        ];
+        let serialize = |mut serializer: DinSerializer| {
+            let mut bytes = Vec::new();
+            for &event in &events {
+                serializer.push(event).unwrap();
+                bytes.extend(core::iter::from_fn(|| serializer.next_byte()));
+            }
+            bytes
+        };
+        assert_eq!(
+            serialize(DinSerializer::with_running_status(false)),
+            [0x90, 60, 100, 0x80, 60, 30, 0xf8, 0x90, 62, 100, 0xf3, 1, 0x90, 64, 100]
+        );
+        assert_eq!(
+            serialize(DinSerializer::with_running_status(true)),
+            [0x90, 60, 100, 60, 0, 0xf8, 62, 100, 0xf3, 1, 0x90, 64, 100]
+        );
+    }
+
+    #[test]
+    fn invalidates_running_status() {
+        fn send(serializer: &mut DinSerializer, event: Event) -> Vec<u8> {
+            serializer.push(event).unwrap();
+            core::iter::from_fn(|| serializer.next_byte()).collect()
+        }
+
+        let serializer = &mut DinSerializer::with_running_status(false);
+        assert_eq!(send(serializer, Event::ControlChange(0xb0, 1, 2)), [0xb0, 1, 2]);
+        assert_eq!(send(serializer, Event::ControlChange(0xb0, 1, 3)), [1, 3]);
+        assert_eq!(send(serializer, Event::SysExEnd3(0xf0, 0x7d, 0xf7)), [0xf0, 0x7d, 0xf7]);
+        assert_eq!(send(serializer, Event::ControlChange(0xb0, 1, 4)), [0xb0, 1, 4]);
+        serializer.invalidate_running_status();
+        assert_eq!(send(serializer, Event::ControlChange(0xb0, 1, 5)), [0xb0, 1, 5]);
+    }
+
+    #[test]
+    fn real_time_bytes_overtake_sysex() {
+        let events = [Event::SysExStartCont(0xf0, 1, 2), Event::SysExEnd2(3, 0xf7)];
+        assert_eq!(serialize(&events, 4), [0xf0, 1, 2, 3, 0xf8, 0xf7]);
+
+        let mut serializer = DinSerializer::new();
+        serializer.push(events[0]).unwrap();
+        assert_eq!(serializer.push(events[1]), Err(Error::BufferOverflow));
+        serializer.next_byte();
+        for _ in 0..REAL_TIME_QUEUE_SIZE {
+            serializer.push(Event::SingleByte(0xfe)).unwrap();
+        }
+        assert_eq!(serializer.push(Event::SingleByte(0xf8)), Err(Error::BufferOverflow));
+        assert_eq!(serializer.next_byte(), Some(0xfe));
+    }
+
+    #[test]
+    fn recovers_from_framing_errors() {
+        let mut parser = DinParser::new();
+        let mut parse = |bytes: &[u8]| bytes.iter().filter_map(|&byte| parser.push(byte)).collect::<Vec<_>>();
+        assert_eq!(parse(&[60, 100, 0xb0, 7]), []);
+        assert_eq!(
+            parse(&[100, 7, 90]),
+            [Event::ControlChange(0xb0, 7, 100), Event::ControlChange(0xb0, 7, 90)]
+        );
+        parser.framing_error();
+        assert_eq!(parser.errors(), 3);
+
+        let mut parse = |bytes: &[u8]| bytes.iter().filter_map(|&byte| parser.push(byte)).collect::<Vec<_>>();
+        // The byte lost was the running status of the next message.
+        assert_eq!(
+            parse(&[7, 80, 0x90, 60, 100]),
+            [Event::NoteOn(0x90, Note::new(60), 100)]
+        );
+        assert_eq!(parser.errors(), 5);
+    }
+
+    #[test]
+    fn detects_polarity() {
+        let mut detector = PolarityDetector::new();
+        let at = |micros| Instant::from_micros(micros);
+        // A byte of 0x00 keeps an inverted line high for 288 µs.
+        assert_eq!(detector.sample(false, at(0)), None);
+        assert_eq!(detector.sample(true, at(32)), None);
+        assert_eq!(detector.sample(true, at(320)), None);
+        assert_eq!(detector.sample(false, at(352)), None);
+        assert_eq!(detector.sample(false, at(640)), None);
+        assert_eq!(detector.sample(false, at(672)), Some(InputPolarity::Inverted));
+        assert!(InputPolarity::from_idle_level(false).is_inverted());
+    }
+
+    #[test]
+    fn interleaves_real_time_bytes() {
+        assert_eq!(
+            parse(&[0x90, 60, 0xf8, 100]),
+            [Event::SingleByte(0xf8), Event::NoteOn(0x90, Note::new(60), 100)]
+        );
+    }
+
+    #[test]
+    fn ignores_data_without_status() {
+        assert_eq!(parse(&[60, 100, 0xf3, 1, 2]), [Event::SystemCommon2(0xf3, 1)]);
+        assert_eq!(parse(&[0xf6]), [Event::SystemCommon1SysExEnd1(0xf6)]);
+    }
+
+    #[test]
+    #[cfg(feature = "sysex")]
+    fn passes_sysex() {
+        assert_eq!(
+            parse(&[0xf0, 0x7e, 0xf8, 0x7f, 0x06, 0x01, 0xf7]),
+            [
+                Event::SingleByte(0xf8),
+                Event::SysExStartCont(0xf0, 0x7e, 0x7f),
+                Event::SysExEnd3(0x06, 0x01, 0xf7),
+            ]
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn parses_any_stream(bytes in vec(any::<u8>(), 0..64)) {
+            for event in parse(&bytes) {
+                prop_assert!(event.size() > 0);
+            }
+        }
+    }
+}
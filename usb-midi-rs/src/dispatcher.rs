@@ -0,0 +1,309 @@
+#[cfg(feature = "usb")]
+use core::future::Future;
+
+use embassy_sync::blocking_mutex::raw::RawMutex;
+use embassy_sync::channel::Channel;
+use embassy_time::Instant;
+#[cfg(feature = "usb")]
+use embassy_usb::driver::Driver;
+
+#[cfg(feature = "latency")]
+use crate::LatencyProbe;
+use crate::{CableNumber, Counter, Event};
+#[cfg(feature = "usb")]
+use crate::{Error, Heartbeat, Receiver, MAX_PACKET_SIZE};
+
+/// Default capacity of each per-cable queue.
+pub const RX_QUEUE_SIZE: usize = 16;
+
+/// Per-cable queues of events received from the host.
+///
+/// Each queue holds up to `Q` events. Every queued event takes a few bytes of
+/// RAM per cable, so small parts may want to lower `Q`. Events are stamped
+/// with the time their transfer arrived.
+pub struct Queues<M: RawMutex, const N: usize, const Q: usize = RX_QUEUE_SIZE> {
+    cables: [Channel<M, (Instant, Event), Q>; N],
+    overflows: Counter,
+}
+
+impl<M: RawMutex, const N: usize, const Q: usize> Queues<M, N, Q> {
+    pub fn new() -> Self {
+        Self {
+            cables: core::array::from_fn(|_| Channel::new()),
+            overflows: Counter::new(),
+        }
+    }
+
+    /// Number of events dropped because their queue was full.
+    pub fn overflows(&self) -> u32 {
+        self.overflows.get()
+    }
+
+    pub async fn receive(&self, cable: CableNumber<N>) -> Event {
+        self.receive_timestamped(cable).await.1
+    }
+
+    pub fn try_receive(&self, cable: CableNumber<N>) -> Option<Event> {
+        self.try_receive_timestamped(cable).map(|(_, event)| event)
+    }
+
+    pub async fn receive_timestamped(&self, cable: CableNumber<N>) -> (Instant, Event) {
+        self.cable(cable).recv().await
+    }
+
+    pub fn try_receive_timestamped(&self, cable: CableNumber<N>) -> Option<(Instant, Event)> {
+        self.cable(cable).try_recv().ok()
+    }
+
+    pub(crate) fn cable(&self, cable: CableNumber<N>) -> &Channel<M, (Instant, Event), Q> {
+        &self.cables[cable.number() as usize]
+    }
+
+    /// Queues `event` received at `at`, dropping it if the queue is full.
+    #[cfg(feature = "usb")]
+    pub(crate) fn push(&self, cable: CableNumber<N>, at: Instant, event: Event) {
+        if self.cable(cable).try_send((at, event)).is_err() {
+            warn!("queue of cable {} full, event dropped", cable.number());
+            self.overflows.increment();
+        }
+    }
+
+    #[cfg(feature = "usb")]
+    fn clear(&self) {
+        for queue in &self.cables {
+            while queue.try_recv().is_ok() {}
+        }
+    }
+}
+
+impl<M: RawMutex, const N: usize, const Q: usize> Default for Queues<M, N, Q> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Callbacks of the [`Dispatcher`] on connection changes.
+pub trait ConnectionHandler {
+    /// The host enabled the endpoints.
+    fn connected(&mut self) {}
+
+    /// The host disabled the endpoints. Events that have not been received
+    /// from the queues yet are discarded.
+    fn disconnected(&mut self) {}
+}
+
+impl ConnectionHandler for () {}
+
+/// Distributes events received from the host to per-cable queues.
+#[cfg(feature = "usb")]
+pub struct Dispatcher<'a, 'd, M: RawMutex, D: Driver<'d>, const N: usize, const Q: usize = RX_QUEUE_SIZE> {
+    receiver: Receiver<'d, D, N>,
+    queues: &'a Queues<M, N, Q>,
+    heartbeat: Option<&'a Heartbeat>,
+    #[cfg(feature = "latency")]
+    latency: Option<&'static LatencyProbe>,
+    /// Channel forced on the channel messages of every cable.
+    channels: [Option<crate::Channel>; N],
+}
+
+#[cfg(feature = "usb")]
+impl<'a, 'd, M: RawMutex, D: Driver<'d>, const N: usize, const Q: usize> Dispatcher<'a, 'd, M, D, N, Q> {
+    pub fn new(receiver: Receiver<'d, D, N>, queues: &'a Queues<M, N, Q>) -> Self {
+        Self {
+            receiver,
+            queues,
+            heartbeat: None,
+            #[cfg(feature = "latency")]
+            latency: None,
+            channels: [None; N],
+        }
+    }
+
+    /// Reports every dispatched transfer to `heartbeat`, so that a watchdog
+    /// is only fed while the dispatcher waits for the host or keeps up with
+    /// it, see [`feed_watchdog`](crate::feed_watchdog).
+    pub fn with_heartbeat(mut self, heartbeat: &'a Heartbeat) -> Self {
+        self.heartbeat = Some(heartbeat);
+        self
+    }
+
+    /// Moves all channel messages received on `cable` to `channel`, e.g. for
+    /// a sound module listening on a single channel.
+    pub fn with_forced_channel(mut self, cable: CableNumber<N>, channel: crate::Channel) -> Self {
+        self.channels[cable.number() as usize] = Some(channel);
+        self
+    }
+
+    /// Measures the latency of every read with `probe`.
+    #[cfg(feature = "latency")]
+    pub fn with_latency_probe(mut self, probe: &'static LatencyProbe) -> Self {
+        self.latency = Some(probe);
+        self
+    }
+
+    /// Dispatches events for as long as the device runs.
+    ///
+    /// After a disconnect the queues are cleared and the dispatcher waits for
+    /// the host to reconnect.
+    pub async fn run(&mut self, handler: &mut impl ConnectionHandler) -> ! {
+        let mut buf = [0; MAX_PACKET_SIZE as usize];
+        loop {
+            idle(self.heartbeat, self.receiver.wait_connection()).await;
+            debug!("connected");
+            handler.connected();
+
+            loop {
+                let read = self.receiver.read_events(&mut buf);
+                #[cfg(feature = "latency")]
+                let read = measure(self.latency, read);
+                match idle(self.heartbeat, read).await {
+                    Ok(events) => {
+                        let now = Instant::now();
+                        for (cable, mut event) in events.flatten() {
+                            if let Some(channel) = self.channels[cable.number() as usize] {
+                                event = event.with_channel(channel);
+                            }
+                            self.queues.push(cable, now, event);
+                        }
+                        #[cfg(feature = "latency")]
+                        if let Some(probe) = self.latency {
+                            probe.delivered();
+                        }
+                        if let Some(heartbeat) = self.heartbeat {
+                            heartbeat.beat();
+                        }
+                    }
+                    Err(Error::Disconnected) => break,
+                    Err(error) => warn!("read failed: {}", error),
+                }
+            }
+
+            debug!("disconnected");
+            self.queues.clear();
+            handler.disconnected();
+        }
+    }
+}
+
+/// Awaits `input`, idling `heartbeat` if there is one.
+#[cfg(feature = "usb")]
+async fn idle<F: Future>(heartbeat: Option<&Heartbeat>, input: F) -> F::Output {
+    match heartbeat {
+        Some(heartbeat) => heartbeat.idle(input).await,
+        None => input.await,
+    }
+}
+
+/// Awaits `input`, measuring it with `probe` if there is one.
+#[cfg(feature = "latency")]
+async fn measure<F: Future>(probe: Option<&'static LatencyProbe>, input: F) -> F::Output {
+    match probe {
+        Some(probe) => probe.measure(input).await,
+        None => input.await,
+    }
+}
+
+#[cfg(all(test, feature = "usb"))]
+mod tests {
+    use core::cell::Cell;
+    use core::pin::pin;
+
+    use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+
+    use super::*;
+    use crate::fault::{endpoints, poll_once, Faults, FaultyDriver, Pipe};
+    use crate::{MidiMessage, Receiver};
+
+    struct Connections<'a> {
+        connected: &'a Cell<u32>,
+        disconnected: &'a Cell<u32>,
+    }
+
+    impl ConnectionHandler for Connections<'_> {
+        fn connected(&mut self) {
+            self.connected.set(self.connected.get() + 1);
+        }
+
+        fn disconnected(&mut self) {
+            self.disconnected.set(self.disconnected.get() + 1);
+        }
+    }
+
+    #[test]
+    fn recovers_from_disconnects() {
+        let (pipe, faults) = (Pipe::default(), Faults::default());
+        let receiver: Receiver<FaultyDriver<Pipe>, 1> = Receiver::from_endpoint(endpoints(&pipe, &faults).0, 1);
+        let queues = Queues::<NoopRawMutex, 1>::new();
+        let mut dispatcher = Dispatcher::new(receiver, &queues);
+        let (connected, disconnected) = (Cell::new(0), Cell::new(0));
+        let mut handler = Connections {
+            connected: &connected,
+            disconnected: &disconnected,
+        };
+        let mut run = pin!(dispatcher.run(&mut handler));
+        let cable = CableNumber::new(0).unwrap();
+        let start = Event::from(MidiMessage::Start);
+
+        pipe.send(&[0x0f, 0xfa, 0, 0]);
+        assert!(poll_once(run.as_mut()).is_pending());
+        assert_eq!(queues.try_receive(cable), Some(start));
+        assert_eq!(connected.get(), 1);
+
+        // Events not received before the disconnect are discarded.
+        pipe.send(&[0x0f, 0xfa, 0, 0]);
+        assert!(poll_once(run.as_mut()).is_pending());
+        faults.disable();
+        assert!(poll_once(run.as_mut()).is_pending());
+        assert_eq!((connected.get(), disconnected.get()), (1, 1));
+        assert_eq!(queues.try_receive(cable), None);
+
+        faults.enable();
+        pipe.send(&[0x0f, 0xfc, 0, 0]);
+        assert!(poll_once(run.as_mut()).is_pending());
+        assert_eq!(connected.get(), 2);
+        assert_eq!(queues.try_receive(cable), Some(MidiMessage::Stop.into()));
+    }
+
+    #[test]
+    fn forces_channels() {
+        let (pipe, faults) = (Pipe::default(), Faults::default());
+        let receiver: Receiver<FaultyDriver<Pipe>, 2> = Receiver::from_endpoint(endpoints(&pipe, &faults).0, 2);
+        let queues = Queues::<NoopRawMutex, 2>::new();
+        let (forced, other) = (CableNumber::new(1).unwrap(), CableNumber::new(0).unwrap());
+        let mut dispatcher = Dispatcher::new(receiver, &queues).with_forced_channel(forced, crate::Channel::new(9));
+        let handler = &mut ();
+        let mut run = pin!(dispatcher.run(handler));
+
+        pipe.send(&[0x19, 0x92, 60, 100, 0x1f, 0xf8, 0, 0, 0x09, 0x92, 60, 100]);
+        assert!(poll_once(run.as_mut()).is_pending());
+        assert_eq!(
+            queues.try_receive(forced),
+            Some(Event::NoteOn(0x99, crate::Note::new(60), 100))
+        );
+        assert_eq!(queues.try_receive(forced), Some(Event::SingleByte(0xf8)));
+        assert_eq!(
+            queues.try_receive(other),
+            Some(Event::NoteOn(0x92, crate::Note::new(60), 100))
+        );
+    }
+
+    #[test]
+    fn beats_per_transfer() {
+        let (pipe, faults) = (Pipe::default(), Faults::default());
+        let receiver: Receiver<FaultyDriver<Pipe>, 1> = Receiver::from_endpoint(endpoints(&pipe, &faults).0, 1);
+        let queues = Queues::<NoopRawMutex, 1>::new();
+        let heartbeat = Heartbeat::new();
+        let mut dispatcher = Dispatcher::new(receiver, &queues).with_heartbeat(&heartbeat);
+        let handler = &mut ();
+        let mut run = pin!(dispatcher.run(handler));
+        let mut last = 0;
+
+        pipe.send(&[0x0f, 0xfa, 0, 0]);
+        pipe.send(&[0x0f, 0xfc, 0, 0]);
+        assert!(poll_once(run.as_mut()).is_pending());
+        assert!(heartbeat.is_alive(&mut last));
+        assert_eq!(last, 2);
+        // Waiting for the host is no livelock.
+        assert!(heartbeat.is_alive(&mut last));
+    }
+}
@@ -0,0 +1,47 @@
+use embassy_time::TimeoutError;
+#[cfg(feature = "usb")]
+use embassy_usb::driver::EndpointError;
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// The endpoint was disabled, e.g. because the host deconfigured the
+    /// device or the cable was unplugged.
+    Disconnected,
+    /// Something does not fit the space meant for it, e.g. a received
+    /// transfer its buffer or a name its maximum length.
+    BufferOverflow,
+    /// The received data is not valid MIDI.
+    Malformed,
+    /// The host did not read the data in time.
+    Timeout,
+    /// The other side does not support the operation, e.g. a device without
+    /// MIDI output.
+    Unsupported,
+}
+
+/// A descriptor buffer is too small for the descriptors of the device.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct BufferTooSmall {
+    /// Bytes the descriptors need.
+    pub required: usize,
+    /// Length of the buffer.
+    pub len: usize,
+}
+
+#[cfg(feature = "usb")]
+impl From<EndpointError> for Error {
+    fn from(error: EndpointError) -> Self {
+        match error {
+            EndpointError::BufferOverflow => Error::BufferOverflow,
+            EndpointError::Disabled => Error::Disconnected,
+        }
+    }
+}
+
+impl From<TimeoutError> for Error {
+    fn from(_: TimeoutError) -> Self {
+        Error::Timeout
+    }
+}
@@ -0,0 +1,408 @@
+use core::fmt;
+use core::str::FromStr;
+
+use crate::Channel;
+
+/// The MIDI bytes of a USB-MIDI event packet, tagged with its code index.
+///
+/// ```
+/// use usb_midi_rs::{Event, Note};
+///
+/// let event = Event::new(&[0x19, 0x92, 60, 100]);
+/// assert_eq!(event, Event::NoteOn(0x92, Note::new(60), 100));
+/// assert_eq!(event.size(), 3);
+/// // The cable number is not part of the event.
+/// assert_eq!(event.to_packet(0), [0x09, 0x92, 60, 100]);
+/// ```
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Event {
+    Misc,
+    Cable,
+    SystemCommon2(u8, u8),
+    SystemCommon3(u8, u8, u8),
+    SysExStartCont(u8, u8, u8),
+    SystemCommon1SysExEnd1(u8),
+    SysExEnd2(u8, u8),
+    SysExEnd3(u8, u8, u8),
+    NoteOff(u8, Note, u8),
+    NoteOn(u8, Note, u8),
+    PolyKeyPress(u8, u8, u8),
+    ControlChange(u8, u8, u8),
+    ProgramChange(u8, u8),
+    ChannelPressure(u8, u8),
+    PitchBendChange(u8, u8, u8),
+    SingleByte(u8),
+}
+
+impl Event {
+    pub fn new(data: &[u8]) -> Event {
+        assert_eq!(data.len(), 4);
+        match data[0] & 0xf {
+            0x0 => Event::Misc,
+            0x1 => Event::Cable,
+            0x2 => Event::SystemCommon2(data[1], data[2]),
+            0x3 => Event::SystemCommon3(data[1], data[2], data[3]),
+            0x4 => Event::SysExStartCont(data[1], data[2], data[3]),
+            0x5 => Event::SystemCommon1SysExEnd1(data[1]),
+            0x6 => Event::SysExEnd2(data[1], data[2]),
+            0x7 => Event::SysExEnd3(data[1], data[2], data[3]),
+            // Note numbers have 7 bits, whatever the wire says.
+            0x8 => Event::NoteOff(data[1], Note(data[2] & 0x7f), data[3]),
+            0x9 => Event::NoteOn(data[1], Note(data[2] & 0x7f), data[3]),
+            0xa => Event::PolyKeyPress(data[1], data[2], data[3]),
+            0xb => Event::ControlChange(data[1], data[2], data[3]),
+            0xc => Event::ProgramChange(data[1], data[2]),
+            0xd => Event::ChannelPressure(data[1], data[2]),
+            0xe => Event::PitchBendChange(data[1], data[2], data[3]),
+            0xf => Event::SingleByte(data[1]),
+            _ => panic!("now that's surprising"),
+        }
+    }
+
+    /// Serializes the event into a USB-MIDI event packet for `cable`.
+    ///
+    /// Being a `const fn`, it also builds tables of packets at compile time.
+    pub const fn to_packet(&self, cable: u8) -> [u8; 4] {
+        let (cin, data) = match *self {
+            Event::Misc => (0x0, [0, 0, 0]),
+            Event::Cable => (0x1, [0, 0, 0]),
+            Event::SystemCommon2(a, b) => (0x2, [a, b, 0]),
+            Event::SystemCommon3(a, b, c) => (0x3, [a, b, c]),
+            Event::SysExStartCont(a, b, c) => (0x4, [a, b, c]),
+            Event::SystemCommon1SysExEnd1(a) => (0x5, [a, 0, 0]),
+            Event::SysExEnd2(a, b) => (0x6, [a, b, 0]),
+            Event::SysExEnd3(a, b, c) => (0x7, [a, b, c]),
+            Event::NoteOff(a, Note(b), c) => (0x8, [a, b, c]),
+            Event::NoteOn(a, Note(b), c) => (0x9, [a, b, c]),
+            Event::PolyKeyPress(a, b, c) => (0xa, [a, b, c]),
+            Event::ControlChange(a, b, c) => (0xb, [a, b, c]),
+            Event::ProgramChange(a, b) => (0xc, [a, b, 0]),
+            Event::ChannelPressure(a, b) => (0xd, [a, b, 0]),
+            Event::PitchBendChange(a, b, c) => (0xe, [a, b, c]),
+            Event::SingleByte(a) => (0xf, [a, 0, 0]),
+        };
+        [cable << 4 | cin, data[0], data[1], data[2]]
+    }
+
+    /// Whether the event is a System Real-Time message, which may be sent in
+    /// between the bytes of any other message.
+    pub const fn is_real_time(&self) -> bool {
+        matches!(self, Event::SingleByte(0xf8..=0xff))
+    }
+
+    /// Channel of a channel message.
+    pub const fn channel(&self) -> Option<Channel> {
+        match *self {
+            Event::NoteOff(status, ..)
+            | Event::NoteOn(status, ..)
+            | Event::PolyKeyPress(status, ..)
+            | Event::ControlChange(status, ..)
+            | Event::ProgramChange(status, ..)
+            | Event::ChannelPressure(status, ..)
+            | Event::PitchBendChange(status, ..) => Some(Channel::new(status & 0x0f)),
+            _ => None,
+        }
+    }
+
+    /// Moves a channel message to `channel`. Other events stay as they are.
+    pub const fn with_channel(self, channel: Channel) -> Self {
+        let number = channel.number();
+        match self {
+            Event::NoteOff(status, note, velocity) => Event::NoteOff(status & 0xf0 | number, note, velocity),
+            Event::NoteOn(status, note, velocity) => Event::NoteOn(status & 0xf0 | number, note, velocity),
+            Event::PolyKeyPress(status, note, pressure) => Event::PolyKeyPress(status & 0xf0 | number, note, pressure),
+            Event::ControlChange(status, control, value) => {
+                Event::ControlChange(status & 0xf0 | number, control, value)
+            }
+            Event::ProgramChange(status, program) => Event::ProgramChange(status & 0xf0 | number, program),
+            Event::ChannelPressure(status, pressure) => Event::ChannelPressure(status & 0xf0 | number, pressure),
+            Event::PitchBendChange(status, lsb, msb) => Event::PitchBendChange(status & 0xf0 | number, lsb, msb),
+            event => event,
+        }
+    }
+
+    /// Rewrites a Note On with velocity 0 into the Note Off it stands for,
+    /// with the default release velocity of 64.
+    pub const fn normalize_note_off(self) -> Self {
+        match self {
+            Event::NoteOn(status, note, 0) => Event::NoteOff(status & 0x0f | 0x80, note, 64),
+            event => event,
+        }
+    }
+
+    /// Rewrites a Note Off into a Note On with velocity 0, dropping its
+    /// release velocity.
+    ///
+    /// Note Offs then share the running status of the Note Ons on a DIN
+    /// output, see [`DinSerializer::with_running_status`](crate::DinSerializer::with_running_status).
+    pub const fn note_off_as_note_on(self) -> Self {
+        match self {
+            Event::NoteOff(status, note, _) => Event::NoteOn(status & 0x0f | 0x90, note, 0),
+            event => event,
+        }
+    }
+
+    /// Number of MIDI bytes carried by the event.
+    pub const fn size(&self) -> usize {
+        match self {
+            Event::Misc | Event::Cable => 0,
+            Event::SystemCommon1SysExEnd1(..) | Event::SingleByte(..) => 1,
+            Event::SystemCommon2(..) | Event::SysExEnd2(..) | Event::ProgramChange(..) | Event::ChannelPressure(..) => {
+                2
+            }
+            _ => 3,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct Note(u8);
+
+impl Note {
+    pub const fn new(number: u8) -> Self {
+        assert!(number < 0x80, "note number out of range");
+        Self(number)
+    }
+
+    pub const fn number(self) -> u8 {
+        self.0
+    }
+}
+
+const NOTE_NAMES: [&str; 12] = ["C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B"];
+
+/// Octave numbering of note names.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Octaves {
+    /// Middle C (note 60) is C3 and note 0 is C-2, as used by Yamaha and
+    /// most DAWs.
+    #[default]
+    MiddleC3,
+    /// Middle C (note 60) is C4 and note 0 is C-1, as in scientific pitch
+    /// notation.
+    MiddleC4,
+}
+
+impl Octaves {
+    /// Octave number of note 0.
+    const fn lowest(self) -> i16 {
+        match self {
+            Octaves::MiddleC3 => -2,
+            Octaves::MiddleC4 => -1,
+        }
+    }
+}
+
+/// A string that is not the name of a note from 0 to 127.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct InvalidNoteName;
+
+impl Note {
+    /// Parses a note name such as `C#3`, `Eb4` or `A-1` with the given
+    /// octave numbering. Letters may be lowercase.
+    pub fn parse(name: &str, octaves: Octaves) -> Result<Self, InvalidNoteName> {
+        let mut chars = name.chars();
+        let pitch: i16 = match chars.next().map(|letter| letter.to_ascii_uppercase()) {
+            Some('C') => 0,
+            Some('D') => 2,
+            Some('E') => 4,
+            Some('F') => 5,
+            Some('G') => 7,
+            Some('A') => 9,
+            Some('B') => 11,
+            _ => return Err(InvalidNoteName),
+        };
+        let rest = chars.as_str();
+        let (pitch, octave) = match rest.as_bytes().first() {
+            Some(b'#') => (pitch + 1, &rest[1..]),
+            Some(b'b') => (pitch - 1, &rest[1..]),
+            _ => (pitch, rest),
+        };
+        let octave: i16 = octave.parse().map_err(|_| InvalidNoteName)?;
+        let number = octave
+            .checked_sub(octaves.lowest())
+            .and_then(|octave| octave.checked_mul(12))
+            .and_then(|number| number.checked_add(pitch))
+            .ok_or(InvalidNoteName)?;
+        match number {
+            0..=127 => Ok(Note(number as u8)),
+            _ => Err(InvalidNoteName),
+        }
+    }
+
+    /// The name of the note with the given octave numbering, using sharps.
+    pub const fn name(self, octaves: Octaves) -> NoteName {
+        NoteName { note: self, octaves }
+    }
+}
+
+/// Parses note names with middle C as C3, see [`Note::parse`].
+impl FromStr for Note {
+    type Err = InvalidNoteName;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Note::parse(name, Octaves::MiddleC3)
+    }
+}
+
+/// Formats the note name with middle C as C3.
+impl fmt::Display for Note {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.name(Octaves::MiddleC3).fmt(f)
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for Note {
+    fn format(&self, fmt: defmt::Formatter) {
+        self.name(Octaves::MiddleC3).format(fmt)
+    }
+}
+
+/// Name of a note, see [`Note::name`].
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct NoteName {
+    note: Note,
+    octaves: Octaves,
+}
+
+impl NoteName {
+    fn parts(&self) -> (&'static str, i16) {
+        let octave = (self.note.0 / 12) as i16 + self.octaves.lowest();
+        (NOTE_NAMES[(self.note.0 % 12) as usize], octave)
+    }
+}
+
+impl fmt::Display for NoteName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (name, octave) = self.parts();
+        write!(f, "{}{}", name, octave)
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for NoteName {
+    fn format(&self, fmt: defmt::Formatter) {
+        let (name, octave) = self.parts();
+        defmt::write!(fmt, "{}{}", name, octave);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+
+    use super::*;
+
+    #[test]
+    fn parses_note_names() {
+        assert_eq!("C3".parse(), Ok(Note::new(60)));
+        assert_eq!("c#3".parse(), Ok(Note::new(61)));
+        assert_eq!("Db3".parse(), Ok(Note::new(61)));
+        assert_eq!("A-1".parse(), Ok(Note::new(21)));
+        assert_eq!("C-2".parse(), Ok(Note::new(0)));
+        assert_eq!("G8".parse(), Ok(Note::new(127)));
+        assert_eq!(Note::parse("C4", Octaves::MiddleC4), Ok(Note::new(60)));
+        assert_eq!(Note::parse("C-1", Octaves::MiddleC4), Ok(Note::new(0)));
+        for name in ["", "H3", "C", "Cb-2", "G#8", "C#x", "C999999"] {
+            assert_eq!(name.parse::<Note>(), Err(InvalidNoteName), "{}", name);
+        }
+    }
+
+    #[test]
+    fn formats_note_names() {
+        assert_eq!(Note::new(60).to_string(), "C3");
+        assert_eq!(Note::new(61).to_string(), "C#3");
+        assert_eq!(Note::new(21).to_string(), "A-1");
+        assert_eq!(Note::new(0).name(Octaves::MiddleC4).to_string(), "C-1");
+        assert_eq!(Note::new(127).name(Octaves::MiddleC4).to_string(), "G9");
+    }
+
+    #[test]
+    fn normalizes_note_offs() {
+        let note = Note::new(60);
+        assert_eq!(
+            Event::NoteOn(0x93, note, 0).normalize_note_off(),
+            Event::NoteOff(0x83, note, 64)
+        );
+        assert_eq!(
+            Event::NoteOn(0x93, note, 1).normalize_note_off(),
+            Event::NoteOn(0x93, note, 1)
+        );
+        assert_eq!(
+            Event::NoteOff(0x83, note, 90).note_off_as_note_on(),
+            Event::NoteOn(0x93, note, 0)
+        );
+        assert_eq!(Event::SingleByte(0xf8).note_off_as_note_on(), Event::SingleByte(0xf8));
+    }
+
+    #[test]
+    fn moves_channels() {
+        let channel = Channel::new(9);
+        assert_eq!(
+            Event::NoteOn(0x93, Note::new(60), 5).with_channel(channel),
+            Event::NoteOn(0x99, Note::new(60), 5)
+        );
+        assert_eq!(
+            Event::PitchBendChange(0xe0, 1, 2).with_channel(channel),
+            Event::PitchBendChange(0xe9, 1, 2)
+        );
+        assert_eq!(Event::SingleByte(0xf8).with_channel(channel), Event::SingleByte(0xf8));
+    }
+
+    proptest! {
+        #[test]
+        fn round_trips_note_names(number in 0u8..0x80, middle_c4: bool) {
+            let octaves = if middle_c4 { Octaves::MiddleC4 } else { Octaves::MiddleC3 };
+            let note = Note::new(number);
+            prop_assert_eq!(Note::parse(&note.name(octaves).to_string(), octaves), Ok(note));
+        }
+
+        #[test]
+        fn parses_any_packet(packet: [u8; 4]) {
+            Event::new(&packet);
+        }
+
+        #[test]
+        fn masks_note_numbers(packet: [u8; 4]) {
+            if let Event::NoteOff(_, note, _) | Event::NoteOn(_, note, _) = Event::new(&packet) {
+                prop_assert!(note.number() < 0x80);
+            }
+        }
+
+        #[test]
+        fn ignores_cable_number(packet: [u8; 4], cable in 0u8..16) {
+            let mut other = packet;
+            other[0] = cable << 4 | packet[0] & 0x0f;
+            prop_assert_eq!(Event::new(&packet), Event::new(&other));
+        }
+
+        #[test]
+        fn round_trips_packets(mut packet: [u8; 4], cin in 0x2u8..=0xf) {
+            packet[0] = packet[0] & 0xf0 | cin;
+            if cin == 0x8 || cin == 0x9 {
+                packet[2] &= 0x7f;
+            }
+            let size = Event::new(&packet).size();
+            packet[1 + size..].fill(0);
+            prop_assert_eq!(Event::new(&packet).to_packet(packet[0] >> 4), packet);
+        }
+
+        #[test]
+        fn round_trips_events(packet: [u8; 4], cable in 0u8..16) {
+            let event = Event::new(&packet);
+            prop_assert_eq!(Event::new(&event.to_packet(cable)), event);
+        }
+
+        #[test]
+        fn parses_any_transfer(transfer in vec(any::<u8>(), 0..=64)) {
+            for packet in transfer.chunks_exact(4) {
+                Event::new(packet);
+            }
+        }
+    }
+}
@@ -0,0 +1,390 @@
+//! A driver for host-side tests, with faults injected on demand.
+//!
+//! [`Pipe`] is an in-memory driver whose endpoints carry transfers between
+//! the test, playing the host, and the code under test. [`FaultyDriver`]
+//! wraps any driver and lets the test disable its endpoints, cut reads short
+//! and hold back writes, so the reactions of the dispatcher, the TX path
+//! and the reconnection logic can be checked deterministically.
+//!
+//! For end-to-end tests, [`virtual_bus`] links a device to a simulated host,
+//! both of them a [`UsbMidiClass`].
+
+use core::future::{poll_fn, Future};
+use core::pin::Pin;
+use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::vec::Vec;
+
+use embassy_usb::driver::{
+    Bus, ControlPipe, Driver, Endpoint, EndpointAddress, EndpointAllocError, EndpointError, EndpointIn, EndpointInfo,
+    EndpointOut, EndpointType, Event, Unsupported,
+};
+
+use crate::UsbMidiClass;
+
+/// Polls `future` once with a waker that does nothing.
+pub(crate) fn poll_once<F: Future>(future: Pin<&mut F>) -> Poll<F::Output> {
+    static VTABLE: RawWakerVTable = RawWakerVTable::new(|data| RawWaker::new(data, &VTABLE), |_| {}, |_| {}, |_| {});
+    // Safety: the functions of the vtable do not touch the data pointer.
+    let waker = unsafe { Waker::from_raw(RawWaker::new(core::ptr::null(), &VTABLE)) };
+    future.poll(&mut Context::from_waker(&waker))
+}
+
+#[derive(Default)]
+struct Transfers {
+    /// From the host to the device.
+    out: VecDeque<Vec<u8>>,
+    /// From the device to the host.
+    written: VecDeque<Vec<u8>>,
+}
+
+/// An in-memory driver of the device, or of the host for the end returned
+/// by [`host_end`](Self::host_end).
+#[derive(Clone, Default)]
+pub(crate) struct Pipe {
+    transfers: Rc<RefCell<Transfers>>,
+    host: bool,
+}
+
+impl Pipe {
+    /// Sends `transfer` to the OUT endpoint of the device.
+    pub(crate) fn send(&self, transfer: &[u8]) {
+        self.transfers.borrow_mut().out.push_back(transfer.to_vec());
+    }
+
+    /// Takes the transfers the device wrote to its IN endpoint.
+    pub(crate) fn take_written(&self) -> Vec<Vec<u8>> {
+        self.transfers.borrow_mut().written.drain(..).collect()
+    }
+
+    /// The other end of the pipe, whose endpoints read what the device
+    /// writes and the other way round.
+    pub(crate) fn host_end(&self) -> Pipe {
+        Pipe {
+            transfers: self.transfers.clone(),
+            host: true,
+        }
+    }
+
+    /// Transfers to be read and written at this end.
+    fn with_queues<R>(&self, f: impl FnOnce(&mut VecDeque<Vec<u8>>, &mut VecDeque<Vec<u8>>) -> R) -> R {
+        let transfers = &mut *self.transfers.borrow_mut();
+        match self.host {
+            false => f(&mut transfers.out, &mut transfers.written),
+            true => f(&mut transfers.written, &mut transfers.out),
+        }
+    }
+}
+
+fn info(ep_type: EndpointType, max_packet_size: u16) -> EndpointInfo {
+    EndpointInfo {
+        addr: EndpointAddress::from(0x01),
+        ep_type,
+        max_packet_size,
+        interval_ms: 0,
+    }
+}
+
+pub(crate) struct PipeEndpoint {
+    pipe: Pipe,
+    info: EndpointInfo,
+}
+
+impl Endpoint for PipeEndpoint {
+    fn info(&self) -> &EndpointInfo {
+        &self.info
+    }
+
+    async fn wait_enabled(&mut self) {}
+}
+
+impl EndpointOut for PipeEndpoint {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, EndpointError> {
+        let transfer = poll_fn(|_| match self.pipe.with_queues(|inbound, _| inbound.pop_front()) {
+            Some(transfer) => Poll::Ready(transfer),
+            None => Poll::Pending,
+        })
+        .await;
+        let buf = buf.get_mut(..transfer.len()).ok_or(EndpointError::BufferOverflow)?;
+        buf.copy_from_slice(&transfer);
+        Ok(transfer.len())
+    }
+}
+
+impl EndpointIn for PipeEndpoint {
+    async fn write(&mut self, buf: &[u8]) -> Result<(), EndpointError> {
+        self.pipe.with_queues(|_, outbound| outbound.push_back(buf.to_vec()));
+        Ok(())
+    }
+}
+
+/// The bus and the control pipe of a [`Pipe`], which the tests never start.
+pub(crate) enum Unused {}
+
+impl Bus for Unused {
+    async fn enable(&mut self) {}
+
+    async fn disable(&mut self) {}
+
+    async fn poll(&mut self) -> Event {
+        match *self {}
+    }
+
+    fn endpoint_set_enabled(&mut self, _ep_addr: EndpointAddress, _enabled: bool) {}
+
+    fn endpoint_set_stalled(&mut self, _ep_addr: EndpointAddress, _stalled: bool) {}
+
+    fn endpoint_is_stalled(&mut self, _ep_addr: EndpointAddress) -> bool {
+        match *self {}
+    }
+
+    async fn remote_wakeup(&mut self) -> Result<(), Unsupported> {
+        match *self {}
+    }
+}
+
+impl ControlPipe for Unused {
+    fn max_packet_size(&self) -> usize {
+        match *self {}
+    }
+
+    async fn setup(&mut self) -> [u8; 8] {
+        match *self {}
+    }
+
+    async fn data_out(&mut self, _buf: &mut [u8], _first: bool, _last: bool) -> Result<usize, EndpointError> {
+        match *self {}
+    }
+
+    async fn data_in(&mut self, _data: &[u8], _first: bool, _last: bool) -> Result<(), EndpointError> {
+        match *self {}
+    }
+
+    async fn accept(&mut self) {}
+
+    async fn reject(&mut self) {}
+
+    async fn accept_set_address(&mut self, _addr: u8) {}
+}
+
+impl<'a> Driver<'a> for Pipe {
+    type EndpointOut = PipeEndpoint;
+    type EndpointIn = PipeEndpoint;
+    type ControlPipe = Unused;
+    type Bus = Unused;
+
+    fn alloc_endpoint_out(
+        &mut self,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+        _interval_ms: u8,
+    ) -> Result<PipeEndpoint, EndpointAllocError> {
+        Ok(PipeEndpoint {
+            pipe: self.clone(),
+            info: info(ep_type, max_packet_size),
+        })
+    }
+
+    fn alloc_endpoint_in(
+        &mut self,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+        _interval_ms: u8,
+    ) -> Result<PipeEndpoint, EndpointAllocError> {
+        self.alloc_endpoint_out(ep_type, max_packet_size, 0)
+    }
+
+    fn start(self, _control_max_packet_size: u16) -> (Unused, Unused) {
+        panic!("the pipe has no bus")
+    }
+}
+
+#[derive(Default)]
+struct FaultState {
+    disabled: bool,
+    /// Length the next read is cut to.
+    short_read: Option<usize>,
+    /// Number of polls every write stays pending.
+    write_delay: u32,
+}
+
+/// The faults of a [`FaultyDriver`], shared with the test.
+#[derive(Clone, Default)]
+pub(crate) struct Faults(Rc<RefCell<FaultState>>);
+
+impl Faults {
+    /// Disables the endpoints as if the host deconfigured the device. Reads
+    /// and writes in progress fail with [`EndpointError::Disabled`].
+    pub(crate) fn disable(&self) {
+        self.0.borrow_mut().disabled = true;
+    }
+
+    pub(crate) fn enable(&self) {
+        self.0.borrow_mut().disabled = false;
+    }
+
+    /// Cuts the next read to `len` bytes.
+    pub(crate) fn short_read(&self, len: usize) {
+        self.0.borrow_mut().short_read = Some(len);
+    }
+
+    /// Keeps every write pending for `polls` polls before it starts.
+    pub(crate) fn delay_writes(&self, polls: u32) {
+        self.0.borrow_mut().write_delay = polls;
+    }
+
+    fn is_disabled(&self) -> bool {
+        self.0.borrow().disabled
+    }
+
+    /// Runs `operation` unless or until the endpoints are disabled.
+    async fn unless_disabled<T>(
+        &self,
+        operation: impl Future<Output = Result<T, EndpointError>>,
+    ) -> Result<T, EndpointError> {
+        let mut operation = core::pin::pin!(operation);
+        poll_fn(|cx| match self.is_disabled() {
+            true => Poll::Ready(Err(EndpointError::Disabled)),
+            false => operation.as_mut().poll(cx),
+        })
+        .await
+    }
+}
+
+/// Wraps the endpoints of `D` to inject [`Faults`].
+pub(crate) struct FaultyDriver<D> {
+    driver: D,
+    faults: Faults,
+}
+
+impl<D> FaultyDriver<D> {
+    pub(crate) fn new(driver: D, faults: Faults) -> Self {
+        Self { driver, faults }
+    }
+}
+
+pub(crate) struct FaultyEndpoint<E> {
+    endpoint: E,
+    faults: Faults,
+}
+
+impl<E: Endpoint> Endpoint for FaultyEndpoint<E> {
+    fn info(&self) -> &EndpointInfo {
+        self.endpoint.info()
+    }
+
+    async fn wait_enabled(&mut self) {
+        poll_fn(|_| match self.faults.is_disabled() {
+            true => Poll::Pending,
+            false => Poll::Ready(()),
+        })
+        .await;
+        self.endpoint.wait_enabled().await
+    }
+}
+
+impl<E: EndpointOut> EndpointOut for FaultyEndpoint<E> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, EndpointError> {
+        let len = self.faults.unless_disabled(self.endpoint.read(buf)).await?;
+        Ok(match self.faults.0.borrow_mut().short_read.take() {
+            Some(short) => len.min(short),
+            None => len,
+        })
+    }
+}
+
+impl<E: EndpointIn> EndpointIn for FaultyEndpoint<E> {
+    async fn write(&mut self, buf: &[u8]) -> Result<(), EndpointError> {
+        let mut delay = self.faults.0.borrow().write_delay;
+        let endpoint = &mut self.endpoint;
+        let write = async move {
+            poll_fn(|_| match delay {
+                0 => Poll::Ready(()),
+                _ => {
+                    delay -= 1;
+                    Poll::Pending
+                }
+            })
+            .await;
+            endpoint.write(buf).await
+        };
+        self.faults.unless_disabled(write).await
+    }
+}
+
+impl<'a, D: Driver<'a>> Driver<'a> for FaultyDriver<D> {
+    type EndpointOut = FaultyEndpoint<D::EndpointOut>;
+    type EndpointIn = FaultyEndpoint<D::EndpointIn>;
+    type ControlPipe = D::ControlPipe;
+    type Bus = D::Bus;
+
+    fn alloc_endpoint_out(
+        &mut self,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<Self::EndpointOut, EndpointAllocError> {
+        Ok(FaultyEndpoint {
+            endpoint: self.driver.alloc_endpoint_out(ep_type, max_packet_size, interval_ms)?,
+            faults: self.faults.clone(),
+        })
+    }
+
+    fn alloc_endpoint_in(
+        &mut self,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<Self::EndpointIn, EndpointAllocError> {
+        Ok(FaultyEndpoint {
+            endpoint: self.driver.alloc_endpoint_in(ep_type, max_packet_size, interval_ms)?,
+            faults: self.faults.clone(),
+        })
+    }
+
+    fn start(self, control_max_packet_size: u16) -> (D::Bus, D::ControlPipe) {
+        self.driver.start(control_max_packet_size)
+    }
+}
+
+/// A faulty pipe with its endpoints allocated, for building a
+/// [`Receiver`](crate::Receiver) and a [`Sender`](crate::Sender).
+pub(crate) fn endpoints(pipe: &Pipe, faults: &Faults) -> (FaultyEndpoint<PipeEndpoint>, FaultyEndpoint<PipeEndpoint>) {
+    sized_endpoints(pipe, faults, 64, 64)
+}
+
+/// Like [`endpoints`], with the given `wMaxPacketSize` for the OUT and the
+/// IN endpoint.
+pub(crate) fn sized_endpoints(
+    pipe: &Pipe,
+    faults: &Faults,
+    out_size: u16,
+    in_size: u16,
+) -> (FaultyEndpoint<PipeEndpoint>, FaultyEndpoint<PipeEndpoint>) {
+    let mut driver = FaultyDriver::new(pipe.clone(), faults.clone());
+    let read_ep = driver.alloc_endpoint_out(EndpointType::Bulk, out_size, 0).unwrap();
+    let write_ep = driver.alloc_endpoint_in(EndpointType::Bulk, in_size, 0).unwrap();
+    (read_ep, write_ep)
+}
+
+/// A device whose endpoints suffer `faults`, linked to a simulated host.
+///
+/// Whatever the [`Sender`](crate::Sender) of one side writes, the
+/// [`Receiver`](crate::Receiver) of the other side reads, transfer by
+/// transfer.
+pub(crate) fn virtual_bus<const N: usize>(
+    faults: &Faults,
+) -> (
+    UsbMidiClass<'static, FaultyDriver<Pipe>, N>,
+    UsbMidiClass<'static, Pipe, N>,
+) {
+    let pipe = Pipe::default();
+    let (read_ep, write_ep) = endpoints(&pipe, faults);
+    let device = UsbMidiClass::from_endpoints(read_ep, write_ep, N);
+    let mut host = pipe.host_end();
+    let read_ep = host.alloc_endpoint_out(EndpointType::Bulk, 64, 0).unwrap();
+    let write_ep = host.alloc_endpoint_in(EndpointType::Bulk, 64, 0).unwrap();
+    (device, UsbMidiClass::from_endpoints(read_ep, write_ep, N))
+}
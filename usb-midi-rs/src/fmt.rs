@@ -0,0 +1,42 @@
+//! Logging macros of the library.
+//!
+//! They expand to `defmt` calls with the `defmt` feature and to nothing
+//! otherwise. [`trace_io!`] logs individual transfers and is compiled out
+//! unless the `instrument-io` feature is enabled as well, as logging every
+//! packet would wreck the timing of the data path.
+#![macro_use]
+// Not every combination of features uses all of them.
+#![allow(unused_macros)]
+
+macro_rules! trace_io {
+    ($s:literal $(, $x:expr)* $(,)?) => {
+        {
+            #[cfg(feature = "instrument-io")]
+            defmt::trace!($s $(, $x)*);
+            #[cfg(not(feature = "instrument-io"))]
+            let _ = ($( & $x ),*);
+        }
+    };
+}
+
+macro_rules! debug {
+    ($s:literal $(, $x:expr)* $(,)?) => {
+        {
+            #[cfg(feature = "defmt")]
+            defmt::debug!($s $(, $x)*);
+            #[cfg(not(feature = "defmt"))]
+            let _ = ($( & $x ),*);
+        }
+    };
+}
+
+macro_rules! warn {
+    ($s:literal $(, $x:expr)* $(,)?) => {
+        {
+            #[cfg(feature = "defmt")]
+            defmt::warn!($s $(, $x)*);
+            #[cfg(not(feature = "defmt"))]
+            let _ = ($( & $x ),*);
+        }
+    };
+}
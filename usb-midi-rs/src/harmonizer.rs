@@ -0,0 +1,163 @@
+use crate::transport::{forward_with, MidiSink, MidiSource};
+use crate::{Error, Event, Note};
+
+/// A note of a chord relative to the played note.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Interval {
+    pub semitones: i8,
+    /// Velocity in percent of the played velocity.
+    pub velocity: u8,
+}
+
+impl Interval {
+    pub const fn new(semitones: i8, velocity: u8) -> Self {
+        Self { semitones, velocity }
+    }
+}
+
+/// Plays a chord for every note.
+///
+/// Note Ons and Note Offs are expanded into one note per interval, so the
+/// played note itself is only kept if one of the intervals is 0. Notes
+/// falling outside the MIDI range are left out. All other events pass
+/// unchanged.
+///
+/// The Note Offs of the chord are derived from the intervals in effect when
+/// they arrive, so the intervals should only be changed while no notes are
+/// held, or the notes need to be released with a
+/// [`NoteTracker`](crate::NoteTracker).
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Harmonizer<const I: usize> {
+    intervals: [Interval; I],
+}
+
+impl<const I: usize> Harmonizer<I> {
+    pub const fn new(intervals: [Interval; I]) -> Self {
+        Self { intervals }
+    }
+
+    pub fn set_intervals(&mut self, intervals: [Interval; I]) {
+        self.intervals = intervals;
+    }
+
+    /// Events that `event` turns into.
+    pub fn process(&self, event: Event) -> Chord<'_> {
+        Chord {
+            event,
+            intervals: match event {
+                Event::NoteOn(..) | Event::NoteOff(..) => self.intervals.iter(),
+                _ => [].iter(),
+            },
+            passed: false,
+        }
+    }
+
+    /// Plays chords for the events of `source` on `sink`, see [`forward_with`].
+    pub async fn run(&self, source: &mut impl MidiSource, sink: &mut impl MidiSink) -> Error {
+        forward_with(source, sink, |event| self.process(event)).await
+    }
+}
+
+/// Events of a chord, see [`Harmonizer::process`].
+pub struct Chord<'a> {
+    event: Event,
+    intervals: core::slice::Iter<'a, Interval>,
+    passed: bool,
+}
+
+impl Iterator for Chord<'_> {
+    type Item = Event;
+
+    fn next(&mut self) -> Option<Event> {
+        let (status, note, velocity) = match self.event {
+            Event::NoteOn(status, note, velocity) | Event::NoteOff(status, note, velocity) => (status, note, velocity),
+            event if !self.passed => {
+                self.passed = true;
+                return Some(event);
+            }
+            _ => return None,
+        };
+        for interval in self.intervals.by_ref() {
+            let number = i16::from(note.number()) + i16::from(interval.semitones);
+            if !(0..128).contains(&number) {
+                continue;
+            }
+            let note = Note::new(number as u8);
+            return Some(match self.event {
+                // A velocity of 0 stands for a Note Off and must stay 0.
+                Event::NoteOn(_, _, 0) => Event::NoteOn(status, note, 0),
+                Event::NoteOn(..) => {
+                    let scaled = u16::from(velocity) * u16::from(interval.velocity) / 100;
+                    Event::NoteOn(status, note, scaled.clamp(1, 127) as u8)
+                }
+                _ => Event::NoteOff(status, note, velocity),
+            });
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_futures::block_on;
+    use embassy_futures::select::{select, Either};
+    use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+    use embassy_sync::channel::Channel;
+
+    use super::*;
+
+    const MAJOR: Harmonizer<3> = Harmonizer::new([Interval::new(0, 100), Interval::new(4, 80), Interval::new(7, 150)]);
+
+    #[test]
+    fn plays_chords() {
+        let chord: Vec<_> = MAJOR.process(Event::NoteOn(0x91, Note::new(60), 100)).collect();
+        assert_eq!(
+            chord,
+            [
+                Event::NoteOn(0x91, Note::new(60), 100),
+                Event::NoteOn(0x91, Note::new(64), 80),
+                Event::NoteOn(0x91, Note::new(67), 127),
+            ]
+        );
+        let chord: Vec<_> = MAJOR.process(Event::NoteOn(0x91, Note::new(60), 0)).collect();
+        assert_eq!(chord[2], Event::NoteOn(0x91, Note::new(67), 0));
+        let chord: Vec<_> = MAJOR.process(Event::NoteOff(0x81, Note::new(122), 30)).collect();
+        assert_eq!(
+            chord,
+            [
+                Event::NoteOff(0x81, Note::new(122), 30),
+                Event::NoteOff(0x81, Note::new(126), 30),
+            ]
+        );
+    }
+
+    #[test]
+    fn passes_other_events() {
+        let event = Event::ControlChange(0xb0, 1, 64);
+        assert_eq!(MAJOR.process(event).collect::<Vec<_>>(), [event]);
+    }
+
+    #[test]
+    fn runs_between_queues() {
+        let input = Channel::<NoopRawMutex, Event, 4>::new();
+        let output = Channel::<NoopRawMutex, Event, 4>::new();
+        input.try_send(Event::NoteOn(0x90, Note::new(48), 100)).unwrap();
+        let (mut source, mut sink) = (&input, &output);
+        let run = MAJOR.run(&mut source, &mut sink);
+        let received = async {
+            let mut notes = Vec::new();
+            for _ in 0..3 {
+                if let Event::NoteOn(_, note, _) = output.recv().await {
+                    notes.push(note.number());
+                }
+            }
+            notes
+        };
+        match block_on(select(run, received)) {
+            Either::Second(notes) => assert_eq!(notes, [48, 52, 55]),
+            Either::First(error) => panic!("{:?}", error),
+        }
+    }
+}
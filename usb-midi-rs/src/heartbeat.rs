@@ -0,0 +1,107 @@
+use core::future::Future;
+
+use embassy_time::{Duration, Timer};
+use portable_atomic::{AtomicBool, AtomicU32, Ordering};
+
+/// A hardware watchdog, fed by [`feed_watchdog`].
+pub trait Watchdog {
+    /// Restarts the countdown of the watchdog.
+    fn feed(&mut self);
+}
+
+/// Tells whether a pipeline, such as the `Dispatcher` or a task routing
+/// events, is alive.
+///
+/// The pipeline calls [`beat`](Self::beat) whenever it made progress and
+/// awaits its input through [`idle`](Self::idle). A pipeline that neither
+/// waits for input nor makes progress is stuck, e.g. in a livelock that
+/// keeps the executor busy, and [`feed_watchdog`] stops feeding the
+/// watchdog so the device resets. A pipeline that has not started yet counts
+/// as waiting.
+pub struct Heartbeat {
+    beats: AtomicU32,
+    waiting: AtomicBool,
+}
+
+impl Heartbeat {
+    pub const fn new() -> Self {
+        Self {
+            beats: AtomicU32::new(0),
+            waiting: AtomicBool::new(true),
+        }
+    }
+
+    /// Reports progress.
+    pub fn beat(&self) {
+        self.beats.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Awaits `input`, counting as alive while it is pending.
+    pub async fn idle<F: Future>(&self, input: F) -> F::Output {
+        self.waiting.store(true, Ordering::Relaxed);
+        let output = input.await;
+        self.waiting.store(false, Ordering::Relaxed);
+        output
+    }
+
+    /// Whether the pipeline waits for input or made progress since `last`
+    /// beats, which is updated.
+    pub fn is_alive(&self, last: &mut u32) -> bool {
+        let beats = self.beats.load(Ordering::Relaxed);
+        let progressed = core::mem::replace(last, beats) != beats;
+        progressed || self.waiting.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Feeds `watchdog` every `interval` for as long as all of `heartbeats` are
+/// alive.
+///
+/// The watchdog has to be configured with a timeout of a few intervals. The
+/// task runs at the lowest priority, so it also stops feeding when a busy
+/// task starves the executor.
+pub async fn feed_watchdog<const P: usize>(
+    heartbeats: [&Heartbeat; P],
+    watchdog: &mut impl Watchdog,
+    interval: Duration,
+) -> ! {
+    let mut last = [0; P];
+    loop {
+        Timer::after(interval).await;
+        let mut alive = true;
+        for (index, (heartbeat, last)) in heartbeats.iter().zip(&mut last).enumerate() {
+            if !heartbeat.is_alive(last) {
+                warn!("pipeline {} stuck", index);
+                alive = false;
+            }
+        }
+        if alive {
+            watchdog.feed();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_futures::block_on;
+
+    use super::*;
+
+    #[test]
+    fn tells_waiting_from_stuck() {
+        let heartbeat = Heartbeat::new();
+        let mut last = 0;
+        assert!(heartbeat.is_alive(&mut last));
+
+        assert_eq!(block_on(heartbeat.idle(async { 1 })), 1);
+        assert!(!heartbeat.is_alive(&mut last));
+        heartbeat.beat();
+        assert!(heartbeat.is_alive(&mut last));
+        assert!(!heartbeat.is_alive(&mut last));
+    }
+}
@@ -0,0 +1,679 @@
+//! Host side of the USB MIDI class.
+//!
+//! embassy-usb only implements device mode, so enumerating an attached device
+//! is left to the host stack of the application. Once it has read the
+//! configuration descriptor, [`parse_configuration`] finds the MIDI streaming
+//! interface, and a [`UsbMidiHost`] talks to the device through the bulk
+//! pipes the host stack provides, with the same cable-based API as
+//! [`UsbMidiClass`](crate::UsbMidiClass).
+//!
+//! # Forwarding between an attached device and a computer
+//!
+//! A "USB MIDI host box" runs device mode on one USB port and host mode on
+//! another, e.g. OTG_FS and OTG_HS of an STM32F4, and forwards the traffic of
+//! an attached keyboard to the computer and back with
+//! [`forward_to_computer`] and [`forward_to_device`].
+
+use core::future::Future;
+
+#[cfg(feature = "usb")]
+use embassy_usb::driver::Driver;
+use heapless::Vec;
+
+use crate::descriptor::{JackType, CS_ENDPOINT, CS_INTERFACE, MIDI_IN_JACK, MIDI_OUT_JACK, MS_GENERAL};
+use crate::router::MAX_DESTINATIONS;
+use crate::{
+    CableNumber, CablePolicy, Error, Event, Events, MidiMessage, Router, AUDIO_SUBCLASS_MIDISTREAMING, USB_CLASS_AUDIO,
+};
+#[cfg(feature = "usb")]
+use crate::{Counter, Receiver, Sender};
+
+const INTERFACE: u8 = 0x04;
+const ENDPOINT: u8 = 0x05;
+
+/// Maximum number of jacks recorded per interface.
+pub const MAX_JACKS: usize = 32;
+
+/// Bulk pipes to an attached device, provided by the USB host stack.
+pub trait HostPipes {
+    type ReadFuture<'a>: Future<Output = Result<usize, Error>> + 'a
+    where
+        Self: 'a;
+    type WriteFuture<'a>: Future<Output = Result<(), Error>> + 'a
+    where
+        Self: 'a;
+
+    /// Reads a transfer from the IN endpoint with address `endpoint`.
+    fn read<'a>(&'a mut self, endpoint: u8, data: &'a mut [u8]) -> Self::ReadFuture<'a>;
+
+    /// Writes a transfer to the OUT endpoint with address `endpoint`.
+    fn write<'a>(&'a mut self, endpoint: u8, data: &'a [u8]) -> Self::WriteFuture<'a>;
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Direction {
+    In,
+    Out,
+}
+
+/// A MIDI jack of an attached device.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct Jack {
+    pub direction: Direction,
+    pub jack_type: JackType,
+    pub id: u8,
+    /// String index of the jack name, or 0.
+    pub name: u8,
+}
+
+/// A bulk endpoint of an attached device.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct MidiEndpoint {
+    pub address: u8,
+    pub max_packet_size: u16,
+    /// Number of embedded jacks, i.e. cables, behind the endpoint.
+    pub cables: u8,
+}
+
+/// The MIDI streaming interface of an attached device.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct MidiStreamingInterface {
+    pub number: u8,
+    pub alternate_setting: u8,
+    /// Endpoint sending MIDI to the host.
+    pub input: Option<MidiEndpoint>,
+    /// Endpoint receiving MIDI from the host.
+    pub output: Option<MidiEndpoint>,
+    pub jacks: Vec<Jack, MAX_JACKS>,
+}
+
+/// Finds the first MIDI streaming interface in a configuration descriptor.
+///
+/// Parsing stops at the first malformed descriptor; everything found up to
+/// there is returned.
+pub fn parse_configuration(configuration: &[u8]) -> Option<MidiStreamingInterface> {
+    let mut interface: Option<MidiStreamingInterface> = None;
+    let mut endpoint = None;
+
+    for descriptor in descriptors(configuration) {
+        match (descriptor[1], interface.as_mut()) {
+            (INTERFACE, None)
+                if descriptor.len() >= 9
+                    && descriptor[5] == USB_CLASS_AUDIO
+                    && descriptor[6] == AUDIO_SUBCLASS_MIDISTREAMING =>
+            {
+                interface = Some(MidiStreamingInterface {
+                    number: descriptor[2],
+                    alternate_setting: descriptor[3],
+                    input: None,
+                    output: None,
+                    jacks: Vec::new(),
+                });
+            }
+            (INTERFACE, Some(_)) => break,
+            (CS_INTERFACE, Some(interface)) => {
+                if let Some(jack) = parse_jack(descriptor) {
+                    // Jacks beyond the capacity are not needed for talking
+                    // to the device.
+                    let _ = interface.jacks.push(jack);
+                }
+            }
+            (ENDPOINT, Some(_)) if descriptor.len() >= 7 => {
+                endpoint = Some(MidiEndpoint {
+                    address: descriptor[2],
+                    max_packet_size: u16::from_le_bytes([descriptor[4], descriptor[5]]),
+                    cables: 0,
+                });
+            }
+            (CS_ENDPOINT, Some(interface)) if descriptor.len() >= 4 && descriptor[2] == MS_GENERAL => {
+                if let Some(mut endpoint) = endpoint.take() {
+                    endpoint.cables = descriptor[3];
+                    if endpoint.address & 0x80 != 0 {
+                        interface.input = Some(endpoint);
+                    } else {
+                        interface.output = Some(endpoint);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    interface
+}
+
+fn parse_jack(descriptor: &[u8]) -> Option<Jack> {
+    if descriptor.len() < 6 {
+        return None;
+    }
+    let direction = match descriptor[2] {
+        MIDI_IN_JACK => Direction::In,
+        MIDI_OUT_JACK => Direction::Out,
+        _ => return None,
+    };
+    let jack_type = match descriptor[3] {
+        0x01 => JackType::Embedded,
+        0x02 => JackType::External,
+        _ => return None,
+    };
+    Some(Jack {
+        direction,
+        jack_type,
+        id: descriptor[4],
+        name: descriptor[descriptor.len() - 1],
+    })
+}
+
+/// Splits a configuration descriptor into its descriptors.
+fn descriptors(mut data: &[u8]) -> impl Iterator<Item = &[u8]> {
+    core::iter::from_fn(move || {
+        let len = *data.first()? as usize;
+        if len < 2 || len > data.len() {
+            return None;
+        }
+        let (descriptor, rest) = data.split_at(len);
+        data = rest;
+        Some(descriptor)
+    })
+}
+
+/// A class-compliant MIDI device attached to the host.
+pub struct UsbMidiHost<P: HostPipes, const N: usize> {
+    pipes: P,
+    interface: MidiStreamingInterface,
+    cable_policy: CablePolicy,
+    dropped_packets: u32,
+}
+
+impl<P: HostPipes, const N: usize> UsbMidiHost<P, N> {
+    /// Talks to `interface` through `pipes`.
+    ///
+    /// The host stack must have selected the configuration and alternate
+    /// setting of the interface already.
+    pub fn new(pipes: P, interface: MidiStreamingInterface) -> Self {
+        Self {
+            pipes,
+            interface,
+            cable_policy: CablePolicy::default(),
+            dropped_packets: 0,
+        }
+    }
+
+    pub fn interface(&self) -> &MidiStreamingInterface {
+        &self.interface
+    }
+
+    /// Reads a transfer of events from the device.
+    ///
+    /// Fails with [`Error::Unsupported`] if the device has no MIDI input.
+    pub async fn read_events<'a>(&'a mut self, data: &'a mut [u8]) -> Result<Events<'a, N>, Error> {
+        let endpoint = self.interface.input.ok_or(Error::Unsupported)?;
+        let count = self.pipes.read(endpoint.address, data).await?;
+        Ok(Events::new(
+            &data[..count],
+            N as u8,
+            self.cable_policy,
+            &mut self.dropped_packets,
+        ))
+    }
+
+    pub fn set_cable_policy(&mut self, policy: CablePolicy) {
+        self.cable_policy = policy;
+    }
+
+    /// Number of packets dropped because of an invalid cable number.
+    pub fn dropped_packets(&self) -> u32 {
+        self.dropped_packets
+    }
+
+    /// Writes an event to the device.
+    ///
+    /// Fails with [`Error::Unsupported`] if the device has no MIDI output or
+    /// does not declare `cable`.
+    pub async fn write_event(&mut self, cable: CableNumber<N>, event: Event) -> Result<(), Error> {
+        let endpoint = self.interface.output.ok_or(Error::Unsupported)?;
+        if cable.number() >= endpoint.cables {
+            return Err(Error::Unsupported);
+        }
+        self.pipes
+            .write(endpoint.address, &event.to_packet(cable.number()))
+            .await
+    }
+
+    pub async fn write_message(&mut self, cable: CableNumber<N>, message: MidiMessage) -> Result<(), Error> {
+        self.write_event(cable, message.into()).await
+    }
+}
+
+/// Forwards everything `device` sends to `computer`, reading transfers into
+/// `data`, until reading or writing fails.
+///
+/// Events for cables the computer does not declare are counted in `dropped`
+/// and skipped.
+///
+/// Together with [`forward_to_device`], this connects an attached device to
+/// the computer. As a [`UsbMidiHost`] is not split into halves, both
+/// directions need their own [`HostPipes`] handle to the device:
+///
+/// ```
+/// use embassy_futures::select::select;
+/// use embassy_usb::driver::Driver;
+/// use usb_midi_rs::host::{forward_to_computer, forward_to_device, HostPipes, UsbMidiHost};
+/// use usb_midi_rs::{Counter, UsbMidiClass};
+///
+/// async fn host_box<'d, D: Driver<'d>, P: HostPipes>(
+///     computer: UsbMidiClass<'d, D, 1>,
+///     mut keyboard_in: UsbMidiHost<P, 1>,
+///     mut keyboard_out: UsbMidiHost<P, 1>,
+/// ) {
+///     let (mut to_computer, mut from_computer) = computer.split();
+///     let (mut in_buf, mut out_buf) = ([0; 512], [0; 64]);
+///     let dropped = Counter::new();
+///     // Until either direction fails, e.g. because the keyboard was unplugged.
+///     select(
+///         forward_to_computer(&mut keyboard_in, &mut to_computer, &mut in_buf, &dropped),
+///         forward_to_device(&mut from_computer, &mut keyboard_out, &mut out_buf, &dropped),
+///     )
+///     .await;
+/// }
+/// ```
+#[cfg(feature = "usb")]
+pub async fn forward_to_computer<'d, P: HostPipes, D: Driver<'d>, const N: usize>(
+    device: &mut UsbMidiHost<P, N>,
+    computer: &mut Sender<'d, D, N>,
+    data: &mut [u8],
+    dropped: &Counter,
+) -> Error {
+    loop {
+        let events = match device.read_events(data).await {
+            Ok(events) => events,
+            Err(error) => return error,
+        };
+        for (cable, event) in events.flatten() {
+            match computer.write_event(cable, event).await {
+                Err(Error::Unsupported) => dropped.increment(),
+                Err(error) => return error,
+                Ok(()) => {}
+            }
+        }
+    }
+}
+
+/// Forwards everything `computer` sends to `device`, reading transfers into
+/// `data`, until reading or writing fails.
+///
+/// Events for cables the device does not declare are counted in `dropped`
+/// and skipped.
+#[cfg(feature = "usb")]
+pub async fn forward_to_device<'d, P: HostPipes, D: Driver<'d>, const N: usize>(
+    computer: &mut Receiver<'d, D, N>,
+    device: &mut UsbMidiHost<P, N>,
+    data: &mut [u8],
+    dropped: &Counter,
+) -> Error {
+    loop {
+        let events = match computer.read_events(data).await {
+            Ok(events) => events,
+            Err(error) => return error,
+        };
+        for (cable, event) in events.flatten() {
+            match device.write_event(cable, event).await {
+                Err(Error::Unsupported) => dropped.increment(),
+                Err(error) => return error,
+                Ok(()) => {}
+            }
+        }
+    }
+}
+
+/// Callbacks on devices being attached to and detached from the host.
+pub trait HotplugHandler {
+    /// A MIDI device was attached and occupies `slot`.
+    fn attached(&mut self, _slot: usize, _interface: &MidiStreamingInterface) {}
+
+    /// The device in `slot` was detached. Routes to and from it should be
+    /// removed with [`Router::isolate`], as [`DeviceRoutes`] does.
+    fn detached(&mut self, _slot: usize) {}
+}
+
+impl HotplugHandler for () {}
+
+/// MIDI devices attached to the host, directly or behind hubs.
+///
+/// Every device occupies one of `D` slots for as long as it stays attached.
+/// Slot numbers are meant to be used as router indices, e.g. source and
+/// destination `FIRST_DEVICE + slot` after the local ports, which
+/// [`DeviceRoutes`] connects and isolates as devices come and go.
+pub struct Devices<const D: usize> {
+    addresses: [Option<u8>; D],
+}
+
+impl<const D: usize> Devices<D> {
+    pub const fn new() -> Self {
+        Self { addresses: [None; D] }
+    }
+
+    /// Assigns a slot to the device with the USB `address`.
+    ///
+    /// Returns `None` if all slots are taken. Attaching a device twice
+    /// returns its existing slot without notifying `handler` again.
+    pub fn attach(
+        &mut self,
+        address: u8,
+        interface: &MidiStreamingInterface,
+        handler: &mut impl HotplugHandler,
+    ) -> Option<usize> {
+        if let Some(slot) = self.slot(address) {
+            return Some(slot);
+        }
+        let slot = self.addresses.iter().position(Option::is_none)?;
+        self.addresses[slot] = Some(address);
+        handler.attached(slot, interface);
+        Some(slot)
+    }
+
+    /// Frees the slot of the device with the USB `address`.
+    pub fn detach(&mut self, address: u8, handler: &mut impl HotplugHandler) -> Option<usize> {
+        let slot = self.slot(address)?;
+        self.addresses[slot] = None;
+        handler.detached(slot);
+        Some(slot)
+    }
+
+    pub fn slot(&self, address: u8) -> Option<usize> {
+        self.addresses.iter().position(|&slot| slot == Some(address))
+    }
+
+    /// USB address of the device in `slot`.
+    pub fn address(&self, slot: usize) -> Option<u8> {
+        self.addresses.get(slot).copied().flatten()
+    }
+}
+
+impl<const D: usize> Default for Devices<D> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Connects attached devices in a [`Router`], as node `first_device + slot`.
+///
+/// A device sending MIDI is routed to the destinations, and the sources are
+/// routed to a device receiving MIDI. All its routes are removed when it is
+/// detached, so a device attached to the same slot later starts from the
+/// same routes. The router needs `first_device + D` sources for `D` slots;
+/// devices in slots beyond its sources or its 32 destinations stay
+/// unrouted.
+///
+/// ```
+/// use usb_midi_rs::host::{DeviceRoutes, Devices};
+/// use usb_midi_rs::Router;
+///
+/// // Nodes 0 and 1 are the USB cables to the computer, 2 and 3 the devices.
+/// const FIRST_DEVICE: usize = 2;
+/// let mut router = Router::<4>::new();
+/// let mut devices = Devices::<2>::new();
+/// let mut routes = DeviceRoutes::new(&mut router, FIRST_DEVICE)
+///     .with_destinations(&[0])
+///     .with_sources(&[1]);
+/// // On enumeration: devices.attach(address, &interface, &mut routes);
+/// # let _ = (&mut devices, &mut routes);
+/// ```
+pub struct DeviceRoutes<'a, const S: usize> {
+    router: &'a mut Router<S>,
+    first_device: usize,
+    destinations: &'a [usize],
+    sources: &'a [usize],
+}
+
+impl<'a, const S: usize> DeviceRoutes<'a, S> {
+    /// Routes devices nowhere until destinations or sources are given.
+    pub fn new(router: &'a mut Router<S>, first_device: usize) -> Self {
+        Self {
+            router,
+            first_device,
+            destinations: &[],
+            sources: &[],
+        }
+    }
+
+    /// Routes MIDI from devices to `destinations`.
+    pub fn with_destinations(self, destinations: &'a [usize]) -> Self {
+        Self { destinations, ..self }
+    }
+
+    /// Routes MIDI from `sources` to devices.
+    pub fn with_sources(self, sources: &'a [usize]) -> Self {
+        Self { sources, ..self }
+    }
+
+    pub fn router(&self) -> &Router<S> {
+        self.router
+    }
+
+    /// Router node of the device in `slot`, or `None` if the router has no
+    /// node for it.
+    pub fn node(&self, slot: usize) -> Option<usize> {
+        let node = self.first_device.checked_add(slot)?;
+        (node < S.min(MAX_DESTINATIONS)).then_some(node)
+    }
+}
+
+impl<const S: usize> HotplugHandler for DeviceRoutes<'_, S> {
+    fn attached(&mut self, slot: usize, interface: &MidiStreamingInterface) {
+        let node = match self.node(slot) {
+            Some(node) => node,
+            None => {
+                warn!("No router node for device slot {}", slot);
+                return;
+            }
+        };
+        if interface.input.is_some() {
+            for &destination in self.destinations {
+                self.router.connect(node, destination);
+            }
+        }
+        if interface.output.is_some() {
+            for &source in self.sources {
+                self.router.connect(source, node);
+            }
+        }
+    }
+
+    fn detached(&mut self, slot: usize) {
+        if let Some(node) = self.node(slot) {
+            self.router.isolate(node);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Configuration descriptor of the example adapter in appendix B of the
+    /// USB MIDI specification.
+    const CONFIGURATION: [u8; 101] = [
+        0x09, 0x02, 0x65, 0x00, 0x02, 0x01, 0x00, 0x80, 0x32, // configuration
+        0x09, 0x04, 0x00, 0x00, 0x00, 0x01, 0x01, 0x00, 0x00, // audio control interface
+        0x09, 0x24, 0x01, 0x00, 0x01, 0x09, 0x00, 0x01, 0x01, // audio control header
+        0x09, 0x04, 0x01, 0x00, 0x02, 0x01, 0x03, 0x00, 0x00, // MIDI streaming interface
+        0x07, 0x24, 0x01, 0x00, 0x01, 0x41, 0x00, // MIDI streaming header
+        0x06, 0x24, 0x02, 0x01, 0x01, 0x00, // embedded in jack
+        0x06, 0x24, 0x02, 0x02, 0x02, 0x05, // external in jack
+        0x09, 0x24, 0x03, 0x01, 0x03, 0x01, 0x02, 0x01, 0x00, // embedded out jack
+        0x09, 0x24, 0x03, 0x02, 0x04, 0x01, 0x01, 0x01, 0x00, // external out jack
+        0x09, 0x05, 0x01, 0x02, 0x40, 0x00, 0x00, 0x00, 0x00, // bulk out endpoint
+        0x05, 0x25, 0x01, 0x01, 0x01, // MIDI streaming endpoint
+        0x09, 0x05, 0x81, 0x02, 0x40, 0x00, 0x00, 0x00, 0x00, // bulk in endpoint
+        0x05, 0x25, 0x01, 0x01, 0x03, // MIDI streaming endpoint
+    ];
+
+    #[test]
+    fn parses_configuration() {
+        let interface = parse_configuration(&CONFIGURATION).unwrap();
+        assert_eq!((interface.number, interface.alternate_setting), (1, 0));
+        let endpoint = |address| MidiEndpoint {
+            address,
+            max_packet_size: 64,
+            cables: 1,
+        };
+        assert_eq!(interface.output, Some(endpoint(0x01)));
+        assert_eq!(interface.input, Some(endpoint(0x81)));
+        assert_eq!(interface.jacks.len(), 4);
+        assert_eq!(
+            interface.jacks[1],
+            Jack {
+                direction: Direction::In,
+                jack_type: JackType::External,
+                id: 2,
+                name: 5
+            }
+        );
+    }
+
+    #[test]
+    fn stops_at_malformed_descriptors() {
+        let mut configuration = CONFIGURATION;
+        configuration[87] = 0;
+        let interface = parse_configuration(&configuration).unwrap();
+        assert!(interface.output.is_some());
+        assert_eq!(interface.input, None);
+        assert_eq!(parse_configuration(&CONFIGURATION[..20]), None);
+    }
+
+    #[derive(Default)]
+    struct Log(std::vec::Vec<(bool, usize)>);
+
+    impl HotplugHandler for Log {
+        fn attached(&mut self, slot: usize, _interface: &MidiStreamingInterface) {
+            self.0.push((true, slot));
+        }
+
+        fn detached(&mut self, slot: usize) {
+            self.0.push((false, slot));
+        }
+    }
+
+    #[test]
+    fn assigns_slots() {
+        let interface = parse_configuration(&CONFIGURATION).unwrap();
+        let mut devices = Devices::<2>::new();
+        let mut log = Log::default();
+        assert_eq!(devices.attach(5, &interface, &mut log), Some(0));
+        assert_eq!(devices.attach(7, &interface, &mut log), Some(1));
+        assert_eq!(devices.attach(5, &interface, &mut log), Some(0));
+        assert_eq!(devices.attach(9, &interface, &mut log), None);
+        assert_eq!(devices.detach(5, &mut log), Some(0));
+        assert_eq!(devices.attach(9, &interface, &mut log), Some(0));
+        assert_eq!(devices.address(0), Some(9));
+        assert_eq!(log.0, [(true, 0), (true, 1), (false, 0), (true, 0)]);
+    }
+
+    #[test]
+    fn routes_devices() {
+        let mut interface = parse_configuration(&CONFIGURATION).unwrap();
+        let mut router = Router::<4>::new();
+        let mut devices = Devices::<2>::new();
+        let mut routes = DeviceRoutes::new(&mut router, 2)
+            .with_destinations(&[0])
+            .with_sources(&[1]);
+        devices.attach(5, &interface, &mut routes);
+        assert!(routes.router().is_connected(2, 0));
+        assert!(routes.router().is_connected(1, 2));
+
+        // A device without MIDI output gets nothing routed to it.
+        interface.output = None;
+        devices.attach(7, &interface, &mut routes);
+        assert!(routes.router().is_connected(3, 0));
+        assert_eq!(routes.router().destinations(1).collect::<std::vec::Vec<_>>(), [2]);
+
+        devices.detach(5, &mut routes);
+        assert_eq!(routes.router().destinations(2).count(), 0);
+        assert_eq!(routes.router().destinations(1).count(), 0);
+        assert!(routes.router().is_connected(3, 0));
+
+        // Slots beyond the router stay unrouted.
+        let mut router = Router::<40>::new();
+        let mut routes = DeviceRoutes::new(&mut router, 30).with_destinations(&[0]);
+        assert_eq!(routes.node(1), Some(31));
+        assert_eq!(routes.node(2), None);
+        routes.attached(2, &interface);
+        routes.detached(2);
+        assert_eq!(routes.router().destinations(32).count(), 0);
+    }
+
+    /// Transfers written to a device, with the endpoint address.
+    #[cfg(feature = "usb")]
+    type Written = std::rc::Rc<core::cell::RefCell<std::vec::Vec<(u8, std::vec::Vec<u8>)>>>;
+
+    /// Pipes to a device sending `reads` and disconnecting afterwards.
+    #[cfg(feature = "usb")]
+    #[derive(Default)]
+    struct DevicePipes {
+        reads: std::collections::VecDeque<std::vec::Vec<u8>>,
+        written: Written,
+    }
+
+    #[cfg(feature = "usb")]
+    impl HostPipes for DevicePipes {
+        type ReadFuture<'a> = core::future::Ready<Result<usize, Error>>;
+        type WriteFuture<'a> = core::future::Ready<Result<(), Error>>;
+
+        fn read<'a>(&'a mut self, _endpoint: u8, data: &'a mut [u8]) -> Self::ReadFuture<'a> {
+            core::future::ready(match self.reads.pop_front() {
+                Some(transfer) => {
+                    data[..transfer.len()].copy_from_slice(&transfer);
+                    Ok(transfer.len())
+                }
+                None => Err(Error::Disconnected),
+            })
+        }
+
+        fn write<'a>(&'a mut self, endpoint: u8, data: &'a [u8]) -> Self::WriteFuture<'a> {
+            self.written.borrow_mut().push((endpoint, data.to_vec()));
+            core::future::ready(Ok(()))
+        }
+    }
+
+    #[cfg(feature = "usb")]
+    #[test]
+    fn forwards_between_device_and_computer() {
+        use core::pin::pin;
+
+        use embassy_futures::block_on;
+
+        use crate::fault::{poll_once, virtual_bus, Faults};
+
+        let (device, mut computer) = virtual_bus::<2>(&Faults::default());
+        let (mut sender, mut receiver) = device.split();
+        let interface = parse_configuration(&CONFIGURATION).unwrap();
+        let cable = CableNumber::new(0).unwrap();
+        let note = Event::NoteOn(0x90, crate::Note::new(60), 100);
+        let mut buf = [0; 64];
+        let dropped = Counter::new();
+
+        let pipes = DevicePipes {
+            reads: [note.to_packet(0).to_vec()].into(),
+            ..Default::default()
+        };
+        let mut keyboard = UsbMidiHost::<_, 2>::new(pipes, interface.clone());
+        let error = block_on(forward_to_computer(&mut keyboard, &mut sender, &mut buf, &dropped));
+        assert_eq!(error, Error::Disconnected);
+        let events: std::vec::Vec<_> = block_on(computer.read_events(&mut buf)).unwrap().flatten().collect();
+        assert_eq!(events, [(cable, note)]);
+
+        let pipes = DevicePipes::default();
+        let written = pipes.written.clone();
+        let mut keyboard = UsbMidiHost::<_, 2>::new(pipes, interface);
+        // The keyboard declares a single cable, so the first note is skipped.
+        block_on(computer.write_event(CableNumber::new(1).unwrap(), note)).unwrap();
+        block_on(computer.write_event(cable, note)).unwrap();
+        let mut forward = pin!(forward_to_device(&mut receiver, &mut keyboard, &mut buf, &dropped));
+        assert!(poll_once(forward.as_mut()).is_pending());
+        assert_eq!(*written.borrow(), [(0x01, note.to_packet(0).to_vec())]);
+        assert_eq!(dropped.get(), 1);
+    }
+}
@@ -0,0 +1,163 @@
+use embassy_time::{Duration, Instant};
+
+use crate::schedule::{run_timed, Schedule, Timed};
+use crate::soak::xorshift32;
+use crate::transport::{MidiSink, MidiSource};
+use crate::{Error, Event};
+
+/// Loosens the timing and dynamics of notes, e.g. of a step sequencer.
+///
+/// Every Note On is delayed by up to `max_delay` and its velocity changed by
+/// up to `max_velocity` either way, never reaching 0. The offsets are
+/// pseudo-random and repeat for the same seed. The Note Off of a delayed
+/// note follows it, and everything else passes unchanged.
+///
+/// Up to `P` notes can wait at a time; more pass undelayed.
+pub struct Humanizer<const P: usize> {
+    /// State of the xorshift generator, never 0.
+    state: u32,
+    max_delay: Duration,
+    max_velocity: u8,
+    pending: Schedule<Event, P>,
+}
+
+impl<const P: usize> Humanizer<P> {
+    pub fn new(seed: u32, max_delay: Duration, max_velocity: u8) -> Self {
+        Self {
+            state: if seed == 0 { 0x2545_f491 } else { seed },
+            max_delay,
+            max_velocity,
+            pending: Schedule::new(),
+        }
+    }
+
+    /// Takes `event` received at `at` into account and returns it if it is
+    /// to be sent at once. Otherwise it is returned by [`poll`](Self::poll)
+    /// later.
+    pub fn process(&mut self, event: Event, at: Instant) -> Option<Event> {
+        match event {
+            Event::NoteOn(status, note, velocity) if velocity > 0 => {
+                let random = xorshift32(&mut self.state);
+                let spread = 2 * i16::from(self.max_velocity) + 1;
+                let offset = (random % spread as u32) as i16 - i16::from(self.max_velocity);
+                let velocity = (i16::from(velocity) + offset).clamp(1, 127) as u8;
+                let event = Event::NoteOn(status, note, velocity);
+                let delay = ((random >> 16) as u64 * (self.max_delay.as_ticks() + 1)) >> 16;
+                match delay {
+                    0 => Some(event),
+                    delay => self.pending.insert(at + Duration::from_ticks(delay), event),
+                }
+            }
+            Event::NoteOn(..) | Event::NoteOff(..) => self.pending.insert_note_off(event),
+            event => Some(event),
+        }
+    }
+
+    /// Returns the next event due at `now`, if any.
+    pub fn poll(&mut self, now: Instant) -> Option<Event> {
+        self.pending.poll(now)
+    }
+
+    /// When the next waiting event is due.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.pending.deadline()
+    }
+
+    /// Humanizes the events of `source` for `sink` until sending fails.
+    pub async fn run(&mut self, source: &mut impl MidiSource, sink: &mut impl MidiSink) -> Error {
+        run_timed(self, source, sink).await
+    }
+}
+
+impl<const P: usize> Timed for Humanizer<P> {
+    fn process(&mut self, event: Event, at: Instant) -> Option<Event> {
+        Humanizer::process(self, event, at)
+    }
+
+    fn poll(&mut self, now: Instant) -> Option<Event> {
+        Humanizer::poll(self, now)
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        Humanizer::deadline(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Channel, MidiMessage, Note};
+
+    fn humanize(seed: u32) -> std::vec::Vec<(u64, Event)> {
+        let mut humanizer = Humanizer::<4>::new(seed, Duration::from_micros(10_000), 10);
+        let mut output = std::vec::Vec::new();
+        for step in 0..16 {
+            let at = Instant::from_micros(step * 100_000);
+            let note = Note::new(60 + step as u8);
+            let note_on = MidiMessage::NoteOn(Channel::new(0), note, 100).into();
+            let note_off = MidiMessage::NoteOff(Channel::new(0), note, 0).into();
+            for event in [humanizer.process(note_on, at), humanizer.process(note_off, at)] {
+                output.extend(event.map(|event| (at.as_micros(), event)));
+            }
+            while let Some(deadline) = humanizer.deadline() {
+                output.push((deadline.as_micros(), humanizer.poll(deadline).unwrap()));
+            }
+        }
+        output
+    }
+
+    #[test]
+    fn jitters_notes() {
+        let output = humanize(7);
+        assert_eq!(output, humanize(7));
+        assert_ne!(output, humanize(8));
+        assert_eq!(output.len(), 32);
+        for (step, pair) in output.chunks(2).enumerate() {
+            let (on_time, off_time) = (pair[0].0, pair[1].0);
+            assert!((0..=10_000).contains(&(on_time - step as u64 * 100_000)));
+            assert_eq!(on_time, off_time);
+            match pair[0].1 {
+                Event::NoteOn(_, note, velocity) => {
+                    assert_eq!(note.number(), 60 + step as u8);
+                    assert!((90..=110).contains(&velocity));
+                }
+                event => panic!("unexpected {:?}", event),
+            }
+        }
+        let velocities: std::collections::BTreeSet<_> = output.iter().map(|(_, event)| event.to_packet(0)[3]).collect();
+        assert!(velocities.len() > 4);
+    }
+
+    #[test]
+    fn ends_notes_when_full() {
+        let mut humanizer = Humanizer::<1>::new(7, Duration::from_micros(10_000), 0);
+        let at = Instant::from_micros(0);
+        let mut output = std::vec::Vec::new();
+        for (note, on) in [(60, true), (62, true), (60, false), (62, false)] {
+            let channel = Channel::new(0);
+            let event = match on {
+                true => MidiMessage::NoteOn(channel, Note::new(note), 100),
+                false => MidiMessage::NoteOff(channel, Note::new(note), 0),
+            };
+            output.extend(humanizer.process(event.into(), at));
+        }
+        while let Some(deadline) = humanizer.deadline() {
+            output.push(humanizer.poll(deadline).unwrap());
+        }
+        // Every Note Off follows its Note On.
+        let notes: std::vec::Vec<_> = output
+            .iter()
+            .map(|event| match *event {
+                Event::NoteOn(_, note, _) => (note.number(), true),
+                Event::NoteOff(_, note, _) => (note.number(), false),
+                event => panic!("unexpected {:?}", event),
+            })
+            .collect();
+        assert_eq!(notes.len(), 4);
+        for note in [60, 62] {
+            let on = notes.iter().position(|&event| event == (note, true)).unwrap();
+            let off = notes.iter().position(|&event| event == (note, false)).unwrap();
+            assert!(on < off, "{:?}", notes);
+        }
+    }
+}
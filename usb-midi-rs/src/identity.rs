@@ -0,0 +1,93 @@
+use embassy_usb::Config;
+
+/// IDs and strings the device identifies itself with to the host.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DeviceIdentity<'a> {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub manufacturer: Option<&'a str>,
+    pub product: Option<&'a str>,
+    pub serial_number: Option<&'a str>,
+}
+
+impl<'a> DeviceIdentity<'a> {
+    /// The identity of the examples.
+    ///
+    /// The IDs are not assigned to anyone, so they may collide with other
+    /// prototypes and must not be used for devices leaving the bench.
+    pub const DEVELOPMENT: DeviceIdentity<'static> = DeviceIdentity {
+        vendor_id: 0xc0de,
+        product_id: 0xcafe,
+        manufacturer: Some("MIDIbox"),
+        product: Some("USB-MIDI device"),
+        serial_number: Some("87654321"),
+    };
+
+    /// A device with the IDs `vendor_id` and `product_id` and no strings.
+    pub const fn new(vendor_id: u16, product_id: u16) -> Self {
+        Self {
+            vendor_id,
+            product_id,
+            manufacturer: None,
+            product: None,
+            serial_number: None,
+        }
+    }
+
+    pub const fn with_manufacturer(self, manufacturer: &'a str) -> Self {
+        Self {
+            manufacturer: Some(manufacturer),
+            ..self
+        }
+    }
+
+    pub const fn with_product(self, product: &'a str) -> Self {
+        Self {
+            product: Some(product),
+            ..self
+        }
+    }
+
+    /// Sets the serial number, e.g. the string of a
+    /// [`SerialNumber`](crate::SerialNumber).
+    pub const fn with_serial_number(self, serial_number: &'a str) -> Self {
+        Self {
+            serial_number: Some(serial_number),
+            ..self
+        }
+    }
+
+    /// A device configuration with this identity and the defaults of
+    /// embassy otherwise.
+    pub fn config(&self) -> Config<'a> {
+        let mut config = Config::new(self.vendor_id, self.product_id);
+        config.manufacturer = self.manufacturer;
+        config.product = self.product;
+        config.serial_number = self.serial_number;
+        config
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn configures_devices() {
+        let identity = DeviceIdentity::new(0x1209, 0x0001)
+            .with_manufacturer("Acme")
+            .with_product("MIDI Thing");
+        let config = identity.config();
+        assert_eq!((config.vendor_id, config.product_id), (0x1209, 0x0001));
+        assert_eq!(
+            (config.manufacturer, config.product),
+            (Some("Acme"), Some("MIDI Thing"))
+        );
+        assert_eq!(config.serial_number, None);
+
+        let config = DeviceIdentity::DEVELOPMENT.with_product("Metronome").config();
+        assert_eq!((config.vendor_id, config.product_id), (0xc0de, 0xcafe));
+        assert_eq!(config.product, Some("Metronome"));
+    }
+}
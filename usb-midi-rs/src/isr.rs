@@ -0,0 +1,204 @@
+use core::cell::UnsafeCell;
+use core::future::poll_fn;
+use core::mem::MaybeUninit;
+use core::task::Poll;
+
+use embassy_sync::blocking_mutex::raw::RawMutex;
+use embassy_sync::waitqueue::AtomicWaker;
+use portable_atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::{CableNumber, Counter, Error, Event, TxQueue};
+
+/// Queue carrying events from interrupt handlers to the TX path.
+///
+/// Tasks, including those of an `InterruptExecutor` running at a higher
+/// priority, write to the [`TxQueue`] directly, which just needs a
+/// `CriticalSectionRawMutex` when it is shared between executors. Interrupt
+/// handlers outside of any executor, e.g. a timer capturing drum triggers,
+/// cannot wait and use an [`IsrSender`] instead: sending never blocks and
+/// never waits for the task draining the queue with [`run`](Self::run).
+///
+/// ```
+/// use usb_midi_rs::{IsrQueue, IsrSender};
+///
+/// static TRIGGERS: IsrQueue<1, 8> = IsrQueue::new();
+/// static SENDER: IsrSender<'static, 1, 8> = TRIGGERS.sender();
+/// ```
+pub struct IsrQueue<const N: usize, const Q: usize> {
+    slots: UnsafeCell<[MaybeUninit<(CableNumber<N>, Event)>; Q]>,
+    /// Number of events ever sent, only changed by the sender.
+    head: AtomicUsize,
+    /// Number of events ever received, only changed by the receiver.
+    tail: AtomicUsize,
+    /// A context is sending, so any other has to back off.
+    sending: AtomicBool,
+    receiving: AtomicBool,
+    waker: AtomicWaker,
+    overflows: Counter,
+}
+
+// The slots between tail and head belong to the receiver, the others to the
+// sender, and there is at most one of each at a time.
+unsafe impl<const N: usize, const Q: usize> Sync for IsrQueue<N, Q> {}
+
+impl<const N: usize, const Q: usize> IsrQueue<N, Q> {
+    pub const fn new() -> Self {
+        Self {
+            slots: UnsafeCell::new([MaybeUninit::uninit(); Q]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            sending: AtomicBool::new(false),
+            receiving: AtomicBool::new(false),
+            waker: AtomicWaker::new(),
+            overflows: Counter::new(),
+        }
+    }
+
+    /// A handle for interrupt handlers, e.g. to be kept in a `static`.
+    pub const fn sender(&self) -> IsrSender<'_, N, Q> {
+        IsrSender { queue: self }
+    }
+
+    /// Number of events dropped because the queue was full.
+    pub fn overflows(&self) -> u32 {
+        self.overflows.get()
+    }
+
+    fn slot(&self, index: usize) -> *mut MaybeUninit<(CableNumber<N>, Event)> {
+        // Pointers to single slots are taken without referencing the array,
+        // which the other side may be accessing.
+        unsafe { (self.slots.get() as *mut MaybeUninit<(CableNumber<N>, Event)>).add(index % Q) }
+    }
+
+    fn try_send(&self, cable: CableNumber<N>, event: Event) -> Result<(), Error> {
+        if self.sending.swap(true, Ordering::Acquire) {
+            self.overflows.increment();
+            return Err(Error::BufferOverflow);
+        }
+        let head = self.head.load(Ordering::Relaxed);
+        let result = if head.wrapping_sub(self.tail.load(Ordering::Acquire)) < Q {
+            unsafe { self.slot(head).write(MaybeUninit::new((cable, event))) };
+            self.head.store(head.wrapping_add(1), Ordering::Release);
+            Ok(())
+        } else {
+            self.overflows.increment();
+            Err(Error::BufferOverflow)
+        };
+        self.sending.store(false, Ordering::Release);
+        if result.is_ok() {
+            self.waker.wake();
+        }
+        result
+    }
+
+    /// Takes the oldest event, if any.
+    ///
+    /// Returns `None` as well while another task is receiving.
+    pub fn try_receive(&self) -> Option<(CableNumber<N>, Event)> {
+        if self.receiving.swap(true, Ordering::Acquire) {
+            return None;
+        }
+        let tail = self.tail.load(Ordering::Relaxed);
+        let event = if tail != self.head.load(Ordering::Acquire) {
+            let event = unsafe { self.slot(tail).read().assume_init() };
+            self.tail.store(tail.wrapping_add(1), Ordering::Release);
+            Some(event)
+        } else {
+            None
+        };
+        self.receiving.store(false, Ordering::Release);
+        event
+    }
+
+    /// Waits for the oldest event.
+    ///
+    /// Only one task may wait at a time.
+    pub async fn receive(&self) -> (CableNumber<N>, Event) {
+        poll_fn(|cx| {
+            self.waker.register(cx.waker());
+            match self.try_receive() {
+                Some(event) => Poll::Ready(event),
+                None => Poll::Pending,
+            }
+        })
+        .await
+    }
+
+    /// Moves the events sent from interrupt handlers to `tx`, forever.
+    pub async fn run<M: RawMutex, const T: usize>(&self, tx: &TxQueue<M, N, T>) -> ! {
+        loop {
+            let (cable, event) = self.receive().await;
+            tx.write_event(cable, event).await;
+        }
+    }
+}
+
+impl<const N: usize, const Q: usize> Default for IsrQueue<N, Q> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Sends events from interrupt context, see [`IsrQueue`].
+#[derive(Copy, Clone)]
+pub struct IsrSender<'q, const N: usize, const Q: usize> {
+    queue: &'q IsrQueue<N, Q>,
+}
+
+impl<const N: usize, const Q: usize> IsrSender<'_, N, Q> {
+    /// Queues `event` without waiting.
+    ///
+    /// Fails with [`Error::BufferOverflow`] and drops the event if the queue
+    /// is full, or if another interrupt handler preempted one sending on the
+    /// same queue.
+    pub fn try_send(&self, cable: CableNumber<N>, event: Event) -> Result<(), Error> {
+        self.queue.try_send(cable, event)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_futures::block_on;
+
+    use super::*;
+
+    #[test]
+    fn passes_events_in_order() {
+        let queue = IsrQueue::<2, 2>::new();
+        let sender = queue.sender();
+        let cable = CableNumber::new(1).unwrap();
+        for round in 0..3 {
+            sender.try_send(cable, Event::SingleByte(0xf8)).unwrap();
+            sender.try_send(cable, Event::ProgramChange(0xc0, round)).unwrap();
+            assert_eq!(
+                sender.try_send(cable, Event::SingleByte(0xfa)),
+                Err(Error::BufferOverflow)
+            );
+            assert_eq!(queue.try_receive(), Some((cable, Event::SingleByte(0xf8))));
+            assert_eq!(block_on(queue.receive()), (cable, Event::ProgramChange(0xc0, round)));
+            assert_eq!(queue.try_receive(), None);
+        }
+        assert_eq!(queue.overflows(), 3);
+    }
+
+    #[test]
+    fn sends_from_threads() {
+        static QUEUE: IsrQueue<1, 4> = IsrQueue::new();
+        let cable = CableNumber::new(0).unwrap();
+        let producer = std::thread::spawn(move || {
+            for value in 0..1000u16 {
+                while QUEUE
+                    .sender()
+                    .try_send(cable, Event::ProgramChange(0xc0, value as u8))
+                    .is_err()
+                {}
+            }
+        });
+        for value in 0..1000u16 {
+            let (_, event) = block_on(QUEUE.receive());
+            assert_eq!(event, Event::ProgramChange(0xc0, value as u8));
+        }
+        producer.join().unwrap();
+        assert_eq!(QUEUE.try_receive(), None);
+    }
+}
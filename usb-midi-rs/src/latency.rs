@@ -0,0 +1,150 @@
+use core::future::{poll_fn, Future};
+use core::pin::pin;
+use core::task::{Context, RawWaker, RawWakerVTable, Waker};
+
+use embassy_sync::waitqueue::AtomicWaker;
+use portable_atomic::{AtomicBool, AtomicU32, Ordering};
+
+use crate::LatencyStats;
+
+/// Current value of the cycle counter.
+///
+/// On Cortex-M this is the DWT cycle counter, which the application has to
+/// enable with `DCB::enable_trace` and `DWT::enable_cycle_counter`. Other
+/// targets count ticks of the time driver instead.
+pub fn cycles() -> u32 {
+    #[cfg(target_arch = "arm")]
+    return cortex_m::peripheral::DWT::cycle_count();
+    #[cfg(not(target_arch = "arm"))]
+    return embassy_time::Instant::now().as_ticks() as u32;
+}
+
+/// Measures the latency of the read path of a
+/// [`Dispatcher`](crate::Dispatcher), from the interrupt completing a
+/// transfer to its events being queued, in [`cycles`].
+///
+/// The probe stands in for the waker of the task while a read is pending,
+/// noting the time the driver wakes it from its interrupt handler. Transfers
+/// that arrive while the dispatcher is busy are read without waiting and are
+/// not measured.
+///
+/// ```ignore
+/// static PROBE: LatencyProbe = LatencyProbe::new();
+///
+/// let mut dispatcher = Dispatcher::new(receiver, &queues).with_latency_probe(&PROBE);
+/// // later
+/// info!("read path: {} to {} cycles", PROBE.stats().min(), PROBE.stats().max());
+/// ```
+pub struct LatencyProbe {
+    stats: LatencyStats,
+    waker: AtomicWaker,
+    /// A measured future is pending.
+    armed: AtomicBool,
+    /// The future was woken at `woken_at`.
+    woken: AtomicBool,
+    woken_at: AtomicU32,
+}
+
+impl LatencyProbe {
+    pub const fn new() -> Self {
+        Self {
+            stats: LatencyStats::new(),
+            waker: AtomicWaker::new(),
+            armed: AtomicBool::new(false),
+            woken: AtomicBool::new(false),
+            woken_at: AtomicU32::new(0),
+        }
+    }
+
+    pub fn stats(&self) -> &LatencyStats {
+        &self.stats
+    }
+
+    fn wake(&self) {
+        if self.armed.swap(false, Ordering::Relaxed) {
+            self.woken_at.store(cycles(), Ordering::Relaxed);
+            self.woken.store(true, Ordering::Release);
+        }
+        self.waker.wake();
+    }
+
+    /// Runs `future`, noting when it is woken.
+    pub(crate) async fn measure<F: Future>(&'static self, future: F) -> F::Output {
+        let mut future = pin!(future);
+        self.woken.store(false, Ordering::Relaxed);
+        poll_fn(|cx| {
+            self.waker.register(cx.waker());
+            // Safety: the vtable only turns the data pointer back into the
+            // reference it was made from, which lives forever.
+            let waker = unsafe { Waker::from_raw(RawWaker::new(self as *const Self as *const (), &VTABLE)) };
+            if !self.woken.load(Ordering::Acquire) {
+                self.armed.store(true, Ordering::Relaxed);
+            }
+            let poll = future.as_mut().poll(&mut Context::from_waker(&waker));
+            if poll.is_ready() {
+                self.armed.store(false, Ordering::Relaxed);
+            }
+            poll
+        })
+        .await
+    }
+
+    /// Records the latency of the last measured future, if it was woken.
+    pub(crate) fn delivered(&self) {
+        if self.woken.swap(false, Ordering::Acquire) {
+            let latency = cycles().wrapping_sub(self.woken_at.load(Ordering::Relaxed));
+            self.stats.record(latency);
+        }
+    }
+}
+
+impl Default for LatencyProbe {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+static VTABLE: RawWakerVTable = RawWakerVTable::new(
+    |data| RawWaker::new(data, &VTABLE),
+    |data| unsafe { &*(data as *const LatencyProbe) }.wake(),
+    |data| unsafe { &*(data as *const LatencyProbe) }.wake(),
+    |_| {},
+);
+
+#[cfg(test)]
+mod tests {
+    use core::task::Poll;
+
+    use super::*;
+    use crate::fault::poll_once;
+
+    /// Pending until woken once.
+    async fn woken_once() {
+        let mut waiting = true;
+        poll_fn(|cx| match core::mem::replace(&mut waiting, false) {
+            true => {
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+            false => Poll::Ready(()),
+        })
+        .await
+    }
+
+    #[test]
+    fn measures_woken_reads() {
+        static PROBE: LatencyProbe = LatencyProbe::new();
+
+        assert_eq!(poll_once(pin!(PROBE.measure(async {}))), Poll::Ready(()));
+        PROBE.delivered();
+        assert_eq!(PROBE.stats().count(), 0);
+
+        let mut read = pin!(PROBE.measure(woken_once()));
+        assert!(poll_once(read.as_mut()).is_pending());
+        assert_eq!(poll_once(read.as_mut()), Poll::Ready(()));
+        PROBE.delivered();
+        assert_eq!(PROBE.stats().count(), 1);
+        PROBE.delivered();
+        assert_eq!(PROBE.stats().count(), 1);
+    }
+}
@@ -0,0 +1,207 @@
+use core::ops::RangeInclusive;
+
+use crate::transport::{MidiSink, MidiSource};
+use crate::{Channel, Error, Event};
+
+/// A sound of a [`VelocityLayers`] setup, played by the notes of a range of
+/// velocities.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct Layer {
+    /// Destination of the layer, e.g. a cable or port index of a
+    /// [`Router`](crate::Router) setup.
+    pub destination: usize,
+    /// Channel to play the layer on, or `None` for the channel played.
+    pub channel: Option<Channel>,
+    pub velocities: RangeInclusive<u8>,
+    /// Number of velocities over which the layer fades in above the lowest
+    /// and out below the highest velocity of its range, 0 for hard switching.
+    pub fade: u8,
+}
+
+impl Layer {
+    pub const fn new(destination: usize, velocities: RangeInclusive<u8>) -> Self {
+        Self {
+            destination,
+            channel: None,
+            velocities,
+            fade: 0,
+        }
+    }
+
+    pub const fn with_channel(mut self, channel: Channel) -> Self {
+        self.channel = Some(channel);
+        self
+    }
+
+    pub const fn with_fade(mut self, fade: u8) -> Self {
+        self.fade = fade;
+        self
+    }
+
+    /// Velocity the layer plays `velocity` with, if it plays it at all.
+    pub fn velocity(&self, velocity: u8) -> Option<u8> {
+        if !self.velocities.contains(&velocity) {
+            return None;
+        }
+        if self.fade == 0 {
+            return Some(velocity);
+        }
+        let edge = (velocity - self.velocities.start()).min(self.velocities.end() - velocity) + 1;
+        let scaled = u16::from(velocity) * u16::from(edge.min(self.fade)) / u16::from(self.fade);
+        Some(scaled.clamp(1, 127) as u8)
+    }
+
+    fn route(&self, event: Event) -> Event {
+        match self.channel {
+            Some(channel) => event.with_channel(channel),
+            None => event,
+        }
+    }
+}
+
+/// Switches and layers sounds by velocity, as found on master keyboards.
+///
+/// A Note On is played by every layer whose velocity range contains its
+/// velocity. Layers with overlapping ranges fade into each other if they
+/// have a fade set. All other events, including Note Offs, go to all layers,
+/// so notes are released even if the layers changed meanwhile. An event
+/// reaching the same destination and channel through several layers is only
+/// sent once.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct VelocityLayers<const L: usize> {
+    layers: [Layer; L],
+}
+
+impl<const L: usize> VelocityLayers<L> {
+    pub const fn new(layers: [Layer; L]) -> Self {
+        Self { layers }
+    }
+
+    pub fn layers(&self) -> &[Layer; L] {
+        &self.layers
+    }
+
+    pub fn set_layers(&mut self, layers: [Layer; L]) {
+        self.layers = layers;
+    }
+
+    /// Events that `event` turns into, with their destinations.
+    pub fn process(&self, event: Event) -> LayerEvents<'_> {
+        LayerEvents {
+            event,
+            layers: &self.layers,
+            index: 0,
+        }
+    }
+
+    /// Layers everything received from `source`, sending it to the sink of
+    /// its destination in `sinks` until sending fails. Events for
+    /// destinations without a sink are dropped.
+    pub async fn run<S: MidiSink>(&self, source: &mut impl MidiSource, sinks: &mut [S]) -> Error {
+        loop {
+            let event = source.receive().await;
+            for (destination, event) in self.process(event) {
+                if let Some(sink) = sinks.get_mut(destination) {
+                    if let Err(error) = sink.send(event).await {
+                        return error;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Events of [`VelocityLayers::process`].
+pub struct LayerEvents<'a> {
+    event: Event,
+    layers: &'a [Layer],
+    index: usize,
+}
+
+impl Iterator for LayerEvents<'_> {
+    type Item = (usize, Event);
+
+    fn next(&mut self) -> Option<(usize, Event)> {
+        while let Some(layer) = self.layers.get(self.index) {
+            self.index += 1;
+            let event = match self.event {
+                Event::NoteOn(status, note, velocity) if velocity > 0 => match layer.velocity(velocity) {
+                    Some(velocity) => layer.route(Event::NoteOn(status, note, velocity)),
+                    None => continue,
+                },
+                event => {
+                    let event = layer.route(event);
+                    let earlier = &self.layers[..self.index - 1];
+                    if earlier
+                        .iter()
+                        .any(|other| other.destination == layer.destination && other.route(self.event) == event)
+                    {
+                        continue;
+                    }
+                    event
+                }
+            };
+            return Some((layer.destination, event));
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Note;
+
+    fn layers() -> VelocityLayers<3> {
+        VelocityLayers::new([
+            Layer::new(0, 1..=80),
+            Layer::new(0, 60..=127).with_channel(Channel::new(1)).with_fade(20),
+            Layer::new(2, 1..=127),
+        ])
+    }
+
+    fn process(layers: &VelocityLayers<3>, event: Event) -> Vec<(usize, Event)> {
+        layers.process(event).collect()
+    }
+
+    #[test]
+    fn switches_by_velocity() {
+        let layers = layers();
+        let note = Note::new(60);
+        assert_eq!(
+            process(&layers, Event::NoteOn(0x90, note, 40)),
+            [(0, Event::NoteOn(0x90, note, 40)), (2, Event::NoteOn(0x90, note, 40))]
+        );
+        assert_eq!(
+            process(&layers, Event::NoteOn(0x90, note, 70)),
+            [
+                (0, Event::NoteOn(0x90, note, 70)),
+                (0, Event::NoteOn(0x91, note, 38)),
+                (2, Event::NoteOn(0x90, note, 70)),
+            ]
+        );
+        assert_eq!(
+            process(&layers, Event::NoteOn(0x90, note, 100))[0],
+            (0, Event::NoteOn(0x91, note, 100))
+        );
+        assert_eq!(process(&layers, Event::NoteOff(0x80, note, 0)).len(), 3);
+    }
+
+    #[test]
+    fn sends_other_events_once() {
+        let layers = VelocityLayers::new([Layer::new(0, 1..=63), Layer::new(0, 64..=127)]);
+        let event = Event::ControlChange(0xb0, 1, 2);
+        assert_eq!(layers.process(event).collect::<Vec<_>>(), [(0, event)]);
+        assert_eq!(layers.process(Event::SingleByte(0xf8)).count(), 1);
+    }
+
+    #[test]
+    fn fades_layers() {
+        let layer = Layer::new(0, 60..=100).with_fade(4);
+        assert_eq!(layer.velocity(59), None);
+        assert_eq!(layer.velocity(60), Some(15));
+        assert_eq!(layer.velocity(63), Some(63));
+        assert_eq!(layer.velocity(80), Some(80));
+        assert_eq!(layer.velocity(100), Some(25));
+    }
+}
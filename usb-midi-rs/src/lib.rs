@@ -1,14 +1,185 @@
-pub fn add(left: usize, right: usize) -> usize {
-    left + right
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn it_works() {
-        let result = add(2, 2);
-        assert_eq!(result, 4);
-    }
-}
+#![cfg_attr(not(test), no_std)]
+
+// Declared first so the macros are available in the other modules.
+mod fmt;
+
+#[cfg(feature = "sysex")]
+mod access;
+pub mod ble;
+pub mod broadcast;
+#[cfg(feature = "usb")]
+mod buffers;
+mod cable;
+pub mod cc;
+#[cfg(feature = "usb")]
+mod class;
+mod clock;
+#[cfg(feature = "sysex")]
+mod config;
+#[cfg(feature = "usb")]
+mod configurations;
+#[cfg(feature = "usb")]
+mod connection;
+#[cfg(any(feature = "presets", feature = "sysex"))]
+mod crc;
+mod dedupe;
+mod defaults;
+mod delay;
+pub mod descriptor;
+mod din;
+mod dispatcher;
+mod error;
+mod event;
+#[cfg(all(test, feature = "usb"))]
+mod fault;
+#[cfg(feature = "gm")]
+pub mod gm;
+mod harmonizer;
+mod heartbeat;
+pub mod host;
+mod humanizer;
+#[cfg(feature = "usb")]
+mod identity;
+mod isr;
+#[cfg(feature = "latency")]
+mod latency;
+mod layers;
+#[cfg(feature = "librarian")]
+mod librarian;
+mod looper;
+mod matrix;
+mod message;
+mod monitor;
+mod notes;
+mod nrpn;
+#[cfg(feature = "osc")]
+pub mod osc;
+pub mod pipeline;
+#[cfg(feature = "usb")]
+mod port_names;
+mod power;
+#[cfg(feature = "presets")]
+pub mod preset;
+mod pressure;
+mod program;
+mod quantizer;
+#[cfg(feature = "usb")]
+mod reconnect;
+#[cfg(feature = "sysex")]
+mod report;
+#[cfg(feature = "usb")]
+mod retry;
+mod router;
+pub mod rtp;
+mod schedule;
+pub mod serial;
+#[cfg(feature = "usb")]
+mod shared;
+mod soak;
+mod stats;
+mod surface;
+#[cfg(feature = "sysex")]
+pub mod sysex;
+pub mod transport;
+#[cfg(feature = "sysex")]
+pub mod tuning;
+#[cfg(feature = "tunnel")]
+pub mod tunnel;
+mod tx;
+#[cfg(feature = "sysex")]
+pub mod universal;
+#[cfg(feature = "sysex")]
+pub mod vendor;
+#[cfg(feature = "usb")]
+mod wakeup;
+
+#[cfg(feature = "sysex")]
+pub use crate::access::{
+    AccessGuard, AccessReply, Lock, BACK_OFF_TIME, CHALLENGE_REPLY, CHALLENGE_REQUEST, MAX_FAILURES, UNLOCK_REPLY,
+    UNLOCK_REQUEST, UNLOCK_TIME,
+};
+pub use crate::broadcast::{Broadcast, BroadcastReceiver};
+#[cfg(feature = "usb")]
+pub use crate::buffers::{build_usb_midi_device, UsbMidiBuffers, EP_OUT_BUFFER_LEN};
+pub use crate::cable::{CableNumber, CablePolicy, Events, InvalidCable};
+#[cfg(feature = "usb")]
+pub use crate::class::{
+    check_config_descriptor, required_config_descriptor_len, Control, PacketSize, Receiver, Sender, State,
+    UsbMidiClass, IAD_LEN,
+};
+pub use crate::clock::{ClockEvent, ClockFollower, ClockGenerator, Swing, SyncOut, SyncOutput, TapTempo, PPQN};
+#[cfg(feature = "sysex")]
+pub use crate::config::{
+    ConfigReply, ConfigTransaction, CONFIG_ACK, CONFIG_BEGIN, CONFIG_COMMIT, CONFIG_DATA, CONFIG_NAK,
+};
+#[cfg(feature = "usb")]
+pub use crate::configurations::{DualPowerControl, DualPowerDriver, PowerConfigurations};
+#[cfg(feature = "usb")]
+pub use crate::connection::{ConnectionMonitor, ConnectionState};
+pub use crate::dedupe::Dedupe;
+#[cfg(feature = "sysex")]
+pub use crate::defaults::FACTORY_RESET;
+pub use crate::defaults::{Defaults, FactoryReset};
+pub use crate::delay::Delay;
+pub use crate::din::{DinParser, DinSerializer, InputPolarity, PolarityDetector};
+#[cfg(feature = "usb")]
+pub use crate::dispatcher::Dispatcher;
+pub use crate::dispatcher::{ConnectionHandler, Queues, RX_QUEUE_SIZE};
+pub use crate::error::{BufferTooSmall, Error};
+pub use crate::event::{Event, InvalidNoteName, Note, NoteName, Octaves};
+pub use crate::harmonizer::{Chord, Harmonizer, Interval};
+pub use crate::heartbeat::{feed_watchdog, Heartbeat, Watchdog};
+pub use crate::humanizer::Humanizer;
+#[cfg(feature = "usb")]
+pub use crate::identity::DeviceIdentity;
+pub use crate::isr::{IsrQueue, IsrSender};
+#[cfg(feature = "latency")]
+pub use crate::latency::{cycles, LatencyProbe};
+pub use crate::layers::{Layer, LayerEvents, VelocityLayers};
+#[cfg(feature = "librarian")]
+pub use crate::librarian::{DumpSink, Librarian};
+pub use crate::looper::{LoopState, Looper};
+pub use crate::matrix::{Debouncer, Encoder, KeyEvent, KeyEvents, KeyMatrix};
+pub use crate::message::{Channel, ChannelMask, MidiMessage};
+pub use crate::monitor::{EventText, MidiMonitor, MonitorEntry};
+#[cfg(feature = "sysex")]
+pub use crate::monitor::{MONITOR_REPLY, MONITOR_REQUEST};
+pub use crate::notes::{NoteTracker, Releases};
+pub use crate::nrpn::{CcToNrpn, NrpnToCc};
+pub use crate::pipeline::{pipeline, Node, Pipeline};
+#[cfg(feature = "usb")]
+pub use crate::port_names::{PortNames, PORT_NAME_LEN};
+pub use crate::power::{PowerConfig, PowerHandler, MAX_BUS_POWER};
+#[cfg(feature = "presets")]
+pub use crate::preset::{Loaded, Preset, PresetError, PresetStore};
+pub use crate::pressure::{ChannelToPolyPressure, PolyPressures, PolyToChannelPressure};
+pub use crate::program::{Patch, ProgramMapper};
+pub use crate::quantizer::Quantizer;
+#[cfg(feature = "usb")]
+pub use crate::reconnect::SoftReconnect;
+#[cfg(feature = "sysex")]
+pub use crate::report::{CableReport, HealthReport, REPORT_REPLY, REPORT_REQUEST};
+#[cfg(feature = "usb")]
+pub use crate::retry::{RetryPolicy, RetryingSender};
+pub use crate::router::{RouteTrace, Router, TraceLog};
+pub use crate::serial::{SerialNumber, UniqueId};
+#[cfg(feature = "usb")]
+pub use crate::shared::SharedSender;
+#[cfg(all(feature = "usb", feature = "sysex"))]
+pub use crate::shared::SysExTransaction;
+pub use crate::soak::TestPattern;
+pub use crate::stats::{Counter, HighWaterMark, LatencyStats};
+pub use crate::surface::{AnalogInputs, ControlSurface, EncoderInputs};
+pub use crate::transport::{MidiSink, MidiSource};
+#[cfg(feature = "tunnel")]
+pub use crate::tunnel::ControlTunnel;
+pub use crate::tx::{TxQueue, TX_QUEUE_SIZE};
+#[cfg(feature = "usb")]
+pub use crate::wakeup::RemoteWakeup;
+
+const USB_CLASS_AUDIO: u8 = 0x01;
+const AUDIO_SUBCLASS_MIDISTREAMING: u8 = 0x03;
+
+/// Largest `wMaxPacketSize` of the bulk endpoints, so buffers of this size
+/// fit any packet.
+pub const MAX_PACKET_SIZE: u16 = 64;
@@ -0,0 +1,210 @@
+use embedded_io::blocking::Write;
+use heapless::Vec;
+
+use crate::sysex::{manufacturer_id, sysex_bytes, SYSEX_END, SYSEX_START};
+use crate::{CableNumber, Event};
+
+/// Where a [`Librarian`] stores the dumps it captures, e.g. files on an SD
+/// card.
+///
+/// Every dump is written completely, from `0xF0` to `0xF7`, as in a `.syx`
+/// file.
+pub trait DumpSink: Write {
+    /// A dump from the manufacturer with the ID `manufacturer` begins, so
+    /// e.g. a file named after the manufacturer should be opened.
+    fn begin(&mut self, manufacturer: &[u8]) -> Result<(), Self::Error>;
+
+    /// The dump ended. If it is not `complete`, it was cut off by another
+    /// message and misses its end.
+    fn end(&mut self, complete: bool) -> Result<(), Self::Error>;
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum State {
+    Idle,
+    /// The manufacturer ID is not complete yet.
+    Header,
+    Dump,
+}
+
+/// Streams the System Exclusive messages received on one cable to a
+/// [`DumpSink`], turning the device into a patch librarian.
+///
+/// Dumps are passed on as they arrive, so their size is only limited by the
+/// sink.
+pub struct Librarian<S: DumpSink, const N: usize> {
+    sink: S,
+    cable: CableNumber<N>,
+    /// `0xF0` and what followed in the first two events.
+    header: Vec<u8, 6>,
+    state: State,
+    dumps: u32,
+}
+
+impl<S: DumpSink, const N: usize> Librarian<S, N> {
+    /// Captures the dumps received on `cable` to `sink`.
+    pub fn new(sink: S, cable: CableNumber<N>) -> Self {
+        Self {
+            sink,
+            cable,
+            header: Vec::new(),
+            state: State::Idle,
+            dumps: 0,
+        }
+    }
+
+    /// Number of complete dumps captured.
+    pub fn dumps(&self) -> u32 {
+        self.dumps
+    }
+
+    pub fn sink(&mut self) -> &mut S {
+        &mut self.sink
+    }
+
+    pub fn into_sink(self) -> S {
+        self.sink
+    }
+
+    /// Feeds an event received on `cable`.
+    ///
+    /// Events on other cables and real-time messages are ignored. Any other
+    /// message ends the dump in progress.
+    pub fn push(&mut self, cable: CableNumber<N>, event: Event) -> Result<(), S::Error> {
+        if cable != self.cable || event.is_real_time() {
+            return Ok(());
+        }
+        let bytes = match sysex_bytes(event) {
+            Some(bytes) => bytes,
+            None => return self.abort(),
+        };
+        if bytes[0] == SYSEX_START {
+            self.abort()?;
+            self.header.clear();
+            self.state = State::Header;
+        }
+        match self.state {
+            State::Idle => Ok(()),
+            State::Header => {
+                // Two events always carry an ID, so the header never overflows.
+                let _ = self.header.extend_from_slice(&bytes);
+                let complete = bytes[bytes.len() - 1] == SYSEX_END;
+                let data = &self.header[1..self.header.len() - usize::from(complete)];
+                match manufacturer_id(data) {
+                    Some(manufacturer) => self.sink.begin(manufacturer)?,
+                    // Too short to carry an ID, not worth keeping.
+                    None if complete => self.state = State::Idle,
+                    None => return Ok(()),
+                }
+                if self.state == State::Header {
+                    self.state = State::Dump;
+                    self.sink.write_all(&self.header)?;
+                    self.finish(complete)?;
+                }
+                Ok(())
+            }
+            State::Dump => {
+                self.sink.write_all(&bytes)?;
+                self.finish(bytes[bytes.len() - 1] == SYSEX_END)
+            }
+        }
+    }
+
+    fn finish(&mut self, complete: bool) -> Result<(), S::Error> {
+        if complete {
+            self.state = State::Idle;
+            self.dumps = self.dumps.wrapping_add(1);
+            self.sink.end(true)?;
+        }
+        Ok(())
+    }
+
+    /// Ends the dump in progress without its end.
+    fn abort(&mut self) -> Result<(), S::Error> {
+        let state = core::mem::replace(&mut self.state, State::Idle);
+        match state {
+            State::Dump => self.sink.end(false),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::convert::Infallible;
+
+    use super::*;
+    use crate::sysex::SysExFragmenter;
+
+    #[derive(Default)]
+    struct Files {
+        files: std::vec::Vec<(std::vec::Vec<u8>, std::vec::Vec<u8>, bool)>,
+    }
+
+    impl embedded_io::Io for Files {
+        type Error = Infallible;
+    }
+
+    impl Write for Files {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, Infallible> {
+            self.files.last_mut().unwrap().1.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> Result<(), Infallible> {
+            Ok(())
+        }
+    }
+
+    impl DumpSink for Files {
+        fn begin(&mut self, manufacturer: &[u8]) -> Result<(), Infallible> {
+            self.files.push((manufacturer.to_vec(), std::vec::Vec::new(), false));
+            Ok(())
+        }
+
+        fn end(&mut self, complete: bool) -> Result<(), Infallible> {
+            self.files.last_mut().unwrap().2 = complete;
+            Ok(())
+        }
+    }
+
+    fn events(message: &[u8]) -> impl Iterator<Item = Event> + '_ {
+        let mut fragmenter = SysExFragmenter::new();
+        message.iter().filter_map(move |&byte| fragmenter.push(byte))
+    }
+
+    #[test]
+    fn captures_dumps() {
+        let cable = CableNumber::<2>::new(1).unwrap();
+        let mut librarian = Librarian::new(Files::default(), cable);
+        let roland = [0xf0, 0x41, 0x10, 0x42, 0x12, 0x40, 0x00, 0x7f, 0x00, 0x41, 0xf7];
+        let extended = [0xf0, 0x00, 0x20, 0x33, 0x01, 0xf7];
+        for event in events(&roland).chain(events(&extended)) {
+            librarian.push(cable, event).unwrap();
+            librarian.push(CableNumber::new(0).unwrap(), event).unwrap();
+        }
+        librarian.push(cable, Event::SysExEnd2(0xf0, 0xf7)).unwrap();
+
+        assert_eq!(librarian.dumps(), 2);
+        let files = librarian.into_sink().files;
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0], (vec![0x41], roland.to_vec(), true));
+        assert_eq!(files[1], (vec![0x00, 0x20, 0x33], extended.to_vec(), true));
+    }
+
+    #[test]
+    fn ends_interrupted_dumps() {
+        let cable = CableNumber::<1>::new(0).unwrap();
+        let mut librarian = Librarian::new(Files::default(), cable);
+        let mut events = events(&[0xf0, 0x43, 0x00, 0x09, 0x20, 0x00, 0x01, 0xf7]);
+        librarian.push(cable, events.next().unwrap()).unwrap();
+        librarian.push(cable, Event::SingleByte(0xf8)).unwrap();
+        librarian.push(cable, events.next().unwrap()).unwrap();
+        librarian.push(cable, Event::ProgramChange(0xc0, 1)).unwrap();
+        librarian.push(cable, events.next().unwrap()).unwrap();
+
+        assert_eq!(librarian.dumps(), 0);
+        let files = librarian.into_sink().files;
+        assert_eq!(files, [(vec![0x43], vec![0xf0, 0x43, 0x00, 0x09, 0x20, 0x00], false)]);
+    }
+}
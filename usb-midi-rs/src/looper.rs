@@ -0,0 +1,374 @@
+use embassy_time::{Duration, Instant};
+use heapless::{Deque, Vec};
+
+use crate::schedule::Schedule;
+use crate::{CableNumber, ClockFollower, Event, MidiMessage, PPQN};
+
+/// What a [`Looper`] is doing.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum LoopState {
+    Stopped,
+    Playing,
+    /// Recording the first pass, which is played from then on.
+    Recording,
+    /// Recording on top of what is played.
+    Overdubbing,
+}
+
+/// An event of a loop.
+#[derive(Copy, Clone, Debug)]
+struct Recorded<const N: usize> {
+    /// Clock pulse since the start of the loop.
+    pulse: u32,
+    /// Time since the pulse in 1/256 of the pulse interval.
+    fraction: u8,
+    cable: CableNumber<N>,
+    event: Event,
+}
+
+/// Records played events into a loop of a fixed number of clock pulses and
+/// plays them back in time with the MIDI clock.
+///
+/// The loop begins with the first pulse after a Start and is only recorded
+/// and played while the transport runs. Events are placed within a pulse,
+/// so playback follows tempo changes. Up to `E` events are kept, more are
+/// not recorded, and up to `P` events of a pulse can wait to be played.
+/// When more wait, the earliest are played at once rather than dropped, so
+/// no Note Off is lost.
+///
+/// Stopping or clearing the loop does not release the notes it played, which
+/// a [`NoteTracker`](crate::NoteTracker) on the output can take care of.
+pub struct Looper<const N: usize, const E: usize, const P: usize> {
+    clock: ClockFollower,
+    length: u32,
+    state: LoopState,
+    running: bool,
+    /// The next clock pulse since the start of the loop, and when the last
+    /// one arrived.
+    position: u32,
+    last_pulse: Option<Instant>,
+    /// Sorted by time within the loop.
+    events: Vec<Recorded<N>, E>,
+    pending: Schedule<(CableNumber<N>, Event), P>,
+    /// Events made room for in `pending`, played at once.
+    early: Deque<(CableNumber<N>, Event), E>,
+}
+
+impl<const N: usize, const E: usize, const P: usize> Looper<N, E, P> {
+    /// A looper of `length` clock pulses, e.g. `4 * 4 * PPQN` for four bars
+    /// of 4/4.
+    pub fn new(length: u32) -> Self {
+        assert!(length > 0, "loop must not be empty");
+        Self {
+            clock: ClockFollower::new(),
+            length,
+            state: LoopState::Stopped,
+            running: false,
+            position: 0,
+            last_pulse: None,
+            events: Vec::new(),
+            pending: Schedule::new(),
+            early: Deque::new(),
+        }
+    }
+
+    pub fn state(&self) -> LoopState {
+        self.state
+    }
+
+    /// Number of events in the loop.
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    /// Replaces the loop with what is played from now until the end of the
+    /// loop.
+    pub fn record(&mut self) {
+        self.events.clear();
+        self.state = LoopState::Recording;
+    }
+
+    /// Adds what is played from now on to the loop.
+    pub fn overdub(&mut self) {
+        self.state = LoopState::Overdubbing;
+    }
+
+    pub fn play(&mut self) {
+        self.state = LoopState::Playing;
+    }
+
+    pub fn stop(&mut self) {
+        self.state = LoopState::Stopped;
+        self.pending.clear();
+        self.early.clear();
+    }
+
+    /// Empties the loop, keeping the state.
+    pub fn clear(&mut self) {
+        self.events.clear();
+        self.pending.clear();
+        self.early.clear();
+    }
+
+    /// Takes the clock `message` received at `at` into account, scheduling
+    /// the events of the loop.
+    pub fn clock(&mut self, message: MidiMessage, at: Instant) {
+        self.clock.update(message, at);
+        match message {
+            MidiMessage::Start => {
+                self.running = true;
+                self.position = 0;
+                self.last_pulse = None;
+            }
+            MidiMessage::Continue => self.running = true,
+            MidiMessage::Stop => {
+                self.running = false;
+                self.pending.clear();
+                self.early.clear();
+            }
+            MidiMessage::TimingClock if self.running => self.pulse(at),
+            _ => {}
+        }
+    }
+
+    fn pulse(&mut self, at: Instant) {
+        if self.position == 0 && self.state == LoopState::Recording && self.last_pulse.is_some() {
+            self.state = LoopState::Playing;
+        }
+        let pulse = self.position;
+        self.last_pulse = Some(at);
+        self.position = (pulse + 1) % self.length;
+        if !matches!(self.state, LoopState::Playing | LoopState::Overdubbing) {
+            return;
+        }
+        let interval = self.pulse_interval();
+        for recorded in self.events.iter().filter(|recorded| recorded.pulse == pulse) {
+            let time = at + interval * u32::from(recorded.fraction) / 256;
+            if self.pending.is_full() {
+                if let Some(early) = self.pending.pop() {
+                    // Room for the events of a whole pulse, as long as they are polled.
+                    let _ = self.early.push_back(early);
+                }
+            }
+            let _ = self.pending.insert(time, (recorded.cable, recorded.event));
+        }
+    }
+
+    /// Records `event` played on `cable` at `at`, if the loop is recording.
+    pub fn record_event(&mut self, cable: CableNumber<N>, event: Event, at: Instant) {
+        if !self.running || !matches!(self.state, LoopState::Recording | LoopState::Overdubbing) {
+            return;
+        }
+        let last_pulse = match self.last_pulse {
+            Some(last_pulse) => last_pulse,
+            None => return,
+        };
+        let since = at
+            .checked_duration_since(last_pulse)
+            .map_or(0, |since| since.as_ticks());
+        let interval = self.pulse_interval().as_ticks().max(1);
+        let recorded = Recorded {
+            pulse: (self.position + self.length - 1) % self.length,
+            fraction: (since * 256 / interval).min(255) as u8,
+            cable,
+            event,
+        };
+        let key = |recorded: &Recorded<N>| (recorded.pulse, recorded.fraction);
+        let index = self.events.iter().position(|other| key(other) > key(&recorded));
+        let _ = self.events.insert(index.unwrap_or(self.events.len()), recorded);
+    }
+
+    /// Returns the next event of the loop due at `now`, if any.
+    pub fn poll(&mut self, now: Instant) -> Option<(CableNumber<N>, Event)> {
+        self.early.pop_front().or_else(|| self.pending.poll(now))
+    }
+
+    /// When the next event of the loop is due.
+    pub fn deadline(&self) -> Option<Instant> {
+        match self.early.is_empty() {
+            true => self.pending.deadline(),
+            false => self.last_pulse,
+        }
+    }
+
+    fn pulse_interval(&self) -> Duration {
+        self.clock
+            .beat_duration()
+            .map_or(Duration::from_ticks(0), |beat| beat / PPQN)
+    }
+}
+
+/// Length of a stored event: pulse, fraction and packet.
+#[cfg(feature = "presets")]
+const STORED_LEN: usize = 4 + 1 + 4;
+
+/// Stores the loop as a pattern, e.g. to recall it later. A loaded looper is
+/// stopped and plays the pattern once it is told to.
+#[cfg(feature = "presets")]
+impl<const N: usize, const E: usize, const P: usize> crate::preset::Preset for Looper<N, E, P> {
+    const KIND: u8 = 2;
+    const VERSION: u8 = 1;
+
+    fn write(&self, data: &mut [u8]) -> Result<usize, crate::Error> {
+        let len = 4 + STORED_LEN * self.events.len();
+        let data = data.get_mut(..len).ok_or(crate::Error::BufferOverflow)?;
+        data[..4].copy_from_slice(&self.length.to_le_bytes());
+        for (bytes, recorded) in data[4..].chunks_exact_mut(STORED_LEN).zip(&self.events) {
+            bytes[..4].copy_from_slice(&recorded.pulse.to_le_bytes());
+            bytes[4] = recorded.fraction;
+            bytes[5..].copy_from_slice(&recorded.event.to_packet(recorded.cable.number()));
+        }
+        Ok(len)
+    }
+
+    fn read(data: &[u8], _version: u8) -> Result<Self, crate::Error> {
+        if data.len() < 4 {
+            return Err(crate::Error::Malformed);
+        }
+        let stored = data[4..].chunks_exact(STORED_LEN);
+        let length = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+        if length == 0 || !stored.remainder().is_empty() {
+            return Err(crate::Error::Malformed);
+        }
+        let mut looper = Self::new(length);
+        let key = |recorded: &Recorded<N>| (recorded.pulse, recorded.fraction);
+        for bytes in stored {
+            let recorded = Recorded {
+                pulse: u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+                fraction: bytes[4],
+                cable: CableNumber::new(bytes[5] >> 4).ok_or(crate::Error::Malformed)?,
+                event: Event::new(&bytes[5..]),
+            };
+            if recorded.pulse >= length || looper.events.last().map(key) > Some(key(&recorded)) {
+                return Err(crate::Error::Malformed);
+            }
+            looper.events.push(recorded).map_err(|_| crate::Error::BufferOverflow)?;
+        }
+        Ok(looper)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Channel, Note};
+
+    /// Pulse interval at 125 BPM.
+    const PULSE: u64 = 20_000;
+
+    fn at(micros: u64) -> Instant {
+        Instant::from_micros(micros)
+    }
+
+    fn note_on(note: u8) -> Event {
+        MidiMessage::NoteOn(Channel::new(0), Note::new(note), 100).into()
+    }
+
+    /// Runs `pulses` clock pulses from `first`, recording `played` events
+    /// and collecting what the looper plays.
+    fn run(
+        looper: &mut Looper<2, 8, 4>,
+        first: u64,
+        pulses: u64,
+        played: &[(u64, Event)],
+    ) -> std::vec::Vec<(u64, Event)> {
+        let cable = CableNumber::new(1).unwrap();
+        let mut output = std::vec::Vec::new();
+        for pulse in first..first + pulses {
+            looper.clock(MidiMessage::TimingClock, at(pulse * PULSE));
+            for &(time, event) in played.iter().filter(|(time, _)| time / PULSE == pulse) {
+                looper.record_event(cable, event, at(time));
+            }
+            while let Some(deadline) = looper.deadline().filter(|&deadline| deadline < at((pulse + 1) * PULSE)) {
+                let (played_cable, event) = looper.poll(deadline).unwrap();
+                assert_eq!(played_cable, cable);
+                output.push((deadline.as_micros(), event));
+            }
+        }
+        output
+    }
+
+    #[test]
+    fn loops_recordings() {
+        let mut looper = Looper::<2, 8, 4>::new(4);
+        looper.clock(MidiMessage::Start, at(0));
+        looper.record();
+        assert_eq!(run(&mut looper, 0, 4, &[(PULSE + 5_000, note_on(60))]), []);
+        assert_eq!(looper.state(), LoopState::Recording);
+        assert_eq!(run(&mut looper, 4, 4, &[]), [(5 * PULSE + 5_000, note_on(60))]);
+        assert_eq!(looper.state(), LoopState::Playing);
+
+        looper.overdub();
+        let played = run(&mut looper, 8, 4, &[(11 * PULSE, note_on(62))]);
+        assert_eq!(played, [(9 * PULSE + 5_000, note_on(60))]);
+        let played = run(&mut looper, 12, 4, &[]);
+        assert_eq!(played, [(13 * PULSE + 5_000, note_on(60)), (15 * PULSE, note_on(62))]);
+        assert_eq!(looper.len(), 2);
+
+        looper.stop();
+        assert_eq!(run(&mut looper, 16, 4, &[]), []);
+        looper.clear();
+        assert!(looper.is_empty());
+    }
+
+    #[test]
+    fn plays_overflowing_events_early() {
+        let cable = CableNumber::new(0).unwrap();
+        let mut looper = Looper::<2, 8, 2>::new(2);
+        looper.clock(MidiMessage::Start, at(0));
+        looper.record();
+        looper.clock(MidiMessage::TimingClock, at(0));
+        for (offset, note) in [(1_000, 60), (2_000, 62), (3_000, 64)] {
+            looper.record_event(cable, note_on(note), at(offset));
+        }
+        looper.clock(MidiMessage::TimingClock, at(PULSE));
+
+        // The first event makes room for the last and is played at once.
+        looper.clock(MidiMessage::TimingClock, at(2 * PULSE));
+        assert_eq!(looper.deadline(), Some(at(2 * PULSE)));
+        assert_eq!(looper.poll(at(2 * PULSE)), Some((cable, note_on(60))));
+        assert_eq!(looper.poll(at(2 * PULSE)), None);
+        assert_eq!(looper.poll(at(3 * PULSE)), Some((cable, note_on(62))));
+        assert_eq!(looper.poll(at(3 * PULSE)), Some((cable, note_on(64))));
+        assert_eq!(looper.poll(at(3 * PULSE)), None);
+    }
+
+    #[cfg(feature = "presets")]
+    #[test]
+    fn stores_patterns() {
+        use crate::preset::Preset;
+
+        let mut looper = Looper::<2, 8, 4>::new(4);
+        looper.clock(MidiMessage::Start, at(0));
+        looper.record();
+        run(
+            &mut looper,
+            0,
+            4,
+            &[(PULSE + 5_000, note_on(60)), (3 * PULSE, note_on(64))],
+        );
+        let mut data = [0; 64];
+        let len = looper.write(&mut data).unwrap();
+        assert_eq!(len, 4 + 2 * STORED_LEN);
+
+        let mut loaded = Looper::<2, 8, 4>::read(&data[..len], 1).unwrap();
+        assert_eq!((loaded.state(), loaded.len()), (LoopState::Stopped, 2));
+        loaded.clock(MidiMessage::Start, at(0));
+        loaded.play();
+        run(&mut loaded, 0, 4, &[]);
+        assert_eq!(
+            run(&mut loaded, 4, 4, &[]),
+            [(5 * PULSE + 5_000, note_on(60)), (7 * PULSE, note_on(64))]
+        );
+
+        assert!(Looper::<2, 1, 4>::read(&data[..len], 1).is_err());
+        assert!(Looper::<1, 8, 4>::read(&data[..len], 1).is_err());
+        assert!(Looper::<2, 8, 4>::read(&data[..len - 1], 1).is_err());
+        assert!(looper.write(&mut data[..len - 1]).is_err());
+    }
+}
@@ -0,0 +1,158 @@
+/// A matrix of up to 16 columns of keys or switches per row.
+pub trait KeyMatrix<const R: usize> {
+    /// Returns one bit per column for every row, set while a key is pressed.
+    fn scan(&mut self) -> [u16; R];
+}
+
+/// A key that changed its state.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct KeyEvent {
+    pub row: usize,
+    pub column: usize,
+    pub pressed: bool,
+}
+
+/// Debounces the scans of a [`KeyMatrix`].
+///
+/// A row is only taken over once it read the same for a number of
+/// consecutive scans, so the debounce time is that number times the scan
+/// interval.
+pub struct Debouncer<const R: usize> {
+    stable: [u16; R],
+    candidate: [u16; R],
+    count: [u8; R],
+    scans: u8,
+}
+
+impl<const R: usize> Debouncer<R> {
+    pub const fn new(scans: u8) -> Self {
+        Self {
+            stable: [0; R],
+            candidate: [0; R],
+            count: [0; R],
+            scans,
+        }
+    }
+
+    /// Debounced state of the matrix.
+    pub fn state(&self) -> &[u16; R] {
+        &self.stable
+    }
+
+    /// Feeds the next scan and returns the keys that changed.
+    pub fn update(&mut self, scan: [u16; R]) -> KeyEvents<R> {
+        let mut changed = [0; R];
+        for row in 0..R {
+            if scan[row] != self.candidate[row] {
+                self.candidate[row] = scan[row];
+                self.count[row] = 0;
+            }
+            if self.count[row] < self.scans {
+                self.count[row] += 1;
+                if self.count[row] == self.scans {
+                    changed[row] = self.stable[row] ^ scan[row];
+                    self.stable[row] = scan[row];
+                }
+            }
+        }
+        KeyEvents {
+            changed,
+            state: self.stable,
+            row: 0,
+        }
+    }
+}
+
+/// Keys that changed in a scan, in row-major order.
+pub struct KeyEvents<const R: usize> {
+    changed: [u16; R],
+    state: [u16; R],
+    row: usize,
+}
+
+impl<const R: usize> Iterator for KeyEvents<R> {
+    type Item = KeyEvent;
+
+    fn next(&mut self) -> Option<KeyEvent> {
+        while self.row < R {
+            let changed = self.changed[self.row];
+            if changed != 0 {
+                let column = changed.trailing_zeros() as usize;
+                self.changed[self.row] &= !(1 << column);
+                return Some(KeyEvent {
+                    row: self.row,
+                    column,
+                    pressed: self.state[self.row] & 1 << column != 0,
+                });
+            }
+            self.row += 1;
+        }
+        None
+    }
+}
+
+/// Decodes the two switches of a rotary encoder.
+pub struct Encoder {
+    state: u8,
+    steps: i8,
+    steps_per_detent: i8,
+}
+
+impl Encoder {
+    pub const fn new(steps_per_detent: i8) -> Self {
+        Self {
+            state: 0,
+            steps: 0,
+            steps_per_detent,
+        }
+    }
+
+    /// Feeds the debounced switch states and returns the number of detents
+    /// turned since the last call, positive when `a` leads.
+    pub fn update(&mut self, a: bool, b: bool) -> i8 {
+        const STEPS: [i8; 16] = [0, -1, 1, 0, 1, 0, 0, -1, -1, 0, 0, 1, 0, 1, -1, 0];
+        let state = (a as u8) << 1 | b as u8;
+        self.steps += STEPS[(self.state << 2 | state) as usize];
+        self.state = state;
+        let detents = self.steps / self.steps_per_detent;
+        self.steps %= self.steps_per_detent;
+        detents
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn debounces_keys() {
+        let mut debouncer = Debouncer::<2>::new(3);
+        assert_eq!(debouncer.update([0b100, 0]).count(), 0);
+        assert_eq!(debouncer.update([0, 0]).count(), 0);
+        assert_eq!(debouncer.update([0b100, 0]).count(), 0);
+        assert_eq!(debouncer.update([0b100, 0]).count(), 0);
+        let events: Vec<_> = debouncer.update([0b100, 0]).collect();
+        assert_eq!(
+            events,
+            [KeyEvent {
+                row: 0,
+                column: 2,
+                pressed: true
+            }]
+        );
+        assert_eq!(debouncer.update([0b100, 0]).count(), 0);
+        assert_eq!(debouncer.state(), &[0b100, 0]);
+    }
+
+    #[test]
+    fn decodes_encoders() {
+        let mut encoder = Encoder::new(4);
+        let clockwise = [(true, false), (true, true), (false, true), (false, false)];
+        let steps: Vec<_> = clockwise.iter().map(|&(a, b)| encoder.update(a, b)).collect();
+        assert_eq!(steps, [0, 0, 0, 1]);
+        let counterclockwise = [(false, true), (true, true), (true, false), (false, false)];
+        let steps: Vec<_> = counterclockwise.iter().map(|&(a, b)| encoder.update(a, b)).collect();
+        assert_eq!(steps, [0, 0, 0, -1]);
+    }
+}
@@ -0,0 +1,350 @@
+use core::ops::{BitAnd, BitAndAssign, BitOr, BitOrAssign, Not};
+
+use crate::error::Error;
+use crate::event::{Event, Note};
+
+/// MIDI channel, counted from 0.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Channel(u8);
+
+impl Channel {
+    pub const fn new(number: u8) -> Self {
+        assert!(number < 16, "channel number out of range");
+        Self(number)
+    }
+
+    pub const fn number(self) -> u8 {
+        self.0
+    }
+}
+
+/// A set of MIDI channels, one bit per channel.
+///
+/// ```
+/// use usb_midi_rs::{Channel, ChannelMask};
+///
+/// let lower_zone = ChannelMask::range(Channel::new(1), Channel::new(7));
+/// let omni = ChannelMask::NONE.with(Channel::new(0)) | lower_zone;
+/// assert_eq!(omni.len(), 8);
+/// assert!(!(omni & !lower_zone).contains(Channel::new(5)));
+/// ```
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ChannelMask(u16);
+
+impl ChannelMask {
+    pub const NONE: Self = Self(0);
+    pub const ALL: Self = Self(0xffff);
+
+    /// The channels whose bits are set in `bits`, channel 0 in bit 0.
+    pub const fn from_bits(bits: u16) -> Self {
+        Self(bits)
+    }
+
+    pub const fn bits(self) -> u16 {
+        self.0
+    }
+
+    /// The channels from `first` to `last`, both included.
+    pub const fn range(first: Channel, last: Channel) -> Self {
+        Self(0xffff << first.0 & 0xffff >> (15 - last.0))
+    }
+
+    /// The mask with `channel` added.
+    pub const fn with(self, channel: Channel) -> Self {
+        Self(self.0 | 1 << channel.0)
+    }
+
+    /// The mask with `channel` removed.
+    pub const fn without(self, channel: Channel) -> Self {
+        Self(self.0 & !(1 << channel.0))
+    }
+
+    pub fn insert(&mut self, channel: Channel) {
+        *self = self.with(channel);
+    }
+
+    pub fn remove(&mut self, channel: Channel) {
+        *self = self.without(channel);
+    }
+
+    pub const fn contains(self, channel: Channel) -> bool {
+        self.0 & 1 << channel.0 != 0
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Number of channels in the mask.
+    pub const fn len(self) -> usize {
+        self.0.count_ones() as usize
+    }
+
+    /// The lowest channel in the mask.
+    pub const fn first(self) -> Option<Channel> {
+        match self.0 {
+            0 => None,
+            bits => Some(Channel(bits.trailing_zeros() as u8)),
+        }
+    }
+
+    /// The channels in the mask, in ascending order.
+    pub fn iter(self) -> impl Iterator<Item = Channel> {
+        (0..16).filter(move |&number| self.0 & 1 << number != 0).map(Channel)
+    }
+}
+
+impl From<Channel> for ChannelMask {
+    fn from(channel: Channel) -> Self {
+        Self::NONE.with(channel)
+    }
+}
+
+impl FromIterator<Channel> for ChannelMask {
+    fn from_iter<I: IntoIterator<Item = Channel>>(channels: I) -> Self {
+        channels.into_iter().fold(Self::NONE, Self::with)
+    }
+}
+
+impl BitOr for ChannelMask {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+impl BitOrAssign for ChannelMask {
+    fn bitor_assign(&mut self, other: Self) {
+        self.0 |= other.0;
+    }
+}
+
+impl BitAnd for ChannelMask {
+    type Output = Self;
+
+    fn bitand(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+}
+
+impl BitAndAssign for ChannelMask {
+    fn bitand_assign(&mut self, other: Self) {
+        self.0 &= other.0;
+    }
+}
+
+impl Not for ChannelMask {
+    type Output = Self;
+
+    fn not(self) -> Self {
+        Self(!self.0)
+    }
+}
+
+/// A complete MIDI message other than System Exclusive.
+///
+/// ```
+/// use usb_midi_rs::{Channel, Event, MidiMessage};
+///
+/// let message = MidiMessage::from_bytes(&[0xe1, 0x00, 0x40]).unwrap();
+/// assert_eq!(message, MidiMessage::PitchBend(Channel::new(1), 0x2000));
+/// let event = Event::from(message);
+/// assert_eq!(MidiMessage::from_event(event), Some(message));
+/// ```
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum MidiMessage {
+    NoteOff(Channel, Note, u8),
+    NoteOn(Channel, Note, u8),
+    PolyKeyPressure(Channel, Note, u8),
+    ControlChange(Channel, u8, u8),
+    ProgramChange(Channel, u8),
+    ChannelPressure(Channel, u8),
+    /// 14-bit pitch bend value, centered at 0x2000.
+    PitchBend(Channel, u16),
+    TimeCodeQuarterFrame(u8),
+    SongPosition(u16),
+    SongSelect(u8),
+    TuneRequest,
+    TimingClock,
+    Start,
+    Continue,
+    Stop,
+    ActiveSensing,
+    SystemReset,
+}
+
+impl MidiMessage {
+    /// Parses a message from its MIDI bytes.
+    ///
+    /// Returns `None` unless `bytes` is exactly one complete message.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let (&status, data) = bytes.split_first()?;
+        if status & 0x80 == 0 || data.iter().any(|byte| byte & 0x80 != 0) {
+            return None;
+        }
+
+        let channel = Channel(status & 0x0f);
+        let message = match (status & 0xf0, data) {
+            (0x80, &[note, velocity]) => MidiMessage::NoteOff(channel, Note::new(note), velocity),
+            (0x90, &[note, velocity]) => MidiMessage::NoteOn(channel, Note::new(note), velocity),
+            (0xa0, &[note, pressure]) => MidiMessage::PolyKeyPressure(channel, Note::new(note), pressure),
+            (0xb0, &[control, value]) => MidiMessage::ControlChange(channel, control, value),
+            (0xc0, &[program]) => MidiMessage::ProgramChange(channel, program),
+            (0xd0, &[pressure]) => MidiMessage::ChannelPressure(channel, pressure),
+            (0xe0, &[lsb, msb]) => MidiMessage::PitchBend(channel, u16::from(msb) << 7 | u16::from(lsb)),
+            (0xf0, _) => match (status, data) {
+                (0xf1, &[value]) => MidiMessage::TimeCodeQuarterFrame(value),
+                (0xf2, &[lsb, msb]) => MidiMessage::SongPosition(u16::from(msb) << 7 | u16::from(lsb)),
+                (0xf3, &[song]) => MidiMessage::SongSelect(song),
+                (0xf6, &[]) => MidiMessage::TuneRequest,
+                (0xf8, &[]) => MidiMessage::TimingClock,
+                (0xfa, &[]) => MidiMessage::Start,
+                (0xfb, &[]) => MidiMessage::Continue,
+                (0xfc, &[]) => MidiMessage::Stop,
+                (0xfe, &[]) => MidiMessage::ActiveSensing,
+                (0xff, &[]) => MidiMessage::SystemReset,
+                _ => return None,
+            },
+            _ => return None,
+        };
+        Some(message)
+    }
+
+    /// Converts an event received from the host into a message.
+    ///
+    /// Returns `None` for SysEx and reserved events, and for events whose
+    /// code index does not match the MIDI bytes they carry.
+    pub fn from_event(event: Event) -> Option<Self> {
+        let packet = event.to_packet(0);
+        let message = Self::from_bytes(&packet[1..1 + event.size()])?;
+        (Event::from(message) == event).then_some(message)
+    }
+}
+
+impl TryFrom<Event> for MidiMessage {
+    type Error = Error;
+
+    fn try_from(event: Event) -> Result<Self, Self::Error> {
+        Self::from_event(event).ok_or(Error::Malformed)
+    }
+}
+
+impl From<MidiMessage> for Event {
+    fn from(message: MidiMessage) -> Event {
+        message.to_event()
+    }
+}
+
+impl MidiMessage {
+    /// The event carrying the message, like [`Event::from`] but usable in
+    /// constants.
+    pub const fn to_event(self) -> Event {
+        match self {
+            MidiMessage::NoteOff(channel, note, velocity) => Event::NoteOff(0x80 | channel.0, note, velocity),
+            MidiMessage::NoteOn(channel, note, velocity) => Event::NoteOn(0x90 | channel.0, note, velocity),
+            MidiMessage::PolyKeyPressure(channel, note, pressure) => {
+                Event::PolyKeyPress(0xa0 | channel.0, note.number(), pressure)
+            }
+            MidiMessage::ControlChange(channel, control, value) => {
+                Event::ControlChange(0xb0 | channel.0, control, value)
+            }
+            MidiMessage::ProgramChange(channel, program) => Event::ProgramChange(0xc0 | channel.0, program),
+            MidiMessage::ChannelPressure(channel, pressure) => Event::ChannelPressure(0xd0 | channel.0, pressure),
+            MidiMessage::PitchBend(channel, value) => {
+                Event::PitchBendChange(0xe0 | channel.0, (value & 0x7f) as u8, (value >> 7) as u8)
+            }
+            MidiMessage::TimeCodeQuarterFrame(value) => Event::SystemCommon2(0xf1, value),
+            MidiMessage::SongPosition(position) => {
+                Event::SystemCommon3(0xf2, (position & 0x7f) as u8, (position >> 7) as u8)
+            }
+            MidiMessage::SongSelect(song) => Event::SystemCommon2(0xf3, song),
+            MidiMessage::TuneRequest => Event::SystemCommon1SysExEnd1(0xf6),
+            MidiMessage::TimingClock => Event::SingleByte(0xf8),
+            MidiMessage::Start => Event::SingleByte(0xfa),
+            MidiMessage::Continue => Event::SingleByte(0xfb),
+            MidiMessage::Stop => Event::SingleByte(0xfc),
+            MidiMessage::ActiveSensing => Event::SingleByte(0xfe),
+            MidiMessage::SystemReset => Event::SingleByte(0xff),
+        }
+    }
+
+    /// Serializes the message into a USB-MIDI event packet for `cable`, e.g.
+    /// for a table of packets stored in flash:
+    ///
+    /// ```
+    /// use usb_midi_rs::cc::RESET_ALL_CONTROLLERS;
+    /// use usb_midi_rs::{Channel, MidiMessage};
+    ///
+    /// const RESET_CONTROLLERS: [[u8; 4]; 2] = [
+    ///     MidiMessage::ControlChange(Channel::new(0), RESET_ALL_CONTROLLERS, 0).to_packet(0),
+    ///     MidiMessage::ControlChange(Channel::new(1), RESET_ALL_CONTROLLERS, 0).to_packet(0),
+    /// ];
+    /// assert_eq!(RESET_CONTROLLERS[1], [0x0b, 0xb1, 121, 0]);
+    /// ```
+    pub const fn to_packet(self, cable: u8) -> [u8; 4] {
+        self.to_event().to_packet(cable)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    #[test]
+    fn converts_note_on() {
+        let message = MidiMessage::NoteOn(Channel::new(2), Note::new(60), 100);
+        let event = Event::from(message);
+        assert_eq!(event.to_packet(1), [0x19, 0x92, 60, 100]);
+        assert_eq!(MidiMessage::from_event(event), Some(message));
+    }
+
+    #[test]
+    fn converts_pitch_bend() {
+        let message = MidiMessage::PitchBend(Channel::new(0), 0x2001);
+        assert_eq!(Event::from(message), Event::PitchBendChange(0xe0, 0x01, 0x40));
+    }
+
+    #[test]
+    fn rejects_mismatched_code_index() {
+        assert_eq!(MidiMessage::from_event(Event::new(&[0x09, 0x80, 60, 0])), None);
+        assert_eq!(MidiMessage::from_event(Event::new(&[0x0f, 0x40, 0, 0])), None);
+    }
+
+    #[test]
+    fn builds_channel_masks() {
+        let mask = ChannelMask::NONE.with(Channel::new(3)).with(Channel::new(15));
+        assert_eq!(mask.bits(), 0x8008);
+        assert_eq!(mask.iter().collect::<Vec<_>>(), [Channel::new(3), Channel::new(15)]);
+        assert_eq!(mask.first(), Some(Channel::new(3)));
+        assert_eq!(mask.without(Channel::new(3)), Channel::new(15).into());
+        assert_eq!(mask.iter().collect::<ChannelMask>(), mask);
+        assert_eq!(!mask & mask, ChannelMask::NONE);
+        assert_eq!(ChannelMask::range(Channel::new(0), Channel::new(15)), ChannelMask::ALL);
+        assert_eq!(ChannelMask::range(Channel::new(2), Channel::new(4)).bits(), 0x001c);
+        assert!(ChannelMask::range(Channel::new(4), Channel::new(2)).is_empty());
+        assert_eq!(ChannelMask::NONE.first(), None);
+    }
+
+    proptest! {
+        #[test]
+        fn round_trips_messages(status in 0x80u8..=0xff, data: [u8; 2], len in 1usize..=3) {
+            let bytes = [status, data[0] & 0x7f, data[1] & 0x7f];
+            if let Some(message) = MidiMessage::from_bytes(&bytes[..len]) {
+                prop_assert_eq!(MidiMessage::from_event(Event::from(message)), Some(message));
+            }
+        }
+
+        #[test]
+        fn converts_any_event(packet: [u8; 4]) {
+            if let Some(message) = MidiMessage::from_event(Event::new(&packet)) {
+                prop_assert_eq!(Event::from(message), Event::new(&packet));
+            }
+        }
+    }
+}
@@ -0,0 +1,338 @@
+use core::fmt::{self, Write};
+
+use embassy_time::Instant;
+
+#[cfg(feature = "sysex")]
+use crate::report::{Writer, NUMBER_LEN};
+#[cfg(feature = "sysex")]
+use crate::sysex::{SYSEX_END, SYSEX_START};
+use crate::{CableNumber, Error, Event, MidiMessage, Octaves};
+
+/// Command byte of a request for the contents of a [`MidiMonitor`].
+#[cfg(feature = "sysex")]
+pub const MONITOR_REQUEST: u8 = 0x03;
+/// Command byte of the contents of a [`MidiMonitor`].
+#[cfg(feature = "sysex")]
+pub const MONITOR_REPLY: u8 = 0x04;
+#[cfg(feature = "sysex")]
+const MONITOR_VERSION: u8 = 1;
+
+/// An event captured by a [`MidiMonitor`].
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct MonitorEntry<const N: usize> {
+    pub timestamp: Instant,
+    pub cable: CableNumber<N>,
+    pub event: Event,
+}
+
+/// Keeps the last `K` events passing through the device, for a MIDI monitor
+/// on a display or in a host tool.
+///
+/// The monitor can be frozen to inspect its contents while the traffic goes
+/// on.
+#[derive(Clone, Debug)]
+pub struct MidiMonitor<const N: usize, const K: usize> {
+    entries: [Option<MonitorEntry<N>>; K],
+    /// Where the next entry goes, the oldest entry once the monitor is full.
+    next: usize,
+    frozen: bool,
+}
+
+impl<const N: usize, const K: usize> MidiMonitor<N, K> {
+    pub const fn new() -> Self {
+        Self {
+            entries: [None; K],
+            next: 0,
+            frozen: false,
+        }
+    }
+
+    /// Captures `event` on `cable`, dropping the oldest event if the monitor
+    /// is full. Nothing is captured while the monitor is frozen.
+    pub fn record(&mut self, timestamp: Instant, cable: CableNumber<N>, event: Event) {
+        if self.frozen || K == 0 {
+            return;
+        }
+        self.entries[self.next] = Some(MonitorEntry {
+            timestamp,
+            cable,
+            event,
+        });
+        self.next = (self.next + 1) % K;
+    }
+
+    /// Stops capturing events until [`resume`](Self::resume) is called.
+    pub fn freeze(&mut self) {
+        self.frozen = true;
+    }
+
+    pub fn resume(&mut self) {
+        self.frozen = false;
+    }
+
+    pub fn is_frozen(&self) -> bool {
+        self.frozen
+    }
+
+    pub fn clear(&mut self) {
+        self.entries = [None; K];
+        self.next = 0;
+    }
+
+    /// Number of events captured.
+    pub fn len(&self) -> usize {
+        self.entries.iter().flatten().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.iter().all(Option::is_none)
+    }
+
+    /// The events captured, the oldest first.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &MonitorEntry<N>> {
+        let (newer, older) = self.entries.split_at(self.next);
+        older.iter().chain(newer).flatten()
+    }
+
+    /// Length of the message listing the last `count` events with the given
+    /// manufacturer ID, see [`write`](Self::write).
+    #[cfg(feature = "sysex")]
+    pub const fn message_len(manufacturer: &[u8], count: usize) -> usize {
+        1 + manufacturer.len() + 3 + count * 2 * NUMBER_LEN + 1
+    }
+
+    /// Whether the complete System Exclusive `message` is a request for the
+    /// captured events, i.e. `F0 <manufacturer> 03 F7`.
+    #[cfg(feature = "sysex")]
+    pub fn is_monitor_request(message: &[u8], manufacturer: &[u8]) -> bool {
+        match message.strip_prefix(&[SYSEX_START][..]) {
+            Some(rest) => rest.strip_prefix(manufacturer) == Some(&[MONITOR_REQUEST, SYSEX_END][..]),
+            None => false,
+        }
+    }
+
+    /// Writes the events captured as a System Exclusive message to `data`,
+    /// returning its length.
+    ///
+    /// The message is `F0 <manufacturer> 04 <version> <count>` followed by
+    /// the timestamp in milliseconds and the USB-MIDI packet of every event,
+    /// the oldest first, and `F7`. Numbers are sent like in a
+    /// [`HealthReport`](crate::HealthReport). At most the last 127 events
+    /// are sent. Fails with [`Error::BufferOverflow`] if `data` is shorter
+    /// than [`message_len`](Self::message_len).
+    #[cfg(feature = "sysex")]
+    pub fn write(&self, manufacturer: &[u8], data: &mut [u8]) -> Result<usize, Error> {
+        let count = self.len().min(0x7f);
+        let len = Self::message_len(manufacturer, count);
+        let data = data.get_mut(..len).ok_or(Error::BufferOverflow)?;
+        let mut writer = Writer { data, len: 0 };
+        writer.bytes(&[SYSEX_START]);
+        writer.bytes(manufacturer);
+        writer.bytes(&[MONITOR_REPLY, MONITOR_VERSION, count as u8]);
+        for entry in self.iter().skip(self.len() - count) {
+            writer.number(entry.timestamp.as_millis() as u32);
+            writer.number(u32::from_be_bytes(entry.event.to_packet(entry.cable.number())));
+        }
+        writer.bytes(&[SYSEX_END]);
+        Ok(len)
+    }
+}
+
+impl<const N: usize, const K: usize> Default for MidiMonitor<N, K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Compact description of an event for MIDI monitors on small displays.
+///
+/// Channel messages start with the channel counted from 1, e.g. `1: On C#3
+/// 64` or `2: CC 74=101`, pitch bends are relative to the center. Other
+/// events show their bytes in hex.
+///
+/// ```
+/// use core::fmt::Write;
+///
+/// use usb_midi_rs::{Event, EventText, Note};
+///
+/// let mut line = heapless::String::<16>::new();
+/// write!(line, "{}", EventText::new(Event::NoteOn(0x90, Note::new(61), 64))).unwrap();
+/// assert_eq!(line, "1: On C#3 64");
+/// ```
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct EventText {
+    event: Event,
+    octaves: Octaves,
+}
+
+impl EventText {
+    /// Describes `event`, with middle C as C3.
+    pub const fn new(event: Event) -> Self {
+        Self {
+            event,
+            octaves: Octaves::MiddleC3,
+        }
+    }
+
+    pub const fn with_octaves(self, octaves: Octaves) -> Self {
+        Self { octaves, ..self }
+    }
+
+    /// Writes the description to `text`, returning its length.
+    pub fn render(&self, text: &mut [u8]) -> Result<usize, Error> {
+        let mut writer = SliceWriter { text, len: 0 };
+        write!(writer, "{}", self).map_err(|_| Error::BufferOverflow)?;
+        Ok(writer.len)
+    }
+}
+
+impl fmt::Display for EventText {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let message = match MidiMessage::from_event(self.event) {
+            Some(message) => message,
+            None => return write_bytes(f, self.event),
+        };
+        let octaves = self.octaves;
+        match message {
+            MidiMessage::NoteOff(channel, note, velocity) => {
+                write!(f, "{}: Off {} {}", channel.number() + 1, note.name(octaves), velocity)
+            }
+            MidiMessage::NoteOn(channel, note, velocity) => {
+                write!(f, "{}: On {} {}", channel.number() + 1, note.name(octaves), velocity)
+            }
+            MidiMessage::PolyKeyPressure(channel, note, pressure) => {
+                write!(f, "{}: PP {} {}", channel.number() + 1, note.name(octaves), pressure)
+            }
+            MidiMessage::ControlChange(channel, control, value) => {
+                write!(f, "{}: CC {}={}", channel.number() + 1, control, value)
+            }
+            MidiMessage::ProgramChange(channel, program) => write!(f, "{}: PC {}", channel.number() + 1, program),
+            MidiMessage::ChannelPressure(channel, pressure) => write!(f, "{}: CP {}", channel.number() + 1, pressure),
+            MidiMessage::PitchBend(channel, value) => {
+                write!(f, "{}: PB {:+}", channel.number() + 1, i32::from(value) - 0x2000)
+            }
+            MidiMessage::TimeCodeQuarterFrame(value) => write!(f, "MTC {}", value),
+            MidiMessage::SongPosition(position) => write!(f, "SPP {}", position),
+            MidiMessage::SongSelect(song) => write!(f, "Song {}", song),
+            MidiMessage::TuneRequest => f.write_str("Tune"),
+            MidiMessage::TimingClock => f.write_str("Clock"),
+            MidiMessage::Start => f.write_str("Start"),
+            MidiMessage::Continue => f.write_str("Continue"),
+            MidiMessage::Stop => f.write_str("Stop"),
+            MidiMessage::ActiveSensing => f.write_str("Sense"),
+            MidiMessage::SystemReset => f.write_str("Reset"),
+        }
+    }
+}
+
+/// Writes the bytes of `event`, e.g. a piece of a System Exclusive message.
+fn write_bytes(f: &mut fmt::Formatter, event: Event) -> fmt::Result {
+    let packet = event.to_packet(0);
+    match event.size() {
+        0 => f.write_str("-"),
+        size => {
+            write!(f, "{:02X}", packet[1])?;
+            packet[2..=size].iter().try_for_each(|byte| write!(f, " {:02X}", byte))
+        }
+    }
+}
+
+struct SliceWriter<'a> {
+    text: &'a mut [u8],
+    len: usize,
+}
+
+impl Write for SliceWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+        self.text
+            .get_mut(self.len..end)
+            .ok_or(fmt::Error)?
+            .copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Channel, Note};
+
+    fn entry(millis: u64, event: Event) -> (Instant, CableNumber<2>, Event) {
+        (Instant::from_millis(millis), CableNumber::new(1).unwrap(), event)
+    }
+
+    #[test]
+    fn keeps_last_events() {
+        let mut monitor = MidiMonitor::<2, 3>::new();
+        assert!(monitor.is_empty());
+        for millis in 0..5 {
+            let (timestamp, cable, event) = entry(millis, Event::SingleByte(0xf8));
+            monitor.record(timestamp, cable, event);
+        }
+        monitor.freeze();
+        let (timestamp, cable, event) = entry(5, Event::SingleByte(0xfa));
+        monitor.record(timestamp, cable, event);
+        assert_eq!(monitor.len(), 3);
+        let timestamps: std::vec::Vec<_> = monitor.iter().map(|entry| entry.timestamp.as_millis()).collect();
+        assert_eq!(timestamps, [2, 3, 4]);
+
+        monitor.resume();
+        monitor.clear();
+        monitor.record(timestamp, cable, event);
+        assert_eq!(
+            monitor.iter().map(|entry| entry.event).collect::<std::vec::Vec<_>>(),
+            [event]
+        );
+    }
+
+    #[cfg(feature = "sysex")]
+    #[test]
+    fn writes_contents() {
+        let mut monitor = MidiMonitor::<2, 4>::new();
+        let (timestamp, cable, event) = entry(200, MidiMessage::NoteOn(Channel::new(0), Note::new(60), 100).into());
+        monitor.record(timestamp, cable, event);
+        let mut data = [0; 32];
+        let len = monitor.write(&[0x7d], &mut data).unwrap();
+        assert_eq!(len, MidiMonitor::<2, 4>::message_len(&[0x7d], 1));
+        assert_eq!(
+            data[..len],
+            [0xf0, 0x7d, 0x04, 0x01, 0x01, 0, 0, 0, 0x01, 0x48, 0x01, 0x4c, 0x40, 0x78, 0x64, 0xf7]
+        );
+        assert_eq!(monitor.write(&[0x7d], &mut data[..len - 1]), Err(Error::BufferOverflow));
+        assert!(MidiMonitor::<2, 4>::is_monitor_request(
+            &[0xf0, 0x7d, 0x03, 0xf7],
+            &[0x7d]
+        ));
+    }
+
+    fn text(event: impl Into<Event>) -> std::string::String {
+        EventText::new(event.into()).to_string()
+    }
+
+    #[test]
+    fn describes_events() {
+        let channel = Channel::new(1);
+        assert_eq!(text(MidiMessage::ControlChange(channel, 74, 101)), "2: CC 74=101");
+        assert_eq!(text(MidiMessage::NoteOff(channel, Note::new(0), 0)), "2: Off C-2 0");
+        assert_eq!(text(MidiMessage::PitchBend(channel, 0x1fff)), "2: PB -1");
+        assert_eq!(text(MidiMessage::PitchBend(channel, 0x2000)), "2: PB +0");
+        assert_eq!(text(MidiMessage::TimingClock), "Clock");
+        assert_eq!(text(Event::SysExStartCont(0xf0, 0x41, 0x10)), "F0 41 10");
+        assert_eq!(text(Event::SysExEnd2(0x01, 0xf7)), "01 F7");
+        assert_eq!(text(Event::Misc), "-");
+        let note = EventText::new(MidiMessage::NoteOn(channel, Note::new(60), 1).into());
+        assert_eq!(note.with_octaves(Octaves::MiddleC4).to_string(), "2: On C4 1");
+    }
+
+    #[test]
+    fn renders_into_slices() {
+        let event = MidiMessage::ProgramChange(Channel::new(15), 127).into();
+        let mut text = [0; 10];
+        assert_eq!(EventText::new(event).render(&mut text), Ok(10));
+        assert_eq!(&text, b"16: PC 127");
+        assert_eq!(EventText::new(event).render(&mut text[..9]), Err(Error::BufferOverflow));
+    }
+}
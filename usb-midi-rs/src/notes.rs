@@ -0,0 +1,217 @@
+use crate::cc::{ALL_NOTES_OFF, ALL_SOUND_OFF, SUSTAIN};
+use crate::{Channel, ChannelMask, MidiMessage, Note};
+
+/// Release velocity of the Note Offs sent by [`NoteTracker::release_all`].
+const RELEASE_VELOCITY: u8 = 64;
+
+/// Keeps track of the notes sounding on every channel.
+///
+/// A note sounds from its Note On until its Note Off, or, while the sustain
+/// pedal (controller 64) of its channel is down, until the pedal is released.
+/// [`release_all`](Self::release_all) silences everything that is sounding,
+/// e.g. when the connection to a sound module is about to be lost.
+#[derive(Clone, Eq, PartialEq, Debug, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct NoteTracker {
+    /// Keys held down, one bit per note.
+    held: [u128; 16],
+    /// Notes sounding, a superset of the keys held down.
+    sounding: [u128; 16],
+    /// Channels with the sustain pedal down.
+    sustain: ChannelMask,
+}
+
+impl NoteTracker {
+    pub const fn new() -> Self {
+        Self {
+            held: [0; 16],
+            sounding: [0; 16],
+            sustain: ChannelMask::NONE,
+        }
+    }
+
+    /// Takes `message` into account.
+    pub fn update(&mut self, message: MidiMessage) {
+        match message {
+            MidiMessage::NoteOn(channel, note, velocity) if velocity > 0 => {
+                let index = channel.number() as usize;
+                self.held[index] |= bit(note);
+                self.sounding[index] |= bit(note);
+            }
+            MidiMessage::NoteOn(channel, note, _) | MidiMessage::NoteOff(channel, note, _) => {
+                let index = channel.number() as usize;
+                self.held[index] &= !bit(note);
+                if !self.is_sustained(channel) {
+                    self.sounding[index] &= !bit(note);
+                }
+            }
+            MidiMessage::ControlChange(channel, SUSTAIN, value) => {
+                let index = channel.number() as usize;
+                if value >= 64 {
+                    self.sustain.insert(channel);
+                } else {
+                    self.sustain.remove(channel);
+                    self.sounding[index] = self.held[index];
+                }
+            }
+            MidiMessage::ControlChange(channel, ALL_NOTES_OFF, _) => {
+                let index = channel.number() as usize;
+                self.held[index] = 0;
+                if !self.is_sustained(channel) {
+                    self.sounding[index] = 0;
+                }
+            }
+            MidiMessage::ControlChange(channel, ALL_SOUND_OFF, _) => {
+                let index = channel.number() as usize;
+                self.held[index] = 0;
+                self.sounding[index] = 0;
+            }
+            MidiMessage::SystemReset => *self = Self::new(),
+            _ => {}
+        }
+    }
+
+    pub fn is_sounding(&self, channel: Channel, note: Note) -> bool {
+        self.sounding[channel.number() as usize] & bit(note) != 0
+    }
+
+    pub fn is_held(&self, channel: Channel, note: Note) -> bool {
+        self.held[channel.number() as usize] & bit(note) != 0
+    }
+
+    /// Keys held down on `channel`, one bit per note.
+    pub(crate) fn held_keys(&self, channel: Channel) -> u128 {
+        self.held[channel.number() as usize]
+    }
+
+    pub fn is_sustained(&self, channel: Channel) -> bool {
+        self.sustain.contains(channel)
+    }
+
+    /// Channels with the sustain pedal down.
+    pub fn sustained(&self) -> ChannelMask {
+        self.sustain
+    }
+
+    /// Returns the messages that silence everything that is sounding: the
+    /// pedal releases of all sustained channels and the Note Offs of all
+    /// sounding notes.
+    ///
+    /// The tracker is cleared, as if the messages had been passed to it.
+    pub fn release_all(&mut self) -> Releases {
+        let releases = Releases {
+            sounding: self.sounding,
+            sustain: self.sustain,
+            channel: 0,
+        };
+        *self = Self::new();
+        releases
+    }
+}
+
+fn bit(note: Note) -> u128 {
+    1 << note.number()
+}
+
+/// Messages silencing the notes of a [`NoteTracker`], see
+/// [`NoteTracker::release_all`].
+pub struct Releases {
+    sounding: [u128; 16],
+    sustain: ChannelMask,
+    channel: u8,
+}
+
+impl Iterator for Releases {
+    type Item = MidiMessage;
+
+    fn next(&mut self) -> Option<MidiMessage> {
+        while self.channel < 16 {
+            let index = self.channel as usize;
+            let channel = Channel::new(self.channel);
+            if self.sustain.contains(channel) {
+                self.sustain.remove(channel);
+                return Some(MidiMessage::ControlChange(channel, SUSTAIN, 0));
+            }
+            let sounding = self.sounding[index];
+            if sounding != 0 {
+                let note = sounding.trailing_zeros() as u8;
+                self.sounding[index] &= !(1 << note);
+                return Some(MidiMessage::NoteOff(channel, Note::new(note), RELEASE_VELOCITY));
+            }
+            self.channel += 1;
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHANNEL: Channel = Channel::new(1);
+
+    fn note_on(note: u8) -> MidiMessage {
+        MidiMessage::NoteOn(CHANNEL, Note::new(note), 100)
+    }
+
+    fn note_off(note: u8) -> MidiMessage {
+        MidiMessage::NoteOff(CHANNEL, Note::new(note), 0)
+    }
+
+    fn sustain(value: u8) -> MidiMessage {
+        MidiMessage::ControlChange(CHANNEL, SUSTAIN, value)
+    }
+
+    #[test]
+    fn tracks_notes() {
+        let mut tracker = NoteTracker::new();
+        tracker.update(note_on(60));
+        tracker.update(note_on(64));
+        tracker.update(MidiMessage::NoteOn(CHANNEL, Note::new(64), 0));
+        assert!(tracker.is_sounding(CHANNEL, Note::new(60)));
+        assert!(!tracker.is_sounding(CHANNEL, Note::new(64)));
+        assert!(!tracker.is_sounding(Channel::new(0), Note::new(60)));
+
+        // Note numbers off the wire keep their 7 bits.
+        let event = crate::Event::new(&[0x09, 0x91, 0x80 | 62, 100]);
+        tracker.update(MidiMessage::try_from(event).unwrap());
+        assert!(tracker.is_sounding(CHANNEL, Note::new(62)));
+    }
+
+    #[test]
+    fn sustains_notes() {
+        let mut tracker = NoteTracker::new();
+        tracker.update(note_on(60));
+        tracker.update(sustain(127));
+        tracker.update(note_off(60));
+        tracker.update(note_on(62));
+        assert!(tracker.is_sounding(CHANNEL, Note::new(60)));
+        assert!(!tracker.is_held(CHANNEL, Note::new(60)));
+
+        tracker.update(sustain(0));
+        assert!(!tracker.is_sounding(CHANNEL, Note::new(60)));
+        assert!(tracker.is_sounding(CHANNEL, Note::new(62)));
+    }
+
+    #[test]
+    fn releases_everything() {
+        let mut tracker = NoteTracker::new();
+        tracker.update(MidiMessage::NoteOn(Channel::new(0), Note::new(48), 90));
+        tracker.update(note_on(60));
+        tracker.update(sustain(100));
+        tracker.update(note_off(60));
+        tracker.update(note_on(127));
+
+        let releases: Vec<_> = tracker.release_all().collect();
+        assert_eq!(
+            releases,
+            [
+                MidiMessage::NoteOff(Channel::new(0), Note::new(48), 64),
+                sustain(0),
+                MidiMessage::NoteOff(CHANNEL, Note::new(60), 64),
+                MidiMessage::NoteOff(CHANNEL, Note::new(127), 64),
+            ]
+        );
+        assert_eq!(tracker, NoteTracker::new());
+    }
+}
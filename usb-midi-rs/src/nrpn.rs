@@ -0,0 +1,194 @@
+use heapless::Vec;
+
+use crate::cc::{DATA_ENTRY, DATA_ENTRY_LSB, NRPN_LSB, NRPN_MSB, RPN_LSB, RPN_MSB};
+use crate::pipeline::Node;
+use crate::transport::{forward_with, MidiSink, MidiSource};
+use crate::{Error, Event};
+
+/// Translates controllers into NRPNs, for synths that only expose their
+/// parameters as NRPNs.
+///
+/// The map pairs controller numbers with 14-bit parameter numbers. A mapped
+/// controller turns into the selection of its parameter, followed by a Data
+/// Entry with the value. The selection is left out if the parameter is
+/// selected already. All other events pass unchanged.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct CcToNrpn<'a> {
+    map: &'a [(u8, u16)],
+    /// Parameter selected on every channel, if known.
+    selected: [Option<u16>; 16],
+}
+
+impl<'a> CcToNrpn<'a> {
+    pub const fn new(map: &'a [(u8, u16)]) -> Self {
+        Self {
+            map,
+            selected: [None; 16],
+        }
+    }
+
+    /// Events that `event` turns into.
+    pub fn process(&mut self, event: Event) -> impl Iterator<Item = Event> {
+        let mut events = Vec::<Event, 3>::new();
+        match event {
+            Event::ControlChange(status, control, value) => {
+                let selected = &mut self.selected[status as usize & 0x0f];
+                match self.map.iter().find(|&&(from, _)| from == control) {
+                    Some(&(_, parameter)) => {
+                        if *selected != Some(parameter) {
+                            *selected = Some(parameter);
+                            let (msb, lsb) = ((parameter >> 7) as u8 & 0x7f, parameter as u8 & 0x7f);
+                            let _ = events.push(Event::ControlChange(status, NRPN_MSB, msb));
+                            let _ = events.push(Event::ControlChange(status, NRPN_LSB, lsb));
+                        }
+                        let _ = events.push(Event::ControlChange(status, DATA_ENTRY, value));
+                    }
+                    None => {
+                        // Someone else selects a parameter.
+                        if matches!(control, NRPN_LSB..=RPN_MSB) {
+                            *selected = None;
+                        }
+                        let _ = events.push(event);
+                    }
+                }
+            }
+            event => {
+                let _ = events.push(event);
+            }
+        }
+        events.into_iter()
+    }
+
+    /// Translates the events of `source` for `sink`, see [`forward_with`].
+    pub async fn run(&mut self, source: &mut impl MidiSource, sink: &mut impl MidiSink) -> Error {
+        forward_with(source, sink, |event| self.process(event)).await
+    }
+}
+
+/// Translates NRPNs into controllers, for synths that only respond to
+/// controllers.
+///
+/// The map pairs controller numbers with 14-bit parameter numbers. Data
+/// Entry MSBs of a mapped parameter turn into its controller and their LSBs
+/// are dropped. The selection of parameters passes, as do the Data Entries
+/// of other parameters, so the translator can be used as a [`Node`] of a
+/// [`Pipeline`](crate::Pipeline).
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct NrpnToCc<'a> {
+    map: &'a [(u8, u16)],
+    /// NRPN selected on every channel, `None` while an RPN is.
+    selected: [Option<u16>; 16],
+}
+
+impl<'a> NrpnToCc<'a> {
+    pub const fn new(map: &'a [(u8, u16)]) -> Self {
+        Self {
+            map,
+            selected: [None; 16],
+        }
+    }
+
+    fn control(&self, status: u8) -> Option<u8> {
+        let parameter = self.selected[status as usize & 0x0f]?;
+        self.map
+            .iter()
+            .find(|&&(_, to)| to == parameter)
+            .map(|&(control, _)| control)
+    }
+}
+
+impl Node for NrpnToCc<'_> {
+    fn process(&mut self, event: Event) -> Option<Event> {
+        if let Event::ControlChange(status, control, value) = event {
+            let selected = &mut self.selected[status as usize & 0x0f];
+            match control {
+                NRPN_MSB => *selected = Some(selected.unwrap_or(0) & 0x7f | u16::from(value & 0x7f) << 7),
+                NRPN_LSB => *selected = Some(selected.unwrap_or(0) & 0x3f80 | u16::from(value & 0x7f)),
+                RPN_LSB | RPN_MSB => *selected = None,
+                DATA_ENTRY => {
+                    if let Some(control) = self.control(status) {
+                        return Some(Event::ControlChange(status, control, value));
+                    }
+                }
+                DATA_ENTRY_LSB if self.control(status).is_some() => return None,
+                _ => {}
+            }
+        }
+        Some(event)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cc::{BRIGHTNESS, MODULATION, RESONANCE};
+
+    const MAP: [(u8, u16); 2] = [(BRIGHTNESS, 0x0203), (RESONANCE, 0x0204)];
+
+    fn translate(translator: &mut CcToNrpn, event: Event) -> std::vec::Vec<Event> {
+        translator.process(event).collect()
+    }
+
+    #[test]
+    fn translates_to_nrpns() {
+        let mut translator = CcToNrpn::new(&MAP);
+        assert_eq!(
+            translate(&mut translator, Event::ControlChange(0xb1, BRIGHTNESS, 90)),
+            [
+                Event::ControlChange(0xb1, NRPN_MSB, 4),
+                Event::ControlChange(0xb1, NRPN_LSB, 3),
+                Event::ControlChange(0xb1, DATA_ENTRY, 90),
+            ]
+        );
+        assert_eq!(
+            translate(&mut translator, Event::ControlChange(0xb1, BRIGHTNESS, 91)),
+            [Event::ControlChange(0xb1, DATA_ENTRY, 91)]
+        );
+        assert_eq!(
+            translate(&mut translator, Event::ControlChange(0xb1, RESONANCE, 1)).len(),
+            3
+        );
+        assert_eq!(
+            translate(&mut translator, Event::ControlChange(0xb2, RESONANCE, 1)).len(),
+            3
+        );
+
+        let event = Event::ControlChange(0xb1, RPN_MSB, 0);
+        assert_eq!(translate(&mut translator, event), [event]);
+        assert_eq!(
+            translate(&mut translator, Event::ControlChange(0xb1, RESONANCE, 1)).len(),
+            3
+        );
+        let event = Event::ControlChange(0xb1, MODULATION, 5);
+        assert_eq!(translate(&mut translator, event), [event]);
+    }
+
+    #[test]
+    fn translates_to_controllers() {
+        let mut translator = NrpnToCc::new(&MAP);
+        assert_eq!(
+            translator.process(Event::ControlChange(0xb1, DATA_ENTRY, 7)),
+            Some(Event::ControlChange(0xb1, DATA_ENTRY, 7))
+        );
+        translator.process(Event::ControlChange(0xb1, NRPN_MSB, 4));
+        translator.process(Event::ControlChange(0xb1, NRPN_LSB, 4));
+        assert_eq!(
+            translator.process(Event::ControlChange(0xb1, DATA_ENTRY, 7)),
+            Some(Event::ControlChange(0xb1, RESONANCE, 7))
+        );
+        assert_eq!(translator.process(Event::ControlChange(0xb1, DATA_ENTRY_LSB, 7)), None);
+        // Other channels select their own parameters.
+        assert_eq!(
+            translator.process(Event::ControlChange(0xb0, DATA_ENTRY, 7)),
+            Some(Event::ControlChange(0xb0, DATA_ENTRY, 7))
+        );
+
+        translator.process(Event::ControlChange(0xb1, RPN_LSB, 0));
+        assert_eq!(
+            translator.process(Event::ControlChange(0xb1, DATA_ENTRY, 2)),
+            Some(Event::ControlChange(0xb1, DATA_ENTRY, 2))
+        );
+    }
+}
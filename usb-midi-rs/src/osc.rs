@@ -0,0 +1,208 @@
+//! Conversion between OSC messages and MIDI.
+//!
+//! An [`OscMap`] assigns OSC addresses to controllers and notes: a float
+//! argument from 0.0 to 1.0 becomes the value of a Control Change, and a
+//! trigger becomes a Note On while its argument is non-zero and a Note Off
+//! otherwise. Messages without arguments trigger a note at full velocity.
+//! Going the other way, the map turns MIDI messages into OSC messages with a
+//! single float argument, so a control surface speaking OSC can be connected
+//! to the [`Router`](crate::Router) like any other MIDI port.
+//!
+//! Only single messages with `f`, `i`, `T` and `F` arguments are supported,
+//! bundles are ignored.
+
+use crate::{Channel, Error, MidiMessage, Note};
+
+/// What an OSC address is mapped to.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Target {
+    /// A controller, with the float argument scaled to its value.
+    Control(Channel, u8),
+    /// A note, triggered with the float argument scaled to the velocity.
+    Note(Channel, Note),
+}
+
+/// A received OSC message.
+pub struct OscMessage<'a> {
+    pub address: &'a str,
+    types: &'a [u8],
+    arguments: &'a [u8],
+}
+
+impl OscMessage<'_> {
+    /// The first argument as a float, converting integers and booleans.
+    pub fn value(&self) -> Option<f32> {
+        match *self.types.first()? {
+            b'f' => Some(f32::from_bits(read_u32(self.arguments)?)),
+            b'i' => Some(read_u32(self.arguments)? as i32 as f32),
+            b'T' => Some(1.0),
+            b'F' => Some(0.0),
+            _ => None,
+        }
+    }
+}
+
+/// Parses an OSC message. Returns `None` for bundles and malformed packets.
+pub fn decode(packet: &[u8]) -> Option<OscMessage<'_>> {
+    let (address, rest) = read_string(packet)?;
+    if !address.starts_with('/') {
+        return None;
+    }
+    // Old implementations omit the type tags altogether.
+    let (types, arguments) = match read_string(rest) {
+        Some((types, arguments)) if types.starts_with(',') => (&types.as_bytes()[1..], arguments),
+        _ => (&[][..], rest),
+    };
+    Some(OscMessage {
+        address,
+        types,
+        arguments,
+    })
+}
+
+/// Writes an OSC message with a single float argument to `buffer`.
+///
+/// Returns the length of the message or [`Error::BufferOverflow`] if it does
+/// not fit.
+pub fn encode(address: &str, value: f32, buffer: &mut [u8]) -> Result<usize, Error> {
+    let len = padded(address.len()) + 4 + 4;
+    let buffer = buffer.get_mut(..len).ok_or(Error::BufferOverflow)?;
+    buffer.fill(0);
+    buffer[..address.len()].copy_from_slice(address.as_bytes());
+    let types = padded(address.len());
+    buffer[types..types + 2].copy_from_slice(b",f");
+    buffer[len - 4..].copy_from_slice(&value.to_bits().to_be_bytes());
+    Ok(len)
+}
+
+/// Assignment of OSC addresses to MIDI controllers and notes.
+pub struct OscMap<'a> {
+    entries: &'a [(&'a str, Target)],
+}
+
+impl<'a> OscMap<'a> {
+    pub const fn new(entries: &'a [(&'a str, Target)]) -> Self {
+        Self { entries }
+    }
+
+    /// Converts `message`, if its address is mapped.
+    pub fn to_midi(&self, message: &OscMessage) -> Option<MidiMessage> {
+        let (_, target) = self.entries.iter().find(|(address, _)| *address == message.address)?;
+        match *target {
+            Target::Control(channel, control) => {
+                Some(MidiMessage::ControlChange(channel, control, to_value(message.value()?)))
+            }
+            Target::Note(channel, note) => match message.value() {
+                None => Some(MidiMessage::NoteOn(channel, note, 127)),
+                Some(value) if value > 0.0 => Some(MidiMessage::NoteOn(channel, note, to_value(value).max(1))),
+                Some(_) => Some(MidiMessage::NoteOff(channel, note, 0)),
+            },
+        }
+    }
+
+    /// Address and value of the OSC message for `message`, if it is mapped.
+    ///
+    /// A Note On with velocity 0 is a Note Off.
+    pub fn to_osc(&self, message: MidiMessage) -> Option<(&'a str, f32)> {
+        let (target, value) = match message {
+            MidiMessage::ControlChange(channel, control, value) => (Target::Control(channel, control), value),
+            MidiMessage::NoteOn(channel, note, velocity) => (Target::Note(channel, note), velocity),
+            MidiMessage::NoteOff(channel, note, _) => (Target::Note(channel, note), 0),
+            _ => return None,
+        };
+        let (address, _) = self.entries.iter().find(|(_, entry)| *entry == target)?;
+        Some((address, f32::from(value) / 127.0))
+    }
+}
+
+fn to_value(value: f32) -> u8 {
+    (value.clamp(0.0, 1.0) * 127.0 + 0.5) as u8
+}
+
+/// Length of a string of `len` bytes with its terminator and padding.
+fn padded(len: usize) -> usize {
+    (len / 4 + 1) * 4
+}
+
+fn read_string(bytes: &[u8]) -> Option<(&str, &[u8])> {
+    let len = bytes.iter().position(|&byte| byte == 0)?;
+    let string = core::str::from_utf8(&bytes[..len]).ok()?;
+    Some((string, bytes.get(padded(len)..)?))
+}
+
+fn read_u32(bytes: &[u8]) -> Option<u32> {
+    Some(u32::from_be_bytes(bytes.get(..4)?.try_into().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAP: OscMap = OscMap::new(&[
+        ("/1/fader1", Target::Control(Channel::new(0), 7)),
+        ("/1/push1", Target::Note(Channel::new(9), Note::new(36))),
+    ]);
+
+    fn message(address: &str, value: f32) -> std::vec::Vec<u8> {
+        let mut buffer = [0; 64];
+        let len = encode(address, value, &mut buffer).unwrap();
+        buffer[..len].to_vec()
+    }
+
+    #[test]
+    fn encodes_messages() {
+        assert_eq!(
+            message("/1/fader1", 0.5),
+            b"/1/fader1\0\0\0,f\0\0\x3f\x00\x00\x00".to_vec()
+        );
+        assert_eq!(message("/abc", 0.0).len(), 16);
+        assert_eq!(encode("/1/fader1", 0.5, &mut [0; 19]), Err(Error::BufferOverflow));
+    }
+
+    #[test]
+    fn converts_controllers() {
+        let packet = message("/1/fader1", 0.5);
+        let osc = decode(&packet).unwrap();
+        assert_eq!(
+            MAP.to_midi(&osc),
+            Some(MidiMessage::ControlChange(Channel::new(0), 7, 64))
+        );
+        assert_eq!(
+            MAP.to_osc(MidiMessage::ControlChange(Channel::new(0), 7, 127)),
+            Some(("/1/fader1", 1.0))
+        );
+        assert_eq!(MAP.to_osc(MidiMessage::ControlChange(Channel::new(1), 7, 127)), None);
+    }
+
+    #[test]
+    fn triggers_notes() {
+        let on = message("/1/push1", 1.0);
+        let off = message("/1/push1", 0.0);
+        assert_eq!(
+            MAP.to_midi(&decode(&on).unwrap()),
+            Some(MidiMessage::NoteOn(Channel::new(9), Note::new(36), 127))
+        );
+        assert_eq!(
+            MAP.to_midi(&decode(&off).unwrap()),
+            Some(MidiMessage::NoteOff(Channel::new(9), Note::new(36), 0))
+        );
+        assert_eq!(
+            MAP.to_midi(&decode(b"/1/push1\0\0\0\0").unwrap()),
+            Some(MidiMessage::NoteOn(Channel::new(9), Note::new(36), 127))
+        );
+        assert_eq!(
+            MAP.to_osc(MidiMessage::NoteOn(Channel::new(9), Note::new(36), 0)),
+            Some(("/1/push1", 0.0))
+        );
+    }
+
+    #[test]
+    fn converts_arguments() {
+        let packet = b"/1/fader1\0\0\0,i\0\0\0\0\0\x01";
+        assert_eq!(decode(packet).unwrap().value(), Some(1.0));
+        assert_eq!(decode(b"/1/push1\0\0\0\0,T\0\0").unwrap().value(), Some(1.0));
+        assert!(decode(b"#bundle\0").is_none());
+        assert!(decode(b"/unterminated").is_none());
+    }
+}
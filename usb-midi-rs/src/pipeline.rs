@@ -0,0 +1,497 @@
+//! Chains of simple processing nodes, assembled like iterator adapters.
+//!
+//! A pipeline is a single value holding all its nodes, so its size is known
+//! at compile time and it needs no allocation. Connected to a sink with
+//! [`Pipeline::into_sink`], it is a [`MidiSink`] itself:
+//!
+//! ```
+//! use usb_midi_rs::{pipeline, Channel, ChannelMask};
+//! # use embassy_sync::{blocking_mutex::raw::NoopRawMutex, channel::Channel as Queue};
+//! # let output = Queue::<NoopRawMutex, usb_midi_rs::Event, 4>::new();
+//!
+//! let mut sink = pipeline()
+//!     .channels(ChannelMask::from(Channel::new(0)))
+//!     .transpose(-12)
+//!     .velocity_curve(|velocity| velocity / 2 + 64)
+//!     .into_sink(&output);
+//! // usb_midi_rs::transport::forward(&mut source, &mut sink).await;
+//! ```
+//!
+//! Nodes turn every event into at most one event. Nodes producing more, like
+//! the [`Harmonizer`](crate::Harmonizer), run as tasks of their own.
+
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+use embassy_time::Duration;
+
+use crate::transport::MidiSink;
+use crate::{Channel, ChannelMask, Dedupe, Error, Event, Note};
+
+/// A step of a [`Pipeline`].
+pub trait Node {
+    /// The event `event` turns into, if any.
+    fn process(&mut self, event: Event) -> Option<Event>;
+}
+
+impl<F: FnMut(Event) -> Option<Event>> Node for F {
+    fn process(&mut self, event: Event) -> Option<Event> {
+        self(event)
+    }
+}
+
+/// Passes all events unchanged, the start of every pipeline.
+#[derive(Copy, Clone, Default, Debug)]
+pub struct Pass;
+
+impl Node for Pass {
+    fn process(&mut self, event: Event) -> Option<Event> {
+        Some(event)
+    }
+}
+
+/// Two nodes, one after the other.
+#[derive(Copy, Clone, Debug)]
+pub struct Chain<A, B> {
+    first: A,
+    second: B,
+}
+
+impl<A: Node, B: Node> Node for Chain<A, B> {
+    fn process(&mut self, event: Event) -> Option<Event> {
+        self.first.process(event).and_then(|event| self.second.process(event))
+    }
+}
+
+/// Passes the events a predicate holds for, see [`Pipeline::filter`].
+#[derive(Copy, Clone, Debug)]
+pub struct Filter<F>(F);
+
+impl<F: FnMut(&Event) -> bool> Node for Filter<F> {
+    fn process(&mut self, event: Event) -> Option<Event> {
+        (self.0)(&event).then_some(event)
+    }
+}
+
+/// Passes channel messages on some channels, see [`Pipeline::channels`].
+#[derive(Copy, Clone, Debug)]
+pub struct Channels(ChannelMask);
+
+impl Node for Channels {
+    fn process(&mut self, event: Event) -> Option<Event> {
+        match event.channel() {
+            Some(channel) if !self.0.contains(channel) => None,
+            _ => Some(event),
+        }
+    }
+}
+
+/// Moves channel messages to one channel, see [`Pipeline::force_channel`].
+#[derive(Copy, Clone, Debug)]
+pub struct ForceChannel(Channel);
+
+impl Node for ForceChannel {
+    fn process(&mut self, event: Event) -> Option<Event> {
+        Some(event.with_channel(self.0))
+    }
+}
+
+/// Shifts notes, see [`Pipeline::transpose`].
+#[derive(Copy, Clone, Debug)]
+pub struct Transpose(i8);
+
+impl Node for Transpose {
+    fn process(&mut self, event: Event) -> Option<Event> {
+        let shift = |note: Note| {
+            let number = i16::from(note.number()) + i16::from(self.0);
+            (0..128).contains(&number).then_some(number as u8)
+        };
+        Some(match event {
+            Event::NoteOff(status, note, velocity) => Event::NoteOff(status, Note::new(shift(note)?), velocity),
+            Event::NoteOn(status, note, velocity) => Event::NoteOn(status, Note::new(shift(note)?), velocity),
+            Event::PolyKeyPress(status, note, pressure) => {
+                Event::PolyKeyPress(status, shift(Note::new(note & 0x7f))?, pressure)
+            }
+            event => event,
+        })
+    }
+}
+
+/// Maps Note On velocities, see [`Pipeline::velocity_curve`].
+#[derive(Copy, Clone, Debug)]
+pub struct VelocityCurve<F>(F);
+
+impl<F: FnMut(u8) -> u8> Node for VelocityCurve<F> {
+    fn process(&mut self, event: Event) -> Option<Event> {
+        Some(match event {
+            // A velocity of 0 stands for a Note Off and must stay 0.
+            Event::NoteOn(status, note, velocity) if velocity > 0 => {
+                Event::NoteOn(status, note, (self.0)(velocity).clamp(1, 127))
+            }
+            event => event,
+        })
+    }
+}
+
+/// A controller moved to another number, see [`Pipeline::remap_control`].
+///
+/// ```
+/// use usb_midi_rs::cc::{BREATH, EXPRESSION};
+/// use usb_midi_rs::pipeline::ControlMapping;
+///
+/// // Breath controls expression, but never fades out completely.
+/// let mapping = ControlMapping::new(BREATH, EXPRESSION).with_range(20, 127);
+/// assert_eq!(mapping.map(0), 20);
+/// ```
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ControlMapping {
+    pub from: u8,
+    pub to: u8,
+    /// Channels the mapping applies to.
+    pub channels: ChannelMask,
+    /// Values the range from 0 to 127 is scaled to.
+    pub min: u8,
+    pub max: u8,
+    /// Whether the value is inverted before it is scaled.
+    pub invert: bool,
+}
+
+impl ControlMapping {
+    /// Moves controller `from` to `to` on all channels, keeping its value.
+    pub const fn new(from: u8, to: u8) -> Self {
+        Self {
+            from,
+            to,
+            channels: ChannelMask::ALL,
+            min: 0,
+            max: 127,
+            invert: false,
+        }
+    }
+
+    pub const fn with_channels(mut self, channels: ChannelMask) -> Self {
+        self.channels = channels;
+        self
+    }
+
+    /// Scales the values to the range from `min` to `max`. `min` may be
+    /// greater than `max`.
+    pub const fn with_range(mut self, min: u8, max: u8) -> Self {
+        self.min = min;
+        self.max = max;
+        self
+    }
+
+    pub const fn inverted(mut self) -> Self {
+        self.invert = true;
+        self
+    }
+
+    /// The value `value` of the original controller is mapped to.
+    pub fn map(&self, value: u8) -> u8 {
+        let value = if self.invert {
+            127 - (value & 0x7f)
+        } else {
+            value & 0x7f
+        };
+        let (min, max) = (i16::from(self.min & 0x7f), i16::from(self.max & 0x7f));
+        (min + (max - min) * i16::from(value) / 127) as u8
+    }
+}
+
+/// Moves a controller, see [`Pipeline::remap_control`].
+#[derive(Copy, Clone, Debug)]
+pub struct RemapControl(ControlMapping);
+
+impl Node for RemapControl {
+    fn process(&mut self, event: Event) -> Option<Event> {
+        Some(match event {
+            Event::ControlChange(status, control, value)
+                if control == self.0.from && self.0.channels.contains(Channel::new(status & 0x0f)) =>
+            {
+                Event::ControlChange(status, self.0.to, self.0.map(value))
+            }
+            event => event,
+        })
+    }
+}
+
+/// Shapes Channel and Poly Key Pressure, see [`Pipeline::pressure_curve`].
+#[derive(Copy, Clone, Debug)]
+pub struct PressureCurve<F> {
+    curve: F,
+    threshold: u8,
+    /// Channels whose Channel Pressure was last sent as 0.
+    released: u16,
+}
+
+impl<F: FnMut(u8) -> u8> PressureCurve<F> {
+    fn shape(&mut self, pressure: u8) -> u8 {
+        let pressure = pressure & 0x7f;
+        if pressure < self.threshold {
+            return 0;
+        }
+        let scaled = u16::from(pressure - self.threshold + 1) * 127 / u16::from(128 - self.threshold);
+        match scaled {
+            0 => 0,
+            scaled => (self.curve)(scaled as u8).min(127),
+        }
+    }
+}
+
+impl<F: FnMut(u8) -> u8> Node for PressureCurve<F> {
+    fn process(&mut self, event: Event) -> Option<Event> {
+        Some(match event {
+            Event::ChannelPressure(status, pressure) => {
+                let pressure = self.shape(pressure);
+                let bit = 1 << (status & 0x0f);
+                if pressure == 0 {
+                    if self.released & bit != 0 {
+                        return None;
+                    }
+                    self.released |= bit;
+                } else {
+                    self.released &= !bit;
+                }
+                Event::ChannelPressure(status, pressure)
+            }
+            Event::PolyKeyPress(status, note, pressure) => Event::PolyKeyPress(status, note, self.shape(pressure)),
+            event => event,
+        })
+    }
+}
+
+/// A chain of nodes, see the [module documentation](self).
+#[derive(Copy, Clone, Debug)]
+pub struct Pipeline<N> {
+    node: N,
+}
+
+/// An empty pipeline, to be extended with the methods of [`Pipeline`].
+pub const fn pipeline() -> Pipeline<Pass> {
+    Pipeline { node: Pass }
+}
+
+impl<N: Node> Pipeline<N> {
+    /// Appends `node`.
+    pub fn then<M: Node>(self, node: M) -> Pipeline<Chain<N, M>> {
+        Pipeline {
+            node: Chain {
+                first: self.node,
+                second: node,
+            },
+        }
+    }
+
+    /// Drops the events `predicate` does not hold for.
+    pub fn filter<F: FnMut(&Event) -> bool>(self, predicate: F) -> Pipeline<Chain<N, Filter<F>>> {
+        self.then(Filter(predicate))
+    }
+
+    /// Drops channel messages on channels other than `channels`. System
+    /// messages pass.
+    pub fn channels(self, channels: ChannelMask) -> Pipeline<Chain<N, Channels>> {
+        self.then(Channels(channels))
+    }
+
+    /// Moves all channel messages to `channel`, merging all channels into
+    /// one. System messages pass.
+    pub fn force_channel(self, channel: Channel) -> Pipeline<Chain<N, ForceChannel>> {
+        self.then(ForceChannel(channel))
+    }
+
+    /// Moves a controller to another number, scaling and inverting its values
+    /// as `mapping` says, e.g. breath to expression for a synth without a
+    /// breath input. Pipelines are per cable, so are their mappings.
+    pub fn remap_control(self, mapping: ControlMapping) -> Pipeline<Chain<N, RemapControl>> {
+        self.then(RemapControl(mapping))
+    }
+
+    /// Shifts notes by `semitones`, dropping those falling outside the MIDI
+    /// range.
+    pub fn transpose(self, semitones: i8) -> Pipeline<Chain<N, Transpose>> {
+        self.then(Transpose(semitones))
+    }
+
+    /// Replaces the velocity of Note Ons by what `curve` returns for it,
+    /// clamped from 1 to 127.
+    pub fn velocity_curve<F: FnMut(u8) -> u8>(self, curve: F) -> Pipeline<Chain<N, VelocityCurve<F>>> {
+        self.then(VelocityCurve(curve))
+    }
+
+    /// Shapes Channel and Poly Key Pressure, which many keybeds send with a
+    /// lot of noise at light touch.
+    ///
+    /// Pressures below `threshold` become 0 and the ones above are stretched
+    /// to the full range from 1 to 127 before `curve` is applied. A Channel
+    /// Pressure of 0 is only sent once until the pressure rises again.
+    pub fn pressure_curve<F: FnMut(u8) -> u8>(self, threshold: u8, curve: F) -> Pipeline<Chain<N, PressureCurve<F>>> {
+        self.then(PressureCurve {
+            curve,
+            threshold: threshold.min(127),
+            released: 0,
+        })
+    }
+
+    /// Drops controllers, Pitch Bends and Program Changes repeating what was
+    /// sent less than `window` ago, keeping track of `S` of them, see
+    /// [`Dedupe`].
+    pub fn dedupe<const S: usize>(self, window: Duration) -> Pipeline<Chain<N, Dedupe<S>>> {
+        self.then(Dedupe::new(window))
+    }
+
+    /// Sends the output of the pipeline to `sink`.
+    pub fn into_sink<S: MidiSink>(self, sink: S) -> PipelineSink<N, S> {
+        PipelineSink { node: self.node, sink }
+    }
+}
+
+impl<N: Node> Node for Pipeline<N> {
+    fn process(&mut self, event: Event) -> Option<Event> {
+        self.node.process(event)
+    }
+}
+
+/// A pipeline connected to a sink, see [`Pipeline::into_sink`].
+pub struct PipelineSink<N, S> {
+    node: N,
+    sink: S,
+}
+
+impl<N, S> PipelineSink<N, S> {
+    pub fn into_inner(self) -> S {
+        self.sink
+    }
+}
+
+impl<N: Node, S: MidiSink> MidiSink for PipelineSink<N, S> {
+    type SendFuture<'a>
+        = MaybeSend<S::SendFuture<'a>>
+    where
+        Self: 'a;
+
+    fn send(&mut self, event: Event) -> Self::SendFuture<'_> {
+        match self.node.process(event) {
+            Some(event) => MaybeSend::Send(self.sink.send(event)),
+            None => MaybeSend::Dropped,
+        }
+    }
+}
+
+/// Future of [`PipelineSink::send`], completing at once if the pipeline
+/// dropped the event.
+pub enum MaybeSend<F> {
+    Send(F),
+    Dropped,
+}
+
+impl<F: Future<Output = Result<(), Error>>> Future for MaybeSend<F> {
+    type Output = Result<(), Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // SAFETY: the future is never moved out of the pinned enum.
+        match unsafe { self.get_unchecked_mut() } {
+            MaybeSend::Send(future) => unsafe { Pin::new_unchecked(future) }.poll(cx),
+            MaybeSend::Dropped => Poll::Ready(Ok(())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_futures::block_on;
+    use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+    use embassy_sync::channel::Channel as Queue;
+
+    use super::*;
+    use crate::MidiMessage;
+
+    fn note_on(channel: u8, note: u8, velocity: u8) -> Event {
+        MidiMessage::NoteOn(Channel::new(channel), Note::new(note), velocity).into()
+    }
+
+    #[test]
+    fn chains_nodes() {
+        let mut pipeline = pipeline()
+            .channels(ChannelMask::from(Channel::new(1)))
+            .transpose(12)
+            .velocity_curve(|velocity| velocity.saturating_mul(2))
+            .filter(|event| !event.is_real_time());
+        assert_eq!(pipeline.process(note_on(1, 60, 100)), Some(note_on(1, 72, 127)));
+        assert_eq!(pipeline.process(note_on(1, 60, 0)), Some(note_on(1, 72, 0)));
+        assert_eq!(pipeline.process(note_on(1, 120, 1)), None);
+        assert_eq!(pipeline.process(note_on(2, 60, 100)), None);
+        assert_eq!(pipeline.process(Event::SingleByte(0xf8)), None);
+        assert_eq!(pipeline.process(Event::SingleByte(0xf6)), Some(Event::SingleByte(0xf6)));
+        assert_eq!(
+            pipeline.process(Event::PolyKeyPress(0xa1, 60, 5)),
+            Some(Event::PolyKeyPress(0xa1, 72, 5))
+        );
+    }
+
+    #[test]
+    fn forces_channels() {
+        let mut pipeline = pipeline().force_channel(Channel::new(3)).transpose(1);
+        assert_eq!(pipeline.process(note_on(0, 60, 100)), Some(note_on(3, 61, 100)));
+        assert_eq!(
+            pipeline.process(Event::ControlChange(0xbf, 7, 1)),
+            Some(Event::ControlChange(0xb3, 7, 1))
+        );
+        assert_eq!(pipeline.process(Event::SingleByte(0xfa)), Some(Event::SingleByte(0xfa)));
+    }
+
+    #[test]
+    fn remaps_controls() {
+        use crate::cc::{BREATH, EXPRESSION};
+
+        let mapping = ControlMapping::new(BREATH, EXPRESSION)
+            .with_channels(ChannelMask::from(Channel::new(2)))
+            .with_range(100, 20)
+            .inverted();
+        let mut remap = pipeline().remap_control(mapping);
+        assert_eq!(
+            remap.process(Event::ControlChange(0xb2, BREATH, 127)),
+            Some(Event::ControlChange(0xb2, EXPRESSION, 100))
+        );
+        assert_eq!(
+            remap.process(Event::ControlChange(0xb2, BREATH, 0)),
+            Some(Event::ControlChange(0xb2, EXPRESSION, 20))
+        );
+        let event = Event::ControlChange(0xb1, BREATH, 0);
+        assert_eq!(remap.process(event), Some(event));
+        assert_eq!(ControlMapping::new(1, 2).map(77), 77);
+    }
+
+    #[test]
+    fn shapes_pressure() {
+        let mut shaper = pipeline().pressure_curve(8, |pressure| pressure / 2 + 63);
+        let mut pressure = |pressure| shaper.process(Event::ChannelPressure(0xd1, pressure));
+        assert_eq!(pressure(5), Some(Event::ChannelPressure(0xd1, 0)));
+        assert_eq!(pressure(7), None);
+        assert_eq!(pressure(8), Some(Event::ChannelPressure(0xd1, 63)));
+        assert_eq!(pressure(127), Some(Event::ChannelPressure(0xd1, 126)));
+        assert_eq!(pressure(0), Some(Event::ChannelPressure(0xd1, 0)));
+        assert_eq!(
+            shaper.process(Event::PolyKeyPress(0xa1, 60, 3)),
+            Some(Event::PolyKeyPress(0xa1, 60, 0))
+        );
+
+        let mut linear = pipeline().pressure_curve(0, |pressure| pressure);
+        for pressure in [1, 64, 127] {
+            let event = Event::PolyKeyPress(0xa0, 60, pressure);
+            assert_eq!(linear.process(event), Some(event));
+        }
+    }
+
+    #[test]
+    fn sends_to_sinks() {
+        let output = Queue::<NoopRawMutex, Event, 4>::new();
+        let mut sink = pipeline().transpose(-60).into_sink(&output);
+        block_on(sink.send(note_on(0, 30, 1))).unwrap();
+        block_on(sink.send(note_on(0, 70, 1))).unwrap();
+        assert_eq!(output.try_recv().ok(), Some(note_on(0, 10, 1)));
+        assert!(output.try_recv().is_err());
+    }
+}
@@ -0,0 +1,90 @@
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::raw::RawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+
+use crate::Error;
+
+/// Longest name of a port in bytes.
+pub const PORT_NAME_LEN: usize = 32;
+
+#[derive(Copy, Clone)]
+struct Name {
+    bytes: [u8; PORT_NAME_LEN],
+    len: u8,
+}
+
+/// Names of the `N` ports of a [`UsbMidiClass`](crate::UsbMidiClass) that
+/// can be changed at runtime, e.g. by a configuration tool.
+///
+/// The class reads the names whenever the host asks for its string
+/// descriptors, so it is passed to [`State::with_port_names`](crate::State::with_port_names)
+/// and usually kept in a `static`. Most hosts only read the names when they
+/// enumerate the device and keep them until it is plugged in again, see
+/// [`SoftReconnect`](crate::SoftReconnect). Ports
+/// without a name are called "Port 1", "Port 2" and so on.
+pub struct PortNames<M: RawMutex, const N: usize> {
+    names: Mutex<M, RefCell<[Name; N]>>,
+}
+
+impl<M: RawMutex, const N: usize> PortNames<M, N> {
+    pub const fn new() -> Self {
+        let name = Name {
+            bytes: [0; PORT_NAME_LEN],
+            len: 0,
+        };
+        Self {
+            names: Mutex::new(RefCell::new([name; N])),
+        }
+    }
+
+    /// Names `port`, or gives it its default name again if `name` is empty.
+    ///
+    /// Fails with [`Error::BufferOverflow`] if `name` is longer than
+    /// [`PORT_NAME_LEN`] bytes.
+    pub fn set(&self, port: usize, name: &str) -> Result<(), Error> {
+        let bytes = name.as_bytes();
+        if bytes.len() > PORT_NAME_LEN {
+            return Err(Error::BufferOverflow);
+        }
+        self.names.lock(|names| {
+            let name = &mut names.borrow_mut()[port];
+            name.bytes[..bytes.len()].copy_from_slice(bytes);
+            name.len = bytes.len() as u8;
+        });
+        Ok(())
+    }
+
+    /// Calls `f` with the name of `port`, which is empty for the default
+    /// name.
+    pub fn with_name<R>(&self, port: usize, f: impl FnOnce(&str) -> R) -> R {
+        self.names.lock(|names| {
+            let name = &names.borrow()[port];
+            f(core::str::from_utf8(&name.bytes[..name.len as usize]).unwrap_or_default())
+        })
+    }
+}
+
+impl<M: RawMutex, const N: usize> Default for PortNames<M, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// [`PortNames`] without their type parameters, as kept by the class.
+pub(crate) trait NameTable {
+    /// Copies the name of `port` to `buffer`, returning its length.
+    fn copy_name(&self, port: usize, buffer: &mut [u8; PORT_NAME_LEN]) -> usize;
+}
+
+impl<M: RawMutex, const N: usize> NameTable for PortNames<M, N> {
+    fn copy_name(&self, port: usize, buffer: &mut [u8; PORT_NAME_LEN]) -> usize {
+        if port >= N {
+            return 0;
+        }
+        self.with_name(port, |name| {
+            buffer[..name.len()].copy_from_slice(name.as_bytes());
+            name.len()
+        })
+    }
+}
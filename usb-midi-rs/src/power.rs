@@ -0,0 +1,108 @@
+#[cfg(feature = "usb")]
+use embassy_usb::Config;
+
+/// Highest current a device may draw from the bus, in mA.
+pub const MAX_BUS_POWER: u16 = 500;
+
+/// Callbacks on bus suspend and resume and on the choice of a power
+/// configuration.
+pub trait PowerHandler {
+    /// The host suspended the bus.
+    ///
+    /// A bus-powered device must drop to its suspend current of 2.5 mA within
+    /// a few milliseconds, so clocks, LEDs and other loads should be switched
+    /// off here.
+    fn suspended(&mut self) {}
+
+    /// The bus was resumed, either by the host or by remote wakeup.
+    fn resumed(&mut self) {}
+
+    /// The host selected the configuration with the `power` settings of
+    /// [`PowerConfigurations`](crate::PowerConfigurations).
+    fn configured(&mut self, _power: PowerConfig) {}
+}
+
+impl PowerHandler for () {}
+
+/// Power supply of the device as declared in the configuration descriptor.
+///
+/// The [`Builder`](embassy_usb::Builder) of `embassy-usb` writes a single
+/// configuration. Devices with an optional external supply either pick it at
+/// startup, e.g. from the state of the supply pin, or offer a bus-powered and
+/// a self-powered one with [`PowerConfigurations`](crate::PowerConfigurations)
+/// and let the host choose.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PowerConfig {
+    self_powered: bool,
+    /// Maximum current in units of 2 mA, as in `bMaxPower`.
+    max_power: u8,
+}
+
+impl PowerConfig {
+    /// A device drawing up to `max_power` mA from the bus.
+    ///
+    /// Odd values are rounded up to the 2 mA resolution of the descriptor.
+    pub const fn bus_powered(max_power: u16) -> Self {
+        Self::new(false, max_power)
+    }
+
+    /// A device with its own supply drawing up to `max_power` mA from the
+    /// bus.
+    pub const fn self_powered(max_power: u16) -> Self {
+        Self::new(true, max_power)
+    }
+
+    const fn new(self_powered: bool, max_power: u16) -> Self {
+        assert!(max_power <= MAX_BUS_POWER);
+        Self {
+            self_powered,
+            max_power: (max_power / 2 + max_power % 2) as u8,
+        }
+    }
+
+    pub const fn is_self_powered(&self) -> bool {
+        self.self_powered
+    }
+
+    /// Maximum current in mA, as reported to the host.
+    pub const fn max_power(&self) -> u16 {
+        self.max_power as u16 * 2
+    }
+
+    /// Applies the power settings to the device configuration.
+    ///
+    /// Must be called before the configuration is passed to the
+    /// [`Builder`](embassy_usb::Builder).
+    #[cfg(feature = "usb")]
+    pub fn configure(&self, config: &mut Config<'_>) {
+        config.self_powered = self.self_powered;
+        config.max_power = self.max_power();
+    }
+}
+
+impl Default for PowerConfig {
+    /// The 100 mA a bus-powered device may draw before it is configured.
+    fn default() -> Self {
+        Self::bus_powered(100)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_descriptor_resolution() {
+        assert_eq!(PowerConfig::bus_powered(100).max_power(), 100);
+        assert_eq!(PowerConfig::bus_powered(99).max_power(), 100);
+        assert_eq!(PowerConfig::self_powered(0).max_power(), 0);
+        assert_eq!(PowerConfig::bus_powered(MAX_BUS_POWER).max_power(), MAX_BUS_POWER);
+    }
+
+    #[test]
+    #[should_panic]
+    fn rejects_excessive_current() {
+        PowerConfig::bus_powered(MAX_BUS_POWER + 2);
+    }
+}
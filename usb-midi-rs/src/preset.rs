@@ -0,0 +1,502 @@
+//! Presets kept in flash, e.g. routings the user saved.
+//!
+//! A [`PresetStore`] divides part of a NOR flash into slots of whole erase
+//! sectors, each holding one named [`Preset`] with a checksum and the
+//! version of its format. [`PresetObserver`]s learn about every change, so
+//! a configuration protocol and a user interface showing the presets stay in
+//! sync.
+//!
+//! The [`Router`](crate::Router) stores its routes and the
+//! [`Looper`](crate::Looper) its loop as presets. Velocity curves are
+//! closures and cannot be stored as they are; an application keeping curves
+//! in flash stores the parameters it builds them from as a [`Preset`] of its
+//! own.
+
+use embassy_sync::blocking_mutex::raw::RawMutex;
+use embassy_sync::signal::Signal;
+use embedded_storage_async::nor_flash::AsyncNorFlash;
+
+use crate::crc::{crc16, crc16_update};
+use crate::Error;
+
+const MAGIC: [u8; 2] = *b"PR";
+
+/// Longest name of a preset in bytes.
+pub const NAME_LEN: usize = 16;
+
+/// Magic, kind, version, name, data length and CRC.
+const HEADER_LEN: usize = 2 + 1 + 1 + NAME_LEN + 2 + 2;
+
+/// Something that can be stored in a [`PresetStore`].
+pub trait Preset: Sized {
+    /// Tells the kinds of presets apart, e.g. routings from patterns.
+    const KIND: u8;
+    /// Version of the format written by [`write`](Self::write), to be
+    /// increased whenever it changes.
+    const VERSION: u8;
+
+    /// Writes the preset to `data`, returning its length.
+    fn write(&self, data: &mut [u8]) -> Result<usize, Error>;
+
+    /// Reads a preset written with `version` of the format, which may be
+    /// older than [`VERSION`](Self::VERSION).
+    fn read(data: &[u8], version: u8) -> Result<Self, Error>;
+}
+
+/// Gets told about changes of a [`PresetStore`].
+pub trait PresetObserver {
+    /// The preset in `slot` was saved or deleted.
+    fn changed(&self, slot: usize);
+}
+
+/// Lets a task wait for changes, e.g. to redraw a list of presets.
+impl<M: RawMutex> PresetObserver for Signal<M, usize> {
+    fn changed(&self, slot: usize) {
+        self.signal(slot);
+    }
+}
+
+/// Failure of a [`PresetStore`].
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PresetError<E> {
+    Flash(E),
+    /// [`Error::Malformed`] for a damaged slot or a preset of another kind,
+    /// [`Error::Unsupported`] for a newer version and
+    /// [`Error::BufferOverflow`] for a preset not fitting the slot or the
+    /// buffer.
+    Preset(Error),
+}
+
+impl<E> From<Error> for PresetError<E> {
+    fn from(error: Error) -> Self {
+        PresetError::Preset(error)
+    }
+}
+
+/// Where the preset returned by [`PresetStore::load_or_default`] came from.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Loaded {
+    /// The slot held the preset in the current format.
+    Current,
+    /// The slot held the preset in an older format, which was converted.
+    /// Saving the preset stores it in the current format.
+    Migrated { from: u8 },
+    /// The slot was empty, so the preset is the default.
+    Empty,
+    /// The slot could not be read, e.g. because it was damaged or written by
+    /// a newer firmware, so the preset is the default. The slot is left as
+    /// it is until the preset is saved.
+    Defaulted(Error),
+}
+
+/// Kind, version and name of a stored preset.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PresetInfo {
+    pub kind: u8,
+    pub version: u8,
+    name: [u8; NAME_LEN],
+}
+
+impl PresetInfo {
+    pub fn name(&self) -> &str {
+        let len = self.name.iter().position(|&byte| byte == 0).unwrap_or(NAME_LEN);
+        core::str::from_utf8(&self.name[..len]).unwrap_or_default()
+    }
+}
+
+/// Named presets in `slots` slots of `slot_size` bytes of a NOR flash.
+///
+/// Presets are written through a buffer of `B` bytes, which limits their
+/// size together with the slot size. It has to hold the header and be a
+/// multiple of the read size of the flash.
+///
+/// A slot is erased before the preset is written to it, so if the power
+/// fails while saving, the preset that was in the slot is lost. Where that
+/// matters, save to a free slot and only then delete the old one.
+pub struct PresetStore<'a, F: AsyncNorFlash, const B: usize> {
+    flash: F,
+    offset: u32,
+    slot_size: u32,
+    slots: usize,
+    buffer: [u8; B],
+    observers: &'a [&'a dyn PresetObserver],
+}
+
+impl<'a, F: AsyncNorFlash, const B: usize> PresetStore<'a, F, B> {
+    const BUFFER_FITS: () = assert!(
+        B >= HEADER_LEN && round_up(B, F::READ_SIZE) == B,
+        "buffer must hold the header in whole reads"
+    );
+
+    /// Stores presets from `offset` of `flash`. The slot size has to be a
+    /// multiple of the erase size of the flash.
+    pub fn new(flash: F, offset: u32, slot_size: u32, slots: usize) -> Self {
+        assert_eq!(
+            slot_size as usize % F::ERASE_SIZE,
+            0,
+            "slots must be whole erase sectors"
+        );
+        let () = Self::BUFFER_FITS;
+        Self {
+            flash,
+            offset,
+            slot_size,
+            slots,
+            buffer: [0; B],
+            observers: &[],
+        }
+    }
+
+    /// Tells `observers` about every change.
+    pub fn with_observers(self, observers: &'a [&'a dyn PresetObserver]) -> Self {
+        Self { observers, ..self }
+    }
+
+    pub fn slots(&self) -> usize {
+        self.slots
+    }
+
+    pub fn into_inner(self) -> F {
+        self.flash
+    }
+
+    /// Saves `preset` under `name` to `slot`, replacing what was there,
+    /// which is lost if the power fails meanwhile.
+    pub async fn save<P: Preset>(&mut self, slot: usize, name: &str, preset: &P) -> Result<(), PresetError<F::Error>> {
+        let start = self.slot_offset(slot);
+        let name = name.as_bytes();
+        if name.len() > NAME_LEN {
+            return Err(Error::BufferOverflow.into());
+        }
+        let len = preset.write(&mut self.buffer[HEADER_LEN..])?;
+        let total = HEADER_LEN + len;
+        // Programming is only possible in whole words.
+        let written = round_up(total, F::WRITE_SIZE);
+        if len > usize::from(u16::MAX) || written > self.slot_size as usize || written > B {
+            return Err(Error::BufferOverflow.into());
+        }
+        let header = &mut self.buffer[..HEADER_LEN];
+        header[..2].copy_from_slice(&MAGIC);
+        header[2] = P::KIND;
+        header[3] = P::VERSION;
+        header[4..4 + NAME_LEN].fill(0);
+        header[4..4 + name.len()].copy_from_slice(name);
+        header[4 + NAME_LEN..6 + NAME_LEN].copy_from_slice(&(len as u16).to_le_bytes());
+        let crc = crc16(&self.buffer[2..HEADER_LEN - 2]);
+        let crc = crc16_update(crc, &self.buffer[HEADER_LEN..total]);
+        self.buffer[HEADER_LEN - 2..HEADER_LEN].copy_from_slice(&crc.to_le_bytes());
+        self.buffer[total..written].fill(0xff);
+
+        self.flash
+            .erase(start, start + self.slot_size)
+            .await
+            .map_err(PresetError::Flash)?;
+        self.flash
+            .write(start, &self.buffer[..written])
+            .await
+            .map_err(PresetError::Flash)?;
+        self.notify(slot);
+        Ok(())
+    }
+
+    /// Loads the preset in `slot`, if there is one.
+    pub async fn load<P: Preset>(&mut self, slot: usize) -> Result<Option<P>, PresetError<F::Error>> {
+        match self.read(slot).await? {
+            Some(info) => Ok(Some(self.decode(&info)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Loads the preset in `slot`, or the default preset if there is none or
+    /// it cannot be read, e.g. at startup, where a damaged or outdated
+    /// setting must not keep the device from working.
+    ///
+    /// Only fails if the flash does, telling otherwise what happened, so the
+    /// user can be told that the settings were reset.
+    pub async fn load_or_default<P: Preset + Default>(
+        &mut self,
+        slot: usize,
+    ) -> Result<(P, Loaded), PresetError<F::Error>> {
+        let decoded = match self.read(slot).await {
+            Ok(Some(info)) => self.decode(&info).map(|preset| (preset, info.version)),
+            Ok(None) => return Ok((P::default(), Loaded::Empty)),
+            Err(PresetError::Preset(error)) => Err(error),
+            Err(error) => return Err(error),
+        };
+        Ok(match decoded {
+            Ok((preset, version)) if version == P::VERSION => (preset, Loaded::Current),
+            Ok((preset, version)) => (preset, Loaded::Migrated { from: version }),
+            Err(error) => {
+                warn!("preset in slot {} replaced by the default: {}", slot, error);
+                (P::default(), Loaded::Defaulted(error))
+            }
+        })
+    }
+
+    /// Kind, version and name of the preset in `slot`, if there is one.
+    pub async fn info(&mut self, slot: usize) -> Result<Option<PresetInfo>, PresetError<F::Error>> {
+        self.read(slot).await
+    }
+
+    /// The first slot holding a preset named `name`.
+    pub async fn find(&mut self, name: &str) -> Result<Option<usize>, PresetError<F::Error>> {
+        for slot in 0..self.slots {
+            match self.read(slot).await {
+                Ok(Some(info)) if info.name() == name => return Ok(Some(slot)),
+                // A damaged slot does not keep the others from being found.
+                Ok(_) | Err(PresetError::Preset(_)) => {}
+                Err(error) => return Err(error),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Erases the preset in `slot`.
+    pub async fn delete(&mut self, slot: usize) -> Result<(), PresetError<F::Error>> {
+        let start = self.slot_offset(slot);
+        self.flash
+            .erase(start, start + self.slot_size)
+            .await
+            .map_err(PresetError::Flash)?;
+        self.notify(slot);
+        Ok(())
+    }
+
+    /// Reads the preset in `slot` to the buffer and checks it.
+    async fn read(&mut self, slot: usize) -> Result<Option<PresetInfo>, PresetError<F::Error>> {
+        let start = self.slot_offset(slot);
+        let header_len = round_up(HEADER_LEN, F::READ_SIZE);
+        self.flash
+            .read(start, &mut self.buffer[..header_len])
+            .await
+            .map_err(PresetError::Flash)?;
+        if self.buffer[..2] == [0xff, 0xff] {
+            return Ok(None);
+        }
+        if self.buffer[..2] != MAGIC {
+            return Err(Error::Malformed.into());
+        }
+        let len = u16::from_le_bytes([self.buffer[4 + NAME_LEN], self.buffer[5 + NAME_LEN]]);
+        let total = HEADER_LEN + usize::from(len);
+        let read = round_up(total, F::READ_SIZE);
+        if read > B || total > self.slot_size as usize {
+            return Err(Error::Malformed.into());
+        }
+        if read > header_len {
+            self.flash
+                .read(start + header_len as u32, &mut self.buffer[header_len..read])
+                .await
+                .map_err(PresetError::Flash)?;
+        }
+        let crc = crc16(&self.buffer[2..HEADER_LEN - 2]);
+        let crc = crc16_update(crc, &self.buffer[HEADER_LEN..total]);
+        if self.buffer[HEADER_LEN - 2..HEADER_LEN] != crc.to_le_bytes() {
+            return Err(Error::Malformed.into());
+        }
+        let mut name = [0; NAME_LEN];
+        name.copy_from_slice(&self.buffer[4..4 + NAME_LEN]);
+        Ok(Some(PresetInfo {
+            kind: self.buffer[2],
+            version: self.buffer[3],
+            name,
+        }))
+    }
+
+    /// Reads the preset described by `info` from the buffer.
+    fn decode<P: Preset>(&self, info: &PresetInfo) -> Result<P, Error> {
+        if info.kind != P::KIND {
+            return Err(Error::Malformed);
+        }
+        if info.version > P::VERSION {
+            return Err(Error::Unsupported);
+        }
+        let len = u16::from_le_bytes([self.buffer[4 + NAME_LEN], self.buffer[5 + NAME_LEN]]);
+        P::read(&self.buffer[HEADER_LEN..HEADER_LEN + usize::from(len)], info.version)
+    }
+
+    fn slot_offset(&self, slot: usize) -> u32 {
+        assert!(slot < self.slots, "slot out of range");
+        self.offset + slot as u32 * self.slot_size
+    }
+
+    fn notify(&self, slot: usize) {
+        for observer in self.observers {
+            observer.changed(slot);
+        }
+    }
+}
+
+const fn round_up(len: usize, multiple: usize) -> usize {
+    match len % multiple {
+        0 => len,
+        rest => len + multiple - rest,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::convert::Infallible;
+    use core::future::{ready, Ready};
+
+    use embassy_futures::block_on;
+    use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+    use embedded_storage_async::nor_flash::{AsyncReadNorFlash, ErrorType};
+
+    use super::*;
+    use crate::Router;
+
+    /// Flash with 4-byte words and 256-byte sectors.
+    struct Ram([u8; 1024]);
+
+    impl ErrorType for Ram {
+        type Error = Infallible;
+    }
+
+    impl AsyncReadNorFlash for Ram {
+        const READ_SIZE: usize = 4;
+        type ReadFuture<'a> = Ready<Result<(), Infallible>>;
+
+        fn read<'a>(&'a mut self, offset: u32, bytes: &'a mut [u8]) -> Self::ReadFuture<'a> {
+            assert_eq!(offset % 4, 0);
+            bytes.copy_from_slice(&self.0[offset as usize..offset as usize + bytes.len()]);
+            ready(Ok(()))
+        }
+
+        fn capacity(&self) -> usize {
+            self.0.len()
+        }
+    }
+
+    impl AsyncNorFlash for Ram {
+        const WRITE_SIZE: usize = 4;
+        const ERASE_SIZE: usize = 256;
+        type EraseFuture<'a> = Ready<Result<(), Infallible>>;
+        type WriteFuture<'a> = Ready<Result<(), Infallible>>;
+
+        fn erase(&mut self, from: u32, to: u32) -> Self::EraseFuture<'_> {
+            self.0[from as usize..to as usize].fill(0xff);
+            ready(Ok(()))
+        }
+
+        fn write<'a>(&'a mut self, offset: u32, bytes: &'a [u8]) -> Self::WriteFuture<'a> {
+            assert_eq!((offset as usize % 4, bytes.len() % 4), (0, 0));
+            for (cell, byte) in self.0[offset as usize..].iter_mut().zip(bytes) {
+                *cell &= byte;
+            }
+            ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn stores_presets() {
+        let changed = Signal::<NoopRawMutex, usize>::new();
+        let observers: [&dyn PresetObserver; 1] = [&changed];
+        let mut store = PresetStore::<_, 64>::new(Ram([0xff; 1024]), 256, 256, 3).with_observers(&observers);
+        let mut router = Router::<3>::new();
+        router.connect(0, 2);
+        router.connect(2, 31);
+
+        block_on(async {
+            assert_eq!(store.load::<Router<3>>(1).await, Ok(None));
+            store.save(1, "Live", &router).await.unwrap();
+            assert_eq!(changed.wait().await, 1);
+            assert_eq!(store.load(1).await, Ok(Some(router)));
+            let info = store.info(1).await.unwrap().unwrap();
+            assert_eq!((info.kind, info.version, info.name()), (1, 1, "Live"));
+            assert_eq!(store.find("Live").await, Ok(Some(1)));
+            assert_eq!(
+                store.save(0, "A name too long!!", &router).await,
+                Err(PresetError::Preset(Error::BufferOverflow))
+            );
+            assert_eq!(
+                store.load::<Router<2>>(1).await,
+                Err(PresetError::Preset(Error::Malformed))
+            );
+
+            store.delete(1).await.unwrap();
+            assert_eq!(store.find("Live").await, Ok(None));
+        });
+    }
+
+    /// Settings whose second byte was added with version 2.
+    #[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+    struct Settings(u8, u8);
+
+    impl Preset for Settings {
+        const KIND: u8 = 9;
+        const VERSION: u8 = 2;
+
+        fn write(&self, data: &mut [u8]) -> Result<usize, Error> {
+            data[..2].copy_from_slice(&[self.0, self.1]);
+            Ok(2)
+        }
+
+        fn read(data: &[u8], version: u8) -> Result<Self, Error> {
+            match (version, data) {
+                (1, &[a]) => Ok(Settings(a, 7)),
+                (2, &[a, b]) => Ok(Settings(a, b)),
+                _ => Err(Error::Malformed),
+            }
+        }
+    }
+
+    /// Settings as written by an older firmware.
+    struct Written(&'static [u8]);
+
+    impl Preset for Written {
+        const KIND: u8 = 9;
+        const VERSION: u8 = 1;
+
+        fn write(&self, data: &mut [u8]) -> Result<usize, Error> {
+            data[..self.0.len()].copy_from_slice(self.0);
+            Ok(self.0.len())
+        }
+
+        fn read(_data: &[u8], _version: u8) -> Result<Self, Error> {
+            Err(Error::Unsupported)
+        }
+    }
+
+    #[test]
+    fn migrates_or_defaults() {
+        let mut store = PresetStore::<_, 64>::new(Ram([0xff; 1024]), 0, 256, 4);
+        block_on(async {
+            assert_eq!(store.load_or_default(0).await, Ok((Settings(0, 0), Loaded::Empty)));
+            store.save(0, "", &Written(&[3])).await.unwrap();
+            assert_eq!(
+                store.load_or_default(0).await,
+                Ok((Settings(3, 7), Loaded::Migrated { from: 1 }))
+            );
+            store.save(0, "", &Settings(3, 7)).await.unwrap();
+            assert_eq!(store.load_or_default(0).await, Ok((Settings(3, 7), Loaded::Current)));
+
+            store.save(1, "", &Written(&[1, 2, 3])).await.unwrap();
+            assert_eq!(
+                store.load_or_default(1).await,
+                Ok((Settings(0, 0), Loaded::Defaulted(Error::Malformed)))
+            );
+            store.save(2, "", &Router::<1>::new()).await.unwrap();
+            assert_eq!(
+                store.load_or_default::<Settings>(2).await.unwrap().1,
+                Loaded::Defaulted(Error::Malformed)
+            );
+        });
+    }
+
+    #[test]
+    fn detects_damage() {
+        let mut store = PresetStore::<_, 64>::new(Ram([0xff; 1024]), 0, 256, 4);
+        block_on(store.save(2, "Studio", &Router::<1>::new())).unwrap();
+        let mut flash = store.into_inner();
+        flash.0[2 * 256 + HEADER_LEN] ^= 1;
+        let mut store = PresetStore::<_, 64>::new(flash, 0, 256, 4);
+        assert_eq!(
+            block_on(store.load::<Router<1>>(2)),
+            Err(PresetError::Preset(Error::Malformed))
+        );
+        assert_eq!(block_on(store.find("Studio")), Ok(None));
+    }
+}
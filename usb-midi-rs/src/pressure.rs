@@ -0,0 +1,189 @@
+use heapless::Vec;
+
+use crate::transport::{forward_with, MidiSink, MidiSource};
+use crate::{Channel, Error, Event, MidiMessage, NoteTracker};
+
+/// Turns Channel Pressure into Poly Key Pressure of every key held down on
+/// the channel, for synths that only respond to the latter.
+///
+/// Notes are followed with a [`NoteTracker`], so the converter has to see
+/// the Note Ons and Note Offs of the stream. Channel Pressure while no key
+/// is held down is dropped. All other events pass unchanged.
+#[derive(Clone, Eq, PartialEq, Debug, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ChannelToPolyPressure {
+    notes: NoteTracker,
+}
+
+impl ChannelToPolyPressure {
+    pub const fn new() -> Self {
+        Self {
+            notes: NoteTracker::new(),
+        }
+    }
+
+    /// Events that `event` turns into.
+    pub fn process(&mut self, event: Event) -> PolyPressures {
+        match event {
+            Event::ChannelPressure(status, pressure) => PolyPressures {
+                event: None,
+                status: status & 0x0f | 0xa0,
+                pressure,
+                keys: self.notes.held_keys(Channel::new(status & 0x0f)),
+            },
+            event => {
+                if let Ok(message) = MidiMessage::try_from(event) {
+                    self.notes.update(message);
+                }
+                PolyPressures {
+                    event: Some(event),
+                    status: 0,
+                    pressure: 0,
+                    keys: 0,
+                }
+            }
+        }
+    }
+
+    /// Converts the events of `source` for `sink`, see [`forward_with`].
+    pub async fn run(&mut self, source: &mut impl MidiSource, sink: &mut impl MidiSink) -> Error {
+        forward_with(source, sink, |event| self.process(event)).await
+    }
+}
+
+/// Events of [`ChannelToPolyPressure::process`].
+pub struct PolyPressures {
+    /// An event passing unchanged.
+    event: Option<Event>,
+    status: u8,
+    pressure: u8,
+    /// Keys still to send the pressure to, one bit per note.
+    keys: u128,
+}
+
+impl Iterator for PolyPressures {
+    type Item = Event;
+
+    fn next(&mut self) -> Option<Event> {
+        if let Some(event) = self.event.take() {
+            return Some(event);
+        }
+        if self.keys == 0 {
+            return None;
+        }
+        let note = self.keys.trailing_zeros() as u8;
+        self.keys &= !(1 << note);
+        Some(Event::PolyKeyPress(self.status, note, self.pressure))
+    }
+}
+
+/// Turns Poly Key Pressure into Channel Pressure, for synths that only
+/// respond to the latter.
+///
+/// The Channel Pressure follows the highest pressure on any key of the
+/// channel and is only sent when that changes. A key counts with no pressure
+/// once it is released, so the converter has to see the Note Offs of the
+/// stream. Channel Pressure arriving as such passes, like all other events.
+///
+/// The pressure of every key is kept, which takes 2 KiB.
+#[derive(Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PolyToChannelPressure {
+    keys: [[u8; 128]; 16],
+    /// Channel Pressure last sent on every channel.
+    sent: [u8; 16],
+}
+
+impl PolyToChannelPressure {
+    pub const fn new() -> Self {
+        Self {
+            keys: [[0; 128]; 16],
+            sent: [0; 16],
+        }
+    }
+
+    /// Events that `event` turns into.
+    pub fn process(&mut self, event: Event) -> impl Iterator<Item = Event> {
+        let mut events = Vec::<Event, 2>::new();
+        let (status, note, pressure) = match event {
+            Event::PolyKeyPress(status, note, pressure) => (status, note, pressure),
+            Event::NoteOn(status, note, _) | Event::NoteOff(status, note, _) => {
+                let _ = events.push(event);
+                (status, note.number(), 0)
+            }
+            event => {
+                let _ = events.push(event);
+                return events.into_iter();
+            }
+        };
+        let index = status as usize & 0x0f;
+        self.keys[index][note as usize & 0x7f] = pressure;
+        let highest = self.keys[index].iter().copied().max().unwrap_or(0);
+        if highest != self.sent[index] {
+            self.sent[index] = highest;
+            let _ = events.push(Event::ChannelPressure(status & 0x0f | 0xd0, highest));
+        }
+        events.into_iter()
+    }
+
+    /// Converts the events of `source` for `sink`, see [`forward_with`].
+    pub async fn run(&mut self, source: &mut impl MidiSource, sink: &mut impl MidiSink) -> Error {
+        forward_with(source, sink, |event| self.process(event)).await
+    }
+}
+
+impl Default for PolyToChannelPressure {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Note;
+
+    fn note_on(note: u8) -> Event {
+        Event::NoteOn(0x92, Note::new(note), 100)
+    }
+
+    #[test]
+    fn spreads_channel_pressure() {
+        let mut converter = ChannelToPolyPressure::new();
+        assert_eq!(converter.process(Event::ChannelPressure(0xd2, 40)).count(), 0);
+        for note in [64, 60, 67] {
+            assert_eq!(
+                converter.process(note_on(note)).collect::<std::vec::Vec<_>>(),
+                [note_on(note)]
+            );
+        }
+        converter.process(Event::NoteOff(0x82, Note::new(64), 0)).for_each(drop);
+        assert_eq!(
+            converter
+                .process(Event::ChannelPressure(0xd2, 40))
+                .collect::<std::vec::Vec<_>>(),
+            [Event::PolyKeyPress(0xa2, 60, 40), Event::PolyKeyPress(0xa2, 67, 40)]
+        );
+        assert_eq!(converter.process(Event::ChannelPressure(0xd3, 40)).count(), 0);
+    }
+
+    #[test]
+    fn follows_highest_poly_pressure() {
+        let mut converter = PolyToChannelPressure::new();
+        let mut process = |event| converter.process(event).collect::<std::vec::Vec<_>>();
+        assert_eq!(
+            process(Event::PolyKeyPress(0xa2, 60, 30)),
+            [Event::ChannelPressure(0xd2, 30)]
+        );
+        assert_eq!(
+            process(Event::PolyKeyPress(0xa2, 64, 50)),
+            [Event::ChannelPressure(0xd2, 50)]
+        );
+        // Easing off a key below the highest one changes nothing.
+        assert_eq!(process(Event::PolyKeyPress(0xa2, 60, 20)), []);
+        let release = Event::NoteOff(0x82, Note::new(64), 0);
+        assert_eq!(process(release), [release, Event::ChannelPressure(0xd2, 20)]);
+        let event = Event::ControlChange(0xb2, 1, 3);
+        assert_eq!(process(event), [event]);
+    }
+}
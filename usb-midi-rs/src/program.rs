@@ -0,0 +1,138 @@
+use heapless::Vec;
+
+use crate::cc::{BANK_SELECT, BANK_SELECT_LSB};
+use crate::transport::{forward_with, MidiSink, MidiSource};
+use crate::{Channel, Error, Event};
+
+/// A sound selected by Bank Select and Program Change.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Patch {
+    /// 14-bit bank number, MSB first.
+    pub bank: u16,
+    pub program: u8,
+}
+
+impl Patch {
+    pub const fn new(bank: u16, program: u8) -> Self {
+        Self { bank, program }
+    }
+}
+
+/// Selects patches as a whole and remaps them.
+///
+/// Bank Selects (controllers 0 and 32) are held back until the Program
+/// Change of their channel arrives, which then goes out together with the
+/// complete bank number. Merged with other streams, a Program Change can thus
+/// never end up with the bank of someone else. The patch is replaced if it
+/// is found in the map.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ProgramMapper<'a> {
+    map: &'a [(Patch, Patch)],
+    banks: [u16; 16],
+}
+
+impl<'a> ProgramMapper<'a> {
+    /// A mapper replacing the first patch of every pair in `map` with the
+    /// second one.
+    pub const fn new(map: &'a [(Patch, Patch)]) -> Self {
+        Self { map, banks: [0; 16] }
+    }
+
+    /// Bank selected on `channel` for the next Program Change.
+    pub fn bank(&self, channel: Channel) -> u16 {
+        self.banks[channel.number() as usize]
+    }
+
+    /// Events that `event` turns into: nothing for a Bank Select, the Bank
+    /// Selects and the Program Change of the mapped patch for a Program
+    /// Change and the event itself otherwise.
+    pub fn process(&mut self, event: Event) -> impl Iterator<Item = Event> {
+        let mut events = Vec::<Event, 3>::new();
+        match event {
+            Event::ControlChange(status, BANK_SELECT, value) => {
+                let bank = &mut self.banks[status as usize & 0x0f];
+                *bank = *bank & 0x7f | u16::from(value & 0x7f) << 7;
+            }
+            Event::ControlChange(status, BANK_SELECT_LSB, value) => {
+                let bank = &mut self.banks[status as usize & 0x0f];
+                *bank = *bank & 0x3f80 | u16::from(value & 0x7f);
+            }
+            Event::ProgramChange(status, program) => {
+                let patch = self.map(Patch::new(self.banks[status as usize & 0x0f], program));
+                let control = status & 0x0f | 0xb0;
+                let (msb, lsb) = ((patch.bank >> 7) as u8, (patch.bank & 0x7f) as u8);
+                let _ = events.push(Event::ControlChange(control, BANK_SELECT, msb));
+                let _ = events.push(Event::ControlChange(control, BANK_SELECT_LSB, lsb));
+                let _ = events.push(Event::ProgramChange(status, patch.program));
+            }
+            event => {
+                let _ = events.push(event);
+            }
+        }
+        events.into_iter()
+    }
+
+    /// The patch `patch` is mapped to.
+    pub fn map(&self, patch: Patch) -> Patch {
+        self.map
+            .iter()
+            .find(|(from, _)| *from == patch)
+            .map_or(patch, |&(_, to)| to)
+    }
+
+    /// Maps the events of `source` for `sink`, see [`forward_with`].
+    pub async fn run(&mut self, source: &mut impl MidiSource, sink: &mut impl MidiSink) -> Error {
+        forward_with(source, sink, |event| self.process(event)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAP: [(Patch, Patch); 1] = [(Patch::new(0x0081, 5), Patch::new(0x0200, 17))];
+
+    fn process(mapper: &mut ProgramMapper, event: Event) -> std::vec::Vec<Event> {
+        mapper.process(event).collect()
+    }
+
+    #[test]
+    fn selects_patches_atomically() {
+        let mut mapper = ProgramMapper::new(&MAP);
+        assert_eq!(process(&mut mapper, Event::ControlChange(0xb2, 0, 1)), []);
+        assert_eq!(process(&mut mapper, Event::ControlChange(0xb2, 32, 3)), []);
+        assert_eq!(mapper.bank(Channel::new(2)), 0x0083);
+        assert_eq!(
+            process(&mut mapper, Event::ProgramChange(0xc2, 9)),
+            [
+                Event::ControlChange(0xb2, 0, 1),
+                Event::ControlChange(0xb2, 32, 3),
+                Event::ProgramChange(0xc2, 9),
+            ]
+        );
+        // Other channels keep their own bank.
+        assert_eq!(
+            process(&mut mapper, Event::ProgramChange(0xc0, 9))[0],
+            Event::ControlChange(0xb0, 0, 0)
+        );
+    }
+
+    #[test]
+    fn maps_patches() {
+        let mut mapper = ProgramMapper::new(&MAP);
+        process(&mut mapper, Event::ControlChange(0xb0, 0, 1));
+        process(&mut mapper, Event::ControlChange(0xb0, 32, 1));
+        assert_eq!(
+            process(&mut mapper, Event::ProgramChange(0xc0, 5)),
+            [
+                Event::ControlChange(0xb0, 0, 4),
+                Event::ControlChange(0xb0, 32, 0),
+                Event::ProgramChange(0xc0, 17),
+            ]
+        );
+        let event = Event::NoteOn(0x90, crate::Note::new(60), 1);
+        assert_eq!(process(&mut mapper, event), [event]);
+    }
+}
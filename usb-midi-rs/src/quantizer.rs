@@ -0,0 +1,169 @@
+use embassy_time::{Duration, Instant};
+
+use crate::schedule::{run_timed, Schedule, Timed};
+use crate::transport::{MidiSink, MidiSource};
+use crate::{ClockFollower, Error, Event, MidiMessage, PPQN};
+
+/// Moves played notes towards the grid of the MIDI clock.
+///
+/// A Note On played shortly before a subdivision of the beat, at most the
+/// window ahead, is delayed towards it by `strength` percent of the
+/// distance. Notes played late cannot be moved back and pass at once, as do
+/// all notes while the tempo of the clock is unknown. The Note Off of a
+/// delayed note follows it, and everything else passes unchanged.
+///
+/// Up to `P` notes can wait at a time; more pass unquantized.
+pub struct Quantizer<const P: usize> {
+    clock: ClockFollower,
+    /// Clock pulses since the start, and when the last one arrived.
+    pulses: u32,
+    last_pulse: Option<Instant>,
+    subdivision: u8,
+    strength: u8,
+    window: Duration,
+    pending: Schedule<Event, P>,
+}
+
+impl<const P: usize> Quantizer<P> {
+    /// Quantizes to subdivisions of `subdivision` clock pulses, e.g. 6 for
+    /// sixteenth notes, with `strength` from 0 to 100 percent.
+    pub fn new(subdivision: u8, strength: u8, window: Duration) -> Self {
+        assert!(subdivision > 0, "subdivision must not be empty");
+        Self {
+            clock: ClockFollower::new(),
+            pulses: 0,
+            last_pulse: None,
+            subdivision,
+            strength: strength.min(100),
+            window,
+            pending: Schedule::new(),
+        }
+    }
+
+    /// Takes `event` received at `at` into account and returns it if it is
+    /// to be sent at once. Otherwise it is returned by [`poll`](Self::poll)
+    /// later.
+    pub fn process(&mut self, event: Event, at: Instant) -> Option<Event> {
+        match event {
+            Event::NoteOn(_, _, velocity) if velocity > 0 => match self.grid_delay(at) {
+                Some(delay) if delay > Duration::from_ticks(0) => self.pending.insert(at + delay, event),
+                _ => Some(event),
+            },
+            Event::NoteOn(..) | Event::NoteOff(..) => self.pending.insert_note_off(event),
+            event => {
+                if let Ok(message) = MidiMessage::try_from(event) {
+                    self.follow(message, at);
+                }
+                Some(event)
+            }
+        }
+    }
+
+    /// Returns the next event due at `now`, if any.
+    pub fn poll(&mut self, now: Instant) -> Option<Event> {
+        self.pending.poll(now)
+    }
+
+    /// When the next waiting event is due.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.pending.deadline()
+    }
+
+    /// Quantizes the events of `source` for `sink` until sending fails.
+    pub async fn run(&mut self, source: &mut impl MidiSource, sink: &mut impl MidiSink) -> Error {
+        run_timed(self, source, sink).await
+    }
+
+    fn follow(&mut self, message: MidiMessage, at: Instant) {
+        self.clock.update(message, at);
+        match message {
+            MidiMessage::TimingClock => {
+                self.pulses = self.pulses.wrapping_add(1);
+                self.last_pulse = Some(at);
+            }
+            MidiMessage::Start => self.pulses = 0,
+            _ => {}
+        }
+    }
+
+    /// How long to delay a note played at `at`, if the grid is known.
+    fn grid_delay(&self, at: Instant) -> Option<Duration> {
+        let interval = self.clock.beat_duration()? / PPQN;
+        let last_pulse = self.last_pulse?;
+        // The grid point the last pulse is past, and the one after it.
+        let past = (self.pulses.wrapping_sub(1) % u32::from(self.subdivision)) * interval;
+        let previous = last_pulse.checked_sub(past)?;
+        let next = previous + interval * u32::from(self.subdivision);
+        let ahead = next.checked_duration_since(at)?;
+        let behind = at.checked_duration_since(previous)?;
+        if ahead >= behind || ahead > self.window {
+            return Some(Duration::from_ticks(0));
+        }
+        Some(ahead * u32::from(self.strength) / 100)
+    }
+}
+
+impl<const P: usize> Timed for Quantizer<P> {
+    fn process(&mut self, event: Event, at: Instant) -> Option<Event> {
+        Quantizer::process(self, event, at)
+    }
+
+    fn poll(&mut self, now: Instant) -> Option<Event> {
+        Quantizer::poll(self, now)
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        Quantizer::deadline(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Channel, Note};
+
+    /// Pulse interval at 125 BPM.
+    const PULSE: u64 = 20_000;
+
+    fn at(micros: u64) -> Instant {
+        Instant::from_micros(micros)
+    }
+
+    fn note_on(note: u8) -> Event {
+        MidiMessage::NoteOn(Channel::new(0), Note::new(note), 100).into()
+    }
+
+    fn quantizer() -> Quantizer<4> {
+        let mut quantizer = Quantizer::new(6, 50, Duration::from_micros(3 * PULSE));
+        quantizer.process(MidiMessage::Start.into(), at(0));
+        for pulse in 0..8 {
+            let clock = MidiMessage::TimingClock.into();
+            assert_eq!(quantizer.process(clock, at(pulse * PULSE)), Some(clock));
+        }
+        quantizer
+    }
+
+    #[test]
+    fn delays_early_notes() {
+        let mut quantizer = quantizer();
+        // Played two pulses before the subdivision at pulse 12.
+        let played = 10 * PULSE;
+        assert_eq!(quantizer.process(note_on(60), at(played)), None);
+        let note_off = MidiMessage::NoteOff(Channel::new(0), Note::new(60), 0).into();
+        assert_eq!(quantizer.process(note_off, at(played + 1)), None);
+        assert_eq!(quantizer.deadline(), Some(at(11 * PULSE)));
+        assert_eq!(quantizer.poll(at(11 * PULSE - 1)), None);
+        assert_eq!(quantizer.poll(at(11 * PULSE)), Some(note_on(60)));
+        assert_eq!(quantizer.poll(at(11 * PULSE)), Some(note_off));
+        assert_eq!(quantizer.deadline(), None);
+    }
+
+    #[test]
+    fn passes_late_and_distant_notes() {
+        let mut quantizer = quantizer();
+        assert_eq!(quantizer.process(note_on(60), at(7 * PULSE)), Some(note_on(60)));
+        assert_eq!(quantizer.process(note_on(61), at(8 * PULSE)), Some(note_on(61)));
+        let mut quantizer = Quantizer::<4>::new(6, 100, Duration::from_micros(PULSE));
+        assert_eq!(quantizer.process(note_on(62), at(0)), Some(note_on(62)));
+    }
+}
@@ -0,0 +1,62 @@
+use embassy_futures::select::select;
+use embassy_sync::blocking_mutex::raw::RawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Timer};
+use embassy_usb::driver::Driver;
+use embassy_usb::UsbDevice;
+
+/// How long the device stays off the bus, long enough for every host to
+/// notice.
+const DISCONNECT_TIME: Duration = Duration::from_millis(200);
+
+/// Makes the host enumerate the device again without unplugging it, e.g.
+/// after port names or the port count were changed.
+///
+/// The device is disabled, which on most drivers removes the pull-up of D+
+/// or disconnects the core, so the host sees it unplugged and plugged in
+/// again.
+pub struct SoftReconnect<M: RawMutex> {
+    request: Signal<M, ()>,
+}
+
+impl<M: RawMutex> SoftReconnect<M> {
+    pub const fn new() -> Self {
+        Self { request: Signal::new() }
+    }
+
+    /// Asks the task running the device to reconnect it.
+    pub fn force_reenumeration(&self) {
+        self.request.signal(());
+    }
+
+    /// Runs `device` until re-enumeration is forced, then takes it off the
+    /// bus.
+    ///
+    /// Returns with the device still disabled, so the application can build
+    /// a new device with different descriptors, e.g. another port count,
+    /// which connects again once it runs.
+    pub async fn run_until_reenumeration<'d, D: Driver<'d>>(&self, device: &mut UsbDevice<'d, D>) {
+        self.request.reset();
+        select(device.run(), self.request.wait()).await;
+        debug!("forcing re-enumeration");
+        device.disable().await;
+        Timer::after(DISCONNECT_TIME).await;
+    }
+
+    /// Runs `device`, reconnecting it with the same descriptors whenever
+    /// re-enumeration is forced.
+    ///
+    /// Replaces [`UsbDevice::run`]. Descriptors read through handlers, such
+    /// as [`PortNames`](crate::PortNames), are current after reconnecting.
+    pub async fn run<'d, D: Driver<'d>>(&self, device: &mut UsbDevice<'d, D>) -> ! {
+        loop {
+            self.run_until_reenumeration(device).await;
+        }
+    }
+}
+
+impl<M: RawMutex> Default for SoftReconnect<M> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,176 @@
+use crate::sysex::{SYSEX_END, SYSEX_START};
+use crate::Error;
+
+/// Command byte of a request for a [`HealthReport`].
+pub const REPORT_REQUEST: u8 = 0x01;
+/// Command byte of a [`HealthReport`].
+pub const REPORT_REPLY: u8 = 0x02;
+const REPORT_VERSION: u8 = 1;
+
+/// Bytes taken by a number in a report.
+pub(crate) const NUMBER_LEN: usize = 5;
+
+/// Statistics of one cable in a [`HealthReport`].
+#[derive(Copy, Clone, Default, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct CableReport {
+    pub received: u32,
+    pub sent: u32,
+    pub dropped: u32,
+    /// Most events waiting in the queue of the cable at any time.
+    pub queue_high_water: u32,
+}
+
+/// Health of a device, sent as a System Exclusive message so host tools can
+/// monitor devices in the field without a debugger.
+///
+/// The message is `F0 <manufacturer> 02 <version> <uptime> <errors> <cable
+/// count>` followed by the received, sent, dropped and high-water counts of
+/// each cable and `F7`. Numbers are sent as five 7-bit groups, the most
+/// significant one first. Devices may send the report periodically or answer
+/// requests recognized by [`is_report_request`](Self::is_report_request).
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct HealthReport<const N: usize> {
+    /// Seconds since the device started.
+    pub uptime: u32,
+    pub errors: u32,
+    pub cables: [CableReport; N],
+}
+
+impl<const N: usize> HealthReport<N> {
+    /// Length of the message with the given manufacturer ID.
+    pub const fn len(manufacturer: &[u8]) -> usize {
+        1 + manufacturer.len() + 2 + 2 * NUMBER_LEN + 1 + N * 4 * NUMBER_LEN + 1
+    }
+
+    /// Whether the complete System Exclusive `message` is a request for a
+    /// report, i.e. `F0 <manufacturer> 01 F7`.
+    pub fn is_report_request(message: &[u8], manufacturer: &[u8]) -> bool {
+        match message.strip_prefix(&[SYSEX_START][..]) {
+            Some(rest) => rest.strip_prefix(manufacturer) == Some(&[REPORT_REQUEST, SYSEX_END][..]),
+            None => false,
+        }
+    }
+
+    /// Writes the report to `data`, returning its length.
+    ///
+    /// Fails with [`Error::BufferOverflow`] if `data` is shorter than
+    /// [`len`](Self::len).
+    pub fn write(&self, manufacturer: &[u8], data: &mut [u8]) -> Result<usize, Error> {
+        let len = Self::len(manufacturer);
+        let data = data.get_mut(..len).ok_or(Error::BufferOverflow)?;
+        let mut writer = Writer { data, len: 0 };
+        writer.bytes(&[SYSEX_START]);
+        writer.bytes(manufacturer);
+        writer.bytes(&[REPORT_REPLY, REPORT_VERSION]);
+        writer.number(self.uptime);
+        writer.number(self.errors);
+        writer.bytes(&[N as u8]);
+        for cable in &self.cables {
+            for number in [cable.received, cable.sent, cable.dropped, cable.queue_high_water] {
+                writer.number(number);
+            }
+        }
+        writer.bytes(&[SYSEX_END]);
+        Ok(len)
+    }
+}
+
+/// Writes the parts of a report to a buffer known to be large enough.
+pub(crate) struct Writer<'a> {
+    pub(crate) data: &'a mut [u8],
+    pub(crate) len: usize,
+}
+
+impl Writer<'_> {
+    pub(crate) fn bytes(&mut self, bytes: &[u8]) {
+        self.data[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+        self.len += bytes.len();
+    }
+
+    pub(crate) fn number(&mut self, number: u32) {
+        for shift in (0..NUMBER_LEN).rev() {
+            self.bytes(&[(number >> (7 * shift)) as u8 & 0x7f]);
+        }
+    }
+}
+
+/// Reads a number written by [`Writer::number`], or `None` if `groups` are
+/// not five 7-bit groups of a `u32`.
+pub(crate) fn read_number(groups: &[u8]) -> Option<u32> {
+    // The first group carries the top 4 bits only.
+    match groups {
+        [first, rest @ ..] if groups.len() == NUMBER_LEN && *first <= 0x0f && rest.iter().all(|&byte| byte <= 0x7f) => {
+            Some(groups.iter().fold(0, |number, &byte| number << 7 | u32::from(byte)))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MANUFACTURER: &[u8] = &[0x7d];
+
+    #[test]
+    fn writes_reports() {
+        let report = HealthReport {
+            uptime: 200,
+            errors: 0xffff_ffff,
+            cables: [CableReport {
+                received: 1,
+                sent: 2,
+                dropped: 3,
+                queue_high_water: 4,
+            }],
+        };
+        let mut data = [0; 64];
+        let len = report.write(MANUFACTURER, &mut data).unwrap();
+        assert_eq!(len, HealthReport::<1>::len(MANUFACTURER));
+        assert_eq!(
+            data[..len],
+            [
+                0xf0, 0x7d, 0x02, 0x01, 0, 0, 0, 0x01, 0x48, 0x0f, 0x7f, 0x7f, 0x7f, 0x7f, 1, 0, 0, 0, 0, 1, 0, 0, 0,
+                0, 2, 0, 0, 0, 0, 3, 0, 0, 0, 0, 4, 0xf7
+            ]
+        );
+        assert_eq!(
+            report.write(MANUFACTURER, &mut data[..len - 1]),
+            Err(Error::BufferOverflow)
+        );
+    }
+
+    #[test]
+    fn reads_numbers() {
+        let mut data = [0; NUMBER_LEN];
+        Writer {
+            data: &mut data,
+            len: 0,
+        }
+        .number(u32::MAX);
+        assert_eq!(data, [0x0f, 0x7f, 0x7f, 0x7f, 0x7f]);
+        assert_eq!(read_number(&data), Some(u32::MAX));
+        // Groups beyond 32 bits are not folded away.
+        assert_eq!(read_number(&[0x10, 0, 0, 0, 1]), None);
+        assert_eq!(read_number(&[0, 0, 0, 0x80, 1]), None);
+        assert_eq!(read_number(&[0, 0, 1]), None);
+    }
+
+    #[test]
+    fn recognizes_requests() {
+        assert!(HealthReport::<1>::is_report_request(
+            &[0xf0, 0x7d, 0x01, 0xf7],
+            MANUFACTURER
+        ));
+        assert!(!HealthReport::<1>::is_report_request(
+            &[0xf0, 0x7e, 0x01, 0xf7],
+            MANUFACTURER
+        ));
+        assert!(!HealthReport::<1>::is_report_request(
+            &[0xf0, 0x7d, 0x02, 0xf7],
+            MANUFACTURER
+        ));
+    }
+}
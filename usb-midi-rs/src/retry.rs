@@ -0,0 +1,208 @@
+use embassy_sync::blocking_mutex::raw::RawMutex;
+use embassy_time::{Duration, Timer};
+use embassy_usb::driver::Driver;
+
+use crate::{CableNumber, Error, Event, MidiMessage, Sender, TxQueue, MAX_PACKET_SIZE};
+
+/// How often a failed write is retried, and how long to wait in between.
+///
+/// The delay doubles with every retry, up to `max_delay`.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct RetryPolicy {
+    /// Retries after the first attempt.
+    pub retries: u8,
+    /// Delay before the first retry.
+    pub initial_delay: Duration,
+    /// Longest delay between two retries.
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+    /// Fails on the first error.
+    pub const NEVER: Self = Self::new(0, Duration::from_ticks(0));
+
+    pub const fn new(retries: u8, initial_delay: Duration) -> Self {
+        Self {
+            retries,
+            initial_delay,
+            max_delay: Duration::from_millis(100),
+        }
+    }
+}
+
+impl Default for RetryPolicy {
+    /// Four retries within 75 ms, long enough to bridge a quick suspend and
+    /// resume.
+    fn default() -> Self {
+        Self::new(4, Duration::from_millis(5))
+    }
+}
+
+/// Whether a write failing with `error` may succeed when repeated.
+///
+/// The endpoints are disabled for a moment while the bus is suspended and
+/// resumed or reset, which looks like a disconnect. Buffer overflows and
+/// cables the device does not declare fail again.
+fn is_transient(error: Error) -> bool {
+    matches!(error, Error::Disconnected | Error::Timeout)
+}
+
+/// A [`Sender`] retrying writes that fail with transient errors, each cable
+/// with its own [`RetryPolicy`].
+///
+/// Events are not reordered: a write being retried holds back all later
+/// writes, also those to other cables.
+pub struct RetryingSender<'d, D: Driver<'d>, const N: usize> {
+    sender: Sender<'d, D, N>,
+    policies: [RetryPolicy; N],
+    retries: u32,
+}
+
+impl<'d, D: Driver<'d>, const N: usize> RetryingSender<'d, D, N> {
+    /// Retries writes to all cables with the default policy.
+    pub fn new(sender: Sender<'d, D, N>) -> Self {
+        Self {
+            sender,
+            policies: [RetryPolicy::default(); N],
+            retries: 0,
+        }
+    }
+
+    pub fn set_policy(&mut self, cable: CableNumber<N>, policy: RetryPolicy) {
+        self.policies[cable.number() as usize] = policy;
+    }
+
+    pub fn policy(&self, cable: CableNumber<N>) -> RetryPolicy {
+        self.policies[cable.number() as usize]
+    }
+
+    /// Number of retries so far, a measure of how unreliable the bus is.
+    pub fn retries(&self) -> u32 {
+        self.retries
+    }
+
+    pub fn into_inner(self) -> Sender<'d, D, N> {
+        self.sender
+    }
+
+    /// Writes an event to the host, retrying according to the policy of
+    /// `cable`.
+    ///
+    /// The error of the last attempt is returned once the retries are used
+    /// up.
+    pub async fn write_event(&mut self, cable: CableNumber<N>, event: Event) -> Result<(), Error> {
+        if usize::from(cable.number()) >= self.sender.ports() {
+            return Err(Error::Unsupported);
+        }
+        self.write_packet(&event.to_packet(cable.number()), self.policy(cable))
+            .await
+    }
+
+    /// Writes raw packets, retrying according to `policy`.
+    async fn write_packet(&mut self, data: &[u8], policy: RetryPolicy) -> Result<(), Error> {
+        let mut delay = policy.initial_delay;
+        let mut retries = 0;
+        loop {
+            match self.sender.write_packet(data).await.map_err(Error::from) {
+                Err(error) if is_transient(error) && retries < policy.retries => {
+                    debug!("write failed: {}, retrying", error);
+                    retries += 1;
+                    self.retries = self.retries.wrapping_add(1);
+                    Timer::after(delay).await;
+                    delay = (delay * 2).min(policy.max_delay);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    pub async fn write_message(&mut self, cable: CableNumber<N>, message: MidiMessage) -> Result<(), Error> {
+        self.write_event(cable, message.into()).await
+    }
+
+    /// Sends the events of `tx` for as long as the device runs, like
+    /// [`TxQueue::run`].
+    ///
+    /// A transfer carrying events for several cables is retried according
+    /// to the policy with the most retries among them.
+    pub async fn run<M: RawMutex, const Q: usize>(&mut self, tx: &TxQueue<M, N, Q>) -> ! {
+        let mut buf = [0; MAX_PACKET_SIZE as usize];
+        loop {
+            let buf = &mut buf[..self.sender.packet_size().bytes()];
+            let (len, cables) = tx.gather(buf, self.sender.ports()).await;
+            let policy = (0..N)
+                .filter(|&cable| cables & (1 << cable) != 0)
+                .map(|cable| self.policies[cable])
+                .max_by_key(|policy| policy.retries);
+            if let Some(policy) = policy {
+                if let Err(error) = self.write_packet(&buf[..len], policy).await {
+                    debug!("{} events dropped: {}", len / 4, error);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::future::Future;
+    use core::pin::{pin, Pin};
+    use core::task::Poll;
+
+    use super::*;
+    use crate::fault::{endpoints, poll_once, Faults, FaultyDriver, Pipe};
+
+    fn sender(pipe: &Pipe, faults: &Faults) -> RetryingSender<'static, FaultyDriver<Pipe>, 2> {
+        RetryingSender::new(Sender::from_endpoint(endpoints(pipe, faults).1, 2))
+    }
+
+    /// Polls `future` until it completes, letting the timers expire.
+    fn finish<F: Future>(mut future: Pin<&mut F>) -> F::Output {
+        loop {
+            if let Poll::Ready(output) = poll_once(future.as_mut()) {
+                return output;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn bridges_short_outages() {
+        let (pipe, faults) = (Pipe::default(), Faults::default());
+        let mut sender = sender(&pipe, &faults);
+        let cable = CableNumber::new(0).unwrap();
+        sender.set_policy(cable, RetryPolicy::new(3, Duration::from_millis(1)));
+
+        faults.disable();
+        {
+            let mut write = pin!(sender.write_message(cable, MidiMessage::Start));
+            assert!(poll_once(write.as_mut()).is_pending());
+            faults.enable();
+            assert_eq!(finish(write), Ok(()));
+        }
+        assert_eq!(sender.retries(), 1);
+        assert_eq!(pipe.take_written(), [[0x0f, 0xfa, 0, 0]]);
+    }
+
+    #[test]
+    fn gives_up_after_the_last_retry() {
+        let (pipe, faults) = (Pipe::default(), Faults::default());
+        let mut sender = sender(&pipe, &faults);
+        let (first, second) = (CableNumber::new(0).unwrap(), CableNumber::new(1).unwrap());
+        sender.set_policy(first, RetryPolicy::new(2, Duration::from_millis(1)));
+        sender.set_policy(second, RetryPolicy::NEVER);
+
+        faults.disable();
+        {
+            let mut write = pin!(sender.write_message(second, MidiMessage::Stop));
+            assert_eq!(poll_once(write.as_mut()), Poll::Ready(Err(Error::Disconnected)));
+        }
+        assert_eq!(
+            finish(pin!(sender.write_message(first, MidiMessage::Stop))),
+            Err(Error::Disconnected)
+        );
+        assert_eq!(sender.retries(), 2);
+        assert!(pipe.take_written().is_empty());
+    }
+}
@@ -0,0 +1,284 @@
+use embassy_time::Instant;
+use heapless::HistoryBuffer;
+
+use crate::Event;
+
+/// Destinations a [`Router`] can connect.
+pub(crate) const MAX_DESTINATIONS: usize = 32;
+
+/// Routing matrix connecting `S` sources to up to 32 destinations.
+///
+/// Sources and destinations are plain indices; the application decides which
+/// of them are USB cables, DIN ports or anything else.
+///
+/// ```
+/// use usb_midi_rs::Router;
+///
+/// // Sources 0 and 1 are USB cables, destination 2 is a DIN output.
+/// let mut router = Router::<2>::new();
+/// router.connect(0, 2);
+/// router.connect(1, 2);
+/// router.connect(1, 0);
+/// assert_eq!(router.destinations(1).collect::<Vec<_>>(), [0, 2]);
+/// router.isolate(2);
+/// assert!(!router.is_connected(0, 2));
+/// ```
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Router<const S: usize> {
+    routes: [u32; S],
+}
+
+impl<const S: usize> Router<S> {
+    pub const fn new() -> Self {
+        Self { routes: [0; S] }
+    }
+
+    /// # Panics
+    ///
+    /// If `source` is not below `S` or `destination` not below 32. The same
+    /// holds for [`disconnect`](Self::disconnect) and
+    /// [`is_connected`](Self::is_connected).
+    pub fn connect(&mut self, source: usize, destination: usize) {
+        self.routes[source] |= bit(destination);
+    }
+
+    pub fn disconnect(&mut self, source: usize, destination: usize) {
+        self.routes[source] &= !bit(destination);
+    }
+
+    pub fn is_connected(&self, source: usize, destination: usize) -> bool {
+        self.routes[source] & bit(destination) != 0
+    }
+
+    /// Removes all routes from and to `node`, e.g. a device that was
+    /// detached. Nodes that are neither a source nor a destination are
+    /// ignored.
+    pub fn isolate(&mut self, node: usize) {
+        if let Some(routes) = self.routes.get_mut(node) {
+            *routes = 0;
+        }
+        if node < MAX_DESTINATIONS {
+            for routes in &mut self.routes {
+                *routes &= !bit(node);
+            }
+        }
+    }
+
+    /// Destinations connected to `source`, in ascending order.
+    pub fn destinations(&self, source: usize) -> impl Iterator<Item = usize> {
+        mask_bits(self.routes[source])
+    }
+
+    /// Routes `event` received from `source` at `timestamp`, recording the
+    /// decision for a [`TraceLog`].
+    ///
+    /// Send the event to the [`destinations`](RouteTrace::destinations) of
+    /// the trace and report those it could not be sent to with
+    /// [`mark_dropped`](RouteTrace::mark_dropped).
+    pub fn trace(&self, source: usize, event: Event, timestamp: Instant) -> RouteTrace {
+        RouteTrace {
+            timestamp,
+            source,
+            event,
+            routes: self.routes[source],
+            dropped: 0,
+        }
+    }
+}
+
+fn bit(destination: usize) -> u32 {
+    assert!(destination < MAX_DESTINATIONS, "destination out of range");
+    1 << destination
+}
+
+fn mask_bits(mask: u32) -> impl Iterator<Item = usize> {
+    (0..MAX_DESTINATIONS).filter(move |&destination| mask & bit(destination) != 0)
+}
+
+/// The way an event took through a [`Router`], see [`Router::trace`].
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct RouteTrace {
+    pub timestamp: Instant,
+    pub source: usize,
+    pub event: Event,
+    /// Destinations the event was routed to, one bit each.
+    pub routes: u32,
+    /// Destinations the event could not be sent to, e.g. because their
+    /// queue was full.
+    pub dropped: u32,
+}
+
+impl RouteTrace {
+    /// Destinations the event is routed to, in ascending order.
+    pub fn destinations(&self) -> impl Iterator<Item = usize> {
+        mask_bits(self.routes)
+    }
+
+    /// Records that the event could not be sent to `destination`.
+    ///
+    /// # Panics
+    ///
+    /// If `destination` is not below 32.
+    pub fn mark_dropped(&mut self, destination: usize) {
+        self.dropped |= bit(destination);
+    }
+}
+
+/// Keeps the last `K` [`RouteTrace`]s, to debug a routing setup on a display
+/// or with defmt.
+///
+/// Tracing is off until enabled, as it costs time on every event.
+pub struct TraceLog<const K: usize> {
+    traces: HistoryBuffer<RouteTrace, K>,
+    enabled: bool,
+}
+
+impl<const K: usize> TraceLog<K> {
+    pub const fn new() -> Self {
+        Self {
+            traces: HistoryBuffer::new(),
+            enabled: false,
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Keeps `trace`, dropping the oldest trace if the log is full, and
+    /// logs it with the `defmt` feature. Does nothing unless tracing is
+    /// enabled.
+    pub fn record(&mut self, trace: RouteTrace) {
+        if !self.enabled {
+            return;
+        }
+        debug!(
+            "{} from {}: routed to {:#x}, dropped for {:#x}",
+            trace.event, trace.source, trace.routes, trace.dropped
+        );
+        self.traces.write(trace);
+    }
+
+    /// The traces kept, the oldest first.
+    pub fn iter(&self) -> impl Iterator<Item = &RouteTrace> {
+        self.traces.oldest_ordered()
+    }
+
+    pub fn clear(&mut self) {
+        self.traces = HistoryBuffer::new();
+    }
+}
+
+impl<const K: usize> Default for TraceLog<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Stores the routes, e.g. to recall a setup at startup.
+#[cfg(feature = "presets")]
+impl<const S: usize> crate::preset::Preset for Router<S> {
+    const KIND: u8 = 1;
+    const VERSION: u8 = 1;
+
+    fn write(&self, data: &mut [u8]) -> Result<usize, crate::Error> {
+        let data = data.get_mut(..4 * S).ok_or(crate::Error::BufferOverflow)?;
+        for (bytes, routes) in data.chunks_exact_mut(4).zip(&self.routes) {
+            bytes.copy_from_slice(&routes.to_le_bytes());
+        }
+        Ok(4 * S)
+    }
+
+    fn read(data: &[u8], _version: u8) -> Result<Self, crate::Error> {
+        if data.len() != 4 * S {
+            return Err(crate::Error::Malformed);
+        }
+        let mut router = Self::new();
+        for (routes, bytes) in router.routes.iter_mut().zip(data.chunks_exact(4)) {
+            *routes = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        Ok(router)
+    }
+}
+
+impl<const S: usize> Default for Router<S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn routes_sources() {
+        let mut router = Router::<2>::new();
+        router.connect(0, 3);
+        router.connect(0, 31);
+        router.connect(1, 0);
+        assert_eq!(router.destinations(0).collect::<Vec<_>>(), [3, 31]);
+        router.disconnect(0, 3);
+        assert!(!router.is_connected(0, 3));
+        assert!(router.is_connected(1, 0));
+        assert_eq!(router.destinations(0).collect::<Vec<_>>(), [31]);
+    }
+
+    #[test]
+    #[should_panic(expected = "destination out of range")]
+    fn rejects_far_destinations() {
+        Router::<1>::new().connect(0, 32);
+    }
+
+    #[test]
+    #[should_panic(expected = "destination out of range")]
+    fn rejects_far_drops() {
+        Router::<1>::new()
+            .trace(0, Event::SingleByte(0xf8), Instant::from_millis(1))
+            .mark_dropped(32);
+    }
+
+    #[test]
+    fn traces_routes() {
+        let mut router = Router::<2>::new();
+        router.connect(1, 0);
+        router.connect(1, 4);
+        let mut log = TraceLog::<2>::new();
+        let event = Event::SingleByte(0xf8);
+
+        log.record(router.trace(1, event, Instant::from_millis(1)));
+        assert_eq!(log.iter().count(), 0);
+        log.set_enabled(true);
+        for millis in 2..5 {
+            let mut trace = router.trace(1, event, Instant::from_millis(millis));
+            assert_eq!(trace.destinations().collect::<Vec<_>>(), [0, 4]);
+            trace.mark_dropped(4);
+            log.record(trace);
+        }
+        let traces: Vec<_> = log.iter().collect();
+        assert_eq!(traces.len(), 2);
+        assert_eq!(traces[0].timestamp, Instant::from_millis(3));
+        assert_eq!((traces[1].source, traces[1].routes, traces[1].dropped), (1, 0x11, 0x10));
+    }
+
+    #[test]
+    fn isolates_nodes() {
+        let mut router = Router::<2>::new();
+        router.connect(0, 1);
+        router.connect(1, 0);
+        router.connect(1, 5);
+        router.isolate(1);
+        assert_eq!(router.destinations(0).count(), 0);
+        assert_eq!(router.destinations(1).count(), 0);
+        router.connect(0, 5);
+        router.isolate(5);
+        assert!(!router.is_connected(0, 5));
+        router.isolate(32);
+    }
+}
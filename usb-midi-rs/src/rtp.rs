@@ -0,0 +1,427 @@
+//! RTP-MIDI payloads and the AppleMIDI session protocol.
+//!
+//! Nothing here touches the network: the application receives datagrams on
+//! its control and data ports, e.g. with `embassy-net` UDP sockets, hands
+//! them to a [`Session`] or [`decode`] and sends whatever they produce.
+//!
+//! Only the parts needed by a device that waits for invitations are
+//! implemented. Recovery journals of received packets are skipped, and
+//! packets are sent without one.
+
+use heapless::Vec;
+
+use crate::{DinParser, Error, Event, MidiMessage};
+
+/// Payload type used for RTP-MIDI by AppleMIDI.
+pub const PAYLOAD_TYPE: u8 = 0x61;
+
+const RTP_HEADER_LEN: usize = 12;
+const RTP_VERSION: u8 = 0x80;
+
+const SIGNATURE: [u8; 2] = [0xff, 0xff];
+const INVITATION: [u8; 2] = *b"IN";
+const ACCEPTED: [u8; 2] = *b"OK";
+const BYE: [u8; 2] = *b"BY";
+const SYNC: [u8; 2] = *b"CK";
+const PROTOCOL_VERSION: u32 = 2;
+
+/// An RTP-MIDI packet received from the peer.
+pub struct Packet<'a> {
+    pub sequence: u16,
+    /// Timestamp of the first command, usually in units of 100 µs.
+    pub timestamp: u32,
+    pub ssrc: u32,
+    commands: &'a [u8],
+    first_delta: bool,
+}
+
+impl<'a> Packet<'a> {
+    /// Events of the MIDI command section with their delta times.
+    pub fn events(&self) -> PacketEvents<'a> {
+        PacketEvents {
+            bytes: self.commands,
+            parser: DinParser::new(),
+            expect_delta: self.first_delta,
+            delta: 0,
+        }
+    }
+}
+
+/// Parses an RTP-MIDI packet. Returns `None` for anything else, e.g. the
+/// AppleMIDI control packets.
+pub fn decode(packet: &[u8]) -> Option<Packet<'_>> {
+    if packet.len() < RTP_HEADER_LEN + 1 || packet[0] & 0xc0 != RTP_VERSION || packet[1] & 0x7f != PAYLOAD_TYPE {
+        return None;
+    }
+    let csrcs = usize::from(packet[0] & 0x0f);
+    let section = packet.get(RTP_HEADER_LEN + 4 * csrcs..)?;
+    let &flags = section.first()?;
+    let (len, commands) = if flags & 0x80 != 0 {
+        let len = usize::from(flags & 0x0f) << 8 | usize::from(*section.get(1)?);
+        (len, &section[2..])
+    } else {
+        (usize::from(flags & 0x0f), &section[1..])
+    };
+    Some(Packet {
+        sequence: u16::from_be_bytes([packet[2], packet[3]]),
+        timestamp: u32::from_be_bytes([packet[4], packet[5], packet[6], packet[7]]),
+        ssrc: u32::from_be_bytes([packet[8], packet[9], packet[10], packet[11]]),
+        commands: commands.get(..len)?,
+        first_delta: flags & 0x20 != 0,
+    })
+}
+
+/// Events of an RTP-MIDI packet with their time since the packet timestamp.
+pub struct PacketEvents<'a> {
+    bytes: &'a [u8],
+    parser: DinParser,
+    expect_delta: bool,
+    delta: u32,
+}
+
+impl Iterator for PacketEvents<'_> {
+    type Item = (u32, Event);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((&byte, rest)) = self.bytes.split_first() {
+            self.bytes = rest;
+            if self.expect_delta {
+                // Delta times are big-endian with 7 bits per byte.
+                let mut delta = u32::from(byte & 0x7f);
+                let mut last = byte;
+                while last & 0x80 != 0 {
+                    let (&byte, rest) = self.bytes.split_first()?;
+                    self.bytes = rest;
+                    delta = delta << 7 | u32::from(byte & 0x7f);
+                    last = byte;
+                }
+                self.delta = self.delta.wrapping_add(delta);
+                self.expect_delta = false;
+                continue;
+            }
+            if let Some(event) = self.parser.push(byte) {
+                // Exclusive messages are only complete with their end.
+                self.expect_delta = !matches!(event, Event::SysExStartCont(..));
+                return Some((self.delta, event));
+            }
+        }
+        None
+    }
+}
+
+/// An RTP-MIDI packet of up to `L` bytes under construction.
+pub struct PacketBuilder<const L: usize> {
+    data: Vec<u8, L>,
+}
+
+impl<const L: usize> PacketBuilder<L> {
+    /// Fails to compile if the headers do not fit.
+    const HEADERS_FIT: () = assert!(L >= RTP_HEADER_LEN + 2, "packet too small for the headers");
+
+    pub fn new(sequence: u16, timestamp: u32, ssrc: u32) -> Self {
+        let () = Self::HEADERS_FIT;
+        let mut data = Vec::new();
+        let _ = data.extend_from_slice(&[RTP_VERSION, 0x80 | PAYLOAD_TYPE]);
+        let _ = data.extend_from_slice(&sequence.to_be_bytes());
+        let _ = data.extend_from_slice(&timestamp.to_be_bytes());
+        let _ = data.extend_from_slice(&ssrc.to_be_bytes());
+        // Long command section header without journal, filled in below.
+        let _ = data.extend_from_slice(&[0x80, 0x00]);
+        Self { data }
+    }
+
+    /// Appends `event` with a delta time of 0.
+    ///
+    /// Fails with [`Error::BufferOverflow`] if the packet is full.
+    pub fn push_event(&mut self, event: Event) -> Result<(), Error> {
+        let packet = event.to_packet(0);
+        let bytes = &packet[1..1 + event.size()];
+        let first = self.data.len() == RTP_HEADER_LEN + 2;
+        let delta = usize::from(!first);
+        let len = self.data.len() - RTP_HEADER_LEN - 2 + delta + bytes.len();
+        if len > 0x0fff || self.data.capacity() - self.data.len() < delta + bytes.len() {
+            return Err(Error::BufferOverflow);
+        }
+        if !first {
+            let _ = self.data.push(0);
+        }
+        let _ = self.data.extend_from_slice(bytes);
+        self.data[RTP_HEADER_LEN] = 0x80 | (len >> 8) as u8;
+        self.data[RTP_HEADER_LEN + 1] = len as u8;
+        Ok(())
+    }
+
+    pub fn push(&mut self, message: MidiMessage) -> Result<(), Error> {
+        self.push_event(message.into())
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+/// The two UDP ports of an AppleMIDI session.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Port {
+    Control,
+    Data,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SessionState {
+    Idle,
+    /// The peer has been accepted on the control port.
+    Invited,
+    /// Both ports have been accepted, MIDI may be exchanged.
+    Connected,
+}
+
+/// AppleMIDI session of a device that accepts invitations.
+///
+/// Timestamps are in units of 100 µs, as used by the clock synchronization.
+pub struct Session<'n> {
+    name: &'n str,
+    ssrc: u32,
+    state: SessionState,
+    peer: u32,
+    sequence: u16,
+    /// Offset of the peer clock from the local one, known after a sync.
+    offset: Option<i64>,
+}
+
+impl<'n> Session<'n> {
+    pub const fn new(name: &'n str, ssrc: u32) -> Self {
+        Self {
+            name,
+            ssrc,
+            state: SessionState::Idle,
+            peer: 0,
+            sequence: 0,
+            offset: None,
+        }
+    }
+
+    pub fn state(&self) -> SessionState {
+        self.state
+    }
+
+    /// SSRC of the connected peer.
+    pub fn peer(&self) -> Option<u32> {
+        (self.state != SessionState::Idle).then_some(self.peer)
+    }
+
+    /// Peer clock minus local clock, after the peer synchronized.
+    pub fn clock_offset(&self) -> Option<i64> {
+        self.offset
+    }
+
+    /// Handles a control packet received on `port` at local time `now`.
+    ///
+    /// Returns the length of the reply written to `reply`, which has to be
+    /// sent back to the peer on the same port. RTP-MIDI packets are not
+    /// handled here; pass them to [`decode`].
+    pub fn handle(&mut self, port: Port, packet: &[u8], now: u64, reply: &mut [u8]) -> Result<Option<usize>, Error> {
+        if packet.len() < 4 || packet[..2] != SIGNATURE {
+            return Err(Error::Malformed);
+        }
+        let command = [packet[2], packet[3]];
+        match command {
+            INVITATION => {
+                let (token, peer) = (read_u32(packet, 8)?, read_u32(packet, 12)?);
+                match (port, self.state) {
+                    (Port::Control, _) => self.state = SessionState::Invited,
+                    (Port::Data, SessionState::Invited) if peer == self.peer => self.state = SessionState::Connected,
+                    (Port::Data, SessionState::Connected) if peer == self.peer => {}
+                    _ => return Ok(None),
+                }
+                self.peer = peer;
+                self.write_accept(token, reply).map(Some)
+            }
+            BYE => {
+                if read_u32(packet, 12)? == self.peer {
+                    self.state = SessionState::Idle;
+                    self.offset = None;
+                }
+                Ok(None)
+            }
+            SYNC if port == Port::Data && self.state == SessionState::Connected => {
+                let count = *packet.get(8).ok_or(Error::Malformed)?;
+                let mut timestamps = [read_u64(packet, 12)?, read_u64(packet, 20)?, read_u64(packet, 28)?];
+                match count {
+                    0 => {
+                        timestamps[1] = now;
+                        self.write_sync(1, timestamps, reply).map(Some)
+                    }
+                    2 => {
+                        // Our second timestamp was taken halfway through the
+                        // round trip between the peer's first and third one.
+                        let peer = (timestamps[0] as i64 + timestamps[2] as i64) / 2;
+                        self.offset = Some(peer - timestamps[1] as i64);
+                        Ok(None)
+                    }
+                    _ => Ok(None),
+                }
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Starts an RTP-MIDI packet for sending to the peer at local time `now`.
+    pub fn packet<const L: usize>(&mut self, now: u64) -> PacketBuilder<L> {
+        self.sequence = self.sequence.wrapping_add(1);
+        PacketBuilder::new(self.sequence, now as u32, self.ssrc)
+    }
+
+    fn write_accept(&self, token: u32, reply: &mut [u8]) -> Result<usize, Error> {
+        let name = self.name.as_bytes();
+        let len = 16 + name.len() + 1;
+        let reply = reply.get_mut(..len).ok_or(Error::BufferOverflow)?;
+        reply[..2].copy_from_slice(&SIGNATURE);
+        reply[2..4].copy_from_slice(&ACCEPTED);
+        reply[4..8].copy_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+        reply[8..12].copy_from_slice(&token.to_be_bytes());
+        reply[12..16].copy_from_slice(&self.ssrc.to_be_bytes());
+        reply[16..len - 1].copy_from_slice(name);
+        reply[len - 1] = 0;
+        Ok(len)
+    }
+
+    fn write_sync(&self, count: u8, timestamps: [u64; 3], reply: &mut [u8]) -> Result<usize, Error> {
+        let reply = reply.get_mut(..36).ok_or(Error::BufferOverflow)?;
+        reply[..2].copy_from_slice(&SIGNATURE);
+        reply[2..4].copy_from_slice(&SYNC);
+        reply[4..8].copy_from_slice(&self.ssrc.to_be_bytes());
+        reply[8..12].copy_from_slice(&[count, 0, 0, 0]);
+        for (chunk, timestamp) in reply[12..].chunks_exact_mut(8).zip(timestamps) {
+            chunk.copy_from_slice(&timestamp.to_be_bytes());
+        }
+        Ok(36)
+    }
+}
+
+fn read_u32(packet: &[u8], offset: usize) -> Result<u32, Error> {
+    let bytes = packet.get(offset..offset + 4).ok_or(Error::Malformed)?;
+    Ok(u32::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_u64(packet: &[u8], offset: usize) -> Result<u64, Error> {
+    let bytes = packet.get(offset..offset + 8).ok_or(Error::Malformed)?;
+    Ok(u64::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Channel, Note};
+
+    fn invitation(ssrc: u32) -> std::vec::Vec<u8> {
+        let mut packet = std::vec![0xff, 0xff, b'I', b'N', 0, 0, 0, 2, 0, 0, 0, 7];
+        packet.extend_from_slice(&ssrc.to_be_bytes());
+        packet.extend_from_slice(b"Mac\0");
+        packet
+    }
+
+    fn sync(count: u8, timestamps: [u64; 3]) -> std::vec::Vec<u8> {
+        let mut packet = std::vec![0xff, 0xff, b'C', b'K', 0, 0, 0, 9, count, 0, 0, 0];
+        for timestamp in timestamps {
+            packet.extend_from_slice(&timestamp.to_be_bytes());
+        }
+        packet
+    }
+
+    #[test]
+    fn accepts_invitations() {
+        let mut session = Session::new("MIDIbox", 0x1234);
+        let mut reply = [0; 64];
+        assert_eq!(session.handle(Port::Data, &invitation(9), 0, &mut reply), Ok(None));
+        let len = session
+            .handle(Port::Control, &invitation(9), 0, &mut reply)
+            .unwrap()
+            .unwrap();
+        assert_eq!(&reply[..len], b"\xff\xffOK\0\0\0\x02\0\0\0\x07\0\0\x12\x34MIDIbox\0");
+        assert_eq!(session.state(), SessionState::Invited);
+        assert!(session
+            .handle(Port::Data, &invitation(9), 0, &mut reply)
+            .unwrap()
+            .is_some());
+        assert_eq!(session.state(), SessionState::Connected);
+        assert_eq!(session.peer(), Some(9));
+
+        let bye = [0xff, 0xff, b'B', b'Y', 0, 0, 0, 2, 0, 0, 0, 7, 0, 0, 0, 9];
+        assert_eq!(session.handle(Port::Control, &bye, 0, &mut reply), Ok(None));
+        assert_eq!(session.state(), SessionState::Idle);
+    }
+
+    #[test]
+    fn synchronizes_clocks() {
+        let mut session = Session::new("MIDIbox", 0x1234);
+        let mut reply = [0; 64];
+        session.handle(Port::Control, &invitation(9), 0, &mut reply).unwrap();
+        session.handle(Port::Data, &invitation(9), 0, &mut reply).unwrap();
+
+        let len = session
+            .handle(Port::Data, &sync(0, [1000, 0, 0]), 50, &mut reply)
+            .unwrap()
+            .unwrap();
+        assert_eq!(&reply[4..8], &0x1234u32.to_be_bytes());
+        assert_eq!(&reply[8..len], &sync(1, [1000, 50, 0])[8..]);
+        session
+            .handle(Port::Data, &sync(2, [1000, 50, 1010]), 60, &mut reply)
+            .unwrap();
+        assert_eq!(session.clock_offset(), Some(955));
+    }
+
+    #[test]
+    fn decodes_commands() {
+        let packet = [
+            0x80, 0x61, 0x00, 0x05, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x09, // RTP header
+            0x09, // short header, no delta time for the first command
+            0x90, 60, 100, 0x81, 0x00, 62, 100, 0x00, 0xf8,
+        ];
+        let packet = decode(&packet).unwrap();
+        assert_eq!((packet.sequence, packet.timestamp, packet.ssrc), (5, 256, 9));
+        let events: std::vec::Vec<_> = packet.events().collect();
+        assert_eq!(
+            events,
+            [
+                (0, Event::NoteOn(0x90, Note::new(60), 100)),
+                (128, Event::NoteOn(0x90, Note::new(62), 100)),
+                (128, Event::SingleByte(0xf8)),
+            ]
+        );
+    }
+
+    #[test]
+    fn round_trips_packets() {
+        let mut session = Session::new("MIDIbox", 0x1234);
+        let mut builder = session.packet::<32>(77);
+        builder
+            .push(MidiMessage::NoteOn(Channel::new(0), Note::new(60), 100))
+            .unwrap();
+        builder.push(MidiMessage::Start).unwrap();
+        let packet = decode(builder.as_bytes()).unwrap();
+        assert_eq!((packet.sequence, packet.timestamp, packet.ssrc), (1, 77, 0x1234));
+        let events: std::vec::Vec<_> = packet.events().collect();
+        assert_eq!(
+            events,
+            [
+                (0, Event::NoteOn(0x90, Note::new(60), 100)),
+                (0, Event::SingleByte(0xfa))
+            ]
+        );
+        assert_eq!(decode(&invitation(9)).map(|packet| packet.sequence), None);
+    }
+
+    #[test]
+    fn rejects_truncated_packets() {
+        // One CSRC and nothing after it.
+        let mut packet = [0; 16];
+        packet[..2].copy_from_slice(&[0x81, 0x61]);
+        assert!(decode(&packet).is_none());
+        let mut packet = [0; 17];
+        packet[..2].copy_from_slice(&[0x81, 0x61]);
+        assert_eq!(decode(&packet).map(|packet| packet.events().count()), Some(0));
+    }
+}
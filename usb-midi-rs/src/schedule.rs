@@ -0,0 +1,114 @@
+use embassy_futures::select::{select, Either};
+use embassy_time::{Instant, Timer};
+use heapless::Vec;
+
+use crate::transport::{MidiSink, MidiSource};
+use crate::{Error, Event};
+
+/// A processing node holding events back until their time comes.
+pub(crate) trait Timed {
+    /// Takes `event` received at `at`, returning an event to send at once.
+    fn process(&mut self, event: Event, at: Instant) -> Option<Event>;
+
+    /// Takes the next event due at `now`, if any.
+    fn poll(&mut self, now: Instant) -> Option<Event>;
+
+    fn deadline(&self) -> Option<Instant>;
+}
+
+/// Passes everything received from `source` through `node` to `sink` until
+/// sending fails, sending held back events when they are due.
+pub(crate) async fn run_timed(node: &mut impl Timed, source: &mut impl MidiSource, sink: &mut impl MidiSink) -> Error {
+    loop {
+        let deadline = node.deadline().unwrap_or(Instant::MAX);
+        let event = match select(source.receive(), Timer::at(deadline)).await {
+            Either::First(event) => node.process(event, Instant::now()),
+            Either::Second(()) => node.poll(Instant::now()),
+        };
+        if let Some(event) = event {
+            if let Err(error) = sink.send(event).await {
+                return error;
+            }
+        }
+    }
+}
+
+/// Events held back by a processing node until their time comes, possibly
+/// tagged with their cable.
+pub(crate) struct Schedule<T, const P: usize> {
+    /// The earliest event first, events of the same time in the order they
+    /// were scheduled.
+    events: Vec<(Instant, T), P>,
+}
+
+impl<T: Copy, const P: usize> Schedule<T, P> {
+    pub(crate) const fn new() -> Self {
+        Self { events: Vec::new() }
+    }
+
+    /// Sends `event` at `time`. Returns the event if it has to be sent at
+    /// once because too many events wait.
+    pub(crate) fn insert(&mut self, time: Instant, event: T) -> Option<T> {
+        let index = self.events.iter().position(|&(pending, _)| pending > time);
+        let index = index.unwrap_or(self.events.len());
+        self.events.insert(index, (time, event)).err().map(|(_, event)| event)
+    }
+
+    /// Takes the next event due at `now`, if any.
+    pub(crate) fn poll(&mut self, now: Instant) -> Option<T> {
+        match self.events.first() {
+            Some(&(time, event)) if time <= now => {
+                self.events.remove(0);
+                Some(event)
+            }
+            _ => None,
+        }
+    }
+
+    /// Takes the earliest event, whether it is due or not.
+    pub(crate) fn pop(&mut self) -> Option<T> {
+        match self.events.is_empty() {
+            true => None,
+            false => Some(self.events.remove(0).1),
+        }
+    }
+
+    pub(crate) fn is_full(&self) -> bool {
+        self.events.is_full()
+    }
+
+    pub(crate) fn deadline(&self) -> Option<Instant> {
+        self.events.first().map(|&(time, _)| time)
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.events.clear();
+    }
+}
+
+impl<const P: usize> Schedule<Event, P> {
+    /// Schedules a note ending together with its waiting Note On, so a
+    /// delayed note is not cut short. Returns the event if its Note On is
+    /// not waiting, or the earliest waiting event to make room for it, so
+    /// the note never hangs.
+    pub(crate) fn insert_note_off(&mut self, event: Event) -> Option<Event> {
+        let (channel, note) = match event {
+            Event::NoteOn(status, note, _) | Event::NoteOff(status, note, _) => (status & 0x0f, note),
+            event => return Some(event),
+        };
+        let note_on = self.events.iter().rev().find(|(_, pending)| match *pending {
+            Event::NoteOn(status, n, velocity) => status & 0x0f == channel && n == note && velocity > 0,
+            _ => false,
+        });
+        let time = match note_on {
+            Some(&(time, _)) => time,
+            None => return Some(event),
+        };
+        let early = match self.is_full() {
+            true => self.pop(),
+            false => None,
+        };
+        let _ = self.insert(time, event);
+        early
+    }
+}
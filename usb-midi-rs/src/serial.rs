@@ -0,0 +1,144 @@
+//! USB serial numbers derived from the unique ID of the MCU.
+
+use core::ptr;
+
+#[cfg(feature = "usb")]
+use embassy_usb::Config;
+
+/// Longest unique ID in bytes that fits into a [`SerialNumber`].
+pub const MAX_UNIQUE_ID_LEN: usize = 16;
+
+/// Address of the 96-bit unique ID on STM32F2, STM32F4 and STM32L1 cat. 1.
+pub const STM32F4_UID_ADDRESS: usize = 0x1fff_7a10;
+/// Address of the 96-bit unique ID on STM32F0 and STM32F3.
+pub const STM32F0_UID_ADDRESS: usize = 0x1fff_f7ac;
+/// Address of the 96-bit unique ID on STM32F7.
+pub const STM32F7_UID_ADDRESS: usize = 0x1ff0_f420;
+/// Address of the 96-bit unique ID on STM32G4, STM32L4 and STM32WB.
+pub const STM32L4_UID_ADDRESS: usize = 0x1fff_7590;
+/// Address of the 96-bit unique ID on STM32H7.
+pub const STM32H7_UID_ADDRESS: usize = 0x1ff1_e800;
+
+/// A unique ID of the MCU, programmed in the factory.
+pub trait UniqueId {
+    type Id: AsRef<[u8]>;
+
+    fn unique_id(&self) -> Self::Id;
+}
+
+/// The 96-bit unique ID of an STM32.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Stm32UniqueId {
+    address: usize,
+}
+
+impl Stm32UniqueId {
+    /// The unique ID at `address`, e.g. [`STM32F4_UID_ADDRESS`].
+    ///
+    /// # Safety
+    ///
+    /// `address` must be the address of the unique ID of the MCU the code
+    /// runs on, see the reference manual of the family.
+    pub const unsafe fn at(address: usize) -> Self {
+        Self { address }
+    }
+}
+
+impl UniqueId for Stm32UniqueId {
+    type Id = [u8; 12];
+
+    fn unique_id(&self) -> Self::Id {
+        let mut id = [0; 12];
+        for (offset, byte) in id.iter_mut().enumerate() {
+            // Safety: the address was promised to be valid in `at`.
+            *byte = unsafe { ptr::read_volatile((self.address + offset) as *const u8) };
+        }
+        id
+    }
+}
+
+/// A serial number string made of the hexadecimal digits of a unique ID.
+///
+/// Two identical devices plugged into the same host get different serial
+/// numbers, so the host and the DAWs running on it can tell their ports
+/// apart, and each device keeps its identity across reboots.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct SerialNumber {
+    digits: [u8; 2 * MAX_UNIQUE_ID_LEN],
+    len: usize,
+}
+
+impl SerialNumber {
+    /// The serial number of `id`.
+    ///
+    /// Longer IDs are cut down to their last [`MAX_UNIQUE_ID_LEN`] bytes,
+    /// which tend to vary more, e.g. the wafer position of an STM32.
+    pub fn from_id(id: &[u8]) -> Self {
+        const DIGITS: &[u8; 16] = b"0123456789ABCDEF";
+
+        let id = &id[id.len().saturating_sub(MAX_UNIQUE_ID_LEN)..];
+        let mut digits = [0; 2 * MAX_UNIQUE_ID_LEN];
+        for (pair, byte) in digits.chunks_exact_mut(2).zip(id) {
+            pair[0] = DIGITS[usize::from(byte >> 4)];
+            pair[1] = DIGITS[usize::from(byte & 0x0f)];
+        }
+        Self {
+            digits,
+            len: 2 * id.len(),
+        }
+    }
+
+    /// The serial number of the MCU the code runs on.
+    pub fn of(device: &impl UniqueId) -> Self {
+        Self::from_id(device.unique_id().as_ref())
+    }
+
+    pub fn as_str(&self) -> &str {
+        // Only ASCII digits are ever written.
+        core::str::from_utf8(&self.digits[..self.len]).unwrap()
+    }
+
+    /// Sets the serial number of the device configuration.
+    ///
+    /// Must be called before the configuration is passed to the
+    /// [`Builder`](embassy_usb::Builder).
+    #[cfg(feature = "usb")]
+    pub fn configure<'a>(&'a self, config: &mut Config<'a>) {
+        config.serial_number = Some(self.as_str());
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for SerialNumber {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "{=str}", self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Fixed;
+
+    impl UniqueId for Fixed {
+        type Id = [u8; 8];
+
+        fn unique_id(&self) -> Self::Id {
+            [0xe6, 0x60, 0x38, 0xb7, 0x13, 0x2a, 0x4c, 0x2f]
+        }
+    }
+
+    #[test]
+    fn formats_unique_ids() {
+        assert_eq!(SerialNumber::of(&Fixed).as_str(), "E66038B7132A4C2F");
+        assert_eq!(SerialNumber::from_id(&[]).as_str(), "");
+    }
+
+    #[test]
+    fn keeps_the_end_of_long_ids() {
+        let id: [u8; 20] = core::array::from_fn(|i| i as u8);
+        assert_eq!(SerialNumber::from_id(&id).as_str(), "0405060708090A0B0C0D0E0F10111213");
+    }
+}
@@ -0,0 +1,248 @@
+use embassy_sync::blocking_mutex::raw::RawMutex;
+use embassy_sync::mutex::Mutex;
+#[cfg(feature = "sysex")]
+use embassy_sync::mutex::MutexGuard;
+#[cfg(feature = "sysex")]
+use embassy_time::{Instant, Timer};
+use embassy_usb::driver::Driver;
+
+#[cfg(feature = "sysex")]
+use crate::sysex::{SysExFragmenter, SysExThrottle};
+#[cfg(feature = "sysex")]
+use crate::MAX_PACKET_SIZE;
+use crate::{CableNumber, Error, Event, MidiMessage, Sender};
+
+/// A [`Sender`] that can be shared between tasks.
+///
+/// Every cable has its own lock, so a multi-packet message written through
+/// [`begin_sysex`](Self::begin_sysex) is never interleaved with other
+/// messages on the same cable. Packets for other cables may still be sent in
+/// between.
+pub struct SharedSender<'d, M: RawMutex, D: Driver<'d>, const N: usize> {
+    sender: Mutex<M, Sender<'d, D, N>>,
+    cables: [Mutex<M, Cable>; N],
+}
+
+/// What the lock of a cable guards.
+#[derive(Default)]
+struct Cable {
+    #[cfg(feature = "sysex")]
+    throttle: Option<SysExThrottle>,
+}
+
+impl<'d, M: RawMutex, D: Driver<'d>, const N: usize> SharedSender<'d, M, D, N> {
+    pub fn new(sender: Sender<'d, D, N>) -> Self {
+        Self {
+            sender: Mutex::new(sender),
+            cables: core::array::from_fn(|_| Mutex::new(Cable::default())),
+        }
+    }
+
+    /// Limits the rate of System Exclusive messages written to `cable`
+    /// through [`begin_sysex`](Self::begin_sysex), e.g. to
+    /// [`SysExThrottle::DIN`] for a synth behind a DIN port.
+    ///
+    /// Other messages are not held back.
+    #[cfg(feature = "sysex")]
+    pub fn with_sysex_throttle(mut self, cable: CableNumber<N>, throttle: SysExThrottle) -> Self {
+        self.cables[cable.number() as usize].get_mut().throttle = Some(throttle);
+        self
+    }
+
+    pub async fn write_event(&self, cable: CableNumber<N>, event: Event) -> Result<(), Error> {
+        let _cable = self.cables[cable.number() as usize].lock().await;
+        self.sender.lock().await.write_event(cable, event).await
+    }
+
+    pub async fn write_message(&self, cable: CableNumber<N>, message: MidiMessage) -> Result<(), Error> {
+        self.write_event(cable, message.into()).await
+    }
+
+    /// Writes events or messages packed into as few transfers as possible,
+    /// see [`Sender::write_events`].
+    pub async fn write_events<E: Copy + Into<Event>>(&self, cable: CableNumber<N>, events: &[E]) -> Result<(), Error> {
+        let _cable = self.cables[cable.number() as usize].lock().await;
+        self.sender.lock().await.write_events(cable, events).await
+    }
+
+    /// Locks `cable` for a System Exclusive message.
+    ///
+    /// Other writes to the cable wait until the returned transaction is
+    /// dropped.
+    #[cfg(feature = "sysex")]
+    pub async fn begin_sysex(&self, cable: CableNumber<N>) -> SysExTransaction<'_, 'd, M, D, N> {
+        let lock = self.cables[cable.number() as usize].lock().await;
+        SysExTransaction {
+            sender: &self.sender,
+            cable,
+            lock,
+            fragmenter: SysExFragmenter::new(),
+        }
+    }
+}
+
+/// Exclusive access to a cable for writing a System Exclusive message.
+///
+/// Dropping the transaction before the terminating `0xF7` has been written
+/// leaves the message incomplete.
+#[cfg(feature = "sysex")]
+pub struct SysExTransaction<'a, 'd, M: RawMutex, D: Driver<'d>, const N: usize> {
+    sender: &'a Mutex<M, Sender<'d, D, N>>,
+    cable: CableNumber<N>,
+    lock: MutexGuard<'a, M, Cable>,
+    fragmenter: SysExFragmenter,
+}
+
+#[cfg(feature = "sysex")]
+impl<'a, 'd, M: RawMutex, D: Driver<'d>, const N: usize> SysExTransaction<'a, 'd, M, D, N> {
+    /// Writes the next part of the message.
+    ///
+    /// The first part starts with `0xF0` and the last part ends with `0xF7`.
+    /// Bytes that do not fill a packet yet are held back until the next call.
+    /// With a [`SysExThrottle`] on the cable, every transfer waits for its
+    /// turn, while other cables can be written to.
+    pub async fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.write_with(data, Instant::now).await
+    }
+
+    /// Like [`write`](Self::write), taking the time from `now`.
+    async fn write_with(&mut self, data: &[u8], now: impl Fn() -> Instant) -> Result<(), Error> {
+        let mut buf = [0; MAX_PACKET_SIZE as usize];
+        let buf = &mut buf[..self.sender.lock().await.packet_size().bytes()];
+        let (mut len, mut bytes) = (0, 0);
+        for &byte in data {
+            if let Some(event) = self.fragmenter.push(byte) {
+                buf[len..len + 4].copy_from_slice(&event.to_packet(self.cable.number()));
+                len += 4;
+                bytes += event.size();
+                if len == buf.len() {
+                    self.write_transfer(buf, bytes, now()).await?;
+                    (len, bytes) = (0, 0);
+                }
+            }
+        }
+        if len > 0 {
+            self.write_transfer(&buf[..len], bytes, now()).await?;
+        }
+        Ok(())
+    }
+
+    /// Writes `data`, carrying `bytes` bytes of the message, once the
+    /// throttle lets it if it is `now`.
+    async fn write_transfer(&mut self, data: &[u8], bytes: usize, now: Instant) -> Result<(), Error> {
+        if let Some(throttle) = &mut self.lock.throttle {
+            let at = throttle.schedule(now, bytes);
+            if at > now {
+                Timer::at(at).await;
+            }
+        }
+        Ok(self.sender.lock().await.write_packet(data).await?)
+    }
+}
+
+#[cfg(all(test, feature = "sysex"))]
+mod tests {
+    use embassy_futures::block_on;
+    use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+
+    use super::*;
+    use crate::fault::{sized_endpoints, virtual_bus, Faults, FaultyDriver, Pipe};
+    use crate::sysex::SysExAssembler;
+
+    #[test]
+    fn sends_sysex_to_the_host() {
+        let (device, mut host) = virtual_bus::<2>(&Faults::default());
+        let sender = SharedSender::<NoopRawMutex, _, 2>::new(device.split().0);
+        let cable = CableNumber::new(1).unwrap();
+        let mut dump = vec![0xf0, 0x7d];
+        dump.extend((0..200).map(|i| i as u8 & 0x7f));
+        dump.push(0xf7);
+
+        block_on(async {
+            let mut transaction = sender.begin_sysex(cable).await;
+            transaction.write(&dump[..101]).await.unwrap();
+            transaction.write(&dump[101..]).await.unwrap();
+        });
+        block_on(sender.write_message(cable, MidiMessage::Start)).unwrap();
+
+        let mut assembler = SysExAssembler::<256>::new();
+        let mut buf = [0; 64];
+        let mut received = None;
+        while received.is_none() {
+            for (from, event) in block_on(host.read_events(&mut buf)).unwrap().flatten() {
+                assert_eq!(from, cable);
+                received = assembler.push(event).map(|data| data.to_vec());
+            }
+        }
+        assert_eq!(received, Some(dump));
+        let events: std::vec::Vec<_> = block_on(host.read_events(&mut buf)).unwrap().flatten().collect();
+        assert_eq!(events, [(cable, MidiMessage::Start.into())]);
+    }
+
+    #[test]
+    fn throttles_dumps() {
+        use core::cell::Cell;
+        use core::pin::pin;
+
+        use embassy_time::Duration;
+
+        use crate::fault::poll_once;
+
+        // Far ahead of the timer, so a deferred transfer keeps waiting and
+        // the dumps are written at explicit Instants.
+        let start = Instant::from_secs(1 << 30);
+        let now = Cell::new(start);
+        let (pipe, faults) = (Pipe::default(), Faults::default());
+        let write_ep = sized_endpoints(&pipe, &faults, 8, 8).1;
+        let cable = CableNumber::new(0).unwrap();
+        let sender: SharedSender<NoopRawMutex, FaultyDriver<Pipe>, 1> =
+            SharedSender::new(Sender::from_endpoint(write_ep, 1)).with_sysex_throttle(cable, SysExThrottle::DIN);
+        // Four bytes in one transfer, which take 1.28 ms.
+        let dump = [0xf0, 0x7d, 1, 0xf7];
+
+        let mut transaction = block_on(sender.begin_sysex(cable));
+        block_on(transaction.write_with(&dump, || now.get())).unwrap();
+        drop(transaction);
+        assert_eq!(pipe.take_written().len(), 1);
+
+        now.set(start + Duration::from_millis(1));
+        let mut transaction = block_on(sender.begin_sysex(cable));
+        {
+            let mut write = pin!(transaction.write_with(&dump, || now.get()));
+            assert!(poll_once(write.as_mut()).is_pending());
+            assert!(pipe.take_written().is_empty());
+            // The sender is not held while waiting.
+            assert!(sender.sender.try_lock().is_ok());
+        }
+        drop(transaction);
+
+        // Both dumps were booked, so the next one goes out once they are
+        // through.
+        now.set(start + Duration::from_micros(2_560));
+        let mut transaction = block_on(sender.begin_sysex(cable));
+        block_on(transaction.write_with(&dump, || now.get())).unwrap();
+        assert_eq!(pipe.take_written().len(), 1);
+    }
+
+    #[test]
+    fn fills_small_packets() {
+        let (pipe, faults) = (Pipe::default(), Faults::default());
+        let write_ep = sized_endpoints(&pipe, &faults, 8, 8).1;
+        let sender: SharedSender<NoopRawMutex, FaultyDriver<Pipe>, 1> =
+            SharedSender::new(Sender::from_endpoint(write_ep, 1));
+        let cable = CableNumber::new(0).unwrap();
+
+        block_on(async {
+            let mut transaction = sender.begin_sysex(cable).await;
+            transaction.write(&[0xf0, 0x7d, 1, 2, 3, 4, 5, 0xf7]).await.unwrap();
+        });
+        let written = pipe.take_written();
+        assert_eq!(
+            written
+                .iter()
+                .map(|transfer| transfer.len())
+                .collect::<std::vec::Vec<_>>(),
+            [8, 4]
+        );
+    }
+}
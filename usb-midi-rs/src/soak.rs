@@ -0,0 +1,107 @@
+use embassy_sync::blocking_mutex::raw::RawMutex;
+use embassy_time::{Duration, Instant, Timer};
+
+use crate::{CableNumber, Channel, Event, MidiMessage, Note, TxQueue};
+
+/// Pseudo-random MIDI traffic for soak testing hosts and the TX path.
+///
+/// The traffic only depends on the seed, so a test that fails after a night
+/// can be repeated, and the other side of a loopback can check what it
+/// receives with a pattern of the same seed. Messages are spread over all
+/// `N` cables and 16 channels. Note Ons and Note Offs are not paired, so the
+/// traffic is not meant for sound modules.
+#[derive(Clone, Debug)]
+pub struct TestPattern<const N: usize> {
+    /// State of the xorshift generator, never 0.
+    state: u32,
+    count: u32,
+}
+
+impl<const N: usize> TestPattern<N> {
+    pub const fn new(seed: u32) -> Self {
+        Self {
+            state: if seed == 0 { 0x2545_f491 } else { seed },
+            count: 0,
+        }
+    }
+
+    /// Number of events generated.
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    fn random(&mut self) -> u32 {
+        xorshift32(&mut self.state)
+    }
+
+    /// Generates the next event and the cable to send it on.
+    pub fn next_event(&mut self) -> (CableNumber<N>, Event) {
+        let random = self.random();
+        self.count = self.count.wrapping_add(1);
+        let cable = CableNumber::new((random % N as u32) as u8).unwrap();
+        let channel = Channel::new((random >> 8) as u8 & 0x0f);
+        let values = self.random();
+        let (a, b) = (values as u8 & 0x7f, (values >> 8) as u8 & 0x7f);
+        let message = match (random >> 12) % 6 {
+            0 => MidiMessage::NoteOn(channel, Note::new(a), b.max(1)),
+            1 => MidiMessage::NoteOff(channel, Note::new(a), b),
+            2 => MidiMessage::ControlChange(channel, a, b),
+            3 => MidiMessage::PitchBend(channel, u16::from(b) << 7 | u16::from(a)),
+            4 => MidiMessage::ChannelPressure(channel, a),
+            _ => MidiMessage::ProgramChange(channel, a),
+        };
+        (cable, message.into())
+    }
+
+    /// Whether `event` received on `cable` is the next event of the pattern,
+    /// for the receiving side of a loopback test.
+    pub fn check(&mut self, cable: CableNumber<N>, event: Event) -> bool {
+        self.next_event() == (cable, event)
+    }
+
+    /// Writes `rate` events per second to `tx`, forever.
+    ///
+    /// Events are dropped rather than delayed when the queue is full, so
+    /// [`TxQueue::overflows`] tells whether the host kept up.
+    pub async fn run<M: RawMutex, const Q: usize>(&mut self, tx: &TxQueue<M, N, Q>, rate: u32) -> ! {
+        let interval = Duration::from_micros(1_000_000 / u64::from(rate.max(1)));
+        let mut next = Instant::now();
+        loop {
+            let (cable, event) = self.next_event();
+            let _ = tx.try_write_event(cable, event);
+            next += interval;
+            Timer::at(next).await;
+        }
+    }
+}
+
+/// Advances the xorshift generator `state`, which must not be 0, and returns
+/// the next number.
+pub(crate) fn xorshift32(state: &mut u32) -> u32 {
+    let mut x = *state;
+    x ^= x << 13;
+    x ^= x >> 17;
+    x ^= x << 5;
+    *state = x;
+    x
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeats_patterns() {
+        let mut pattern = TestPattern::<4>::new(42);
+        let events: Vec<_> = (0..1000).map(|_| pattern.next_event()).collect();
+        let mut check = TestPattern::<4>::new(42);
+        assert!(events.iter().all(|&(cable, event)| check.check(cable, event)));
+        assert_eq!(check.count(), 1000);
+        for number in 0..4 {
+            assert!(events.iter().any(|(cable, _)| cable.number() == number));
+        }
+
+        let mut other = TestPattern::<4>::new(43);
+        assert!(!events.iter().all(|&(cable, event)| other.check(cable, event)));
+    }
+}
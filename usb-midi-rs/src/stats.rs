@@ -0,0 +1,158 @@
+use portable_atomic::{AtomicU32, Ordering};
+
+/// An event counter that can be shared between tasks and interrupts.
+///
+/// Counters are built on `portable-atomic`, so they also work on parts
+/// without atomic read-modify-write instructions such as Cortex-M0+ when the
+/// `critical-section` feature is enabled.
+pub struct Counter(AtomicU32);
+
+impl Counter {
+    pub const fn new() -> Self {
+        Self(AtomicU32::new(0))
+    }
+
+    pub fn increment(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u32 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub fn reset(&self) {
+        self.0.store(0, Ordering::Relaxed);
+    }
+}
+
+impl Default for Counter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The highest of a series of values, e.g. the number of events waiting in
+/// a queue, shared like a [`Counter`].
+pub struct HighWaterMark(AtomicU32);
+
+impl HighWaterMark {
+    pub const fn new() -> Self {
+        Self(AtomicU32::new(0))
+    }
+
+    pub fn update(&self, value: u32) {
+        self.0.fetch_max(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u32 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub fn reset(&self) {
+        self.0.store(0, Ordering::Relaxed);
+    }
+}
+
+impl Default for HighWaterMark {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The shortest, the longest and the last of a series of durations, e.g. of
+/// the latency of the read path, shared like a [`Counter`].
+pub struct LatencyStats {
+    min: AtomicU32,
+    max: AtomicU32,
+    last: AtomicU32,
+    count: Counter,
+}
+
+impl LatencyStats {
+    pub const fn new() -> Self {
+        Self {
+            min: AtomicU32::new(u32::MAX),
+            max: AtomicU32::new(0),
+            last: AtomicU32::new(0),
+            count: Counter::new(),
+        }
+    }
+
+    pub fn record(&self, value: u32) {
+        self.min.fetch_min(value, Ordering::Relaxed);
+        self.max.fetch_max(value, Ordering::Relaxed);
+        self.last.store(value, Ordering::Relaxed);
+        self.count.increment();
+    }
+
+    /// Number of durations recorded.
+    pub fn count(&self) -> u32 {
+        self.count.get()
+    }
+
+    /// The shortest duration, `None` if none was recorded.
+    pub fn min(&self) -> Option<u32> {
+        (self.count() > 0).then(|| self.min.load(Ordering::Relaxed))
+    }
+
+    pub fn max(&self) -> Option<u32> {
+        (self.count() > 0).then(|| self.max.load(Ordering::Relaxed))
+    }
+
+    pub fn last(&self) -> Option<u32> {
+        (self.count() > 0).then(|| self.last.load(Ordering::Relaxed))
+    }
+
+    pub fn reset(&self) {
+        self.count.reset();
+        self.min.store(u32::MAX, Ordering::Relaxed);
+        self.max.store(0, Ordering::Relaxed);
+    }
+}
+
+impl Default for LatencyStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts() {
+        let counter = Counter::new();
+        counter.increment();
+        counter.increment();
+        assert_eq!(counter.get(), 2);
+        counter.reset();
+        assert_eq!(counter.get(), 0);
+    }
+
+    #[test]
+    fn keeps_the_highest_value() {
+        let mark = HighWaterMark::new();
+        mark.update(3);
+        mark.update(1);
+        assert_eq!(mark.get(), 3);
+        mark.reset();
+        assert_eq!(mark.get(), 0);
+    }
+
+    #[test]
+    fn tracks_latencies() {
+        let stats = LatencyStats::new();
+        assert_eq!(stats.min(), None);
+        stats.record(120);
+        stats.record(80);
+        stats.record(100);
+        assert_eq!(
+            (stats.min(), stats.max(), stats.last()),
+            (Some(80), Some(120), Some(100))
+        );
+        assert_eq!(stats.count(), 3);
+        stats.reset();
+        assert_eq!((stats.count(), stats.max()), (0, None));
+    }
+}
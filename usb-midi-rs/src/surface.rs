@@ -0,0 +1,187 @@
+use embassy_sync::blocking_mutex::raw::RawMutex;
+
+use crate::{CableNumber, Channel, Encoder, MidiMessage, TxQueue};
+
+/// Analog inputs such as potentiometers and faders.
+pub trait AnalogInputs<const P: usize> {
+    /// Returns the reading of every input, with the resolution passed to
+    /// [`ControlSurface::new`].
+    fn read(&mut self) -> [u16; P];
+}
+
+/// Rotary encoders.
+pub trait EncoderInputs<const E: usize> {
+    /// Returns the debounced states of the two switches of every encoder.
+    fn switches(&mut self) -> [(bool, bool); E];
+}
+
+impl AnalogInputs<0> for () {
+    fn read(&mut self) -> [u16; 0] {
+        []
+    }
+}
+
+impl EncoderInputs<0> for () {
+    fn switches(&mut self) -> [(bool, bool); 0] {
+        []
+    }
+}
+
+/// Turns pots and encoders into Control Change messages.
+///
+/// Every pot and encoder sends its own controller on a common channel. A pot
+/// only follows its reading once that moved further than the deadband, so a
+/// noisy reading near the boundary of two values does not flood the host. A
+/// message is only sent when the value of its controller changed.
+///
+/// Pots send their position with the first scan. Encoders start in the
+/// middle, at 64, and send nothing until they are turned.
+pub struct ControlSurface<const P: usize, const E: usize> {
+    channel: Channel,
+    pot_controls: [u8; P],
+    encoder_controls: [u8; E],
+    /// Bits to drop to get from a reading to a 7-bit value.
+    shift: u8,
+    deadband: u16,
+    readings: [Option<u16>; P],
+    pots_sent: [Option<u8>; P],
+    encoders: [Encoder; E],
+    encoder_values: [u8; E],
+    encoders_sent: [u8; E],
+}
+
+impl<const P: usize, const E: usize> ControlSurface<P, E> {
+    /// A surface sending `pot_controls` and `encoder_controls` on `channel`.
+    ///
+    /// Readings have `resolution` bits, from 7 to 16. `deadband` is in units
+    /// of the readings and should be smaller than one step of the 7-bit
+    /// value, or the ends of the range become hard to reach.
+    pub fn new(
+        channel: Channel,
+        pot_controls: [u8; P],
+        encoder_controls: [u8; E],
+        resolution: u8,
+        deadband: u16,
+        steps_per_detent: i8,
+    ) -> Self {
+        assert!((7..=16).contains(&resolution), "resolution must be from 7 to 16 bits");
+        Self {
+            channel,
+            pot_controls,
+            encoder_controls,
+            shift: resolution - 7,
+            deadband,
+            readings: [None; P],
+            pots_sent: [None; P],
+            encoders: core::array::from_fn(|_| Encoder::new(steps_per_detent)),
+            encoder_values: [64; E],
+            encoders_sent: [64; E],
+        }
+    }
+
+    /// Feeds the next readings and switch states and passes the messages of
+    /// the controllers that changed to `emit`.
+    ///
+    /// If `emit` returns `false`, e.g. because a queue is full, the message is
+    /// offered again on the next update, with the value at that time.
+    pub fn update(
+        &mut self,
+        readings: [u16; P],
+        switches: [(bool, bool); E],
+        mut emit: impl FnMut(MidiMessage) -> bool,
+    ) {
+        for (index, reading) in readings.into_iter().enumerate() {
+            let accepted = match self.readings[index] {
+                Some(previous) if reading.abs_diff(previous) <= self.deadband => previous,
+                _ => reading,
+            };
+            self.readings[index] = Some(accepted);
+            let value = (accepted >> self.shift).min(127) as u8;
+            let message = MidiMessage::ControlChange(self.channel, self.pot_controls[index], value);
+            if self.pots_sent[index] != Some(value) && emit(message) {
+                self.pots_sent[index] = Some(value);
+            }
+        }
+
+        for (index, (a, b)) in switches.into_iter().enumerate() {
+            let detents = self.encoders[index].update(a, b);
+            let value = (i16::from(self.encoder_values[index]) + i16::from(detents)).clamp(0, 127) as u8;
+            self.encoder_values[index] = value;
+            let message = MidiMessage::ControlChange(self.channel, self.encoder_controls[index], value);
+            if self.encoders_sent[index] != value && emit(message) {
+                self.encoders_sent[index] = value;
+            }
+        }
+    }
+
+    /// Scans `pots` and `encoders` and queues the messages of the controllers
+    /// that changed for `cable`.
+    ///
+    /// Never waits, so it can be called from a timer interrupt if the queue
+    /// uses a `CriticalSectionRawMutex`.
+    pub fn scan<M: RawMutex, const N: usize, const Q: usize>(
+        &mut self,
+        pots: &mut impl AnalogInputs<P>,
+        encoders: &mut impl EncoderInputs<E>,
+        tx: &TxQueue<M, N, Q>,
+        cable: CableNumber<N>,
+    ) {
+        self.update(pots.read(), encoders.switches(), |message| {
+            tx.try_write_message(cable, message).is_ok()
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHANNEL: Channel = Channel::new(2);
+
+    fn update<const P: usize, const E: usize>(
+        surface: &mut ControlSurface<P, E>,
+        readings: [u16; P],
+        switches: [(bool, bool); E],
+    ) -> Vec<(u8, u8)> {
+        let mut changes = Vec::new();
+        surface.update(readings, switches, |message| match message {
+            MidiMessage::ControlChange(CHANNEL, control, value) => {
+                changes.push((control, value));
+                true
+            }
+            _ => panic!("unexpected {:?}", message),
+        });
+        changes
+    }
+
+    #[test]
+    fn applies_the_deadband() {
+        let mut surface = ControlSurface::new(CHANNEL, [7, 10], [], 12, 8, 4);
+        assert_eq!(update(&mut surface, [0, 4095], []), [(7, 0), (10, 127)]);
+        // Noise around the boundary of 31 and 32 is ignored.
+        assert_eq!(update(&mut surface, [1020, 4095], []), [(7, 31)]);
+        assert_eq!(update(&mut surface, [1025, 4090], []), []);
+        assert_eq!(update(&mut surface, [1017, 4095], []), []);
+        assert_eq!(update(&mut surface, [1040, 4095], []), [(7, 32)]);
+    }
+
+    #[test]
+    fn counts_encoder_detents() {
+        let mut surface = ControlSurface::new(CHANNEL, [], [20], 7, 0, 4);
+        let clockwise = [(true, false), (true, true), (false, true), (false, false)];
+        let changes: Vec<_> = clockwise
+            .into_iter()
+            .flat_map(|switches| update(&mut surface, [], [switches]))
+            .collect();
+        assert_eq!(changes, [(20, 65)]);
+    }
+
+    #[test]
+    fn offers_rejected_messages_again() {
+        let mut surface = ControlSurface::new(CHANNEL, [1], [], 7, 0, 4);
+        surface.update([5], [], |_| false);
+        surface.update([5], [], |_| false);
+        assert_eq!(update(&mut surface, [6], []), [(1, 6)]);
+        assert_eq!(update(&mut surface, [6], []), []);
+    }
+}
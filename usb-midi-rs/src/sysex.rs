@@ -0,0 +1,594 @@
+use embassy_time::{Duration, Instant};
+use heapless::Vec;
+
+use crate::event::Event;
+use crate::transport::{forward_with, MidiSink, MidiSource};
+use crate::universal::is_addressed_to;
+use crate::Error;
+
+pub const SYSEX_START: u8 = 0xf0;
+pub const SYSEX_END: u8 = 0xf7;
+
+/// Splits a System Exclusive message into events.
+///
+/// Bytes are fed one at a time, starting with `0xF0` and ending with `0xF7`.
+/// Every third byte completes a start/continue event; the terminating byte
+/// completes an end event with the remaining bytes.
+#[derive(Default)]
+pub struct SysExFragmenter {
+    pending: [u8; 3],
+    len: usize,
+}
+
+impl SysExFragmenter {
+    pub const fn new() -> Self {
+        Self {
+            pending: [0; 3],
+            len: 0,
+        }
+    }
+
+    pub fn push(&mut self, byte: u8) -> Option<Event> {
+        self.pending[self.len] = byte;
+        self.len += 1;
+
+        let [a, b, c] = self.pending;
+        let event = match (self.len, byte) {
+            (1, SYSEX_END) => Event::SystemCommon1SysExEnd1(a),
+            (2, SYSEX_END) => Event::SysExEnd2(a, b),
+            (3, SYSEX_END) => Event::SysExEnd3(a, b, c),
+            (3, _) => Event::SysExStartCont(a, b, c),
+            _ => return None,
+        };
+        self.len = 0;
+        Some(event)
+    }
+
+    /// Returns `true` if bytes are waiting for the next event.
+    pub fn is_pending(&self) -> bool {
+        self.len > 0
+    }
+}
+
+/// Limits the rate of outgoing System Exclusive data.
+///
+/// Many synths connected through a USB to DIN interface drop parts of a dump
+/// that arrives faster than DIN MIDI can carry it, as the interface does not
+/// buffer much. The rate is given in bytes per second, as DIN MIDI carries
+/// 3.125 bytes per millisecond.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SysExThrottle {
+    bytes_per_second: u32,
+    /// When the bytes booked so far have been sent.
+    ready_at: Instant,
+}
+
+impl SysExThrottle {
+    /// The rate of DIN MIDI, 31250 baud with 10 bits per byte.
+    pub const DIN: Self = Self::new(3125);
+
+    pub const fn new(bytes_per_second: u32) -> Self {
+        Self {
+            bytes_per_second,
+            ready_at: Instant::from_ticks(0),
+        }
+    }
+
+    pub const fn per_millisecond(bytes: u32) -> Self {
+        Self::new(bytes * 1000)
+    }
+
+    pub fn bytes_per_second(&self) -> u32 {
+        self.bytes_per_second
+    }
+
+    /// Books `bytes` to be sent, returning when they may go out if it is
+    /// `now`.
+    pub fn schedule(&mut self, now: Instant, bytes: usize) -> Instant {
+        let start = now.max(self.ready_at);
+        let micros = bytes as u64 * 1_000_000 / u64::from(self.bytes_per_second.max(1));
+        self.ready_at = start + Duration::from_micros(micros);
+        start
+    }
+}
+
+/// Collects the events of System Exclusive messages into complete messages
+/// of up to `L` bytes, including `0xF0` and `0xF7`.
+///
+/// Longer messages are dropped, as are events outside of a message.
+///
+/// ```
+/// use usb_midi_rs::sysex::{SysExAssembler, SysExFragmenter};
+///
+/// let message = [0xf0, 0x7e, 0x7f, 0x06, 0x01, 0xf7];
+/// let mut fragmenter = SysExFragmenter::new();
+/// let mut assembler = SysExAssembler::<16>::new();
+/// let mut assembled = None;
+/// for event in message.iter().filter_map(|&byte| fragmenter.push(byte)) {
+///     assembled = assembler.push(event).map(|data| data.to_vec());
+/// }
+/// assert_eq!(assembled.as_deref(), Some(&message[..]));
+/// ```
+pub struct SysExAssembler<const L: usize> {
+    data: Vec<u8, L>,
+    active: bool,
+    overflow: bool,
+}
+
+impl<const L: usize> SysExAssembler<L> {
+    pub const fn new() -> Self {
+        Self {
+            data: Vec::new(),
+            active: false,
+            overflow: false,
+        }
+    }
+
+    /// Adds an event, returning the message it completes.
+    pub fn push(&mut self, event: Event) -> Option<&[u8]> {
+        let bytes = sysex_bytes(event)?;
+        if bytes[0] == SYSEX_START {
+            self.data.clear();
+            self.active = true;
+            self.overflow = false;
+        }
+        if !self.active {
+            return None;
+        }
+        if self.data.extend_from_slice(&bytes).is_err() {
+            self.overflow = true;
+        }
+        if bytes[bytes.len() - 1] != SYSEX_END {
+            return None;
+        }
+        self.active = false;
+        match self.overflow {
+            true => None,
+            false => Some(&self.data),
+        }
+    }
+}
+
+impl<const L: usize> Default for SysExAssembler<L> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The bytes of `event` if it is part of a System Exclusive message.
+pub(crate) fn sysex_bytes(event: Event) -> Option<Vec<u8, 3>> {
+    let packet = event.to_packet(0);
+    let bytes = match event {
+        Event::SysExStartCont(..) | Event::SysExEnd2(..) | Event::SysExEnd3(..) => &packet[1..1 + event.size()],
+        Event::SystemCommon1SysExEnd1(SYSEX_END) => &packet[1..2],
+        _ => return None,
+    };
+    Vec::from_slice(bytes).ok()
+}
+
+/// The manufacturer ID at the start of `data`, the bytes following `0xF0`.
+///
+/// IDs are one byte long, or three bytes starting with 0 for the extended
+/// IDs.
+pub fn manufacturer_id(data: &[u8]) -> Option<&[u8]> {
+    match data {
+        [0x00, ..] => data.get(..3),
+        _ => data.get(..1),
+    }
+}
+
+/// Handles complete System Exclusive messages, see [`SysExDispatcher`].
+pub trait SysExHandler {
+    /// Handles `message`, including `0xF0` and `0xF7`.
+    fn handle(&mut self, message: &[u8]);
+}
+
+impl<F: FnMut(&[u8])> SysExHandler for F {
+    fn handle(&mut self, message: &[u8]) {
+        self(message)
+    }
+}
+
+/// Routes System Exclusive messages to up to `H` handlers by their
+/// manufacturer ID.
+///
+/// The Universal IDs `0x7E` and `0x7F` are handled like any other ID, so e.g.
+/// the tuning messages can be routed to their own handler. Once the device ID
+/// is set, Universal messages addressed to other devices are dropped.
+pub struct SysExDispatcher<'a, const H: usize> {
+    handlers: Vec<(&'a [u8], &'a mut dyn SysExHandler), H>,
+    device: Option<u8>,
+}
+
+impl<'a, const H: usize> SysExDispatcher<'a, H> {
+    /// A dispatcher without handlers passing Universal messages for all
+    /// devices.
+    pub const fn new() -> Self {
+        Self {
+            handlers: Vec::new(),
+            device: None,
+        }
+    }
+
+    pub fn device_id(&self) -> Option<u8> {
+        self.device
+    }
+
+    /// Only passes the Universal messages addressed to `device` or to
+    /// [`ALL_DEVICES`](crate::universal::ALL_DEVICES), or all of them for
+    /// `None`.
+    pub fn set_device_id(&mut self, device: Option<u8>) {
+        self.device = device;
+    }
+
+    /// Routes the messages of `manufacturer`, a one-byte ID or a three-byte
+    /// ID starting with 0, to `handler`, replacing the handler registered
+    /// before.
+    ///
+    /// Fails with [`Error::BufferOverflow`] if `H` handlers are registered
+    /// already.
+    ///
+    /// # Panics
+    ///
+    /// If `manufacturer` is not a valid ID.
+    pub fn register(&mut self, manufacturer: &'a [u8], handler: &'a mut dyn SysExHandler) -> Result<(), Error> {
+        assert!(manufacturer_id(manufacturer) == Some(manufacturer) && manufacturer[0] < 0x80);
+        match self.handlers.iter_mut().find(|(id, _)| *id == manufacturer) {
+            Some(registered) => registered.1 = handler,
+            None => self
+                .handlers
+                .push((manufacturer, handler))
+                .map_err(|_| Error::BufferOverflow)?,
+        }
+        Ok(())
+    }
+
+    /// Passes the complete `message` to the handler of its manufacturer,
+    /// returning `false` if there is none or the message is addressed to
+    /// another device.
+    pub fn dispatch(&mut self, message: &[u8]) -> bool {
+        if let Some(device) = self.device {
+            if !is_addressed_to(message, device) {
+                return false;
+            }
+        }
+        let manufacturer = match message {
+            [SYSEX_START, data @ .., SYSEX_END] => manufacturer_id(data),
+            _ => None,
+        };
+        let handler =
+            manufacturer.and_then(|manufacturer| self.handlers.iter_mut().find(|(id, _)| *id == manufacturer));
+        match handler {
+            Some((_, handler)) => {
+                handler.handle(message);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl<const H: usize> Default for SysExDispatcher<'_, H> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Passes or blocks System Exclusive messages by their manufacturer ID, so
+/// that a dump meant for one synth does not reach every output of a thru box.
+///
+/// Every route gets a filter of its own. The first event of a message with
+/// a three-byte ID is held back until the next one completes the ID. Events
+/// other than System Exclusive always pass.
+///
+/// ```
+/// use usb_midi_rs::sysex::SysExFilter;
+/// use usb_midi_rs::Event;
+///
+/// const ROLAND: &[u8] = &[0x41];
+/// let mut filter = SysExFilter::allow(&[ROLAND]);
+/// let dump = Event::SysExStartCont(0xf0, 0x43, 0x10);
+/// assert_eq!(filter.process(dump).count(), 0);
+/// ```
+pub struct SysExFilter<'a> {
+    ids: &'a [&'a [u8]],
+    allow: bool,
+    state: FilterState,
+}
+
+enum FilterState {
+    Passing,
+    Blocking,
+    /// The first event of a message whose ID is not complete yet.
+    Holding(Event, Vec<u8, 3>),
+}
+
+impl<'a> SysExFilter<'a> {
+    /// Passes the messages of the manufacturers in `ids` only.
+    pub const fn allow(ids: &'a [&'a [u8]]) -> Self {
+        Self {
+            ids,
+            allow: true,
+            state: FilterState::Blocking,
+        }
+    }
+
+    /// Blocks the messages of the manufacturers in `ids`.
+    pub const fn deny(ids: &'a [&'a [u8]]) -> Self {
+        Self {
+            ids,
+            allow: false,
+            state: FilterState::Blocking,
+        }
+    }
+
+    /// Whether messages with the manufacturer ID `id` pass.
+    pub fn passes(&self, id: &[u8]) -> bool {
+        self.ids.contains(&id) == self.allow
+    }
+
+    /// Events that `event` turns into.
+    pub fn process(&mut self, event: Event) -> impl Iterator<Item = Event> {
+        let mut events = Vec::<Event, 2>::new();
+        let bytes = match sysex_bytes(event) {
+            Some(bytes) => bytes,
+            None => {
+                let _ = events.push(event);
+                return events.into_iter();
+            }
+        };
+        let end = bytes.last() == Some(&SYSEX_END);
+        let (held, mut id) = match core::mem::replace(&mut self.state, FilterState::Blocking) {
+            _ if bytes[0] == SYSEX_START => (None, Vec::new()),
+            FilterState::Holding(held, id) => (Some(held), id),
+            FilterState::Passing => {
+                if !end {
+                    self.state = FilterState::Passing;
+                }
+                let _ = events.push(event);
+                return events.into_iter();
+            }
+            FilterState::Blocking => return events.into_iter(),
+        };
+        let data = bytes.strip_prefix(&[SYSEX_START]).unwrap_or(&bytes);
+        for &byte in data {
+            if byte == SYSEX_END || manufacturer_id(&id).is_some() {
+                break;
+            }
+            let _ = id.push(byte);
+        }
+        let passes = match manufacturer_id(&id) {
+            Some(id) => self.passes(id),
+            None if !end => {
+                self.state = FilterState::Holding(event, id);
+                return events.into_iter();
+            }
+            None => !self.allow,
+        };
+        if passes {
+            if let Some(held) = held {
+                let _ = events.push(held);
+            }
+            let _ = events.push(event);
+            if !end {
+                self.state = FilterState::Passing;
+            }
+        }
+        events.into_iter()
+    }
+
+    /// Filters the events of `source` for `sink`, see [`forward_with`].
+    pub async fn run(&mut self, source: &mut impl MidiSource, sink: &mut impl MidiSink) -> Error {
+        forward_with(source, sink, |event| self.process(event)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+
+    use super::*;
+
+    fn fragment(message: &[u8]) -> std::vec::Vec<Event> {
+        let mut fragmenter = SysExFragmenter::new();
+        let events = message.iter().filter_map(|&byte| fragmenter.push(byte)).collect();
+        assert!(!fragmenter.is_pending());
+        events
+    }
+
+    #[test]
+    fn filters_by_manufacturer() {
+        const YAMAHA: &[u8] = &[0x43];
+        const EXTENDED: &[u8] = &[0x00, 0x20, 0x29];
+        let allowed = [YAMAHA, EXTENDED];
+        let mut filter = SysExFilter::allow(&allowed);
+        let mut pass = |message: &[u8]| {
+            let events = fragment(message);
+            let passed: std::vec::Vec<_> = events.iter().flat_map(|&event| filter.process(event)).collect();
+            assert!(passed.is_empty() || passed == events, "{:02x?}", message);
+            !passed.is_empty()
+        };
+        assert!(pass(&[0xf0, 0x43, 0x10, 0x4c, 0x00, 0x00, 0x7e, 0x00, 0xf7]));
+        assert!(!pass(&[
+            0xf0, 0x41, 0x10, 0x42, 0x12, 0x40, 0x00, 0x7f, 0x00, 0x41, 0xf7
+        ]));
+        assert!(pass(&[0xf0, 0x00, 0x20, 0x29, 0x02, 0xf7]));
+        assert!(!pass(&[0xf0, 0x00, 0x20, 0x2a, 0x02, 0xf7]));
+        assert!(!pass(&[0xf0, 0xf7]));
+
+        let mut filter = SysExFilter::deny(&allowed);
+        assert_eq!(filter.process(Event::SysExStartCont(0xf0, 0x00, 0x20)).count(), 0);
+        // Real-time messages pass while the ID is incomplete.
+        let clock = Event::SingleByte(0xf8);
+        assert_eq!(filter.process(clock).collect::<std::vec::Vec<_>>(), [clock]);
+        let events: std::vec::Vec<_> = filter.process(Event::SysExEnd3(0x21, 0x01, 0xf7)).collect();
+        assert_eq!(
+            events,
+            [
+                Event::SysExStartCont(0xf0, 0x00, 0x20),
+                Event::SysExEnd3(0x21, 0x01, 0xf7)
+            ]
+        );
+    }
+
+    #[test]
+    fn throttles_dumps() {
+        let mut throttle = SysExThrottle::DIN;
+        let start = Instant::from_millis(10);
+        assert_eq!(throttle.schedule(start, 48), start);
+        // 48 bytes take 15.36 ms at the DIN rate.
+        assert_eq!(throttle.schedule(start, 48), start + Duration::from_micros(15_360));
+        let later = Instant::from_millis(100);
+        assert_eq!(throttle.schedule(later, 3), later);
+        assert_eq!(SysExThrottle::per_millisecond(2).bytes_per_second(), 2000);
+    }
+
+    #[test]
+    fn fragments_messages() {
+        assert_eq!(
+            fragment(&[0xf0, 0x7e, 0x7f, 0x06, 0x01, 0xf7]),
+            [
+                Event::SysExStartCont(0xf0, 0x7e, 0x7f),
+                Event::SysExEnd3(0x06, 0x01, 0xf7)
+            ]
+        );
+        assert_eq!(
+            fragment(&[0xf0, 0x01, 0x02, 0x03, 0xf7]),
+            [Event::SysExStartCont(0xf0, 0x01, 0x02), Event::SysExEnd2(0x03, 0xf7)]
+        );
+        assert_eq!(
+            fragment(&[0xf0, 0x01, 0x02, 0xf7]),
+            [
+                Event::SysExStartCont(0xf0, 0x01, 0x02),
+                Event::SystemCommon1SysExEnd1(0xf7)
+            ]
+        );
+        assert_eq!(fragment(&[0xf0, 0xf7]), [Event::SysExEnd2(0xf0, 0xf7)]);
+    }
+
+    #[test]
+    fn finds_manufacturer_ids() {
+        assert_eq!(manufacturer_id(&[0x41, 0x10, 0x42]), Some(&[0x41][..]));
+        assert_eq!(
+            manufacturer_id(&[0x00, 0x20, 0x33, 0x01]),
+            Some(&[0x00, 0x20, 0x33][..])
+        );
+        assert_eq!(manufacturer_id(&[0x00, 0x20]), None);
+        assert_eq!(manufacturer_id(&[]), None);
+    }
+
+    #[test]
+    fn dispatches_by_manufacturer() {
+        let (mut roland, mut extended) = (0, std::vec::Vec::new());
+        let mut count = |_: &[u8]| roland += 1;
+        let mut collect = |message: &[u8]| extended.push(message.to_vec());
+        let mut ignore = |_: &[u8]| {};
+        let mut dispatcher = SysExDispatcher::<2>::new();
+        dispatcher.register(&[0x41], &mut count).unwrap();
+        dispatcher.register(&[0x00, 0x20, 0x33], &mut collect).unwrap();
+        assert_eq!(dispatcher.register(&[0x7e], &mut ignore), Err(Error::BufferOverflow));
+
+        assert!(dispatcher.dispatch(&[0xf0, 0x41, 0x10, 0x42, 0xf7]));
+        assert!(dispatcher.dispatch(&[0xf0, 0x00, 0x20, 0x33, 0x01, 0xf7]));
+        assert!(!dispatcher.dispatch(&[0xf0, 0x00, 0x20, 0x32, 0x01, 0xf7]));
+        assert!(!dispatcher.dispatch(&[0xf0, 0x43, 0xf7]));
+        assert!(!dispatcher.dispatch(&[0xf0, 0x41]));
+        drop(dispatcher);
+        assert_eq!(roland, 1);
+        assert_eq!(extended, [[0xf0, 0x00, 0x20, 0x33, 0x01, 0xf7]]);
+    }
+
+    #[test]
+    fn filters_universal_messages() {
+        let mut received = 0;
+        let mut count = |_: &[u8]| received += 1;
+        let mut dispatcher = SysExDispatcher::<1>::new();
+        dispatcher.register(&[0x7e], &mut count).unwrap();
+        assert!(dispatcher.dispatch(&[0xf0, 0x7e, 0x05, 0x06, 0x01, 0xf7]));
+        dispatcher.set_device_id(Some(0x10));
+        assert!(!dispatcher.dispatch(&[0xf0, 0x7e, 0x05, 0x06, 0x01, 0xf7]));
+        assert!(dispatcher.dispatch(&[0xf0, 0x7e, 0x10, 0x06, 0x01, 0xf7]));
+        assert!(dispatcher.dispatch(&[0xf0, 0x7e, 0x7f, 0x06, 0x01, 0xf7]));
+        drop(dispatcher);
+        assert_eq!(received, 3);
+    }
+
+    #[test]
+    fn assembles_messages() {
+        let mut assembler = SysExAssembler::<6>::new();
+        let message = [0xf0, 0x7e, 0x7f, 0x06, 0x01, 0xf7];
+        assert_eq!(assembler.push(Event::SysExEnd2(0x01, 0xf7)), None);
+        for event in fragment(&message[..]) {
+            if let Some(assembled) = assembler.push(event) {
+                assert_eq!(assembled, message);
+                return;
+            }
+        }
+        panic!("message not assembled");
+    }
+
+    #[test]
+    fn drops_long_messages() {
+        let mut assembler = SysExAssembler::<4>::new();
+        let events = fragment(&[0xf0, 0x01, 0x02, 0x03, 0xf7]);
+        assert!(events.into_iter().all(|event| assembler.push(event).is_none()));
+        assert_eq!(assembler.push(Event::SysExEnd2(0xf0, 0xf7)), Some(&[0xf0, 0xf7][..]));
+    }
+
+    /// Packets of `message` as they arrive from the host, in transfers
+    /// ending after the packets at `splits`.
+    fn transfers(message: &[u8], splits: &[usize]) -> std::vec::Vec<std::vec::Vec<u8>> {
+        let packets: std::vec::Vec<u8> = fragment(message).iter().flat_map(|event| event.to_packet(0)).collect();
+        let mut ends: std::vec::Vec<usize> = splits.iter().map(|split| (split * 4).min(packets.len())).collect();
+        ends.push(packets.len());
+        ends.sort_unstable();
+        let starts = std::iter::once(0).chain(ends.clone());
+        starts
+            .zip(ends)
+            .map(|(start, end)| packets[start..end].to_vec())
+            .collect()
+    }
+
+    proptest! {
+        #[test]
+        fn reassembles_split_messages(
+            payload in vec(0u8..0x80, 0..64),
+            splits in vec(0usize..24, 0..8),
+        ) {
+            let message = [&[SYSEX_START][..], &payload, &[SYSEX_END]].concat();
+            let mut assembler = SysExAssembler::<32>::new();
+            let mut assembled = std::vec::Vec::new();
+            for transfer in transfers(&message, &splits) {
+                for packet in transfer.chunks_exact(4) {
+                    if let Some(data) = assembler.push(Event::new(packet)) {
+                        assembled.push(data.to_vec());
+                    }
+                }
+            }
+            if message.len() <= 32 {
+                prop_assert_eq!(assembled, [message]);
+            } else {
+                // Too long for the assembler, which is ready for the next one.
+                prop_assert!(assembled.is_empty());
+                let short = [SYSEX_START, 0x7d, SYSEX_END];
+                let events = fragment(&short);
+                let last = events.into_iter().filter_map(|event| assembler.push(event).map(<[u8]>::to_vec)).last();
+                prop_assert_eq!(last, Some(short.to_vec()));
+            }
+        }
+
+        #[test]
+        fn assembles_any_transfer(transfer in vec(any::<u8>(), 0..=256)) {
+            let mut assembler = SysExAssembler::<16>::new();
+            for packet in transfer.chunks_exact(4) {
+                if let Some(data) = assembler.push(Event::new(packet)) {
+                    prop_assert!(data.len() <= 16);
+                    prop_assert_eq!(data[0], SYSEX_START);
+                    prop_assert_eq!(data[data.len() - 1], SYSEX_END);
+                }
+            }
+        }
+    }
+}
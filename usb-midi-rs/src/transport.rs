@@ -0,0 +1,258 @@
+//! Transport-agnostic sources and sinks of events.
+//!
+//! Processing nodes such as filters, splitters or a routing task take any
+//! [`MidiSource`] and [`MidiSink`], so the same code serves USB cables,
+//! queues filled by a DIN, BLE or network task and plain in-memory queues.
+//! The cables of the USB device are available through
+//! [`Queues::source`] and [`TxQueue::sink`], and to several tasks at once
+//! through a [`Broadcast`](crate::Broadcast).
+
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+use embassy_sync::blocking_mutex::raw::RawMutex;
+use embassy_sync::channel::{Channel, RecvFuture, SendFuture};
+use embassy_time::Instant;
+
+use crate::tx::REAL_TIME_QUEUE_SIZE;
+use crate::{CableNumber, Error, Event, Queues, TxQueue};
+
+/// Something events can be received from.
+pub trait MidiSource {
+    type ReceiveFuture<'a>: Future<Output = Event> + 'a
+    where
+        Self: 'a;
+
+    /// Waits for the next event.
+    fn receive(&mut self) -> Self::ReceiveFuture<'_>;
+}
+
+/// Something events can be sent to.
+pub trait MidiSink {
+    type SendFuture<'a>: Future<Output = Result<(), Error>> + 'a
+    where
+        Self: 'a;
+
+    /// Sends `event`, waiting while the transport is busy.
+    fn send(&mut self, event: Event) -> Self::SendFuture<'_>;
+}
+
+/// Forwards everything received from `source` to `sink` until sending fails.
+pub async fn forward(source: &mut impl MidiSource, sink: &mut impl MidiSink) -> Error {
+    forward_with(source, sink, core::iter::once).await
+}
+
+/// Like [`forward`], sending the events `f` turns every received event into,
+/// e.g. those of the `process` method of a processing node.
+pub async fn forward_with<I: IntoIterator<Item = Event>>(
+    source: &mut impl MidiSource,
+    sink: &mut impl MidiSink,
+    mut f: impl FnMut(Event) -> I,
+) -> Error {
+    loop {
+        let event = source.receive().await;
+        for event in f(event) {
+            if let Err(error) = sink.send(event).await {
+                return error;
+            }
+        }
+    }
+}
+
+/// Future returned by the implementations in this module, converting the
+/// output of a future with a function.
+pub struct Map<F: Future, T> {
+    pub(crate) future: F,
+    pub(crate) f: fn(F::Output) -> T,
+}
+
+impl<F: Future + Unpin, T> Future for Map<F, T> {
+    type Output = T;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        let f = self.f;
+        Pin::new(&mut self.future).poll(cx).map(f)
+    }
+}
+
+impl<'c, M: RawMutex, const Q: usize> MidiSource for &'c Channel<M, Event, Q> {
+    type ReceiveFuture<'a>
+        = RecvFuture<'c, M, Event, Q>
+    where
+        Self: 'a;
+
+    fn receive(&mut self) -> Self::ReceiveFuture<'_> {
+        self.recv()
+    }
+}
+
+impl<'c, M: RawMutex, const Q: usize> MidiSink for &'c Channel<M, Event, Q> {
+    type SendFuture<'a>
+        = Map<SendFuture<'c, M, Event, Q>, Result<(), Error>>
+    where
+        Self: 'a;
+
+    fn send(&mut self, event: Event) -> Self::SendFuture<'_> {
+        Map {
+            future: Channel::send(self, event),
+            f: Ok,
+        }
+    }
+}
+
+/// A cable of the USB device as a source, see [`Queues::source`].
+pub struct CableSource<'q, M: RawMutex, const Q: usize> {
+    queue: &'q Channel<M, (Instant, Event), Q>,
+}
+
+impl<'q, M: RawMutex, const Q: usize> MidiSource for CableSource<'q, M, Q> {
+    type ReceiveFuture<'a>
+        = Map<RecvFuture<'q, M, (Instant, Event), Q>, Event>
+    where
+        Self: 'a;
+
+    fn receive(&mut self) -> Self::ReceiveFuture<'_> {
+        Map {
+            future: self.queue.recv(),
+            f: |(_, event)| event,
+        }
+    }
+}
+
+/// A cable of the USB device as a sink, see [`TxQueue::sink`].
+///
+/// Sinks are cheap to copy, so every task sending on a cable can have its
+/// own.
+pub struct CableSink<'q, M: RawMutex, const N: usize, const Q: usize> {
+    queue: &'q TxQueue<M, N, Q>,
+    cable: CableNumber<N>,
+}
+
+impl<'q, M: RawMutex, const N: usize, const Q: usize> CableSink<'q, M, N, Q> {
+    pub fn cable(&self) -> CableNumber<N> {
+        self.cable
+    }
+
+    /// Queues `event` without waiting, see [`TxQueue::try_write_event`].
+    pub fn try_send(&self, event: Event) -> Result<(), Error> {
+        self.queue.try_write_event(self.cable, event)
+    }
+}
+
+impl<'q, M: RawMutex, const N: usize, const Q: usize> Clone for CableSink<'q, M, N, Q> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<'q, M: RawMutex, const N: usize, const Q: usize> Copy for CableSink<'q, M, N, Q> {}
+
+/// Future of [`CableSink::send`], queueing an event in its lane of the
+/// [`TxQueue`].
+pub enum LaneSend<'q, M: RawMutex, const N: usize, const Q: usize> {
+    Events(SendFuture<'q, M, (CableNumber<N>, Event), Q>),
+    RealTime(SendFuture<'q, M, (CableNumber<N>, Event), REAL_TIME_QUEUE_SIZE>),
+}
+
+impl<'q, M: RawMutex, const N: usize, const Q: usize> Future for LaneSend<'q, M, N, Q> {
+    type Output = Result<(), Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match self.get_mut() {
+            LaneSend::Events(future) => Pin::new(future).poll(cx).map(Ok),
+            LaneSend::RealTime(future) => Pin::new(future).poll(cx).map(Ok),
+        }
+    }
+}
+
+impl<'q, M: RawMutex, const N: usize, const Q: usize> MidiSink for CableSink<'q, M, N, Q> {
+    type SendFuture<'a>
+        = LaneSend<'q, M, N, Q>
+    where
+        Self: 'a;
+
+    fn send(&mut self, event: Event) -> Self::SendFuture<'_> {
+        match event.is_real_time() {
+            true => LaneSend::RealTime(self.queue.real_time().send((self.cable, event))),
+            false => LaneSend::Events(self.queue.events().send((self.cable, event))),
+        }
+    }
+}
+
+impl<M: RawMutex, const N: usize, const Q: usize> Queues<M, N, Q> {
+    /// Events received from the host on `cable`.
+    pub fn source(&self, cable: CableNumber<N>) -> CableSource<'_, M, Q> {
+        CableSource {
+            queue: self.cable(cable),
+        }
+    }
+}
+
+impl<M: RawMutex, const N: usize, const Q: usize> TxQueue<M, N, Q> {
+    /// Events to be sent to the host on `cable`.
+    pub fn sink(&self, cable: CableNumber<N>) -> CableSink<'_, M, N, Q> {
+        CableSink { queue: self, cable }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_futures::block_on;
+    use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+
+    use super::*;
+    use crate::{Channel as MidiChannel, MidiMessage};
+
+    #[test]
+    fn forwards_between_queues() {
+        let input = Channel::<NoopRawMutex, Event, 4>::new();
+        let tx = TxQueue::<NoopRawMutex, 2>::new();
+        let cable = CableNumber::new(1).unwrap();
+        let event = Event::from(MidiMessage::ProgramChange(MidiChannel::new(3), 7));
+
+        block_on(MidiSink::send(&mut &input, event)).unwrap();
+        let mut source = &input;
+        let mut sink = tx.sink(cable);
+        let received = block_on(source.receive());
+        block_on(sink.send(received)).unwrap();
+        assert_eq!(tx.try_next(), Some((cable, event)));
+    }
+
+    #[test]
+    fn receives_from_cables() {
+        let queues = Queues::<NoopRawMutex, 2>::new();
+        let cable = CableNumber::new(1).unwrap();
+        let event = Event::from(MidiMessage::Start);
+        queues.cable(cable).try_send((Instant::from_ticks(0), event)).unwrap();
+        assert_eq!(block_on(queues.source(cable).receive()), event);
+    }
+
+    /// Takes `len` events, then fails.
+    struct Limited(std::vec::Vec<Event>, usize);
+
+    impl MidiSink for Limited {
+        type SendFuture<'a> = core::future::Ready<Result<(), Error>>;
+
+        fn send(&mut self, event: Event) -> Self::SendFuture<'_> {
+            if self.0.len() == self.1 {
+                return core::future::ready(Err(Error::Disconnected));
+            }
+            self.0.push(event);
+            core::future::ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn forwards_processed_events() {
+        let input = Channel::<NoopRawMutex, Event, 4>::new();
+        let (start, stop) = (Event::from(MidiMessage::Start), Event::from(MidiMessage::Stop));
+        input.try_send(start).unwrap();
+        input.try_send(stop).unwrap();
+        let mut sink = Limited(std::vec::Vec::new(), 3);
+
+        let error = block_on(forward_with(&mut &input, &mut sink, |event| [event, event]));
+        assert_eq!(error, Error::Disconnected);
+        assert_eq!(sink.0, [start, start, stop]);
+    }
+}
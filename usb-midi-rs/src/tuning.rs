@@ -0,0 +1,323 @@
+//! MIDI Tuning Standard messages.
+//!
+//! Bulk tuning dumps transfer a whole [`TuningTable`], single note tuning
+//! changes retune some notes while they may be sounding. Messages are
+//! complete System Exclusive messages including `0xF0` and `0xF7`, as
+//! collected by a [`SysExAssembler`](crate::sysex::SysExAssembler) and sent
+//! with a [`SysExTransaction`](crate::SysExTransaction).
+
+use crate::sysex::{SYSEX_END, SYSEX_START};
+use crate::{Error, Note};
+
+const NON_REAL_TIME: u8 = 0x7e;
+const REAL_TIME: u8 = 0x7f;
+const MIDI_TUNING: u8 = 0x08;
+const BULK_DUMP_REQUEST: u8 = 0x00;
+const BULK_DUMP: u8 = 0x01;
+const NOTE_CHANGE: u8 = 0x02;
+
+pub use crate::universal::ALL_DEVICES;
+
+/// Length of a bulk tuning dump.
+pub const BULK_DUMP_LEN: usize = 6 + NAME_LEN + 3 * 128 + 2;
+
+const NAME_LEN: usize = 16;
+
+/// Pitch of a note, a semitone plus a fraction of 1/16384 semitone.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Tuning {
+    semitone: u8,
+    fraction: u16,
+}
+
+impl Tuning {
+    /// Tuning of the given semitone and 14-bit fraction.
+    pub const fn new(semitone: u8, fraction: u16) -> Self {
+        assert!(semitone < 0x80 && fraction < 0x4000, "tuning out of range");
+        Self { semitone, fraction }
+    }
+
+    /// The pitch of `note` in equal temperament.
+    pub const fn equal(note: Note) -> Self {
+        Self::new(note.number(), 0)
+    }
+
+    pub const fn semitone(self) -> u8 {
+        self.semitone
+    }
+
+    pub const fn fraction(self) -> u16 {
+        self.fraction
+    }
+
+    /// The pitch in cents above note 0, or `None` outside the MIDI range.
+    pub fn from_cents(cents: f32) -> Option<Self> {
+        if !(0.0..12_800.0).contains(&cents) {
+            return None;
+        }
+        let semitone = (cents / 100.0) as u8;
+        let fraction = ((cents - f32::from(semitone) * 100.0) * 16_384.0 / 100.0) as u16;
+        Some(Self::new(semitone, fraction.min(0x3fff)))
+    }
+
+    pub fn cents(self) -> f32 {
+        f32::from(self.semitone) * 100.0 + f32::from(self.fraction) * 100.0 / 16_384.0
+    }
+
+    fn to_bytes(self) -> [u8; 3] {
+        [self.semitone, (self.fraction >> 7) as u8, self.fraction as u8 & 0x7f]
+    }
+
+    /// Parses the three bytes of a tuning, `None` meaning no change.
+    fn from_bytes(bytes: &[u8]) -> Result<Option<Self>, Error> {
+        match *bytes {
+            [0x7f, 0x7f, 0x7f] => Ok(None),
+            [semitone, msb, lsb] if (semitone | msb | lsb) & 0x80 == 0 => {
+                Ok(Some(Self::new(semitone, u16::from(msb) << 7 | u16::from(lsb))))
+            }
+            _ => Err(Error::Malformed),
+        }
+    }
+}
+
+/// Tunings of all 128 notes with a name, as sent in a bulk tuning dump.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct TuningTable {
+    /// ASCII name, padded with spaces.
+    pub name: [u8; NAME_LEN],
+    pub notes: [Tuning; 128],
+}
+
+impl TuningTable {
+    /// Twelve-tone equal temperament.
+    pub fn equal_temperament() -> Self {
+        let mut notes = [Tuning::new(0, 0); 128];
+        for (number, tuning) in notes.iter_mut().enumerate() {
+            *tuning = Tuning::equal(Note::new(number as u8));
+        }
+        Self {
+            name: *b"Equal           ",
+            notes,
+        }
+    }
+
+    pub fn tuning(&self, note: Note) -> Tuning {
+        self.notes[note.number() as usize]
+    }
+
+    /// Applies the changes of a single note tuning change message.
+    pub fn apply(&mut self, changes: NoteChanges) {
+        for (note, tuning) in changes {
+            self.notes[note.number() as usize] = tuning;
+        }
+    }
+
+    /// Writes a bulk tuning dump of the table to `data`, returning its length
+    /// [`BULK_DUMP_LEN`].
+    pub fn write_bulk_dump(&self, device: u8, program: u8, data: &mut [u8]) -> Result<usize, Error> {
+        let data = data.get_mut(..BULK_DUMP_LEN).ok_or(Error::BufferOverflow)?;
+        data[..6].copy_from_slice(&[SYSEX_START, NON_REAL_TIME, device, MIDI_TUNING, BULK_DUMP, program]);
+        data[6..6 + NAME_LEN].copy_from_slice(&self.name);
+        for (bytes, tuning) in data[6 + NAME_LEN..].chunks_exact_mut(3).zip(&self.notes) {
+            bytes.copy_from_slice(&tuning.to_bytes());
+        }
+        data[BULK_DUMP_LEN - 2] = checksum(&data[1..BULK_DUMP_LEN - 2]);
+        data[BULK_DUMP_LEN - 1] = SYSEX_END;
+        Ok(BULK_DUMP_LEN)
+    }
+
+    /// Parses a bulk tuning dump, returning the program number and the table.
+    ///
+    /// Notes marked as unchanged keep their equal temperament tuning. Fails
+    /// with [`Error::Malformed`] for other messages or a wrong checksum.
+    pub fn parse_bulk_dump(message: &[u8]) -> Result<(u8, Self), Error> {
+        let (program, payload) = match message {
+            [SYSEX_START, NON_REAL_TIME, _, MIDI_TUNING, BULK_DUMP, program, payload @ .., SYSEX_END]
+                if message.len() == BULK_DUMP_LEN =>
+            {
+                (*program, payload)
+            }
+            _ => return Err(Error::Malformed),
+        };
+        let (data, checksum_byte) = payload.split_at(payload.len() - 1);
+        if checksum_byte[0] != checksum(&message[1..BULK_DUMP_LEN - 2]) {
+            return Err(Error::Malformed);
+        }
+        let mut table = Self::equal_temperament();
+        table.name.copy_from_slice(&data[..NAME_LEN]);
+        for (tuning, bytes) in table.notes.iter_mut().zip(data[NAME_LEN..].chunks_exact(3)) {
+            if let Some(parsed) = Tuning::from_bytes(bytes)? {
+                *tuning = parsed;
+            }
+        }
+        Ok((program, table))
+    }
+}
+
+impl Default for TuningTable {
+    fn default() -> Self {
+        Self::equal_temperament()
+    }
+}
+
+/// A request for the bulk tuning dump of `program`.
+pub const fn bulk_dump_request(device: u8, program: u8) -> [u8; 7] {
+    [
+        SYSEX_START,
+        NON_REAL_TIME,
+        device,
+        MIDI_TUNING,
+        BULK_DUMP_REQUEST,
+        program,
+        SYSEX_END,
+    ]
+}
+
+/// If `message` is a bulk tuning dump request, the device ID and program.
+pub fn parse_bulk_dump_request(message: &[u8]) -> Option<(u8, u8)> {
+    match *message {
+        [SYSEX_START, NON_REAL_TIME, device, MIDI_TUNING, BULK_DUMP_REQUEST, program, SYSEX_END] => {
+            Some((device, program))
+        }
+        _ => None,
+    }
+}
+
+/// Writes a real-time single note tuning change to `data`, returning its
+/// length.
+pub fn write_note_change(device: u8, program: u8, changes: &[(Note, Tuning)], data: &mut [u8]) -> Result<usize, Error> {
+    if changes.len() > 127 {
+        return Err(Error::BufferOverflow);
+    }
+    let len = 8 + 4 * changes.len();
+    let data = data.get_mut(..len).ok_or(Error::BufferOverflow)?;
+    data[..7].copy_from_slice(&[
+        SYSEX_START,
+        REAL_TIME,
+        device,
+        MIDI_TUNING,
+        NOTE_CHANGE,
+        program,
+        changes.len() as u8,
+    ]);
+    for (bytes, (note, tuning)) in data[7..].chunks_exact_mut(4).zip(changes) {
+        bytes[0] = note.number();
+        bytes[1..].copy_from_slice(&tuning.to_bytes());
+    }
+    data[len - 1] = SYSEX_END;
+    Ok(len)
+}
+
+/// Parses a real-time single note tuning change, returning the program
+/// number and the changes.
+pub fn parse_note_change(message: &[u8]) -> Result<(u8, NoteChanges<'_>), Error> {
+    match message {
+        [SYSEX_START, REAL_TIME, _, MIDI_TUNING, NOTE_CHANGE, program, count, changes @ .., SYSEX_END]
+            if changes.len() == 4 * usize::from(*count) =>
+        {
+            if changes
+                .chunks_exact(4)
+                .any(|change| change[0] & 0x80 != 0 || Tuning::from_bytes(&change[1..]).is_err())
+            {
+                return Err(Error::Malformed);
+            }
+            Ok((
+                *program,
+                NoteChanges {
+                    changes: changes.chunks_exact(4),
+                },
+            ))
+        }
+        _ => Err(Error::Malformed),
+    }
+}
+
+/// The changed notes of a single note tuning change.
+pub struct NoteChanges<'a> {
+    changes: core::slice::ChunksExact<'a, u8>,
+}
+
+impl Iterator for NoteChanges<'_> {
+    type Item = (Note, Tuning);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let change = self.changes.next()?;
+            if let Ok(Some(tuning)) = Tuning::from_bytes(&change[1..]) {
+                return Some((Note::new(change[0]), tuning));
+            }
+        }
+    }
+}
+
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |checksum, byte| checksum ^ byte) & 0x7f
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_cents() {
+        let tuning = Tuning::from_cents(6_050.0).unwrap();
+        assert_eq!((tuning.semitone(), tuning.fraction()), (60, 0x2000));
+        assert_eq!(tuning.cents(), 6_050.0);
+        assert_eq!(Tuning::from_cents(-1.0), None);
+        assert_eq!(Tuning::from_cents(12_800.0), None);
+    }
+
+    #[test]
+    fn round_trips_bulk_dumps() {
+        let mut table = TuningTable::equal_temperament();
+        table.name = *b"Quarter tones   ";
+        table.notes[61] = Tuning::new(60, 0x2000);
+        let mut data = [0; BULK_DUMP_LEN];
+        assert_eq!(table.write_bulk_dump(ALL_DEVICES, 3, &mut data), Ok(BULK_DUMP_LEN));
+        assert_eq!(data[6 + NAME_LEN + 3 * 61..][..3], [60, 0x40, 0]);
+        assert_eq!(TuningTable::parse_bulk_dump(&data), Ok((3, table)));
+
+        data[6 + NAME_LEN] ^= 1;
+        assert_eq!(TuningTable::parse_bulk_dump(&data), Err(Error::Malformed));
+        assert_eq!(TuningTable::parse_bulk_dump(&data[1..]), Err(Error::Malformed));
+    }
+
+    #[test]
+    fn handles_dump_requests() {
+        assert_eq!(parse_bulk_dump_request(&bulk_dump_request(0x10, 5)), Some((0x10, 5)));
+        assert_eq!(parse_bulk_dump_request(&[0xf0, 0x7e, 0x10, 0x08, 0x01, 5, 0xf7]), None);
+    }
+
+    #[test]
+    fn round_trips_note_changes() {
+        let changes = [
+            (Note::new(69), Tuning::new(69, 0x100)),
+            (Note::new(70), Tuning::new(69, 0x3000)),
+        ];
+        let mut data = [0; 32];
+        let len = write_note_change(ALL_DEVICES, 0, &changes, &mut data).unwrap();
+        assert_eq!(data[..7], [0xf0, 0x7f, 0x7f, 0x08, 0x02, 0, 2]);
+        let (program, parsed) = parse_note_change(&data[..len]).unwrap();
+        assert_eq!(program, 0);
+        assert!(parsed.eq(changes));
+
+        let mut table = TuningTable::equal_temperament();
+        table.apply(parse_note_change(&data[..len]).unwrap().1);
+        assert_eq!(table.tuning(Note::new(70)), changes[1].1);
+        assert_eq!(table.tuning(Note::new(71)), Tuning::equal(Note::new(71)));
+
+        assert_eq!(
+            write_note_change(0, 0, &changes, &mut data[..15]),
+            Err(Error::BufferOverflow)
+        );
+        data[7] = 0x80;
+        assert!(parse_note_change(&data[..len]).is_err());
+    }
+
+    #[test]
+    fn skips_unchanged_notes() {
+        let message = [0xf0, 0x7f, 0x7f, 0x08, 0x02, 0, 1, 60, 0x7f, 0x7f, 0x7f, 0xf7];
+        assert_eq!(parse_note_change(&message).unwrap().1.count(), 0);
+    }
+}
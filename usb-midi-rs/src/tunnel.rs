@@ -0,0 +1,162 @@
+//! MIDI over the control endpoint, for parts with too few endpoints for the
+//! bulk endpoints of a [`UsbMidiClass`](crate::UsbMidiClass).
+//!
+//! A [`ControlTunnel`] adds a vendor-specific interface without endpoints.
+//! The host sends USB-MIDI packets with [`REQUEST_MIDI_OUT`] and polls for
+//! packets with [`REQUEST_MIDI_IN`], both vendor requests to that interface.
+//! A control transfer takes a few frames, so the tunnel only suits low rates,
+//! e.g. a handful of controls, and needs a driver on the host. The packets go
+//! to the same [`Queues`] and come from the same [`TxQueue`] as those of the
+//! class, so the rest of the stack works unchanged.
+
+use embassy_sync::blocking_mutex::raw::RawMutex;
+use embassy_time::Instant;
+use embassy_usb::control::{ControlHandler, InResponse, OutResponse, Request, RequestType};
+use embassy_usb::driver::Driver;
+use embassy_usb::Builder;
+
+use crate::{CablePolicy, Events, Queues, TxQueue};
+
+/// Vendor request carrying USB-MIDI packets from the host to the device.
+pub const REQUEST_MIDI_OUT: u8 = 0x01;
+/// Vendor request reading the USB-MIDI packets waiting for the host, as many
+/// as fit into `wLength`.
+pub const REQUEST_MIDI_IN: u8 = 0x02;
+
+const VENDOR_CLASS: u8 = 0xff;
+
+/// Tunnels the `N` cables of [`Queues`] and a [`TxQueue`] through vendor
+/// control transfers.
+pub struct ControlTunnel<'d, M: RawMutex, const N: usize, const Q: usize, const T: usize> {
+    queues: &'d Queues<M, N, Q>,
+    tx: &'d TxQueue<M, N, T>,
+    dropped_packets: u32,
+}
+
+impl<'d, M: RawMutex, const N: usize, const Q: usize, const T: usize> ControlTunnel<'d, M, N, Q, T> {
+    pub fn new(queues: &'d Queues<M, N, Q>, tx: &'d TxQueue<M, N, T>) -> Self {
+        Self {
+            queues,
+            tx,
+            dropped_packets: 0,
+        }
+    }
+
+    /// Adds the vendor interface of `tunnel` to the device.
+    pub fn build<D: Driver<'d>>(builder: &mut Builder<'d, D>, tunnel: &'d mut Self) {
+        let mut func = builder.function(VENDOR_CLASS, 0, 0);
+        let mut iface = func.interface();
+        iface.handler(tunnel);
+        iface.alt_setting(VENDOR_CLASS, 0, 0);
+    }
+
+    /// Number of packets dropped because of an invalid cable number.
+    pub fn dropped_packets(&self) -> u32 {
+        self.dropped_packets
+    }
+
+    /// Queues the packets of a [`REQUEST_MIDI_OUT`] transfer.
+    pub fn receive(&mut self, data: &[u8]) {
+        let now = Instant::now();
+        let events = Events::<N>::new(data, N as u8, CablePolicy::Drop, &mut self.dropped_packets);
+        for (cable, event) in events.flatten() {
+            self.queues.push(cable, now, event);
+        }
+    }
+
+    /// Moves waiting packets to `buf` for a [`REQUEST_MIDI_IN`] transfer,
+    /// returning their length.
+    pub fn send(&mut self, buf: &mut [u8]) -> usize {
+        let mut len = 0;
+        for packet in buf.chunks_exact_mut(4) {
+            match self.tx.try_next() {
+                Some((cable, event)) => packet.copy_from_slice(&event.to_packet(cable.number())),
+                None => break,
+            }
+            len += 4;
+        }
+        len
+    }
+}
+
+impl<'d, M: RawMutex, const N: usize, const Q: usize, const T: usize> ControlHandler for ControlTunnel<'d, M, N, Q, T> {
+    fn control_out(&mut self, req: Request, data: &[u8]) -> OutResponse {
+        match (req.request_type, req.request) {
+            (RequestType::Vendor, REQUEST_MIDI_OUT) => {
+                self.receive(data);
+                OutResponse::Accepted
+            }
+            _ => OutResponse::Rejected,
+        }
+    }
+
+    fn control_in<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> InResponse<'a> {
+        match (req.request_type, req.request) {
+            (RequestType::Vendor, REQUEST_MIDI_IN) => {
+                let len = buf.len().min(usize::from(req.length));
+                let len = self.send(&mut buf[..len]);
+                InResponse::Accepted(&buf[..len])
+            }
+            _ => InResponse::Rejected,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+    use embassy_usb::control::{Direction, Recipient};
+
+    use super::*;
+    use crate::{CableNumber, Event, MidiMessage};
+
+    fn request(direction: Direction, request: u8, length: u16) -> Request {
+        Request {
+            direction,
+            request_type: RequestType::Vendor,
+            recipient: Recipient::Interface,
+            request,
+            value: 0,
+            index: 0,
+            length,
+        }
+    }
+
+    #[test]
+    fn tunnels_packets() {
+        let queues = Queues::<NoopRawMutex, 2>::new();
+        let tx = TxQueue::<NoopRawMutex, 2>::new();
+        let mut tunnel = ControlTunnel::new(&queues, &tx);
+        let cable = CableNumber::new(1).unwrap();
+
+        let out = request(Direction::Out, REQUEST_MIDI_OUT, 8);
+        assert_eq!(
+            tunnel.control_out(out, &[0x1f, 0xfa, 0, 0, 0x2f, 0xfc, 0, 0]),
+            OutResponse::Accepted
+        );
+        assert_eq!(queues.try_receive(cable), Some(MidiMessage::Start.into()));
+        assert_eq!(tunnel.dropped_packets(), 1);
+
+        for control in 0..3 {
+            tx.try_write_event(cable, Event::ControlChange(0xb0, control, 1))
+                .unwrap();
+        }
+        let mut buf = [0; 64];
+        let polled = request(Direction::In, REQUEST_MIDI_IN, 8);
+        assert_eq!(
+            tunnel.control_in(polled, &mut buf),
+            InResponse::Accepted(&[0x1b, 0xb0, 0, 1, 0x1b, 0xb0, 1, 1])
+        );
+        assert_eq!(
+            tunnel.control_in(polled, &mut buf),
+            InResponse::Accepted(&[0x1b, 0xb0, 2, 1])
+        );
+        assert_eq!(tunnel.control_in(polled, &mut buf), InResponse::Accepted(&[]));
+
+        let standard = Request {
+            request_type: RequestType::Standard,
+            ..out
+        };
+        assert_eq!(tunnel.control_out(standard, &[]), OutResponse::Rejected);
+    }
+}
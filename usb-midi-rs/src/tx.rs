@@ -0,0 +1,254 @@
+#[cfg(feature = "usb")]
+use embassy_futures::select::{select, Either};
+use embassy_sync::blocking_mutex::raw::RawMutex;
+use embassy_sync::channel::Channel;
+use embassy_time::Duration;
+#[cfg(feature = "usb")]
+use embassy_time::{Instant, Timer};
+#[cfg(feature = "usb")]
+use embassy_usb::driver::Driver;
+
+use crate::{CableNumber, Counter, Error, Event, MidiMessage};
+#[cfg(feature = "usb")]
+use crate::{Sender, MAX_PACKET_SIZE};
+
+/// Default capacity of a [`TxQueue`].
+pub const TX_QUEUE_SIZE: usize = 16;
+/// Capacity of the lane for real-time messages of a [`TxQueue`].
+pub(crate) const REAL_TIME_QUEUE_SIZE: usize = 4;
+
+/// Events waiting to be sent to the host.
+///
+/// Writing to the queue never waits for the host, so it can be used from
+/// tasks with timing constraints such as a keyboard scanner. A separate task
+/// drains the queue with [`run`](Self::run).
+///
+/// Real-time messages such as clocks have a lane of their own, which is
+/// drained first whenever a packet is taken. They thus overtake other events
+/// waiting to be sent, e.g. the packets of a dump, and are delayed by at most
+/// one transfer.
+pub struct TxQueue<M: RawMutex, const N: usize, const Q: usize = TX_QUEUE_SIZE> {
+    events: Channel<M, (CableNumber<N>, Event), Q>,
+    real_time: Channel<M, (CableNumber<N>, Event), REAL_TIME_QUEUE_SIZE>,
+    overflows: Counter,
+    max_latency: Duration,
+}
+
+impl<M: RawMutex, const N: usize, const Q: usize> TxQueue<M, N, Q> {
+    pub fn new() -> Self {
+        Self {
+            events: Channel::new(),
+            real_time: Channel::new(),
+            overflows: Counter::new(),
+            max_latency: Duration::from_ticks(0),
+        }
+    }
+
+    /// Holds back events for up to `max_latency`, e.g. 1 ms, to fill
+    /// transfers with events that follow.
+    ///
+    /// By default events are sent as soon as the host takes them, which
+    /// needs the most transfers under load. A full transfer is sent right
+    /// away.
+    pub fn with_max_latency(mut self, max_latency: Duration) -> Self {
+        self.max_latency = max_latency;
+        self
+    }
+
+    /// Number of events dropped because the queue was full.
+    pub fn overflows(&self) -> u32 {
+        self.overflows.get()
+    }
+
+    /// Queues `event`, waiting for room if the queue is full.
+    pub async fn write_event(&self, cable: CableNumber<N>, event: Event) {
+        match event.is_real_time() {
+            true => self.real_time.send((cable, event)).await,
+            false => self.events.send((cable, event)).await,
+        }
+    }
+
+    /// Queues `event` or drops it with [`Error::BufferOverflow`] if the queue
+    /// is full.
+    pub fn try_write_event(&self, cable: CableNumber<N>, event: Event) -> Result<(), Error> {
+        let result = match event.is_real_time() {
+            true => self.real_time.try_send((cable, event)),
+            false => self.events.try_send((cable, event)),
+        };
+        result.map_err(|_| {
+            warn!("TX queue full, event for cable {} dropped", cable.number());
+            self.overflows.increment();
+            Error::BufferOverflow
+        })
+    }
+
+    pub async fn write_message(&self, cable: CableNumber<N>, message: MidiMessage) {
+        self.write_event(cable, message.into()).await
+    }
+
+    pub fn try_write_message(&self, cable: CableNumber<N>, message: MidiMessage) -> Result<(), Error> {
+        self.try_write_event(cable, message.into())
+    }
+
+    pub(crate) fn events(&self) -> &Channel<M, (CableNumber<N>, Event), Q> {
+        &self.events
+    }
+
+    pub(crate) fn real_time(&self) -> &Channel<M, (CableNumber<N>, Event), REAL_TIME_QUEUE_SIZE> {
+        &self.real_time
+    }
+
+    /// Takes the next event, real-time messages first.
+    #[cfg(any(test, feature = "usb"))]
+    pub(crate) fn try_next(&self) -> Option<(CableNumber<N>, Event)> {
+        self.real_time.try_recv().or_else(|_| self.events.try_recv()).ok()
+    }
+
+    /// Waits for the next event, real-time messages first.
+    #[cfg(feature = "usb")]
+    async fn next(&self) -> (CableNumber<N>, Event) {
+        match select(self.real_time.recv(), self.events.recv()).await {
+            Either::First(next) | Either::Second(next) => next,
+        }
+    }
+
+    /// Waits for events and moves as many of them as fit into `buf`, as
+    /// packets. Events queued in the meantime, or within the maximum latency
+    /// of the first one, share the transfer, whatever their cable.
+    ///
+    /// Returns the length of the packets and the cables they are for as a
+    /// bit mask. Events for cables beyond `ports` are dropped.
+    #[cfg(feature = "usb")]
+    pub(crate) async fn gather(&self, buf: &mut [u8], ports: usize) -> (usize, u16) {
+        self.gather_with(buf, ports, Instant::now).await
+    }
+
+    /// Like [`gather`](Self::gather), taking the time from `now`.
+    #[cfg(feature = "usb")]
+    async fn gather_with(&self, buf: &mut [u8], ports: usize, now: impl Fn() -> Instant) -> (usize, u16) {
+        let (mut len, mut cables) = (0, 0);
+        let mut next = Some(self.next().await);
+        let deadline = now() + self.max_latency;
+        while let Some((cable, event)) = next {
+            if usize::from(cable.number()) < ports {
+                buf[len..len + 4].copy_from_slice(&event.to_packet(cable.number()));
+                len += 4;
+                cables |= 1 << cable.number();
+            } else {
+                debug!("event for cable {} dropped: {}", cable.number(), Error::Unsupported);
+            }
+            if len == buf.len() {
+                break;
+            }
+            next = match self.try_next() {
+                Some(next) => Some(next),
+                None if now() >= deadline => None,
+                None => match select(self.next(), Timer::at(deadline)).await {
+                    Either::First(next) => Some(next),
+                    Either::Second(()) => None,
+                },
+            };
+        }
+        (len, cables)
+    }
+
+    /// Sends queued events for as long as the device runs.
+    ///
+    /// Events waiting for the host are sent together, so the events of all
+    /// cables share transfers under load. Events are dropped while the host
+    /// is not connected. See [`RetryingSender::run`](crate::RetryingSender::run)
+    /// to retry them.
+    #[cfg(feature = "usb")]
+    pub async fn run<'d, D: Driver<'d>>(&self, sender: &mut Sender<'d, D, N>) -> ! {
+        let mut buf = [0; MAX_PACKET_SIZE as usize];
+        loop {
+            let buf = &mut buf[..sender.packet_size().bytes()];
+            let (len, _) = self.gather(buf, sender.ports()).await;
+            if len > 0 {
+                if let Err(error) = sender.write_packet(&buf[..len]).await {
+                    debug!("{} events dropped: {}", len / 4, Error::from(error));
+                }
+            }
+        }
+    }
+}
+
+impl<M: RawMutex, const N: usize, const Q: usize> Default for TxQueue<M, N, Q> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(all(test, feature = "usb"))]
+mod tests {
+    use core::cell::Cell;
+    use core::pin::pin;
+    use core::task::Poll;
+
+    use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+
+    use super::*;
+    use crate::fault::{endpoints, poll_once, Faults, FaultyDriver, Pipe};
+
+    #[test]
+    fn shares_transfers_between_cables() {
+        let (pipe, faults) = (Pipe::default(), Faults::default());
+        let mut sender: Sender<FaultyDriver<Pipe>, 4> = Sender::from_endpoint(endpoints(&pipe, &faults).1, 2);
+        let tx = TxQueue::<NoopRawMutex, 4>::new();
+        for number in [0, 1, 3, 0] {
+            tx.try_write_message(CableNumber::new(number).unwrap(), MidiMessage::Start)
+                .unwrap();
+        }
+
+        let mut run = pin!(tx.run(&mut sender));
+        assert!(poll_once(run.as_mut()).is_pending());
+        // The event for the undeclared cable 3 is dropped.
+        assert_eq!(
+            pipe.take_written(),
+            [[0x0f, 0xfa, 0, 0, 0x1f, 0xfa, 0, 0, 0x0f, 0xfa, 0, 0]]
+        );
+    }
+
+    #[test]
+    fn flushes_partial_transfers() {
+        let tx = TxQueue::<NoopRawMutex, 1>::new().with_max_latency(Duration::from_millis(5));
+        let cable = CableNumber::new(0).unwrap();
+        // Far ahead of the timer, so the deadline passes only on `now`.
+        let start = Instant::from_secs(1 << 30);
+        let now = Cell::new(start);
+        let mut buf = [0; 64];
+
+        {
+            let mut gather = pin!(tx.gather_with(&mut buf, 1, || now.get()));
+            tx.try_write_message(cable, MidiMessage::Start).unwrap();
+            assert!(poll_once(gather.as_mut()).is_pending());
+            now.set(start + Duration::from_millis(4));
+            tx.try_write_message(cable, MidiMessage::Stop).unwrap();
+            assert!(poll_once(gather.as_mut()).is_pending());
+
+            now.set(start + Duration::from_millis(5));
+            tx.try_write_message(cable, MidiMessage::Continue).unwrap();
+            assert_eq!(poll_once(gather.as_mut()), Poll::Ready((12, 1)));
+        }
+        assert_eq!(buf[..12], [0x0f, 0xfa, 0, 0, 0x0f, 0xfc, 0, 0, 0x0f, 0xfb, 0, 0]);
+    }
+
+    #[test]
+    fn lets_clocks_overtake() {
+        let (pipe, faults) = (Pipe::default(), Faults::default());
+        let write_ep = crate::fault::sized_endpoints(&pipe, &faults, 8, 8).1;
+        let mut sender: Sender<FaultyDriver<Pipe>, 1> = Sender::from_endpoint(write_ep, 1);
+        let tx = TxQueue::<NoopRawMutex, 1>::new();
+        let cable = CableNumber::new(0).unwrap();
+        for byte in [0xf0, 0x7d, 1, 2, 3, 4, 5, 0xf7] {
+            tx.try_write_event(cable, Event::SingleByte(byte)).unwrap();
+        }
+        tx.try_write_message(cable, MidiMessage::TimingClock).unwrap();
+
+        let mut run = pin!(tx.run(&mut sender));
+        assert!(poll_once(run.as_mut()).is_pending());
+        let written = pipe.take_written();
+        assert_eq!(written[0][..4], [0x0f, 0xf8, 0, 0]);
+        assert_eq!(written.len(), 5);
+    }
+}
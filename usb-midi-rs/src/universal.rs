@@ -0,0 +1,256 @@
+//! Universal System Exclusive messages of General MIDI and GM2.
+//!
+//! [`UniversalMessage`] covers the messages a sound module commonly needs:
+//! switching General MIDI on and off, the master device controls and the
+//! GM2 reverb and chorus parameters.
+
+use crate::sysex::{SYSEX_END, SYSEX_START};
+use crate::Error;
+
+const NON_REAL_TIME: u8 = 0x7e;
+const REAL_TIME: u8 = 0x7f;
+const GENERAL_MIDI: u8 = 0x09;
+const DEVICE_CONTROL: u8 = 0x04;
+const GLOBAL_PARAMETER: u8 = 0x05;
+const REVERB: u8 = 0x01;
+const CHORUS: u8 = 0x02;
+
+/// Device ID addressing all devices.
+pub const ALL_DEVICES: u8 = 0x7f;
+
+/// Longest message in bytes.
+pub const MAX_LEN: usize = 13;
+
+/// Reverb parameters of GM2.
+pub const REVERB_TYPE: u8 = 0;
+pub const REVERB_TIME: u8 = 1;
+
+/// Chorus parameters of GM2.
+pub const CHORUS_TYPE: u8 = 0;
+pub const CHORUS_MOD_RATE: u8 = 1;
+pub const CHORUS_MOD_DEPTH: u8 = 2;
+pub const CHORUS_FEEDBACK: u8 = 3;
+pub const CHORUS_SEND_TO_REVERB: u8 = 4;
+
+/// A Universal System Exclusive message.
+///
+/// 14-bit values are centered at 0x2000 where that makes sense, like pitch
+/// bend.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum UniversalMessage {
+    GmSystemOn,
+    GmSystemOff,
+    Gm2SystemOn,
+    /// 14-bit volume, 0x3fff being the loudest.
+    MasterVolume(u16),
+    /// 14-bit balance, 0 being hard left.
+    MasterBalance(u16),
+    /// 14-bit fine tuning from -100 to almost +100 cents.
+    MasterFineTuning(u16),
+    /// Coarse tuning in semitones from -64 to 63.
+    MasterCoarseTuning(i8),
+    /// A reverb parameter such as [`REVERB_TIME`] and its value.
+    ReverbParameter(u8, u8),
+    /// A chorus parameter such as [`CHORUS_MOD_RATE`] and its value.
+    ChorusParameter(u8, u8),
+}
+
+impl UniversalMessage {
+    /// Writes the message for `device`, e.g. `0x7f` for all devices, to
+    /// `data` and returns its length.
+    pub fn write(&self, device: u8, data: &mut [u8]) -> Result<usize, Error> {
+        let mut message = [0; MAX_LEN];
+        let body: &[u8] = match *self {
+            UniversalMessage::GmSystemOn => &[NON_REAL_TIME, device, GENERAL_MIDI, 0x01],
+            UniversalMessage::GmSystemOff => &[NON_REAL_TIME, device, GENERAL_MIDI, 0x02],
+            UniversalMessage::Gm2SystemOn => &[NON_REAL_TIME, device, GENERAL_MIDI, 0x03],
+            UniversalMessage::MasterVolume(value) => &device_control(device, 0x01, value),
+            UniversalMessage::MasterBalance(value) => &device_control(device, 0x02, value),
+            UniversalMessage::MasterFineTuning(value) => &device_control(device, 0x03, value),
+            UniversalMessage::MasterCoarseTuning(semitones) => {
+                &device_control(device, 0x04, ((semitones.clamp(-64, 63) + 64) as u16) << 7)
+            }
+            UniversalMessage::ReverbParameter(parameter, value) => &global_parameter(device, REVERB, parameter, value),
+            UniversalMessage::ChorusParameter(parameter, value) => &global_parameter(device, CHORUS, parameter, value),
+        };
+        let len = body.len() + 2;
+        message[0] = SYSEX_START;
+        message[1..len - 1].copy_from_slice(body);
+        message[len - 1] = SYSEX_END;
+        data.get_mut(..len)
+            .ok_or(Error::BufferOverflow)?
+            .copy_from_slice(&message[..len]);
+        Ok(len)
+    }
+
+    /// Parses a complete System Exclusive message, returning the device ID
+    /// and the message.
+    pub fn parse(message: &[u8]) -> Option<(u8, Self)> {
+        let parsed = match *message {
+            [SYSEX_START, NON_REAL_TIME, device, GENERAL_MIDI, sub_id, SYSEX_END] => match sub_id {
+                0x01 => (device, UniversalMessage::GmSystemOn),
+                0x02 => (device, UniversalMessage::GmSystemOff),
+                0x03 => (device, UniversalMessage::Gm2SystemOn),
+                _ => return None,
+            },
+            [SYSEX_START, REAL_TIME, device, DEVICE_CONTROL, sub_id, lsb, msb, SYSEX_END] => {
+                let value = u16::from(msb) << 7 | u16::from(lsb);
+                match sub_id {
+                    0x01 => (device, UniversalMessage::MasterVolume(value)),
+                    0x02 => (device, UniversalMessage::MasterBalance(value)),
+                    0x03 => (device, UniversalMessage::MasterFineTuning(value)),
+                    0x04 => (device, UniversalMessage::MasterCoarseTuning(msb as i8 - 64)),
+                    _ => return None,
+                }
+            }
+            [SYSEX_START, REAL_TIME, device, DEVICE_CONTROL, GLOBAL_PARAMETER, ref rest @ ..] => match *rest {
+                // A slot path of one slot, one-byte parameters and values, then the
+                // two bytes of the slot: 01 01 for reverb or 01 02 for chorus.
+                [0x01, 0x01, 0x01, 0x01, REVERB, parameter, value, SYSEX_END] => {
+                    (device, UniversalMessage::ReverbParameter(parameter, value))
+                }
+                [0x01, 0x01, 0x01, 0x01, CHORUS, parameter, value, SYSEX_END] => {
+                    (device, UniversalMessage::ChorusParameter(parameter, value))
+                }
+                _ => return None,
+            },
+            _ => return None,
+        };
+        match message[1..message.len() - 1].iter().all(|byte| byte & 0x80 == 0) {
+            true => Some(parsed),
+            false => None,
+        }
+    }
+}
+
+/// The device ID of `message` if it is a Universal System Exclusive message.
+pub fn device_id(message: &[u8]) -> Option<u8> {
+    match *message {
+        [SYSEX_START, NON_REAL_TIME | REAL_TIME, device, ..] => Some(device),
+        _ => None,
+    }
+}
+
+/// Whether a device with the ID `device` should respond to `message`, i.e.
+/// the message is not a Universal System Exclusive message or addressed to
+/// the device or to [`ALL_DEVICES`].
+pub fn is_addressed_to(message: &[u8], device: u8) -> bool {
+    match device_id(message) {
+        Some(id) => id == device || id == ALL_DEVICES,
+        None => true,
+    }
+}
+
+fn device_control(device: u8, sub_id: u8, value: u16) -> [u8; 6] {
+    [
+        REAL_TIME,
+        device,
+        DEVICE_CONTROL,
+        sub_id,
+        value as u8 & 0x7f,
+        (value >> 7) as u8 & 0x7f,
+    ]
+}
+
+/// A GM2 global parameter control with a one-byte slot path, parameter and
+/// value.
+fn global_parameter(device: u8, slot: u8, parameter: u8, value: u8) -> [u8; 11] {
+    [
+        REAL_TIME,
+        device,
+        DEVICE_CONTROL,
+        GLOBAL_PARAMETER,
+        0x01,
+        0x01,
+        0x01,
+        0x01,
+        slot,
+        parameter & 0x7f,
+        value & 0x7f,
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    fn write(message: UniversalMessage) -> std::vec::Vec<u8> {
+        let mut data = [0; MAX_LEN];
+        let len = message.write(0x7f, &mut data).unwrap();
+        data[..len].to_vec()
+    }
+
+    #[test]
+    fn writes_messages() {
+        assert_eq!(
+            write(UniversalMessage::GmSystemOn),
+            [0xf0, 0x7e, 0x7f, 0x09, 0x01, 0xf7]
+        );
+        assert_eq!(
+            write(UniversalMessage::MasterVolume(0x3fff)),
+            [0xf0, 0x7f, 0x7f, 0x04, 0x01, 0x7f, 0x7f, 0xf7]
+        );
+        assert_eq!(
+            write(UniversalMessage::MasterCoarseTuning(-12)),
+            [0xf0, 0x7f, 0x7f, 0x04, 0x04, 0x00, 0x34, 0xf7]
+        );
+        assert_eq!(
+            write(UniversalMessage::ReverbParameter(REVERB_TIME, 64)),
+            [0xf0, 0x7f, 0x7f, 0x04, 0x05, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x40, 0xf7]
+        );
+        assert_eq!(
+            UniversalMessage::GmSystemOff.write(0, &mut [0; 5]),
+            Err(Error::BufferOverflow)
+        );
+    }
+
+    #[test]
+    fn rejects_other_messages() {
+        assert_eq!(UniversalMessage::parse(&[0xf0, 0x7e, 0x7f, 0x09, 0x04, 0xf7]), None);
+        assert_eq!(
+            UniversalMessage::parse(&[0xf0, 0x7f, 0x7f, 0x04, 0x01, 0x80, 0x00, 0xf7]),
+            None
+        );
+        assert_eq!(
+            UniversalMessage::parse(&[0xf0, 0x43, 0x10, 0x4c, 0x00, 0x00, 0x7e, 0x00, 0xf7]),
+            None
+        );
+    }
+
+    #[test]
+    fn filters_device_ids() {
+        let message = |device| [0xf0, 0x7e, device, 0x09, 0x01, 0xf7];
+        assert_eq!(device_id(&message(0x10)), Some(0x10));
+        assert!(is_addressed_to(&message(0x10), 0x10));
+        assert!(is_addressed_to(&message(ALL_DEVICES), 0x10));
+        assert!(!is_addressed_to(&message(0x11), 0x10));
+        assert_eq!(device_id(&[0xf0, 0x41, 0x10, 0xf7]), None);
+        assert!(is_addressed_to(&[0xf0, 0x41, 0x11, 0xf7], 0x10));
+    }
+
+    fn messages() -> impl Strategy<Value = UniversalMessage> {
+        prop_oneof![
+            Just(UniversalMessage::GmSystemOn),
+            Just(UniversalMessage::GmSystemOff),
+            Just(UniversalMessage::Gm2SystemOn),
+            (0u16..0x4000).prop_map(UniversalMessage::MasterVolume),
+            (0u16..0x4000).prop_map(UniversalMessage::MasterBalance),
+            (0u16..0x4000).prop_map(UniversalMessage::MasterFineTuning),
+            (-64i8..64).prop_map(UniversalMessage::MasterCoarseTuning),
+            (0u8..0x80, 0u8..0x80).prop_map(|(parameter, value)| UniversalMessage::ReverbParameter(parameter, value)),
+            (0u8..0x80, 0u8..0x80).prop_map(|(parameter, value)| UniversalMessage::ChorusParameter(parameter, value)),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn round_trips_messages(message in messages(), device in 0u8..0x80) {
+            let mut data = [0; MAX_LEN];
+            let len = message.write(device, &mut data).unwrap();
+            prop_assert_eq!(UniversalMessage::parse(&data[..len]), Some((device, message)));
+        }
+    }
+}
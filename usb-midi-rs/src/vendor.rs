@@ -0,0 +1,386 @@
+//! Encodings and checksums found in vendor System Exclusive messages.
+//!
+//! Roland protects the address and data of its Data Set messages with a
+//! 7-bit [`roland_checksum`]. Binary data is sent either split into nibbles,
+//! see [`nibblize`], or packed into groups of eight bytes carrying seven
+//! bytes of data, see [`pack`], or [`Packer`] and [`Unpacker`] for data
+//! spanning several messages. All functions work on the payload only,
+//! without `0xF0`, the header and `0xF7`.
+
+use crate::Error;
+
+/// Checksum of Roland Data Set and Data Request messages over the address
+/// and data bytes: the value that makes their sum a multiple of 128.
+pub fn roland_checksum(data: &[u8]) -> u8 {
+    let sum = data.iter().fold(0u8, |sum, byte| sum.wrapping_add(byte & 0x7f));
+    0u8.wrapping_sub(sum) & 0x7f
+}
+
+/// Whether `data` ends with its correct [`roland_checksum`].
+pub fn is_roland_checksum_valid(data: &[u8]) -> bool {
+    match data.split_last() {
+        Some((&checksum, data)) => roland_checksum(data) == checksum,
+        None => false,
+    }
+}
+
+/// Order of the nibbles of a byte.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum NibbleOrder {
+    /// The high nibble first, as used by Roland.
+    HighFirst,
+    /// The low nibble first, as used by Korg and Yamaha.
+    LowFirst,
+}
+
+/// Splits every byte of `data` into two nibbles, writing them to `encoded`
+/// and returning their number.
+///
+/// Fails with [`Error::BufferOverflow`] if `encoded` is shorter than twice
+/// `data`.
+pub fn nibblize(data: &[u8], order: NibbleOrder, encoded: &mut [u8]) -> Result<usize, Error> {
+    let len = 2 * data.len();
+    let encoded = encoded.get_mut(..len).ok_or(Error::BufferOverflow)?;
+    for (&byte, nibbles) in data.iter().zip(encoded.chunks_exact_mut(2)) {
+        let (high, low) = (byte >> 4, byte & 0x0f);
+        match order {
+            NibbleOrder::HighFirst => nibbles.copy_from_slice(&[high, low]),
+            NibbleOrder::LowFirst => nibbles.copy_from_slice(&[low, high]),
+        }
+    }
+    Ok(len)
+}
+
+/// Joins the nibbles of `encoded` into bytes, writing them to `data` and
+/// returning their number.
+///
+/// Fails with [`Error::Malformed`] if the number of nibbles is odd or a
+/// nibble is larger than 15, and with [`Error::BufferOverflow`] if `data` is
+/// too short.
+pub fn denibblize(encoded: &[u8], order: NibbleOrder, data: &mut [u8]) -> Result<usize, Error> {
+    let pairs = encoded.chunks_exact(2);
+    if !pairs.remainder().is_empty() || encoded.iter().any(|&nibble| nibble > 0x0f) {
+        return Err(Error::Malformed);
+    }
+    let len = pairs.len();
+    let data = data.get_mut(..len).ok_or(Error::BufferOverflow)?;
+    for (byte, nibbles) in data.iter_mut().zip(pairs) {
+        *byte = match order {
+            NibbleOrder::HighFirst => nibbles[0] << 4 | nibbles[1],
+            NibbleOrder::LowFirst => nibbles[1] << 4 | nibbles[0],
+        };
+    }
+    Ok(len)
+}
+
+/// Length of `len` bytes packed by [`pack`].
+pub const fn packed_len(len: usize) -> usize {
+    len / 7 * 8
+        + match len % 7 {
+            0 => 0,
+            rest => rest + 1,
+        }
+}
+
+/// Length of `len` packed bytes unpacked by [`unpack`].
+pub const fn unpacked_len(len: usize) -> usize {
+    len / 8 * 7
+        + match len % 8 {
+            0 => 0,
+            rest => rest - 1,
+        }
+}
+
+/// Packs `data` into 7-bit bytes, writing them to `encoded` and returning
+/// their number.
+///
+/// Every group of up to seven bytes is preceded by a byte carrying their
+/// most significant bits, the one of the first byte in bit 0, as done by
+/// Korg and many others.
+///
+/// Fails with [`Error::BufferOverflow`] if `encoded` is shorter than
+/// [`packed_len`].
+pub fn pack(data: &[u8], encoded: &mut [u8]) -> Result<usize, Error> {
+    let len = packed_len(data.len());
+    let encoded = encoded.get_mut(..len).ok_or(Error::BufferOverflow)?;
+    for (group, packed) in data.chunks(7).zip(encoded.chunks_mut(8)) {
+        packed[0] = 0;
+        for (i, &byte) in group.iter().enumerate() {
+            packed[0] |= (byte >> 7) << i;
+            packed[i + 1] = byte & 0x7f;
+        }
+    }
+    Ok(len)
+}
+
+/// Unpacks the 7-bit bytes of `encoded` written by [`pack`] to `data`,
+/// returning the number of bytes.
+///
+/// Fails with [`Error::Malformed`] if a byte has its most significant bit set
+/// or a group consists of its first byte only, and with
+/// [`Error::BufferOverflow`] if `data` is too short.
+pub fn unpack(encoded: &[u8], data: &mut [u8]) -> Result<usize, Error> {
+    if encoded.len() % 8 == 1 || encoded.iter().any(|&byte| byte & 0x80 != 0) {
+        return Err(Error::Malformed);
+    }
+    let len = unpacked_len(encoded.len());
+    let data = data.get_mut(..len).ok_or(Error::BufferOverflow)?;
+    for (packed, group) in encoded.chunks(8).zip(data.chunks_mut(7)) {
+        for (i, byte) in group.iter_mut().enumerate() {
+            *byte = packed[i + 1] | (packed[0] >> i & 1) << 7;
+        }
+    }
+    Ok(len)
+}
+
+/// Packs data like [`pack`] as it comes, e.g. a firmware image read in
+/// chunks that do not fit into a single message.
+#[derive(Clone, Default, Debug)]
+pub struct Packer {
+    group: [u8; 8],
+    len: usize,
+}
+
+impl Packer {
+    pub const fn new() -> Self {
+        Self { group: [0; 8], len: 0 }
+    }
+
+    /// Adds a byte, returning the group it completes.
+    pub fn push(&mut self, byte: u8) -> Option<[u8; 8]> {
+        if self.len == 0 {
+            self.group[0] = 0;
+        }
+        self.group[0] |= (byte >> 7) << self.len;
+        self.group[self.len + 1] = byte & 0x7f;
+        self.len += 1;
+        if self.len < 7 {
+            return None;
+        }
+        self.len = 0;
+        Some(self.group)
+    }
+
+    /// Adds `data`, writing the groups it completes to `encoded` and
+    /// returning their length.
+    ///
+    /// Fails with [`Error::BufferOverflow`] if `encoded` is too short, in
+    /// which case nothing is added.
+    pub fn encode(&mut self, data: &[u8], encoded: &mut [u8]) -> Result<usize, Error> {
+        let len = (self.len + data.len()) / 7 * 8;
+        let encoded = encoded.get_mut(..len).ok_or(Error::BufferOverflow)?;
+        let mut groups = encoded.chunks_exact_mut(8);
+        for &byte in data {
+            if let Some(group) = self.push(byte) {
+                // There is a chunk for every completed group.
+                if let Some(chunk) = groups.next() {
+                    chunk.copy_from_slice(&group);
+                }
+            }
+        }
+        Ok(len)
+    }
+
+    /// The incomplete group holding the last bytes added, empty if they
+    /// completed a group.
+    ///
+    /// The packer starts over with the next byte.
+    pub fn finish(&mut self) -> &[u8] {
+        let len = match self.len {
+            0 => 0,
+            len => len + 1,
+        };
+        self.len = 0;
+        &self.group[..len]
+    }
+}
+
+/// Unpacks data written by [`pack`] or a [`Packer`] as it comes.
+#[derive(Clone, Default, Debug)]
+pub struct Unpacker {
+    msbs: u8,
+    /// Position in the current group, 0 before its first byte.
+    position: usize,
+}
+
+impl Unpacker {
+    pub const fn new() -> Self {
+        Self { msbs: 0, position: 0 }
+    }
+
+    /// Adds an encoded byte, returning the byte it completes.
+    ///
+    /// Fails with [`Error::Malformed`] if the most significant bit of `byte`
+    /// is set.
+    pub fn push(&mut self, byte: u8) -> Result<Option<u8>, Error> {
+        if byte & 0x80 != 0 {
+            return Err(Error::Malformed);
+        }
+        let position = self.position;
+        self.position = (position + 1) % 8;
+        if position == 0 {
+            self.msbs = byte;
+            return Ok(None);
+        }
+        Ok(Some(byte | (self.msbs >> (position - 1) & 1) << 7))
+    }
+
+    /// Adds `encoded`, writing the bytes it completes to `data` and
+    /// returning their number.
+    ///
+    /// Fails with [`Error::Malformed`] if a byte has its most significant bit
+    /// set, and with [`Error::BufferOverflow`] if `data` is too short. In
+    /// either case nothing is added.
+    pub fn decode(&mut self, encoded: &[u8], data: &mut [u8]) -> Result<usize, Error> {
+        if encoded.iter().any(|&byte| byte & 0x80 != 0) {
+            return Err(Error::Malformed);
+        }
+        let len = unpacked_len(self.position + encoded.len()) - unpacked_len(self.position);
+        let data = data.get_mut(..len).ok_or(Error::BufferOverflow)?;
+        let mut bytes = data.iter_mut();
+        for &byte in encoded {
+            if let Ok(Some(decoded)) = self.push(byte) {
+                // There is a byte in `data` for everything decoded.
+                if let Some(slot) = bytes.next() {
+                    *slot = decoded;
+                }
+            }
+        }
+        Ok(len)
+    }
+
+    /// Whether the last group is complete, i.e. no group byte was added
+    /// without any of its data.
+    pub fn is_complete(&self) -> bool {
+        self.position != 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    #[test]
+    fn computes_roland_checksums() {
+        // Data Set of the GS reset: address 40 00 7F, data 00.
+        assert_eq!(roland_checksum(&[0x40, 0x00, 0x7f, 0x00]), 0x41);
+        assert_eq!(roland_checksum(&[0x40, 0x00, 0x04, 0x7f]), 0x3d);
+        assert_eq!(roland_checksum(&[]), 0);
+        assert!(is_roland_checksum_valid(&[0x40, 0x00, 0x7f, 0x00, 0x41]));
+        assert!(!is_roland_checksum_valid(&[0x40, 0x00, 0x7f, 0x00, 0x42]));
+        assert!(!is_roland_checksum_valid(&[]));
+    }
+
+    #[test]
+    fn nibblizes_data() {
+        let mut encoded = [0; 4];
+        assert_eq!(nibblize(&[0x12, 0xab], NibbleOrder::HighFirst, &mut encoded), Ok(4));
+        assert_eq!(encoded, [0x01, 0x02, 0x0a, 0x0b]);
+        assert_eq!(nibblize(&[0x12, 0xab], NibbleOrder::LowFirst, &mut encoded), Ok(4));
+        assert_eq!(encoded, [0x02, 0x01, 0x0b, 0x0a]);
+        assert_eq!(
+            nibblize(&[0; 3], NibbleOrder::LowFirst, &mut encoded),
+            Err(Error::BufferOverflow)
+        );
+        assert_eq!(
+            denibblize(&[0x01, 0x10], NibbleOrder::LowFirst, &mut [0; 1]),
+            Err(Error::Malformed)
+        );
+        assert_eq!(
+            denibblize(&[0x01], NibbleOrder::LowFirst, &mut [0; 1]),
+            Err(Error::Malformed)
+        );
+    }
+
+    #[test]
+    fn packs_data() {
+        let mut encoded = [0; 10];
+        let data = [0x80, 0x01, 0x02, 0x03, 0x04, 0x05, 0xff, 0xf7];
+        assert_eq!(pack(&data, &mut encoded), Ok(10));
+        assert_eq!(encoded, [0x41, 0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x7f, 0x01, 0x77]);
+        assert_eq!(pack(&[], &mut []), Ok(0));
+        assert_eq!(pack(&data, &mut [0; 9]), Err(Error::BufferOverflow));
+        assert_eq!(unpack(&encoded[..9], &mut [0; 8]), Err(Error::Malformed));
+        assert_eq!(unpack(&[0x00, 0x80], &mut [0; 1]), Err(Error::Malformed));
+    }
+
+    #[test]
+    fn packs_streams() {
+        let mut packer = Packer::new();
+        let mut encoded = [0; 8];
+        assert_eq!(packer.encode(&[0x80, 0x01, 0x02, 0x03], &mut encoded), Ok(0));
+        assert_eq!(
+            packer.encode(&[0x04, 0x05, 0xff, 0xf7], &mut [0; 7]),
+            Err(Error::BufferOverflow)
+        );
+        assert_eq!(packer.encode(&[0x04, 0x05, 0xff, 0xf7], &mut encoded), Ok(8));
+        assert_eq!(encoded, [0x41, 0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x7f]);
+        assert_eq!(packer.finish(), [0x01, 0x77]);
+        assert_eq!(packer.finish(), []);
+        assert_eq!(packer.push(0xff), None);
+    }
+
+    #[test]
+    fn unpacks_streams() {
+        let mut unpacker = Unpacker::new();
+        let mut data = [0; 8];
+        assert_eq!(unpacker.decode(&[0x41, 0x00, 0x01, 0x02], &mut data), Ok(3));
+        assert_eq!(unpacker.decode(&[0x03, 0x04, 0x05, 0x7f, 0x01], &mut data[3..]), Ok(4));
+        assert!(!unpacker.is_complete());
+        assert_eq!(unpacker.decode(&[0x77, 0x80], &mut data[7..]), Err(Error::Malformed));
+        assert_eq!(unpacker.decode(&[0x77], &mut data[7..]), Ok(1));
+        assert!(unpacker.is_complete());
+        assert_eq!(data, [0x80, 0x01, 0x02, 0x03, 0x04, 0x05, 0xff, 0xf7]);
+    }
+
+    proptest! {
+        #[test]
+        fn round_trips_nibbles(data in prop::collection::vec(any::<u8>(), 0..32), high_first: bool) {
+            let order = if high_first { NibbleOrder::HighFirst } else { NibbleOrder::LowFirst };
+            let mut encoded = [0; 64];
+            let len = nibblize(&data, order, &mut encoded).unwrap();
+            let mut decoded = [0; 32];
+            let len = denibblize(&encoded[..len], order, &mut decoded).unwrap();
+            prop_assert_eq!(&decoded[..len], &data[..]);
+        }
+
+        #[test]
+        fn round_trips_packed_data(data in prop::collection::vec(any::<u8>(), 0..32)) {
+            let mut encoded = [0; packed_len(32)];
+            let len = pack(&data, &mut encoded).unwrap();
+            prop_assert!(encoded[..len].iter().all(|byte| byte & 0x80 == 0));
+            let mut decoded = [0; 32];
+            let len = unpack(&encoded[..len], &mut decoded).unwrap();
+            prop_assert_eq!(&decoded[..len], &data[..]);
+        }
+
+        #[test]
+        fn streams_like_packing(data in prop::collection::vec(any::<u8>(), 0..64), chunk in 1usize..20) {
+            let mut packed = [0; packed_len(64)];
+            let len = pack(&data, &mut packed).unwrap();
+
+            let mut packer = Packer::new();
+            let mut encoded = std::vec::Vec::new();
+            for chunk in data.chunks(chunk) {
+                let mut buf = [0; 24];
+                let len = packer.encode(chunk, &mut buf).unwrap();
+                encoded.extend_from_slice(&buf[..len]);
+            }
+            encoded.extend_from_slice(packer.finish());
+            prop_assert_eq!(&encoded[..], &packed[..len]);
+
+            let mut unpacker = Unpacker::new();
+            let mut decoded = std::vec::Vec::new();
+            for chunk in encoded.chunks(chunk) {
+                let mut buf = [0; 20];
+                let len = unpacker.decode(chunk, &mut buf).unwrap();
+                decoded.extend_from_slice(&buf[..len]);
+            }
+            prop_assert!(unpacker.is_complete());
+            prop_assert_eq!(decoded, data);
+        }
+    }
+}
@@ -0,0 +1,65 @@
+use embassy_futures::select::{select, Either};
+use embassy_sync::blocking_mutex::raw::RawMutex;
+use embassy_sync::signal::Signal;
+use embassy_usb::driver::Driver;
+use embassy_usb::{Config, UsbDevice};
+
+use crate::PowerHandler;
+
+/// Wakes up a suspended host when local MIDI input arrives.
+///
+/// Sources that produce MIDI on their own, such as a DIN input or a keyboard
+/// matrix, call [`activity`](Self::activity) for every message. While the
+/// bus is suspended, the first such call signals remote wakeup so that a
+/// keystroke on the attached instrument wakes the sleeping host.
+pub struct RemoteWakeup<M: RawMutex> {
+    enabled: bool,
+    activity: Signal<M, ()>,
+}
+
+impl<M: RawMutex> RemoteWakeup<M> {
+    pub const fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            activity: Signal::new(),
+        }
+    }
+
+    /// Sets the remote wakeup bit of the configuration descriptor.
+    ///
+    /// Must be called before the configuration is passed to the
+    /// [`Builder`](embassy_usb::Builder).
+    pub fn configure(&self, config: &mut Config<'_>) {
+        config.supports_remote_wakeup = self.enabled;
+    }
+
+    /// Reports local MIDI input.
+    pub fn activity(&self) {
+        if self.enabled {
+            self.activity.signal(());
+        }
+    }
+
+    /// Runs `device`, signaling remote wakeup on activity while suspended.
+    ///
+    /// Replaces [`UsbDevice::run`]. `handler` is notified when the bus is
+    /// suspended and resumed. The host has to enable remote wakeup before
+    /// suspending the bus; if it did not, the device stays suspended until
+    /// the host resumes it.
+    pub async fn run<'d, D: Driver<'d>>(&self, device: &mut UsbDevice<'d, D>, handler: &mut impl PowerHandler) -> ! {
+        loop {
+            device.run_until_suspend().await;
+            handler.suspended();
+            // Input from before the suspend has been delivered already.
+            self.activity.reset();
+            if !self.enabled {
+                device.wait_resume().await;
+            } else if let Either::Second(()) = select(device.wait_resume(), self.activity.wait()).await {
+                if device.remote_wakeup().await.is_err() {
+                    device.wait_resume().await;
+                }
+            }
+            handler.resumed();
+        }
+    }
+}